@@ -69,7 +69,9 @@ fn decode_dictionary(mut encoded_value: &[u8], is_utf8: bool) -> Result<(Value,
             encoded_value = &encoded_value[1..];
             break;
         }
-        match decode_string(encoded_value, true) {
+        // keys that are not UTF-8, such as the pieces roots of v2 piece layers, are base64
+        // encoded like other byte strings
+        match decode_string(encoded_value, true).or_else(|_| decode_string(encoded_value, false)) {
             Ok((map_key, remaining)) => {
                 encoded_value = remaining;
                 match decode_bencoded_value(encoded_value, is_utf8) {
@@ -217,4 +219,16 @@ fn test_decode_dict_with_binary_data() {
     }
 }
 
+    #[test]
+    fn test_decode_dict_with_binary_keys() {
+        let encoded_value = b"d2:\xff\x001:a3:key1:be";
+        let expected = serde_json::json!({
+            general_purpose::STANDARD.encode(b"\xff\x00"): general_purpose::STANDARD.encode(b"a"),
+            "key": general_purpose::STANDARD.encode(b"b")
+        });
+        let (result, remaining) = decode_bencoded_value(encoded_value, false).unwrap();
+        assert_eq!(result, expected);
+        assert!(remaining.is_empty());
+    }
+
 }
@@ -15,6 +15,22 @@ pub fn encode_bencoded_value(serde_json_object: &Value) -> Result<Vec<u8>, Box<d
     }
 }
 
+// Encodes a dictionary whose keys are arbitrary bytes (e.g. raw info hashes), which cannot be
+// represented as keys of a serde_json object
+pub fn encode_bencoded_dict_with_byte_keys(entries: &[(Vec<u8>, Value)]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut sorted_entries: Vec<&(Vec<u8>, Value)> = entries.iter().collect();
+    sorted_entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut encoded_dict: Vec<u8> = b"d".to_vec();
+    for (key, value) in sorted_entries {
+        encoded_dict.extend_from_slice(format!("{}:", key.len()).as_bytes());
+        encoded_dict.extend_from_slice(key);
+        encoded_dict.extend_from_slice(&encode_bencoded_value(value)?);
+    }
+    encoded_dict.extend_from_slice(b"e");
+    Ok(encoded_dict)
+}
+
 fn encode_list(serde_json_object: &Value) -> Result<Vec<u8>> {
     let decoded_array = serde_json_object.as_array().ok_or_else(|| anyhow!("Value could not be parsed as array"))?;
     let mut encoded_list: Vec<u8> = b"l".to_vec();
@@ -164,6 +180,16 @@ mod tests {
         helper_test_complex(&given, b"d7:testkeyl4:testi104eli23e6:nestedei203eee".to_vec(), "simple list");
 
     }
+    #[test]
+    fn test_encode_dict_with_byte_keys() {
+        let entries = vec![
+            (b"b\xff".to_vec(), serde_json::json!(2)),
+            (b"a\x80".to_vec(), serde_json::json!(1)),
+        ];
+        let result = encode_bencoded_dict_with_byte_keys(&entries).unwrap();
+        assert_eq!(result, b"d2:a\x80i1e2:b\xffi2ee".to_vec());
+    }
+
     fn helper_test_complex(given: &Value, expectation: Vec<u8>, testname: &str) {
 
         let decoded_json = given;
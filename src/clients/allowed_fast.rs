@@ -0,0 +1,43 @@
+use std::net::Ipv4Addr;
+use sha1::{Digest, Sha1};
+
+// Pieces we let a peer download while it is choked
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+// The allowed fast set of a peer as defined by BEP 6. It depends only on the peer's /24 network
+// and the torrent, so peers cannot collect more pieces by reconnecting from nearby addresses
+pub fn allowed_fast_set(address: Ipv4Addr, info_hash: &[u8; 20], piece_count: u32, size: usize) -> Vec<u32> {
+    let size = size.min(piece_count as usize);
+    let mut set = Vec::with_capacity(size);
+    let mut x = (u32::from(address) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < size {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks(4) {
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % piece_count;
+            if set.len() < size && !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_sets() {
+        // the examples of BEP 6
+        let address = Ipv4Addr::new(80, 4, 4, 200);
+        assert_eq!(allowed_fast_set(address, &[0xaa; 20], 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(allowed_fast_set(address, &[0xaa; 20], 1313, 9), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+        // the same network gets the same set, small torrents get every piece
+        assert_eq!(allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), &[0xaa; 20], 1313, 7)[0], 1059);
+        let mut all = allowed_fast_set(address, &[0xaa; 20], 3, 10);
+        all.sort();
+        assert_eq!(all, vec![0, 1, 2]);
+    }
+}
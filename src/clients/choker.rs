@@ -0,0 +1,314 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+use crate::utils;
+
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+pub const DEFAULT_OPTIMISTIC_SLOTS: usize = 1;
+
+// Time between two choking rounds
+const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// Optimistic unchokes move on every third round, i.e. every 30 seconds
+const OPTIMISTIC_ROUNDS: u64 = 3;
+// A peer we want data from that sent no block for this long is snubbing us
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// Peers connected for less than this are three times as likely to be unchoked optimistically,
+// since they have no pieces to trade yet
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+
+// What a choking round knows about a connection
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub peer: usize,
+    pub download_rate: u64, // bytes per second received from the peer over the last round
+    pub upload_rate: u64,   // bytes per second sent to the peer over the last round
+    pub interested: bool,
+    pub snubbed: bool,
+    pub connected_for: Duration,
+}
+
+// Decides which peers we upload to
+pub trait Choker: Send {
+    // Returns the peers to unchoke. Optimistic unchokes should only move on when asked to
+    fn rechoke(&mut self, peers: &[PeerStats], seeding: bool, rotate_optimistic: bool) -> HashSet<usize>;
+}
+
+// The standard choker: the interested peers that give us the most get the upload slots, plus a
+// few optimistic slots for peers that may turn out to give more. Snubbing peers are only
+// unchoked optimistically
+pub struct TitForTat {
+    upload_slots: usize,
+    optimistic_slots: usize,
+    optimistic: Vec<usize>,
+}
+
+impl TitForTat {
+    pub fn new(upload_slots: usize, optimistic_slots: usize) -> Self {
+        Self { upload_slots, optimistic_slots, optimistic: vec![] }
+    }
+}
+
+impl Choker for TitForTat {
+    fn rechoke(&mut self, peers: &[PeerStats], seeding: bool, rotate_optimistic: bool) -> HashSet<usize> {
+        // when seeding the peers we upload to fastest are preferred, as they spread the data best
+        let mut candidates: Vec<&PeerStats> = peers.iter()
+            .filter(|stats| stats.interested && (seeding || !stats.snubbed))
+            .collect();
+        candidates.sort_by_key(|stats| std::cmp::Reverse(if seeding { stats.upload_rate } else { stats.download_rate }));
+        let regular: HashSet<usize> = candidates.iter().take(self.upload_slots).map(|stats| stats.peer).collect();
+
+        if rotate_optimistic {
+            self.optimistic.clear();
+        }
+        let interested: HashSet<usize> = peers.iter().filter(|stats| stats.interested).map(|stats| stats.peer).collect();
+        self.optimistic.retain(|peer| interested.contains(peer) && !regular.contains(peer));
+        while self.optimistic.len() < self.optimistic_slots {
+            let choked: Vec<&PeerStats> = peers.iter()
+                .filter(|stats| stats.interested && !regular.contains(&stats.peer) && !self.optimistic.contains(&stats.peer))
+                .collect();
+            let weights: Vec<usize> = choked.iter()
+                .map(|stats| if stats.connected_for < NEW_PEER_AGE { 3 } else { 1 })
+                .collect();
+            let total: usize = weights.iter().sum();
+            if total == 0 {
+                break;
+            }
+            let mut ticket = utils::random_below(total);
+            for (stats, weight) in choked.iter().zip(weights) {
+                if ticket < weight {
+                    self.optimistic.push(stats.peer);
+                    break;
+                }
+                ticket -= weight;
+            }
+        }
+
+        regular.into_iter().chain(self.optimistic.iter().copied()).collect()
+    }
+}
+
+// Transfer counters and interest of one connection, updated by the connection
+struct PeerLinkState {
+    connected_at: Instant,
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    peer_interested: AtomicBool,
+    am_interested: AtomicBool,
+    last_block: Mutex<Instant>,
+    unchoke: watch::Sender<bool>,
+}
+
+// Runs the choker over the connections of a torrent and tells each one whether to unchoke its
+// peer. Peers becoming interested are considered right away rather than at the next round, so
+// free upload slots are not left unused
+pub struct ChokeController {
+    choker: Mutex<Box<dyn Choker>>,
+    links: Mutex<HashMap<usize, Arc<PeerLinkState>>>,
+    // byte counters at the last round, to compute the rates
+    last_counts: Mutex<HashMap<usize, (u64, u64)>>,
+    rates: Mutex<HashMap<usize, (u64, u64)>>,
+    next_peer: AtomicUsize,
+    interest_changed: Notify,
+}
+
+impl ChokeController {
+    pub fn new(choker: Box<dyn Choker>) -> Self {
+        Self {
+            choker: Mutex::new(choker),
+            links: Mutex::new(HashMap::new()),
+            last_counts: Mutex::new(HashMap::new()),
+            rates: Mutex::new(HashMap::new()),
+            next_peer: AtomicUsize::new(0),
+            interest_changed: Notify::new(),
+        }
+    }
+
+    // Add a connection; it starts out choked and leaves the rounds when the link is dropped
+    pub fn register(self: &Arc<Self>) -> PeerLink {
+        let peer = self.next_peer.fetch_add(1, Ordering::Relaxed);
+        let (unchoke, unchoke_receiver) = watch::channel(false);
+        let state = Arc::new(PeerLinkState {
+            connected_at: Instant::now(),
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            peer_interested: AtomicBool::new(false),
+            am_interested: AtomicBool::new(false),
+            last_block: Mutex::new(Instant::now()),
+            unchoke,
+        });
+        self.links.lock().unwrap().insert(peer, state.clone());
+        PeerLink { controller: self.clone(), peer, state, unchoke: unchoke_receiver }
+    }
+
+    // Run a round every ten seconds and whenever a peer's interest changed
+    pub async fn run(&self, seeding: bool) {
+        let mut interval = tokio::time::interval(CHOKE_INTERVAL);
+        let mut round = 0;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.update_rates(CHOKE_INTERVAL);
+                    self.rechoke(seeding, round % OPTIMISTIC_ROUNDS == 0);
+                    round += 1;
+                }
+                _ = self.interest_changed.notified() => self.rechoke(seeding, false),
+            }
+        }
+    }
+
+    fn update_rates(&self, elapsed: Duration) {
+        let links = self.links.lock().unwrap();
+        let mut last_counts = self.last_counts.lock().unwrap();
+        let mut rates = self.rates.lock().unwrap();
+        last_counts.retain(|peer, _| links.contains_key(peer));
+        rates.clear();
+        for (&peer, state) in links.iter() {
+            let counts = (state.downloaded.load(Ordering::Relaxed), state.uploaded.load(Ordering::Relaxed));
+            let (last_downloaded, last_uploaded) = last_counts.insert(peer, counts).unwrap_or_default();
+            let per_second = |bytes: u64| (bytes as f64 / elapsed.as_secs_f64()) as u64;
+            rates.insert(peer, (per_second(counts.0 - last_downloaded), per_second(counts.1 - last_uploaded)));
+        }
+    }
+
+    fn rechoke(&self, seeding: bool, rotate_optimistic: bool) {
+        let links = self.links.lock().unwrap();
+        let rates = self.rates.lock().unwrap();
+        let stats: Vec<PeerStats> = links.iter()
+            .map(|(&peer, state)| {
+                let (download_rate, upload_rate) = rates.get(&peer).copied().unwrap_or_default();
+                PeerStats {
+                    peer,
+                    download_rate,
+                    upload_rate,
+                    interested: state.peer_interested.load(Ordering::Relaxed),
+                    snubbed: state.am_interested.load(Ordering::Relaxed) && state.last_block.lock().unwrap().elapsed() > SNUB_TIMEOUT,
+                    connected_for: state.connected_at.elapsed(),
+                }
+            })
+            .collect();
+
+        let unchoked = self.choker.lock().unwrap().rechoke(&stats, seeding, rotate_optimistic);
+        for (peer, state) in links.iter() {
+            state.unchoke.send_if_modified(|unchoke| {
+                let modified = *unchoke != unchoked.contains(peer);
+                *unchoke = unchoked.contains(peer);
+                modified
+            });
+        }
+    }
+}
+
+// The handle of a connection to the choke controller
+pub struct PeerLink {
+    controller: Arc<ChokeController>,
+    peer: usize,
+    state: Arc<PeerLinkState>,
+    unchoke: watch::Receiver<bool>,
+}
+
+impl PeerLink {
+    // Wait until the controller decides differently on unchoking the peer; returns the decision
+    pub async fn changed(&mut self) -> Option<bool> {
+        self.unchoke.changed().await.ok()?;
+        Some(*self.unchoke.borrow())
+    }
+
+    pub fn set_peer_interested(&self, interested: bool) {
+        if self.state.peer_interested.swap(interested, Ordering::Relaxed) != interested {
+            self.controller.interest_changed.notify_one();
+        }
+    }
+
+    pub fn set_am_interested(&self, interested: bool) {
+        if !self.state.am_interested.swap(interested, Ordering::Relaxed) && interested {
+            // the peer gets a full snub timeout to answer
+            *self.state.last_block.lock().unwrap() = Instant::now();
+        }
+    }
+
+    pub fn record_download(&self, bytes: usize) {
+        self.state.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        *self.state.last_block.lock().unwrap() = Instant::now();
+    }
+
+    pub fn record_upload(&self, bytes: usize) {
+        self.state.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for PeerLink {
+    fn drop(&mut self) {
+        self.controller.links.lock().unwrap().remove(&self.peer);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(peer: usize, download_rate: u64, upload_rate: u64) -> PeerStats {
+        PeerStats { peer, download_rate, upload_rate, interested: true, snubbed: false, connected_for: NEW_PEER_AGE * 2 }
+    }
+
+    #[test]
+    fn test_unchokes_best_peers_and_one_optimistic() {
+        let mut choker = TitForTat::new(2, 1);
+        let mut peers = vec![stats(0, 100, 5), stats(1, 300, 1), stats(2, 200, 9), stats(3, 50, 3)];
+        peers[3].interested = false;
+
+        let unchoked = choker.rechoke(&peers, false, true);
+        assert!(unchoked.contains(&1) && unchoked.contains(&2));
+        assert_eq!(unchoked.len(), 3);
+        // the only interested choked peer gets the optimistic slot
+        assert!(unchoked.contains(&0));
+
+        // seeding ranks by upload rate, the optimistic peer stays until rotated
+        let unchoked = choker.rechoke(&peers, true, false);
+        assert_eq!(unchoked, HashSet::from([2, 0, 1]));
+
+        // a snubbing peer loses its regular slot while downloading
+        let mut choker = TitForTat::new(2, 0);
+        peers[1].snubbed = true;
+        assert_eq!(choker.rechoke(&peers, false, false), HashSet::from([2, 0]));
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_favours_new_peers() {
+        let mut choker = TitForTat::new(0, 1);
+        let mut peers: Vec<PeerStats> = (0..4).map(|peer| stats(peer, 0, 0)).collect();
+        peers[3].connected_for = Duration::from_secs(1);
+
+        let picks = (0..600).filter(|_| choker.rechoke(&peers, false, true).contains(&3)).count();
+        // a new peer has half the weight of all peers, the others a sixth each
+        assert!(picks > 200 && picks < 400, "{}", picks);
+    }
+
+    #[tokio::test]
+    async fn test_controller_follows_interest() {
+        let controller = Arc::new(ChokeController::new(Box::new(TitForTat::new(1, 0))));
+        let mut first = controller.register();
+        let mut second = controller.register();
+        first.record_download(5000);
+        second.record_download(1000);
+        controller.update_rates(Duration::from_secs(1));
+
+        second.set_peer_interested(true);
+        controller.rechoke(false, false);
+        assert_eq!(second.changed().await, Some(true));
+
+        // the faster peer takes the only slot once interested
+        first.set_peer_interested(true);
+        controller.rechoke(false, false);
+        assert_eq!(first.changed().await, Some(true));
+        assert_eq!(second.changed().await, Some(false));
+
+        drop(first);
+        controller.rechoke(false, false);
+        assert_eq!(second.changed().await, Some(true));
+    }
+}
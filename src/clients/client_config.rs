@@ -0,0 +1,150 @@
+use crate::utils;
+use crate::clients::choker::{Choker, TitForTat, DEFAULT_OPTIMISTIC_SLOTS, DEFAULT_UPLOAD_SLOTS};
+use crate::clients::mse::EncryptionPolicy;
+use crate::clients::utp::Transport;
+
+// Azureus-style client prefix: '-', two letter client id, four digit version, '-'
+const PEER_ID_PREFIX: &[u8; 8] = b"-XX0100-";
+
+pub const DEFAULT_PORT: u16 = 6881;
+pub const DEFAULT_MAX_OUTSTANDING_REQUESTS: usize = 64;
+
+// Session-wide identity shared by the tracker and peer clients
+#[derive(Clone)]
+pub struct ClientConfig {
+    peer_id: [u8; 20],
+    port: u16,
+    key: String,
+    max_outstanding_requests: usize,
+    upload_slots: usize,
+    optimistic_slots: usize,
+    outgoing_encryption: EncryptionPolicy,
+    incoming_encryption: EncryptionPolicy,
+    transports: Vec<Transport>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            peer_id: generate_peer_id(),
+            port: DEFAULT_PORT,
+            key: hex::encode(utils::random_bytes(4)),
+            max_outstanding_requests: DEFAULT_MAX_OUTSTANDING_REQUESTS,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            optimistic_slots: DEFAULT_OPTIMISTIC_SLOTS,
+            // encrypted connections are accepted, but only opened when asked to
+            outgoing_encryption: EncryptionPolicy::Disable,
+            incoming_encryption: EncryptionPolicy::Prefer,
+            // uTP is tried when a peer does not take TCP connections
+            transports: vec![Transport::Tcp, Transport::Utp],
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Getter for peer_id
+    pub fn get_peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    // Setter for port
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    // Getter for port
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    // Setter for the announce key
+    pub fn set_key(&mut self, key: String) {
+        self.key = key;
+    }
+
+    // Getter for the announce key
+    pub fn get_key(&self) -> &String {
+        &self.key
+    }
+
+    // Setter for the upper bound of block requests kept outstanding per peer
+    pub fn set_max_outstanding_requests(&mut self, max_outstanding_requests: usize) {
+        self.max_outstanding_requests = max_outstanding_requests;
+    }
+
+    // Getter for the upper bound of block requests kept outstanding per peer
+    pub fn get_max_outstanding_requests(&self) -> usize {
+        self.max_outstanding_requests
+    }
+
+    // Setter for the number of peers unchoked for their transfer rates
+    pub fn set_upload_slots(&mut self, upload_slots: usize) {
+        self.upload_slots = upload_slots;
+    }
+
+    // Setter for the number of peers unchoked optimistically
+    pub fn set_optimistic_slots(&mut self, optimistic_slots: usize) {
+        self.optimistic_slots = optimistic_slots;
+    }
+
+    // Setter for the encryption of connections we open
+    pub fn set_outgoing_encryption(&mut self, outgoing_encryption: EncryptionPolicy) {
+        self.outgoing_encryption = outgoing_encryption;
+    }
+
+    // Getter for the encryption of connections we open
+    pub fn get_outgoing_encryption(&self) -> EncryptionPolicy {
+        self.outgoing_encryption
+    }
+
+    // Setter for the encryption of connections peers open to us
+    pub fn set_incoming_encryption(&mut self, incoming_encryption: EncryptionPolicy) {
+        self.incoming_encryption = incoming_encryption;
+    }
+
+    // Getter for the encryption of connections peers open to us
+    pub fn get_incoming_encryption(&self) -> EncryptionPolicy {
+        self.incoming_encryption
+    }
+
+    // Setter for the transports outgoing connections try, in order of preference
+    pub fn set_transports(&mut self, transports: Vec<Transport>) {
+        self.transports = transports;
+    }
+
+    // Getter for the transports outgoing connections try, in order of preference
+    pub fn get_transports(&self) -> &[Transport] {
+        &self.transports
+    }
+
+    // The standard tit-for-tat choker with the configured slots
+    pub fn create_choker(&self) -> Box<dyn Choker> {
+        Box::new(TitForTat::new(self.upload_slots, self.optimistic_slots))
+    }
+}
+
+// Generates a peer id made of the client prefix followed by 12 random bytes
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    peer_id[8..].copy_from_slice(&utils::random_bytes(12));
+    peer_id
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_id_is_azureus_style_and_stable() {
+        let config = ClientConfig::new();
+        assert_eq!(&config.get_peer_id()[..8], b"-XX0100-");
+        assert_eq!(config.clone().get_peer_id(), config.get_peer_id());
+        assert_ne!(ClientConfig::new().get_peer_id(), config.get_peer_id());
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+
+use crate::clients::peer_message::PeerMessage;
+
+// Choke and interest flags of both sides of a connection (BEP 3). Both sides start out choking
+// and not interested
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        Self { am_choking: true, am_interested: false, peer_choking: true, peer_interested: false }
+    }
+}
+
+impl ConnectionState {
+    pub fn on_received(&mut self, message: &PeerMessage) {
+        match message {
+            PeerMessage::Choke => self.peer_choking = true,
+            PeerMessage::Unchoke => self.peer_choking = false,
+            PeerMessage::Interested => self.peer_interested = true,
+            PeerMessage::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+    }
+
+    pub fn on_sent(&mut self, message: &PeerMessage) {
+        match message {
+            PeerMessage::Choke => self.am_choking = true,
+            PeerMessage::Unchoke => self.am_choking = false,
+            PeerMessage::Interested => self.am_interested = true,
+            PeerMessage::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |value: bool| if value { "yes" } else { "no" };
+        write!(
+            f,
+            "we choke: {}, we are interested: {}, peer chokes: {}, peer is interested: {}",
+            flag(self.am_choking), flag(self.am_interested), flag(self.peer_choking), flag(self.peer_interested)
+        )
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        let mut state = ConnectionState::default();
+        state.on_sent(&PeerMessage::Interested);
+        state.on_received(&PeerMessage::Have { index: 1 });
+        state.on_received(&PeerMessage::Unchoke);
+        assert_eq!(state, ConnectionState { am_choking: true, am_interested: true, peer_choking: false, peer_interested: false });
+
+        state.on_received(&PeerMessage::Interested);
+        state.on_sent(&PeerMessage::Unchoke);
+        state.on_received(&PeerMessage::Choke);
+        state.on_sent(&PeerMessage::NotInterested);
+        assert_eq!(state, ConnectionState { am_choking: false, am_interested: false, peer_choking: true, peer_interested: true });
+        assert_eq!(state.to_string(), "we choke: no, we are interested: no, peer chokes: yes, peer is interested: yes");
+    }
+}
@@ -0,0 +1,219 @@
+use crate::utils;
+
+// Length of public keys and shared secrets in bytes
+pub const KEY_LENGTH: usize = 96;
+
+// The 768 bit prime of the MSE key exchange, big endian; the generator is 2
+const PRIME: [u8; KEY_LENGTH] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const GENERATOR: u32 = 2;
+// Private keys of 160 bits are enough for the strength of a 768 bit group
+const PRIVATE_KEY_LENGTH: usize = 20;
+
+const LIMBS: usize = KEY_LENGTH / 4;
+
+// A number below 2^768 as 32 bit limbs, least significant first
+type Limbs = [u32; LIMBS];
+
+// One side of the key exchange
+pub struct KeyPair {
+    private_key: Vec<u8>,
+    public_key: [u8; KEY_LENGTH],
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let private_key = utils::random_bytes(PRIVATE_KEY_LENGTH);
+        let mut generator = [0u8; KEY_LENGTH];
+        generator[KEY_LENGTH - 4..].copy_from_slice(&GENERATOR.to_be_bytes());
+        let public_key = Modulus::new().pow(&generator, &private_key);
+        Self { private_key, public_key }
+    }
+
+    // Getter for public_key
+    pub fn get_public_key(&self) -> &[u8; KEY_LENGTH] {
+        &self.public_key
+    }
+
+    // The secret both sides arrive at from their private key and the other side's public key
+    pub fn shared_secret(&self, peer_public_key: &[u8; KEY_LENGTH]) -> [u8; KEY_LENGTH] {
+        Modulus::new().pow(peer_public_key, &self.private_key)
+    }
+}
+
+// Modular exponentiation with the prime, using Montgomery multiplication
+struct Modulus {
+    prime: Limbs,
+    // -prime^-1 mod 2^32
+    inverse: u32,
+    // 2^(2*768) mod prime, to move numbers into Montgomery form
+    r_squared: Limbs,
+}
+
+impl Modulus {
+    fn new() -> Self {
+        let prime = from_bytes(&PRIME);
+        let mut inverse: u32 = 1;
+        // Newton's iteration doubles the correct low bits each round
+        for _ in 0..5 {
+            inverse = inverse.wrapping_mul(2u32.wrapping_sub(prime[0].wrapping_mul(inverse)));
+        }
+        let mut r_squared = [0u32; LIMBS];
+        r_squared[0] = 1;
+        for _ in 0..2 * 32 * LIMBS {
+            let carry = shift_left(&mut r_squared);
+            if carry || !less_than(&r_squared, &prime) {
+                subtract(&mut r_squared, &prime);
+            }
+        }
+        Self { prime, inverse: inverse.wrapping_neg(), r_squared }
+    }
+
+    // base^exponent mod prime, with the exponent as big endian bytes
+    fn pow(&self, base: &[u8; KEY_LENGTH], exponent: &[u8]) -> [u8; KEY_LENGTH] {
+        let mut base = from_bytes(base);
+        // 2^768 is less than twice the prime, so one subtraction reduces any base
+        if !less_than(&base, &self.prime) {
+            subtract(&mut base, &self.prime);
+        }
+        let mut one = [0u32; LIMBS];
+        one[0] = 1;
+
+        let base = self.multiply(&base, &self.r_squared);
+        let mut result = self.multiply(&one, &self.r_squared);
+        for byte in exponent {
+            for bit in (0..8).rev() {
+                result = self.multiply(&result, &result);
+                if byte >> bit & 1 == 1 {
+                    result = self.multiply(&result, &base);
+                }
+            }
+        }
+        to_bytes(&self.multiply(&result, &one))
+    }
+
+    // a * b / 2^768 mod prime
+    fn multiply(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let mut t = [0u32; LIMBS + 2];
+        for &b_limb in b {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let sum = t[j] as u64 + a[j] as u64 * b_limb as u64 + carry;
+                t[j] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = t[LIMBS] as u64 + carry;
+            t[LIMBS] = sum as u32;
+            t[LIMBS + 1] = (sum >> 32) as u32;
+
+            // add a multiple of the prime that clears the lowest limb, then drop that limb
+            let m = t[0].wrapping_mul(self.inverse) as u64;
+            let mut carry = (t[0] as u64 + m * self.prime[0] as u64) >> 32;
+            for j in 1..LIMBS {
+                let sum = t[j] as u64 + m * self.prime[j] as u64 + carry;
+                t[j - 1] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = t[LIMBS] as u64 + carry;
+            t[LIMBS - 1] = sum as u32;
+            t[LIMBS] = t[LIMBS + 1] + (sum >> 32) as u32;
+        }
+
+        let mut result: Limbs = t[..LIMBS].try_into().unwrap();
+        if t[LIMBS] != 0 || !less_than(&result, &self.prime) {
+            subtract(&mut result, &self.prime);
+        }
+        result
+    }
+}
+
+fn from_bytes(bytes: &[u8; KEY_LENGTH]) -> Limbs {
+    let mut limbs = [0u32; LIMBS];
+    for (i, chunk) in bytes.rchunks(4).enumerate() {
+        limbs[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+    }
+    limbs
+}
+
+fn to_bytes(limbs: &Limbs) -> [u8; KEY_LENGTH] {
+    let mut bytes = [0u8; KEY_LENGTH];
+    for (i, chunk) in bytes.rchunks_mut(4).enumerate() {
+        chunk.copy_from_slice(&limbs[i].to_be_bytes());
+    }
+    bytes
+}
+
+fn less_than(a: &Limbs, b: &Limbs) -> bool {
+    a.iter().rev().lt(b.iter().rev())
+}
+
+// a -= b, wrapping around when b is larger
+fn subtract(a: &mut Limbs, b: &Limbs) {
+    let mut borrow = false;
+    for (a_limb, &b_limb) in a.iter_mut().zip(b) {
+        let (difference, borrow_a) = a_limb.overflowing_sub(b_limb);
+        let (difference, borrow_b) = difference.overflowing_sub(borrow as u32);
+        *a_limb = difference;
+        borrow = borrow_a || borrow_b;
+    }
+}
+
+// Returns the bit shifted out at the top
+fn shift_left(a: &mut Limbs) -> bool {
+    let mut carry = 0;
+    for limb in a.iter_mut() {
+        let next_carry = *limb >> 31;
+        *limb = *limb << 1 | carry;
+        carry = next_carry;
+    }
+    carry == 1
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator() -> [u8; KEY_LENGTH] {
+        let mut generator = [0u8; KEY_LENGTH];
+        generator[KEY_LENGTH - 1] = 2;
+        generator
+    }
+
+    #[test]
+    fn test_modular_exponentiation() {
+        let modulus = Modulus::new();
+        let mut expected = [0u8; KEY_LENGTH];
+        expected[KEY_LENGTH - 2..].copy_from_slice(&[0x04, 0x00]);
+        assert_eq!(modulus.pow(&generator(), &[10]), expected);
+
+        // computed independently
+        let exponent = hex::decode("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let expected = hex::decode(concat!(
+            "6fd4bc7aa649593205ec30348a3ccc737b61fa01e9e1762c2c53eb69033afecbdf7c13b8ac3643af78d0760b0f42db00",
+            "9f2b96c970f009d060faf617f117d0f1c221cea0561b9a86e852fc70a6f09ad0f82378603aa5e56b811deb3f534bf276",
+        )).unwrap();
+        assert_eq!(modulus.pow(&generator(), &exponent).to_vec(), expected);
+
+        // Fermat's little theorem: 2^(p-1) = 1 mod p
+        let mut p_minus_one = PRIME;
+        p_minus_one[KEY_LENGTH - 1] -= 1;
+        let mut one = [0u8; KEY_LENGTH];
+        one[KEY_LENGTH - 1] = 1;
+        assert_eq!(modulus.pow(&generator(), &p_minus_one), one);
+    }
+
+    #[test]
+    fn test_both_sides_agree_on_the_secret() {
+        let a = KeyPair::generate();
+        let b = KeyPair::generate();
+        assert_ne!(a.get_public_key(), b.get_public_key());
+        assert_eq!(a.shared_secret(b.get_public_key()), b.shared_secret(a.get_public_key()));
+    }
+}
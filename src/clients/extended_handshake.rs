@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+// Extended message id reserved for the extension protocol handshake (BEP 10)
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+// Payload of the extension protocol handshake; unknown keys are ignored
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ExtendedHandshake {
+    // Supported extension messages and the ids the sender wants them to use
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    // Local TCP listen port of the sender
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    // Client name and version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    // Number of outstanding requests the sender accepts without dropping any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+}
+
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<ExtendedHandshake, Box<dyn Error>> {
+        Ok(serde_bencode::from_bytes(payload)?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let handshake = ExtendedHandshake { p: Some(6881), reqq: Some(250), ..Default::default() };
+        assert_eq!(handshake.encode().unwrap(), b"d1:mde1:pi6881e4:reqqi250ee".to_vec());
+    }
+
+    #[test]
+    fn test_decode_ignores_unknown_keys() {
+        let handshake = ExtendedHandshake::decode(b"d1:md11:ut_metadatai3ee6:yourip4:\x7f\x00\x00\x014:reqqi500e1:v5:x 1.0e").unwrap();
+        assert_eq!(handshake.m.get("ut_metadata"), Some(&3));
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.v.as_deref(), Some("x 1.0"));
+        assert_eq!(handshake.p, None);
+    }
+}
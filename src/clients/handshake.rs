@@ -0,0 +1,158 @@
+use thiserror::Error;
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LENGTH: usize = 68;
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("peer speaks an unknown protocol: {0:?}")]
+    InvalidProtocol(String),
+    #[error("peer answered for info hash {got}, expected {expected}")]
+    InfoHashMismatch { expected: String, got: String },
+    #[error("connected to ourselves")]
+    SelfConnection,
+}
+
+// Optional protocol features advertised through the reserved bytes of the handshake
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    pub dht: bool,                // BEP 5, bit 0x01 of the last byte
+    pub fast: bool,               // BEP 6, bit 0x04 of the last byte
+    pub extension_protocol: bool, // BEP 10, bit 0x10 of the sixth byte
+}
+
+impl Capabilities {
+    pub fn from_reserved(reserved: &[u8; 8]) -> Self {
+        Self {
+            dht: reserved[7] & 0x01 != 0,
+            fast: reserved[7] & 0x04 != 0,
+            extension_protocol: reserved[5] & 0x10 != 0,
+        }
+    }
+
+    pub fn to_reserved(self) -> [u8; 8] {
+        let mut reserved = [0u8; 8];
+        if self.dht {
+            reserved[7] |= 0x01;
+        }
+        if self.fast {
+            reserved[7] |= 0x04;
+        }
+        if self.extension_protocol {
+            reserved[5] |= 0x10;
+        }
+        reserved
+    }
+
+    // Features usable on a connection are those both sides advertise
+    pub fn intersect(self, other: Capabilities) -> Capabilities {
+        Capabilities {
+            dht: self.dht && other.dht,
+            fast: self.fast && other.fast,
+            extension_protocol: self.extension_protocol && other.extension_protocol,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Handshake {
+    reserved: [u8; 8],
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(capabilities: Capabilities, info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self { reserved: capabilities.to_reserved(), info_hash, peer_id }
+    }
+
+    // Getter for peer_id
+    pub fn get_peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    // Getter for info_hash
+    pub fn get_info_hash(&self) -> &[u8; 20] {
+        &self.info_hash
+    }
+
+    pub fn get_capabilities(&self) -> Capabilities {
+        Capabilities::from_reserved(&self.reserved)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(HANDSHAKE_LENGTH);
+        message.push(PROTOCOL.len() as u8);
+        message.extend_from_slice(PROTOCOL);
+        message.extend_from_slice(&self.reserved);
+        message.extend_from_slice(&self.info_hash);
+        message.extend_from_slice(&self.peer_id);
+        message
+    }
+
+    pub fn decode(message: &[u8; HANDSHAKE_LENGTH]) -> Result<Handshake, HandshakeError> {
+        if message[0] as usize != PROTOCOL.len() || &message[1..20] != PROTOCOL {
+            return Err(HandshakeError::InvalidProtocol(String::from_utf8_lossy(&message[1..20]).to_string()));
+        }
+        Ok(Handshake {
+            reserved: message[20..28].try_into().unwrap(),
+            info_hash: message[28..48].try_into().unwrap(),
+            peer_id: message[48..68].try_into().unwrap(),
+        })
+    }
+
+    // Check the remote handshake against the torrent we asked for and our own peer id
+    pub fn validate(&self, expected_info_hash: &[u8; 20], own_peer_id: &[u8; 20]) -> Result<(), HandshakeError> {
+        if &self.info_hash != expected_info_hash {
+            return Err(HandshakeError::InfoHashMismatch {
+                expected: hex::encode(expected_info_hash),
+                got: hex::encode(self.info_hash),
+            });
+        }
+        if &self.peer_id == own_peer_id {
+            return Err(HandshakeError::SelfConnection);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_capabilities() {
+        let capabilities = Capabilities { dht: true, fast: true, extension_protocol: true };
+        let handshake = Handshake::new(capabilities, [1; 20], [2; 20]);
+        let encoded = handshake.encode();
+        assert_eq!(encoded.len(), HANDSHAKE_LENGTH);
+        assert_eq!(&encoded[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+
+        let decoded = Handshake::decode(&encoded.try_into().unwrap()).unwrap();
+        assert_eq!(decoded, handshake);
+        assert_eq!(decoded.get_capabilities(), capabilities);
+    }
+
+    #[test]
+    fn test_rejects_wrong_protocol() {
+        let mut encoded = Handshake::new(Capabilities::default(), [1; 20], [2; 20]).encode();
+        encoded[1] = b'b';
+        assert!(matches!(Handshake::decode(&encoded.try_into().unwrap()), Err(HandshakeError::InvalidProtocol(_))));
+    }
+
+    #[test]
+    fn test_validate() {
+        let handshake = Handshake::new(Capabilities::default(), [1; 20], [2; 20]);
+        assert!(handshake.validate(&[1; 20], &[3; 20]).is_ok());
+        assert!(matches!(handshake.validate(&[9; 20], &[3; 20]), Err(HandshakeError::InfoHashMismatch { .. })));
+        assert!(matches!(handshake.validate(&[1; 20], &[2; 20]), Err(HandshakeError::SelfConnection)));
+    }
+
+    #[test]
+    fn test_capability_intersection() {
+        let ours = Capabilities { dht: false, fast: true, extension_protocol: true };
+        let theirs = Capabilities { dht: true, fast: true, extension_protocol: false };
+        assert_eq!(ours.intersect(theirs), Capabilities { dht: false, fast: true, extension_protocol: false });
+    }
+}
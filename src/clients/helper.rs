@@ -1,15 +1,103 @@
-use std::collections::HashMap;
+use percent_encoding::{percent_encode, AsciiSet, NON_ALPHANUMERIC};
 
-pub fn create_request_url(root_url: String, params: HashMap<&str, String>) -> String {
-    let mut query_string = String::new();
-    for (key, value) in &params {
-        if !query_string.is_empty() {
-            query_string.push('&');
+// Everything except the unreserved characters of RFC 3986 is escaped, as expected by trackers
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+// Builds tracker request urls; parameters keep their insertion order and are appended to any
+// query the announce url already has (e.g. private tracker passkeys)
+pub struct TrackerUrlBuilder {
+    base_url: String,
+    params: Vec<(String, String)>,
+}
+
+impl TrackerUrlBuilder {
+    pub fn new(base_url: &str) -> Self {
+        Self { base_url: base_url.to_string(), params: vec![] }
+    }
+
+    // Add a textual parameter
+    pub fn param(self, key: &str, value: &str) -> Self {
+        self.bytes_param(key, value.as_bytes())
+    }
+
+    // Add a parameter holding raw bytes such as an info hash or peer id
+    pub fn bytes_param(mut self, key: &str, value: &[u8]) -> Self {
+        self.params.push((
+            percent_encode(key.as_bytes(), QUERY_ENCODE_SET).to_string(),
+            percent_encode(value, QUERY_ENCODE_SET).to_string(),
+        ));
+        self
+    }
+
+    pub fn build(&self) -> String {
+        // A fragment is never sent to the server, so it is dropped
+        let base_url = self.base_url.split('#').next().unwrap_or_default();
+        if self.params.is_empty() {
+            return base_url.to_string();
         }
-        query_string.push_str(&format!("{}={}", key, value));
+
+        let query_string = self.params.iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let separator = match base_url.find('?') {
+            None => "?",
+            Some(_) if base_url.ends_with('?') || base_url.ends_with('&') => "",
+            Some(_) => "&",
+        };
+        format!("{}{}{}", base_url, separator, query_string)
     }
-    
-    // Construct the full URL
-    format!("{}?{}", root_url, query_string)
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builds_query_in_insertion_order() {
+        let url = TrackerUrlBuilder::new("http://tracker.example.com/announce")
+            .param("port", "6881")
+            .param("left", "100")
+            .param("compact", "1")
+            .build();
+        assert_eq!(url, "http://tracker.example.com/announce?port=6881&left=100&compact=1");
+    }
+
+    #[test]
+    fn test_appends_to_existing_query() {
+        let url = TrackerUrlBuilder::new("http://tracker.example.com/announce.php?passkey=ab12cd")
+            .param("port", "6881")
+            .build();
+        assert_eq!(url, "http://tracker.example.com/announce.php?passkey=ab12cd&port=6881");
+
+        let url = TrackerUrlBuilder::new("http://tracker.example.com/a?passkey=ab12cd&")
+            .param("port", "6881")
+            .build();
+        assert_eq!(url, "http://tracker.example.com/a?passkey=ab12cd&port=6881");
+    }
+
+    #[test]
+    fn test_encodes_binary_values() {
+        let info_hash = hex::decode("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap();
+        let url = TrackerUrlBuilder::new("http://tracker.example.com/announce")
+            .bytes_param("info_hash", &info_hash)
+            .bytes_param("peer_id", b"-XX0100-a.b_c~d e\x00\xff")
+            .build();
+        assert_eq!(
+            url,
+            "http://tracker.example.com/announce?info_hash=%D6%9F%91%E6%B2%AELT%24h%D1%07%3Aq%D4%EA%13%87%9A%7F&peer_id=-XX0100-a.b_c~d%20e%00%FF"
+        );
+    }
+
+    #[test]
+    fn test_repeated_keys_and_no_params() {
+        let url = TrackerUrlBuilder::new("http://tracker.example.com/scrape#fragment")
+            .bytes_param("info_hash", b"a")
+            .bytes_param("info_hash", b"b")
+            .build();
+        assert_eq!(url, "http://tracker.example.com/scrape?info_hash=a&info_hash=b");
+        assert_eq!(TrackerUrlBuilder::new("http://t/announce?x=1").build(), "http://t/announce?x=1");
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+use reqwest::{Client, StatusCode};
+
+use crate::clients::helper::TrackerUrlBuilder;
+use crate::clients::web_seed::{parse_retry_after, read_body, PieceFuture, PieceSource, WebSeedError};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// The body of a busy answer is a number of seconds
+const MAX_BUSY_BODY_SIZE: u64 = 64;
+
+// A Hoffman-style HTTP seed (BEP 17): a script serving pieces by info hash and index. A busy
+// seed answers 503 with the number of seconds to wait as the body
+pub struct HttpSeed {
+    client: Client,
+    url: String,
+    info_hash: [u8; 20],
+}
+
+impl HttpSeed {
+    pub fn new(url: &str, info_hash: [u8; 20]) -> Self {
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).build().unwrap_or_default();
+        Self { client, url: url.to_string(), info_hash }
+    }
+
+    // The url requesting the whole piece; ranges are offsets within the piece, both inclusive
+    fn piece_url(&self, index: u32, size: u32) -> String {
+        TrackerUrlBuilder::new(&self.url)
+            .bytes_param("info_hash", &self.info_hash)
+            .param("piece", &index.to_string())
+            .param("ranges", &format!("0-{}", size - 1))
+            .build()
+    }
+
+    async fn fetch(&self, index: u32, size: u32) -> Result<Vec<u8>, WebSeedError> {
+        let response = self.client.get(self.piece_url(index, size)).send().await?;
+        let status = response.status();
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            let body = read_body(response, 0, MAX_BUSY_BODY_SIZE).await?;
+            return Err(WebSeedError::Busy(parse_retry_after(&String::from_utf8_lossy(&body))));
+        }
+        if status != StatusCode::OK {
+            return Err(WebSeedError::Status(status));
+        }
+
+        // one byte more than the piece tells a longer body apart without reading all of it
+        let body = read_body(response, 0, size as u64 + 1).await?;
+        if body.len() != size as usize {
+            return Err(WebSeedError::Length { expected: size as usize, received: body.len() });
+        }
+        Ok(body)
+    }
+}
+
+impl PieceSource for HttpSeed {
+    fn get_url(&self) -> &str {
+        &self.url
+    }
+
+    fn fetch_piece(&self, index: u32, size: u32) -> PieceFuture<'_> {
+        Box::pin(self.fetch(index, size))
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::clients::web_seed::tests::{parse_range, spawn_stand_in};
+
+    // Serve the pieces of the torrent data the BEP 17 way. The first requests are answered as
+    // busy. Returns the seed url
+    pub(crate) async fn spawn_http_seed(data: Vec<u8>, piece_length: usize, info_hash: [u8; 20], busy_requests: usize) -> String {
+        let address = spawn_stand_in(move |earlier_requests, request| {
+            let query = &request.query;
+            let piece = query.get("piece").and_then(|piece| String::from_utf8_lossy(piece).parse::<usize>().ok());
+            let range = query.get("ranges").and_then(|range| parse_range(&String::from_utf8_lossy(range)));
+            match (earlier_requests < busy_requests, piece, range) {
+                (true, _, _) => ("503 Service Unavailable", b"0".to_vec()),
+                (false, Some(piece), Some((from, to))) if query.get("info_hash") == Some(&info_hash.to_vec()) => {
+                    let start = piece * piece_length;
+                    let end = (start + piece_length).min(data.len());
+                    match data.get(start + from..(start + to + 1).min(end)) {
+                        Some(block) => ("200 OK", block.to_vec()),
+                        None => ("400 Bad Request", vec![]),
+                    }
+                }
+                _ => ("400 Bad Request", vec![]),
+            }
+        }).await;
+        format!("http://{}/seed.php", address)
+    }
+
+    #[test]
+    fn test_piece_url() {
+        let seed = HttpSeed::new("http://host/seed.php?key=1", [0xab; 20]);
+        assert_eq!(seed.piece_url(3, 16384), format!("http://host/seed.php?key=1&info_hash={}&piece=3&ranges=0-16383", "%AB".repeat(20)));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_pieces() {
+        let data: Vec<u8> = (0..50).collect();
+        let url = spawn_http_seed(data.clone(), 16, [1; 20], 1).await;
+        let seed = HttpSeed::new(&url, [1; 20]);
+
+        // the busy answer tells how long to wait in its body
+        assert!(matches!(seed.fetch_piece(0, 16).await, Err(WebSeedError::Busy(Some(delay))) if delay.is_zero()));
+        assert_eq!(seed.fetch_piece(1, 16).await.unwrap(), &data[16..32]);
+        assert_eq!(seed.fetch_piece(3, 2).await.unwrap(), &data[48..]);
+
+        // the seed does not serve other torrents
+        let other = HttpSeed::new(&url, [2; 20]);
+        assert!(matches!(other.fetch_piece(0, 16).await, Err(WebSeedError::Status(StatusCode::BAD_REQUEST))));
+
+        // a body longer than the piece is only read past its end by a byte
+        let url = format!("http://{}/seed.php", spawn_stand_in(|_, _| ("200 OK", vec![1; 1 << 20])).await);
+        let long = HttpSeed::new(&url, [1; 20]);
+        assert!(matches!(long.fetch_piece(0, 16).await, Err(WebSeedError::Length { expected: 16, received: 17 })));
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+
+use crate::utils;
+
+// The multicast groups of Local Service Discovery (BEP 14)
+pub const IPV4_GROUP: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 192, 152, 143)), 6771);
+pub const IPV6_GROUP: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f)), 6771);
+
+// Every torrent is announced once within this interval
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// How often the torrents are checked for being due, unless one is registered in between
+const ANNOUNCE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// Announces from one host beyond this number per window are ignored
+const MAX_ANNOUNCES_PER_HOST: usize = 5;
+const HOST_WINDOW: Duration = Duration::from_secs(60);
+// Info hashes per announce, keeping the datagram within a typical MTU
+const MAX_INFO_HASHES_PER_ANNOUNCE: usize = 20;
+
+// A BT-SEARCH announce: the sender takes peer connections on the port for the info hashes
+#[derive(Debug, PartialEq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn encode(&self, group: SocketAddr) -> Vec<u8> {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n", group, self.port);
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    // None for anything but a BT-SEARCH with a port; malformed info hashes are left out
+    pub fn decode(datagram: &[u8]) -> Option<Announce> {
+        let message = std::str::from_utf8(datagram).ok()?;
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let (mut port, mut info_hashes, mut cookie) = (None, vec![], None);
+        for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => info_hashes.extend(hex::decode(value).ok().and_then(|bytes| <[u8; 20]>::try_from(bytes).ok())),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Announce { port: port?, info_hashes, cookie })
+    }
+}
+
+// A multicast group we listen to, and the socket announcing to it. Announces may come from any
+// port, so the receiving socket is bound to the group itself and leaves the group's port free
+// for the wildcard addresses of other families
+struct Group {
+    address: SocketAddr,
+    receiver: UdpSocket,
+    sender: UdpSocket,
+}
+
+struct DiscoveredTorrent {
+    peers: mpsc::UnboundedSender<String>,
+    last_announced: Option<Instant>,
+}
+
+// Finds peers of our torrents on the local network by multicast announces, and announces the
+// torrents in turn. Private torrents must not be registered
+pub struct LocalDiscovery {
+    groups: Vec<Group>,
+    port: u16,
+    // tells our own announces apart when they loop back
+    cookie: String,
+    torrents: Mutex<HashMap<[u8; 20], DiscoveredTorrent>>,
+    registered: Notify,
+    hosts: Mutex<HashMap<IpAddr, (Instant, usize)>>,
+}
+
+impl LocalDiscovery {
+    // Join the groups, announcing the peer port. Groups that cannot be joined, e.g. without
+    // IPv6, are left out; it fails only if none can
+    pub async fn bind(groups: &[SocketAddr], port: u16) -> io::Result<Self> {
+        let mut joined = vec![];
+        let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no multicast groups");
+        for &group in groups {
+            match join(group).await {
+                Ok(group) => joined.push(group),
+                Err(e) => error = e,
+            }
+        }
+        if joined.is_empty() {
+            return Err(error);
+        }
+        Ok(Self {
+            groups: joined,
+            port,
+            cookie: hex::encode(utils::random_bytes(4)),
+            torrents: Mutex::new(HashMap::new()),
+            registered: Notify::new(),
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // The groups joined, with the ports they were bound to
+    pub fn groups(&self) -> Vec<SocketAddr> {
+        self.groups.iter().map(|group| group.address).collect()
+    }
+
+    // Announce the torrent and deliver the addresses of the peers announcing it through the
+    // returned receiver until it is dropped. Registering again keeps the announce schedule
+    pub fn register(&self, info_hash: [u8; 20]) -> mpsc::UnboundedReceiver<String> {
+        let (peers, receiver) = mpsc::unbounded_channel();
+        let mut torrents = self.torrents.lock().unwrap();
+        let last_announced = torrents.get(&info_hash).and_then(|torrent| torrent.last_announced);
+        torrents.insert(info_hash, DiscoveredTorrent { peers, last_announced });
+        self.registered.notify_one();
+        receiver
+    }
+
+    // Announce and receive announces until a socket fails
+    pub async fn run(&self) -> io::Result<()> {
+        let mut buffer = [0u8; 1500];
+        // a timer of its own, so that a steady stream of datagrams does not keep putting the
+        // check off
+        let mut announce_check = tokio::time::interval(ANNOUNCE_CHECK_INTERVAL);
+        announce_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let received = tokio::select! {
+                received = self.receive(&mut buffer) => received?,
+                _ = announce_check.tick() => {
+                    self.announce_due().await;
+                    continue;
+                }
+                _ = self.registered.notified() => {
+                    self.announce_due().await;
+                    continue;
+                }
+            };
+            let (length, source) = received;
+            if let Some(announce) = Announce::decode(&buffer[..length]) {
+                self.handle_announce(announce, source.ip());
+            }
+        }
+    }
+
+    // The next datagram of any group
+    async fn receive(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        std::future::poll_fn(|cx| {
+            for group in &self.groups {
+                let mut read = ReadBuf::new(buffer);
+                if let Poll::Ready(received) = group.receiver.poll_recv_from(cx, &mut read) {
+                    return Poll::Ready(received.map(|source| (read.filled().len(), source)));
+                }
+            }
+            Poll::Pending
+        }).await
+    }
+
+    // Announce the torrents not announced within the interval, to every group
+    async fn announce_due(&self) {
+        let due: Vec<[u8; 20]> = {
+            let mut torrents = self.torrents.lock().unwrap();
+            // torrents no longer downloaded or seeded are forgotten
+            torrents.retain(|_, torrent| !torrent.peers.is_closed());
+            let now = Instant::now();
+            torrents.iter_mut()
+                .filter(|(_, torrent)| torrent.last_announced.is_none_or(|last| now - last >= ANNOUNCE_INTERVAL))
+                .map(|(info_hash, torrent)| {
+                    torrent.last_announced = Some(now);
+                    *info_hash
+                })
+                .collect()
+        };
+
+        for info_hashes in due.chunks(MAX_INFO_HASHES_PER_ANNOUNCE) {
+            let announce = Announce { port: self.port, info_hashes: info_hashes.to_vec(), cookie: Some(self.cookie.clone()) };
+            for group in &self.groups {
+                if let Err(e) = group.sender.send_to(&announce.encode(group.address), group.address).await {
+                    eprintln!("Failed to announce to {}: {}", group.address, e);
+                }
+            }
+        }
+    }
+
+    // Pass the announcing peer on to the torrents it announces, unless the announce is our own
+    // or its host announces too often
+    fn handle_announce(&self, announce: Announce, host: IpAddr) {
+        if announce.cookie.as_ref() == Some(&self.cookie) || announce.port == 0 || !self.allow_host(host) {
+            return;
+        }
+        let address = SocketAddr::new(host, announce.port).to_string();
+        let torrents = self.torrents.lock().unwrap();
+        for info_hash in &announce.info_hashes {
+            if let Some(torrent) = torrents.get(info_hash) {
+                let _ = torrent.peers.send(address.clone());
+            }
+        }
+    }
+
+    fn allow_host(&self, host: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.retain(|_, (start, _)| now - *start < HOST_WINDOW);
+        let (_, count) = hosts.entry(host).or_insert((now, 0));
+        *count += 1;
+        *count <= MAX_ANNOUNCES_PER_HOST
+    }
+}
+
+async fn join(group: SocketAddr) -> io::Result<Group> {
+    let receiver = UdpSocket::from_std(bind_shared(group)?)?;
+    let (sender, address) = match group.ip() {
+        IpAddr::V4(ip) => {
+            receiver.join_multicast_v4(ip, Ipv4Addr::UNSPECIFIED)?;
+            (UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?, receiver.local_addr()?)
+        }
+        IpAddr::V6(ip) => {
+            receiver.join_multicast_v6(&ip, 0)?;
+            (UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?, receiver.local_addr()?)
+        }
+    };
+    Ok(Group { address, receiver, sender })
+}
+
+// Bind a UDP socket other programs can bind as well, as other BitTorrent clients on the host do
+// for LSD. The reuse options have to be set before binding, which std cannot do
+#[cfg(target_os = "linux")]
+fn bind_shared(address: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    use std::os::fd::FromRawFd;
+    use std::os::raw::{c_int, c_void};
+
+    const AF_INET: c_int = 2;
+    const AF_INET6: c_int = 10;
+    const SOCK_DGRAM: c_int = 2;
+    const SOCK_CLOEXEC: c_int = 0o2000000;
+    const SOL_SOCKET: c_int = 1;
+    const SO_REUSEADDR: c_int = 2;
+    const SO_REUSEPORT: c_int = 15;
+
+    #[repr(C)]
+    struct SockaddrIn {
+        family: u16,
+        port: u16,
+        address: [u8; 4],
+        zero: [u8; 8],
+    }
+
+    #[repr(C)]
+    struct SockaddrIn6 {
+        family: u16,
+        port: u16,
+        flow_info: u32,
+        address: [u8; 16],
+        scope_id: u32,
+    }
+
+    extern "C" {
+        fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+        fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, length: u32) -> c_int;
+        fn bind(fd: c_int, address: *const c_void, length: u32) -> c_int;
+    }
+
+    let domain = if address.is_ipv4() { AF_INET } else { AF_INET6 };
+    // SAFETY: socket only creates a descriptor, which the UdpSocket owns from then on
+    let fd = unsafe { socket(domain, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let udp_socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+    let enabled: c_int = 1;
+    for option in [SO_REUSEADDR, SO_REUSEPORT] {
+        // SAFETY: the value points to a c_int of the given length
+        let result = unsafe { setsockopt(fd, SOL_SOCKET, option, &enabled as *const c_int as *const c_void, std::mem::size_of::<c_int>() as u32) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // SAFETY: the addresses are laid out as the kernel's sockaddr_in and sockaddr_in6
+    let result = match address {
+        SocketAddr::V4(address) => {
+            let sockaddr = SockaddrIn { family: AF_INET as u16, port: address.port().to_be(), address: address.ip().octets(), zero: [0; 8] };
+            unsafe { bind(fd, &sockaddr as *const SockaddrIn as *const c_void, std::mem::size_of::<SockaddrIn>() as u32) }
+        }
+        SocketAddr::V6(address) => {
+            let sockaddr = SockaddrIn6 {
+                family: AF_INET6 as u16,
+                port: address.port().to_be(),
+                flow_info: address.flowinfo().to_be(),
+                address: address.ip().octets(),
+                scope_id: address.scope_id(),
+            };
+            unsafe { bind(fd, &sockaddr as *const SockaddrIn6 as *const c_void, std::mem::size_of::<SockaddrIn6>() as u32) }
+        }
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    udp_socket.set_nonblocking(true)?;
+    Ok(udp_socket)
+}
+
+// Elsewhere the group port is taken for ourselves
+#[cfg(not(target_os = "linux"))]
+fn bind_shared(address: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    let udp_socket = std::net::UdpSocket::bind(address)?;
+    udp_socket.set_nonblocking(true)?;
+    Ok(udp_socket)
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Handle an announce as if it was received from the host
+    pub(crate) fn receive_announce(local_discovery: &LocalDiscovery, announce: Announce, host: IpAddr) {
+        local_discovery.handle_announce(announce, host);
+    }
+
+    #[test]
+    fn test_announce_round_trip() {
+        let announce = Announce { port: 6881, info_hashes: vec![[0xab; 20], [1; 20]], cookie: Some("c00k1e".to_string()) };
+        let encoded = announce.encode(IPV4_GROUP);
+        assert!(encoded.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abab"));
+        assert_eq!(Announce::decode(&encoded), Some(announce));
+        assert!(String::from_utf8(Announce { port: 1, info_hashes: vec![], cookie: None }.encode(IPV6_GROUP)).unwrap()
+            .contains("Host: [ff15::efc0:988f]:6771\r\n"));
+
+        // header names are case-insensitive and bad info hashes are skipped
+        let decoded = Announce::decode(b"BT-SEARCH * HTTP/1.1\r\nport: 7000\r\nINFOHASH: 0101010101010101010101010101010101010101\r\nInfohash: zz\r\n\r\n\r\n");
+        assert_eq!(decoded, Some(Announce { port: 7000, info_hashes: vec![[1; 20]], cookie: None }));
+        assert_eq!(Announce::decode(b"BT-SEARCH * HTTP/1.1\r\nInfohash: 01\r\n\r\n"), None);
+        assert_eq!(Announce::decode(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_discovers_peers_announcing_our_torrents() {
+        let group = SocketAddr::new(IPV4_GROUP.ip(), 0);
+        let discovery = std::sync::Arc::new(LocalDiscovery::bind(&[group], 6881).await.unwrap());
+        let group = discovery.groups()[0];
+        let mut peers = discovery.register([1; 20]);
+        let running = discovery.clone();
+        tokio::spawn(async move { let _ = running.run().await; });
+
+        // our own announce comes back to us and is ignored, the other peer's is passed on
+        let other = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+        let announce = |info_hash| Announce { port: 7000, info_hashes: vec![info_hash], cookie: Some("other".to_string()) };
+        other.send_to(&announce([2; 20]).encode(group), group).await.unwrap();
+        other.send_to(&announce([1; 20]).encode(group), group).await.unwrap();
+        let peer = tokio::time::timeout(Duration::from_secs(5), peers.recv()).await.unwrap().unwrap();
+        assert!(peer.ends_with(":7000"));
+        assert!(tokio::time::timeout(Duration::from_millis(200), peers.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shares_the_group_port_with_other_clients() {
+        let first = LocalDiscovery::bind(&[SocketAddr::new(IPV4_GROUP.ip(), 0)], 6881).await.unwrap();
+        let group = first.groups()[0];
+        let second = LocalDiscovery::bind(&[group], 6882).await.unwrap();
+        assert_eq!(second.groups(), vec![group]);
+    }
+
+    #[tokio::test]
+    async fn test_limits_announces_per_host() {
+        let group = SocketAddr::new(IPV4_GROUP.ip(), 0);
+        let discovery = LocalDiscovery::bind(&[group], 6881).await.unwrap();
+        let mut peers = discovery.register([1; 20]);
+        let host: IpAddr = "192.168.1.20".parse().unwrap();
+        for _ in 0..MAX_ANNOUNCES_PER_HOST + 3 {
+            discovery.handle_announce(Announce { port: 7000, info_hashes: vec![[1; 20]], cookie: None }, host);
+        }
+        let own = Announce { port: 7000, info_hashes: vec![[1; 20]], cookie: Some(discovery.cookie.clone()) };
+        discovery.handle_announce(own, "192.168.1.21".parse().unwrap());
+
+        let mut received = 0;
+        while let Ok(peer) = peers.try_recv() {
+            assert_eq!(peer, "192.168.1.20:7000");
+            received += 1;
+        }
+        assert_eq!(received, MAX_ANNOUNCES_PER_HOST);
+    }
+}
@@ -0,0 +1,104 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::utils;
+
+// Reads blocks of verified pieces, e.g. from the downloaded files
+pub trait BlockSource: Send + Sync {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>>;
+}
+
+// The verified pieces of a torrent we can upload, shared by all its connections. Pieces added
+// while connected are announced to the connections, which pass them on as have messages
+pub struct LocalPieces {
+    bitfield: Mutex<Vec<u8>>,
+    piece_sizes: Vec<u32>,
+    source: Arc<dyn BlockSource>,
+    added: broadcast::Sender<u32>,
+}
+
+impl LocalPieces {
+    pub fn new(piece_sizes: Vec<u32>, source: Arc<dyn BlockSource>) -> Self {
+        let (added, _) = broadcast::channel(1024);
+        let bitfield = Mutex::new(vec![0; piece_sizes.len().div_ceil(8)]);
+        Self { bitfield, piece_sizes, source, added }
+    }
+
+    // Call once the piece is verified and can be read from the block source
+    pub fn add_piece(&self, index: u32) {
+        utils::set_bit(&mut self.bitfield.lock().unwrap(), index as usize);
+        let _ = self.added.send(index);
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        utils::has_bit(&self.bitfield.lock().unwrap(), index as usize)
+    }
+
+    // Getter for bitfield
+    pub fn get_bitfield(&self) -> Vec<u8> {
+        self.bitfield.lock().unwrap().clone()
+    }
+
+    // Number of pieces of the torrent, whether we have them or not
+    pub fn piece_count(&self) -> usize {
+        self.piece_sizes.len()
+    }
+
+    pub fn count(&self) -> usize {
+        self.bitfield.lock().unwrap().iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    // Pieces added from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<u32> {
+        self.added.subscribe()
+    }
+
+    // Read a requested block; None if we do not have the piece or the block is not within it
+    pub fn read_block(&self, index: u32, begin: u32, length: u32) -> io::Result<Option<Vec<u8>>> {
+        let piece_size = match self.piece_sizes.get(index as usize) {
+            Some(&piece_size) => piece_size,
+            None => return Ok(None),
+        };
+        if !self.has_piece(index) || begin.checked_add(length).is_none_or(|end| end > piece_size) {
+            return Ok(None);
+        }
+        self.source.read_block(index, begin, length).map(Some)
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Serves blocks out of the concatenated torrent data
+    pub struct MemorySource {
+        pub data: Vec<u8>,
+        pub piece_length: u32,
+    }
+
+    impl BlockSource for MemorySource {
+        fn read_block(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+            let start = (index * self.piece_length + begin) as usize;
+            Ok(self.data[start..start + length as usize].to_vec())
+        }
+    }
+
+    #[test]
+    fn test_read_only_verified_blocks() {
+        let data: Vec<u8> = (0..25).collect();
+        let local_pieces = LocalPieces::new(vec![10, 10, 5], Arc::new(MemorySource { data, piece_length: 10 }));
+        let mut added = local_pieces.subscribe();
+        local_pieces.add_piece(2);
+
+        assert_eq!(added.try_recv().unwrap(), 2);
+        assert_eq!(local_pieces.get_bitfield(), vec![0x20]);
+        assert_eq!(local_pieces.count(), 1);
+        assert_eq!(local_pieces.read_block(2, 1, 4).unwrap(), Some(vec![21, 22, 23, 24]));
+        // past the end of the last piece, pieces we do not have and unknown pieces
+        assert_eq!(local_pieces.read_block(2, 1, 5).unwrap(), None);
+        assert_eq!(local_pieces.read_block(0, 0, 4).unwrap(), None);
+        assert_eq!(local_pieces.read_block(3, 0, 4).unwrap(), None);
+    }
+}
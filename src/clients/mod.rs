@@ -1,3 +1,22 @@
+pub mod allowed_fast;
+pub mod choker;
+pub mod client_config;
+pub mod connection_state;
+pub mod diffie_hellman;
+pub mod extended_handshake;
+pub mod handshake;
+pub mod http_seed;
+pub mod local_discovery;
+pub mod local_pieces;
+pub mod mse;
 pub mod peer_client;
+pub mod peer_listener;
+pub mod peer_message;
+pub mod rc4;
+pub mod request_pipeline;
 pub mod tracker_client;
+pub mod udp_tracker_client;
+pub mod utp;
+pub mod utp_packet;
+pub mod web_seed;
 pub mod helper;
\ No newline at end of file
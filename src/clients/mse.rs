@@ -0,0 +1,368 @@
+use std::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::clients::diffie_hellman::{KeyPair, KEY_LENGTH};
+use crate::clients::rc4::Rc4;
+use crate::utils;
+
+// Verification constant, sent encrypted so the other side can check the keys and find the
+// end of the padding
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+// Random padding hides the length of the handshake messages
+const MAX_PADDING: usize = 512;
+// The start of the RC4 keystream leaks information about the key
+const DISCARDED_KEYSTREAM: usize = 1024;
+
+// Whether peer connections use Message Stream Encryption
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncryptionPolicy {
+    // plain BitTorrent connections only
+    Disable,
+    // encrypt when the peer supports it, otherwise go without
+    Prefer,
+    // refuse peers that do not encrypt
+    Require,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "disable" => Ok(EncryptionPolicy::Disable),
+            "prefer" => Ok(EncryptionPolicy::Prefer),
+            "require" => Ok(EncryptionPolicy::Require),
+            _ => Err(format!("Unknown encryption policy {:?}, expected disable, prefer or require", policy)),
+        }
+    }
+}
+
+// A connection after the MSE handshake. Data is RC4 encrypted in both directions unless the
+// peers agreed on plaintext. Payload the handshake already received is returned first, and
+// written data may stay buffered until the next write or a flush
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    received: Vec<u8>,
+    unwritten: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    fn new(inner: S, ciphers: Option<(Rc4, Rc4)>, received: Vec<u8>) -> Self {
+        let (read_cipher, write_cipher) = match ciphers {
+            Some((read_cipher, write_cipher)) => (Some(read_cipher), Some(write_cipher)),
+            None => (None, None),
+        };
+        Self { inner, read_cipher, write_cipher, received, unwritten: vec![] }
+    }
+
+    // False if the peers agreed on plaintext after the handshake
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_write_unwritten(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unwritten.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.unwritten))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unwritten.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.received.is_empty() {
+            let length = this.received.len().min(buf.remaining());
+            buf.put_slice(&this.received[..length]);
+            this.received.drain(..length);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = this.read_cipher.as_mut() {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        // the keystream moves on with every byte, so encrypted data is kept until it is written
+        ready!(this.poll_write_unwritten(cx))?;
+        this.unwritten = data.to_vec();
+        this.write_cipher.as_mut().unwrap().apply(&mut this.unwritten);
+        if let Poll::Ready(Err(e)) = this.poll_write_unwritten(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_unwritten(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_unwritten(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+// Run the handshake on a connection we opened. The info hash tells the other side which torrent
+// we want without ever crossing the wire; the BitTorrent handshake follows through the stream
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>, Box<dyn Error>> {
+    let keys = KeyPair::generate();
+    let mut message = keys.get_public_key().to_vec();
+    message.extend(random_padding());
+    stream.write_all(&message).await?;
+
+    let mut peer_key = [0u8; KEY_LENGTH];
+    stream.read_exact(&mut peer_key).await?;
+    let secret = keys.shared_secret(&peer_key);
+    let mut read_cipher = cipher(b"keyB", &secret, info_hash);
+    let mut write_cipher = cipher(b"keyA", &secret, info_hash);
+
+    let provide = if policy == EncryptionPolicy::Require { CRYPTO_RC4 } else { CRYPTO_RC4 | CRYPTO_PLAINTEXT };
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(&hash(&[b"req2", info_hash]), &hash(&[b"req3", &secret])));
+    let mut encrypted = VC.to_vec();
+    encrypted.extend(provide.to_be_bytes());
+    // neither padding nor initial payload
+    encrypted.extend(0u16.to_be_bytes());
+    encrypted.extend(0u16.to_be_bytes());
+    write_cipher.apply(&mut encrypted);
+    message.extend(encrypted);
+    stream.write_all(&message).await?;
+
+    // the padding of the other side ends where its encrypted verification constant starts
+    let mut encrypted_vc = VC;
+    read_cipher.apply(&mut encrypted_vc);
+    skip_to(&mut stream, &encrypted_vc).await?;
+    let mut header = [0u8; 6];
+    stream.read_exact(&mut header).await?;
+    read_cipher.apply(&mut header);
+    let select = u32::from_be_bytes(header[..4].try_into().unwrap());
+    read_padding(&mut stream, &mut read_cipher, u16::from_be_bytes([header[4], header[5]])).await?;
+    if select != CRYPTO_RC4 && (select != CRYPTO_PLAINTEXT || policy == EncryptionPolicy::Require) {
+        return Err(format!("Peer selected crypto method {} we did not offer", select).into());
+    }
+    Ok(MseStream::new(stream, (select == CRYPTO_RC4).then_some((read_cipher, write_cipher)), vec![]))
+}
+
+// Run the handshake on a connection a peer opened, given the bytes read from it so far. The
+// torrent is found among the info hashes we serve; returns the stream and that info hash
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    received: &[u8],
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<S>, [u8; 20]), Box<dyn Error>> {
+    let mut peer_key = [0u8; KEY_LENGTH];
+    peer_key[..received.len()].copy_from_slice(received);
+    stream.read_exact(&mut peer_key[received.len()..]).await?;
+    let keys = KeyPair::generate();
+    let mut message = keys.get_public_key().to_vec();
+    message.extend(random_padding());
+    stream.write_all(&message).await?;
+    let secret = keys.shared_secret(&peer_key);
+
+    skip_to(&mut stream, &hash(&[b"req1", &secret])).await?;
+    let mut obfuscated_hash = [0u8; 20];
+    stream.read_exact(&mut obfuscated_hash).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = *info_hashes.iter()
+        .find(|info_hash| xor(&hash(&[b"req2", info_hash.as_slice()]), &req3) == obfuscated_hash)
+        .ok_or("Peer asked for a torrent we do not serve")?;
+    let mut read_cipher = cipher(b"keyA", &secret, &info_hash);
+    let mut write_cipher = cipher(b"keyB", &secret, &info_hash);
+
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).await?;
+    read_cipher.apply(&mut header);
+    if header[..8] != VC {
+        return Err("Invalid verification constant in MSE handshake".into());
+    }
+    let provide = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    read_padding(&mut stream, &mut read_cipher, u16::from_be_bytes([header[12], header[13]])).await?;
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length).await?;
+    read_cipher.apply(&mut length);
+    let mut initial_payload = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut initial_payload).await?;
+    read_cipher.apply(&mut initial_payload);
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Require {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(format!("Peer offered no acceptable crypto method ({})", provide).into());
+    };
+    let mut answer = VC.to_vec();
+    answer.extend(select.to_be_bytes());
+    answer.extend(0u16.to_be_bytes());
+    write_cipher.apply(&mut answer);
+    stream.write_all(&answer).await?;
+
+    let ciphers = (select == CRYPTO_RC4).then_some((read_cipher, write_cipher));
+    Ok((MseStream::new(stream, ciphers, initial_payload), info_hash))
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut result = *a;
+    for (byte, other) in result.iter_mut().zip(b) {
+        *byte ^= other;
+    }
+    result
+}
+
+fn cipher(key_name: &[u8], secret: &[u8], info_hash: &[u8; 20]) -> Rc4 {
+    let mut cipher = Rc4::new(&hash(&[key_name, secret, info_hash]));
+    cipher.discard(DISCARDED_KEYSTREAM);
+    cipher
+}
+
+fn random_padding() -> Vec<u8> {
+    utils::random_bytes(utils::random_below(MAX_PADDING + 1))
+}
+
+// Read past the other side's padding up to and including the marker that follows it
+async fn skip_to<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut window = vec![0u8; marker.len()];
+    stream.read_exact(&mut window).await?;
+    let mut skipped = 0;
+    while window != marker {
+        if skipped == MAX_PADDING {
+            return Err("Peer does not speak MSE".into());
+        }
+        window.remove(0);
+        window.push(stream.read_u8().await?);
+        skipped += 1;
+    }
+    Ok(())
+}
+
+async fn read_padding<S: AsyncRead + Unpin>(stream: &mut S, read_cipher: &mut Rc4, length: u16) -> Result<(), Box<dyn Error>> {
+    if length as usize > MAX_PADDING {
+        return Err(format!("MSE padding of {} bytes is too long", length).into());
+    }
+    let mut padding = vec![0u8; length as usize];
+    stream.read_exact(&mut padding).await?;
+    read_cipher.apply(&mut padding);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const INFO_HASH: [u8; 20] = [4; 20];
+
+    #[tokio::test]
+    async fn test_handshake_and_encrypted_data() {
+        let (a, b) = duplex(4096);
+        let (initiated, responded) = tokio::join!(
+            initiate(a, &INFO_HASH, EncryptionPolicy::Prefer),
+            respond(b, &[], &[[1; 20], INFO_HASH], EncryptionPolicy::Prefer),
+        );
+        let mut a = initiated.unwrap();
+        let (mut b, info_hash) = responded.unwrap();
+        assert_eq!(info_hash, INFO_HASH);
+        assert!(a.is_encrypted() && b.is_encrypted());
+
+        // larger than the pipe, so writes only go through in parts
+        let data: Vec<u8> = (0..20000).map(|i| (i % 253) as u8).collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&sent).await.unwrap();
+            a.flush().await.unwrap();
+            let mut answer = [0u8; 5];
+            a.read_exact(&mut answer).await.unwrap();
+            answer
+        });
+        let mut received = vec![0u8; data.len()];
+        b.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        b.write_all(b"hello").await.unwrap();
+        b.flush().await.unwrap();
+        assert_eq!(&writer.await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_wire_is_obfuscated() {
+        let (a, mut wire) = duplex(65536);
+        let mut a = MseStream::new(a, Some((Rc4::new(b"read"), Rc4::new(b"write"))), b"ahead".to_vec());
+        a.write_all(b"\x13BitTorrent protocol").await.unwrap();
+        a.flush().await.unwrap();
+        let mut raw = [0u8; 20];
+        wire.read_exact(&mut raw).await.unwrap();
+        assert_ne!(&raw, b"\x13BitTorrent protocol");
+        Rc4::new(b"write").apply(&mut raw);
+        assert_eq!(&raw, b"\x13BitTorrent protocol");
+
+        // data received during the handshake comes first
+        let mut encrypted = *b"back";
+        Rc4::new(b"read").apply(&mut encrypted);
+        wire.write_all(&encrypted).await.unwrap();
+        let mut read = [0u8; 9];
+        a.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read, b"aheadback");
+    }
+
+    #[tokio::test]
+    async fn test_refuses_unknown_torrents() {
+        let (a, b) = duplex(4096);
+        let (initiated, responded) = tokio::join!(
+            initiate(a, &INFO_HASH, EncryptionPolicy::Require),
+            async move {
+                let result = respond(b, &[], &[[1; 20]], EncryptionPolicy::Prefer).await;
+                // closes the connection, so the initiator fails as well
+                result.map(|(_, info_hash)| info_hash)
+            },
+        );
+        assert!(responded.unwrap_err().to_string().contains("do not serve"));
+        assert!(initiated.is_err());
+    }
+
+    #[test]
+    fn test_policy_from_str() {
+        assert_eq!("require".parse::<EncryptionPolicy>().unwrap(), EncryptionPolicy::Require);
+        assert!("always".parse::<EncryptionPolicy>().is_err());
+    }
+}
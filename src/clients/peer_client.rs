@@ -1,10 +1,96 @@
 use tokio::net::TcpStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio::sync::broadcast;
+use bytes::BytesMut;
+use crate::clients::allowed_fast::{allowed_fast_set, ALLOWED_FAST_SET_SIZE};
+use crate::clients::choker::PeerLink;
+use crate::clients::client_config::ClientConfig;
+use crate::clients::connection_state::ConnectionState;
+use crate::clients::handshake::{Capabilities, Handshake, HANDSHAKE_LENGTH};
+use crate::clients::extended_handshake::{ExtendedHandshake, EXTENDED_HANDSHAKE_ID};
+use crate::clients::local_pieces::LocalPieces;
+use crate::clients::mse::{self, EncryptionPolicy};
+use crate::clients::peer_listener::{ConnectionPermit, PeerConnection, PeerStream};
+use crate::clients::peer_message::{HashRequest, PeerMessage};
+use crate::clients::request_pipeline::{PieceDownload, RequestPipeline, SharedBlocks, BLOCK_SIZE};
+use crate::clients::utp::{Transport, UtpSocket};
+use crate::torrent_manager::merkle_tree::MerkleTrees;
+use crate::utils;
 
 
+// Protocol extensions we advertise in our handshake
+const OWN_CAPABILITIES: Capabilities = Capabilities { dht: false, fast: true, extension_protocol: true };
+
+// Requests we accept from peers without dropping any, advertised as reqq
+const OWN_REQUEST_QUEUE: u32 = 250;
+
+// How long a piece download waits for a choking peer before giving the piece up
+const CHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A keep-alive is sent when we sent nothing else for this long
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+// Peers keep the connection alive every two minutes, so one that sends nothing for longer is gone
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+// Suggestions of the peer that are remembered, the most recent ones
+const MAX_SUGGESTED_PIECES: usize = 16;
+
+// Allowed fast pieces of the peer that are remembered, the first ones
+const MAX_ALLOWED_FAST_PIECES: usize = 64;
+
+// What waiting for a message may run into first
+enum WaitEvent {
+    Message(PeerMessage),
+    KeepAliveDue,
+    PieceAdded(Result<u32, broadcast::error::RecvError>),
+    ChokeDecision(bool),
+}
+
+// What a piece download waits for
+enum PieceEvent {
+    Message(PeerMessage),
+    ReceivedElsewhere(Result<u32, broadcast::error::RecvError>),
+}
+
 pub struct PeerClient {
-    stream: Option<TcpStream>,
+    stream: Option<Box<dyn PeerStream>>,
+    peer_address: Option<SocketAddr>,
+    read_buffer: BytesMut,
+    // messages not written to the peer yet
+    write_buffer: BytesMut,
+    client_config: ClientConfig,
+    capabilities: Capabilities,
+    state: ConnectionState,
+    peer_bitfield: Vec<u8>,
+    // pieces of the torrent, to expand have all messages
+    piece_count: usize,
+    // pieces the peer lets us download while it chokes us (fast extension)
+    allowed_fast: Vec<u32>,
+    // pieces we let the peer download while we choke it
+    granted_fast: Vec<u32>,
+    // pieces the peer suggested we download, most recent last
+    suggested: Vec<u32>,
+    pipeline: RequestPipeline,
+    choke_timeout: Duration,
+    keepalive_interval: Duration,
+    idle_timeout: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+    local_pieces: Option<Arc<LocalPieces>>,
+    // pieces added to local_pieces since the bitfield was sent
+    added_pieces: Option<broadcast::Receiver<u32>>,
+    // decides whether we upload to the peer
+    choke_link: Option<PeerLink>,
+    // carries uTP connections; without it peers are only reached over TCP
+    utp_socket: Option<Arc<UtpSocket>>,
+    // hashes of a v2 torrent we answer hash requests from
+    merkle_trees: Option<Arc<MerkleTrees>>,
 }
 
 
@@ -12,30 +98,162 @@ impl Default for PeerClient {
     fn default() -> Self {
         Self {
             stream: None,
+            peer_address: None,
+            read_buffer: BytesMut::new(),
+            write_buffer: BytesMut::new(),
+            client_config: ClientConfig::default(),
+            capabilities: Capabilities::default(),
+            state: ConnectionState::default(),
+            peer_bitfield: vec![],
+            piece_count: 0,
+            allowed_fast: vec![],
+            granted_fast: vec![],
+            suggested: vec![],
+            pipeline: RequestPipeline::new(0),
+            choke_timeout: CHOKE_TIMEOUT,
+            keepalive_interval: KEEPALIVE_INTERVAL,
+            idle_timeout: IDLE_TIMEOUT,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            local_pieces: None,
+            added_pieces: None,
+            choke_link: None,
+            utp_socket: None,
+            merkle_trees: None,
         }
     }
 }
 
 impl PeerClient {
 
-    pub fn new() -> Self {
-        Self{ ..Default::default()}
+    pub fn new(client_config: ClientConfig) -> Self {
+        let pipeline = RequestPipeline::new(client_config.get_max_outstanding_requests());
+        Self{ client_config, pipeline, ..Default::default()}
     }
 
+    // Setter for choke_timeout
+    pub fn set_choke_timeout(&mut self, choke_timeout: Duration) {
+        self.choke_timeout = choke_timeout;
+    }
+
+    // Setter for piece_count
+    pub fn set_piece_count(&mut self, piece_count: usize) {
+        self.piece_count = piece_count;
+    }
+
+    // Pieces we upload to the peer. Set before the handshake so the bitfield is sent
+    pub fn set_local_pieces(&mut self, local_pieces: Arc<LocalPieces>) {
+        self.piece_count = local_pieces.piece_count();
+        self.added_pieces = Some(local_pieces.subscribe());
+        self.local_pieces = Some(local_pieces);
+    }
+
+    // The peer is unchoked whenever the choke controller of the link says so
+    pub fn set_choke_link(&mut self, choke_link: PeerLink) {
+        self.choke_link = Some(choke_link);
+    }
+
+    // Setter for utp_socket
+    pub fn set_utp_socket(&mut self, utp_socket: Arc<UtpSocket>) {
+        self.utp_socket = Some(utp_socket);
+    }
+
+    // Setter for merkle_trees
+    pub fn set_merkle_trees(&mut self, merkle_trees: Arc<MerkleTrees>) {
+        self.merkle_trees = Some(merkle_trees);
+    }
+
+    // Pieces the peer announced through its bitfield and have messages
+    pub fn get_bitfield(&self) -> &Vec<u8> {
+        &self.peer_bitfield
+    }
+
+    pub fn is_choked(&self) -> bool {
+        self.state.peer_choking
+    }
+
+    // Getter for allowed_fast
+    pub fn get_allowed_fast(&self) -> &[u32] {
+        &self.allowed_fast
+    }
+
+    // Pieces the peer suggested, most recent first
+    pub fn get_suggested(&self) -> Vec<u32> {
+        self.suggested.iter().rev().copied().collect()
+    }
+
+    // Connect to a peer over the first of the configured transports that works
     pub async fn connect(&mut self, peer_address: &str) -> Result<(), Box<dyn Error>>{
-        // Connect to a peer
-        self.stream = Some(TcpStream::connect(peer_address).await?);
-        Ok(())
+        let mut failures = vec![];
+        for &transport in self.client_config.get_transports() {
+            let connected = match (transport, &self.utp_socket) {
+                (Transport::Tcp, _) => connect_tcp(peer_address).await,
+                (Transport::Utp, Some(utp_socket)) => connect_utp(utp_socket, peer_address).await,
+                (Transport::Utp, None) => continue,
+            };
+            match connected {
+                Ok((stream, address)) => {
+                    self.attach(stream, address);
+                    return Ok(());
+                }
+                Err(e) => failures.push(format!("{}: {}", transport, e)),
+            }
+        }
+        Err(format!("Could not connect to {} ({})", peer_address, failures.join(", ")).into())
+    }
+
+    // Take over a connection the peer opened and answer the handshake the listener read from it
+    async fn accept(&mut self, stream: Box<dyn PeerStream>, address: SocketAddr, peer_handshake: &Handshake, info_hash: [u8; 20]) -> Result<(), Box<dyn Error>> {
+        let peer_id = *self.client_config.get_peer_id();
+        peer_handshake.validate(&info_hash, &peer_id)?;
+        self.attach(stream, address);
+
+        let handshake = Handshake::new(OWN_CAPABILITIES, info_hash, peer_id);
+        let stream = self.ensure_connected()?;
+        stream.write_all(&handshake.encode()).await?;
+        stream.flush().await?;
+        self.start_session(peer_handshake).await
+    }
+
+    // Connect to the peer or take over the connection it opened, and exchange handshakes.
+    // Returns the permit of an incoming connection, to be kept for as long as it is used
+    pub async fn open(&mut self, connection: PeerConnection, info_hash: [u8; 20]) -> Result<Option<ConnectionPermit>, Box<dyn Error>> {
+        match connection {
+            PeerConnection::Outgoing(address) => {
+                self.connect(&address).await?;
+                self.perform_handshake(info_hash).await?;
+                Ok(None)
+            }
+            PeerConnection::Incoming(incoming) => {
+                self.accept(incoming.stream, incoming.address, &incoming.handshake, info_hash).await?;
+                Ok(Some(incoming.permit))
+            }
+        }
+    }
+
+    fn attach(&mut self, stream: Box<dyn PeerStream>, address: SocketAddr) {
+        self.stream = Some(stream);
+        self.peer_address = Some(address);
+        self.read_buffer.clear();
+        self.write_buffer.clear();
+        self.state = ConnectionState::default();
+        self.peer_bitfield = vec![0; self.piece_count.div_ceil(8)];
+        self.allowed_fast.clear();
+        self.granted_fast.clear();
+        self.suggested.clear();
+        self.last_sent = Instant::now();
+        self.last_received = Instant::now();
     }
 
     pub async fn disconnect(&mut self) -> Result<(), Box<dyn Error>> {
+        self.write_queued().await?;
         if let Some(mut stream) = self.stream.take() {
             stream.shutdown().await?;
         }
         Ok(())
     }
 
-    fn ensure_connected(&mut self) -> Result<&mut TcpStream, Box<dyn Error>> {
+    fn ensure_connected(&mut self) -> Result<&mut Box<dyn PeerStream>, Box<dyn Error>> {
         match self.stream.as_mut() {
             Some(stream) => Ok(stream),
             None => Err("Not connected to a peer".into()),
@@ -44,119 +262,969 @@ impl PeerClient {
 
     
     
-    pub async fn perform_handshake(&mut self, mut info_hash: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    // Exchange handshakes and validate the peer's answer; returns the peer's handshake
+    pub async fn perform_handshake(&mut self, info_hash: [u8; 20]) -> Result<Handshake, Box<dyn Error>> {
+        self.encrypt_connection(info_hash).await?;
+        let peer_id = *self.client_config.get_peer_id();
         let stream = self.ensure_connected()?;
-    
-        let mut handshake_message: Vec<u8> = Vec::new();
-        let number: u8 = 19;
-        handshake_message.push(number);
-        handshake_message.extend_from_slice("BitTorrent protocol".as_bytes());
-        handshake_message.extend_from_slice(&[0u8; 8]);
-        handshake_message.extend_from_slice(&mut info_hash);
-        handshake_message.extend_from_slice(&mut b"00112233445566778899".to_vec());
-        // Write some data.
-        stream.write_all(&handshake_message).await?;
-
-        let len = 68;
-        let mut buffer = vec![0; len];
 
+        let handshake = Handshake::new(OWN_CAPABILITIES, info_hash, peer_id);
+        stream.write_all(&handshake.encode()).await?;
+        stream.flush().await?;
+
+        let mut buffer = [0u8; HANDSHAKE_LENGTH];
         stream.read_exact(&mut buffer).await?;
-    
-        Ok(buffer)
 
-    } 
+        let peer_handshake = Handshake::decode(&buffer)?;
+        peer_handshake.validate(&info_hash, &peer_id)?;
+        self.start_session(&peer_handshake).await?;
+        Ok(peer_handshake)
+    }
 
-    async fn read_message(&mut self) -> Result<(u8, Vec<u8>), Box<dyn Error>> {
-        let stream = self.ensure_connected()?;
+    // Run the MSE handshake on a connection we opened, as the outgoing encryption policy says.
+    // When encryption is only preferred, a peer that fails it is connected to again without
+    async fn encrypt_connection(&mut self, info_hash: [u8; 20]) -> Result<(), Box<dyn Error>> {
+        let policy = self.client_config.get_outgoing_encryption();
+        if policy == EncryptionPolicy::Disable {
+            return Ok(());
+        }
+        let stream = self.stream.take().ok_or("Not connected to a peer")?;
+        let address = self.peer_address.ok_or("Not connected to a peer")?;
+        match mse::initiate(stream, &info_hash, policy).await.map_err(|e| e.to_string()) {
+            Ok(stream) => {
+                if !stream.is_encrypted() {
+                    eprintln!("Peer {} chose to continue without encryption", address);
+                }
+                self.attach(Box::new(stream), address);
+                Ok(())
+            }
+            Err(e) if policy == EncryptionPolicy::Prefer => {
+                eprintln!("Encryption with peer {} failed ({}), connecting without", address, e);
+                self.connect(&address.to_string()).await
+            }
+            Err(e) => Err(format!("Encryption failed: {}", e).into()),
+        }
+    }
 
-        // Read the message length prefix (4 bytes)
-        let mut length_prefix = [0u8; 4];
-        stream.read_exact(&mut length_prefix).await?;
-        let message_length = u32::from_be_bytes(length_prefix);
+    // Agree on the extensions both sides support and send what follows the handshakes
+    async fn start_session(&mut self, peer_handshake: &Handshake) -> Result<(), Box<dyn Error>> {
+        self.capabilities = OWN_CAPABILITIES.intersect(peer_handshake.get_capabilities());
 
-        // Read the rest of the message
-        let mut message = vec![0u8; message_length as usize];
-        stream.read_exact(&mut message).await?;
+        // the pieces we have must be announced right after the handshake. Without the fast
+        // extension an empty bitfield is left out, with it have all and have none save the bitfield
+        let local_count = self.local_pieces.as_ref().map_or(0, |local_pieces| local_pieces.count());
+        if self.capabilities.fast && local_count == 0 {
+            self.send_message(PeerMessage::HaveNone).await?;
+        } else if self.capabilities.fast && local_count == self.piece_count {
+            self.send_message(PeerMessage::HaveAll).await?;
+        } else if let Some(local_pieces) = self.local_pieces.clone().filter(|_| local_count > 0) {
+            self.send_message(PeerMessage::Bitfield { bitfield: local_pieces.get_bitfield() }).await?;
+        }
 
-        // The first byte of the message is the message ID
-        let message_id = message[0];
-        let payload = message[1..].to_vec();
+        if self.capabilities.extension_protocol {
+            let extended_handshake = ExtendedHandshake {
+                p: Some(self.client_config.get_port()),
+                reqq: Some(OWN_REQUEST_QUEUE),
+                ..Default::default()
+            };
+            let payload = extended_handshake.encode()?;
+            self.send_message(PeerMessage::Extended { extended_id: EXTENDED_HANDSHAKE_ID, payload }).await?;
+        }
+        if self.capabilities.fast {
+            self.grant_allowed_fast(peer_handshake.get_info_hash()).await?;
+        }
+        Ok(())
+    }
 
-        Ok((message_id, payload))
+    // Let the peer download the pieces of its allowed fast set we have while we choke it, so a
+    // new peer soon has something to trade. The set is only defined for IPv4 peers
+    async fn grant_allowed_fast(&mut self, info_hash: &[u8; 20]) -> Result<(), Box<dyn Error>> {
+        let local_pieces = match self.local_pieces.clone() {
+            Some(local_pieces) => local_pieces,
+            None => return Ok(()),
+        };
+        let address = match self.peer_address.ok_or("Not connected to a peer")?.ip() {
+            IpAddr::V4(address) => address,
+            IpAddr::V6(address) => match address.to_ipv4_mapped() {
+                Some(address) => address,
+                None => return Ok(()),
+            },
+        };
+        for index in allowed_fast_set(address, info_hash, self.piece_count as u32, ALLOWED_FAST_SET_SIZE) {
+            if local_pieces.has_piece(index) {
+                self.granted_fast.push(index);
+                self.send_message(PeerMessage::AllowedFast { index }).await?;
+            }
+        }
+        Ok(())
     }
 
-    async fn send_message(&mut self, message_id: u8, payload:  Vec<u8>) -> Result<(), Box<dyn Error>> {
-        let stream = self.ensure_connected()?;
-        
-        let length_prefix = ((payload.len() + 1) as u32).to_be_bytes();
-        
-        let mut message = vec![];
-        message.extend_from_slice(&length_prefix);
-        message.push(message_id);
-        message.extend_from_slice(&payload);
+    // Reads go through a buffer so that a read interrupted by another event loses no data
+    async fn read_message(stream: &mut Option<Box<dyn PeerStream>>, read_buffer: &mut BytesMut) -> Result<PeerMessage, Box<dyn Error>> {
+        loop {
+            if let Some(message) = PeerMessage::take_from(read_buffer)? {
+                return Ok(message);
+            }
+            let stream = stream.as_mut().ok_or("Not connected to a peer")?;
+            if stream.read_buf(read_buffer).await? == 0 {
+                return Err("Peer closed the connection".into());
+            }
+        }
+    }
+
+    // Writes go through a buffer as well: what an interrupted write did not get to stays queued,
+    // so no message is cut short
+    async fn write_queued(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.write_buffer.is_empty() {
+            return Ok(());
+        }
+        let stream = self.stream.as_mut().ok_or("Not connected to a peer")?;
+        while !self.write_buffer.is_empty() {
+            if stream.write_buf(&mut self.write_buffer).await? == 0 {
+                return Err("Peer closed the connection".into());
+            }
+        }
+        stream.flush().await?;
+        Ok(())
+    }
+
+    async fn send_message(&mut self, message: PeerMessage) -> Result<(), Box<dyn Error>> {
+        self.queue_message(message);
+        self.write_queued().await
+    }
 
-        stream.write_all( &message).await?;
+    // Queue a message for the next write, which is when the connection state counts it as sent
+    fn queue_message(&mut self, message: PeerMessage) {
+        self.write_buffer.extend_from_slice(&message.encode());
+        self.state.on_sent(&message);
+        self.last_sent = Instant::now();
+        if let Some(choke_link) = self.choke_link.as_ref() {
+            match &message {
+                PeerMessage::Piece { block, .. } => choke_link.record_upload(block.len()),
+                PeerMessage::Interested | PeerMessage::NotInterested => choke_link.set_am_interested(self.state.am_interested),
+                _ => {}
+            }
+        }
+    }
 
+    // Wait for the next message and update the connection state from it. Keep-alives are sent
+    // while waiting, and a peer that stays silent for longer than the idle timeout is an error.
+    // Requests of the peer are served and newly verified pieces announced on the way. Callers
+    // race this against other events, so the answers are only queued, and written out at the
+    // start of the next wait or send: interrupting it loses neither a message nor part of one
+    pub async fn wait_for_message(&mut self) -> Result<PeerMessage, Box<dyn Error>> {
+        loop {
+            self.write_queued().await?;
+            let keepalive_at = self.last_sent + self.keepalive_interval;
+            let idle_deadline = self.last_received + self.idle_timeout;
+            let added_pieces = &mut self.added_pieces;
+            let choke_link = &mut self.choke_link;
+            let event = tokio::select! {
+                message = Self::read_message(&mut self.stream, &mut self.read_buffer) => WaitEvent::Message(message?),
+                added = async { added_pieces.as_mut().unwrap().recv().await }, if added_pieces.is_some() => WaitEvent::PieceAdded(added),
+                Some(unchoke) = async { choke_link.as_mut()?.changed().await }, if choke_link.is_some() => WaitEvent::ChokeDecision(unchoke),
+                _ = tokio::time::sleep_until(keepalive_at) => WaitEvent::KeepAliveDue,
+                _ = tokio::time::sleep_until(idle_deadline) => {
+                    return Err(format!("Peer sent nothing for {}s ({})", self.idle_timeout.as_secs(), self.state).into());
+                }
+            };
+            match event {
+                // messages we do not implement only show that the peer is still there
+                WaitEvent::Message(PeerMessage::Unknown { .. }) => self.last_received = Instant::now(),
+                WaitEvent::Message(message) => {
+                    self.last_received = Instant::now();
+                    self.update_state(&message)?;
+                    self.serve(&message);
+                    return Ok(message);
+                }
+                WaitEvent::KeepAliveDue => self.queue_message(PeerMessage::KeepAlive),
+                WaitEvent::PieceAdded(added) => self.announce_pieces(added)?,
+                WaitEvent::ChokeDecision(unchoke) => {
+                    if unchoke == self.state.am_choking {
+                        let message = if unchoke { PeerMessage::Unchoke } else { PeerMessage::Choke };
+                        self.queue_message(message);
+                    }
+                }
+            }
+        }
+    }
+
+    // Answer block requests of an unchoked peer, or for its allowed fast pieces, from the pieces
+    // we have. Requests we do not serve are rejected if the peer supports the fast extension
+    fn serve(&mut self, message: &PeerMessage) {
+        let (index, begin, length) = match *message {
+            PeerMessage::Request { index, begin, length } => (index, begin, length),
+            PeerMessage::HashRequest(request) => return self.serve_hashes(request),
+            _ => return,
+        };
+        let local_pieces = match self.local_pieces.clone() {
+            Some(local_pieces) => local_pieces,
+            None => return self.reject(index, begin, length),
+        };
+        // a choked peer knows its requests are dropped
+        if self.state.am_choking && !self.granted_fast.contains(&index) {
+            return self.reject(index, begin, length);
+        }
+        if length > BLOCK_SIZE {
+            eprintln!("Ignoring request for {} bytes, more than a block", length);
+            return self.reject(index, begin, length);
+        }
+        match local_pieces.read_block(index, begin, length) {
+            Ok(Some(block)) => self.queue_message(PeerMessage::Piece { index, begin, block }),
+            Ok(None) => {
+                eprintln!("Ignoring request for a block of piece {} we do not have", index);
+                self.reject(index, begin, length)
+            }
+            Err(e) => {
+                eprintln!("Could not read block of piece {}: {}", index, e);
+                self.reject(index, begin, length)
+            }
+        }
+    }
+
+    // Answer a hash request from the piece layers we know, or reject it
+    fn serve_hashes(&mut self, request: HashRequest) {
+        let hashes = self.merkle_trees.as_ref().and_then(|merkle_trees| merkle_trees.answer(&request));
+        match hashes {
+            Some(hashes) => self.queue_message(PeerMessage::Hashes { request, hashes }),
+            None => self.queue_message(PeerMessage::HashReject(request)),
+        }
+    }
+
+    fn reject(&mut self, index: u32, begin: u32, length: u32) {
+        if self.capabilities.fast {
+            self.queue_message(PeerMessage::RejectRequest { index, begin, length });
+        }
+    }
+
+    // Queue have messages for pieces verified since the handshake, unless the peer has them
+    fn announce_pieces(&mut self, added: Result<u32, broadcast::error::RecvError>) -> Result<(), Box<dyn Error>> {
+        let local_pieces = self.local_pieces.clone().ok_or("No local pieces to announce")?;
+        let indexes: Vec<u32> = match added {
+            Ok(index) => vec![index],
+            // missed some, announce everything the peer lacks
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                let bitfield = local_pieces.get_bitfield();
+                (0..bitfield.len() as u32 * 8).filter(|&index| utils::has_bit(&bitfield, index as usize)).collect()
+            }
+            Err(broadcast::error::RecvError::Closed) => {
+                self.added_pieces = None;
+                vec![]
+            }
+        };
+        for index in indexes {
+            if !utils::has_bit(&self.peer_bitfield, index as usize) {
+                self.queue_message(PeerMessage::Have { index });
+            }
+        }
         Ok(())
     }
 
-    pub async fn wait_for_message(&mut self) -> Result<(u8, Vec<u8>), Box<dyn Error>> {
-        let (message_id, payload) = self.read_message().await?;
+    // Process incoming messages for a while, e.g. while there is nothing to request from the peer
+    pub async fn idle(&mut self, duration: Duration) -> Result<(), Box<dyn Error>> {
+        let deadline = Instant::now() + duration;
+        loop {
+            tokio::select! {
+                message = self.wait_for_message() => { message?; }
+                _ = tokio::time::sleep_until(deadline) => return Ok(()),
+            }
+        }
+    }
 
-        match message_id {
-            0 => println!("Received choke message"),
-            1 => println!("Received unchoke message"),
-            2 => println!("Received interested message"),
-            3 => println!("Received not interested message"),
-            4 => println!("Received have message"),
-            5 => println!("Received bitfield message"),
-            6 => println!("Received request message"),
-            7 => println!("Received piece message"),
-            8 => println!("Received cancel message"),
-            9 => println!("Received port message"),
-            _ => println!("Received unknown message with ID: {}", message_id),
+    // Tell the peer whether we want any of its pieces, if that changed
+    pub async fn set_interested(&mut self, interested: bool) -> Result<(), Box<dyn Error>> {
+        match (interested, self.state.am_interested) {
+            (true, false) => self.send_message(PeerMessage::Interested).await,
+            (false, true) => self.send_message(PeerMessage::NotInterested).await,
+            _ => Ok(()),
         }
+    }
 
-        Ok((message_id, payload))
+    // Update the connection state from a received message
+    fn update_state(&mut self, message: &PeerMessage) -> Result<(), Box<dyn Error>> {
+        self.state.on_received(message);
+        if let Some(choke_link) = self.choke_link.as_ref() {
+            match message {
+                PeerMessage::Piece { block, .. } => choke_link.record_download(block.len()),
+                PeerMessage::Interested | PeerMessage::NotInterested => choke_link.set_peer_interested(self.state.peer_interested),
+                _ => {}
+            }
+        }
+        match message {
+            PeerMessage::SuggestPiece { .. }
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone
+            | PeerMessage::RejectRequest { .. }
+            | PeerMessage::AllowedFast { .. } if !self.capabilities.fast => {
+                return Err(format!("Peer sent {} message without the fast extension", message.name()).into());
+            }
+            PeerMessage::Have { index } if *index as usize >= self.piece_count => {
+                return Err(format!("Peer sent have message for piece {} of {}", index, self.piece_count).into());
+            }
+            PeerMessage::Bitfield { bitfield } => {
+                // spare bytes are dropped, so the bitfield covers exactly the pieces of the torrent
+                self.peer_bitfield = bitfield.clone();
+                self.peer_bitfield.resize(self.piece_count.div_ceil(8), 0);
+            }
+            PeerMessage::Have { index } => utils::set_bit(&mut self.peer_bitfield, *index as usize),
+            PeerMessage::HaveAll => {
+                self.peer_bitfield = vec![0; self.piece_count.div_ceil(8)];
+                for index in 0..self.piece_count {
+                    utils::set_bit(&mut self.peer_bitfield, index);
+                }
+            }
+            PeerMessage::HaveNone => self.peer_bitfield = vec![0; self.piece_count.div_ceil(8)],
+            PeerMessage::AllowedFast { index } => {
+                let wanted = (*index as usize) < self.piece_count && self.allowed_fast.len() < MAX_ALLOWED_FAST_PIECES;
+                if wanted && !self.allowed_fast.contains(index) {
+                    self.allowed_fast.push(*index);
+                }
+            }
+            PeerMessage::SuggestPiece { index } => {
+                self.suggested.retain(|suggested| suggested != index);
+                self.suggested.push(*index);
+                if self.suggested.len() > MAX_SUGGESTED_PIECES {
+                    self.suggested.remove(0);
+                }
+            }
+            PeerMessage::Extended { extended_id: EXTENDED_HANDSHAKE_ID, payload } => {
+                let extended_handshake = ExtendedHandshake::decode(payload)?;
+                if let Some(reqq) = extended_handshake.reqq {
+                    self.pipeline.set_peer_limit(reqq);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
     }
+
+    // Tell the peer we are interested and wait until it unchokes us
     pub async fn init_download(&mut self) -> Result<(), Box<dyn Error>>{
-        let (message_id, _) = self.wait_for_message().await?;
-        if message_id != 5{
-            return Err("Could not find message id: \"bitfield\"".into())
+        self.set_interested(true).await?;
+        self.wait_for_unchoke().await
+    }
+
+    // Process incoming messages until the peer unchokes us or allows us some pieces while choked
+    pub async fn wait_for_unchoke(&mut self) -> Result<(), Box<dyn Error>> {
+        while self.state.peer_choking && self.allowed_fast.is_empty() {
+            self.wait_for_message().await?;
         }
-        self.send_message(2, vec![]).await?;
+        Ok(())
+    }
 
-        let (message_id, _) = self.wait_for_message().await?;
-        if message_id != 1{
-            return Err("Could not find message id: \"unchoke\"".into())
+    // Download a whole piece, keeping several block requests outstanding at once. Blocks are
+    // shared with other peers downloading the same piece in endgame mode; requests for blocks
+    // they deliver first are cancelled. An allowed fast piece is downloaded even while choked.
+    // Returns None if the peer keeps us choked for longer than the choke timeout or rejects our
+    // requests, so the piece can be requested from another peer
+    pub async fn download_piece(&mut self, index: u32, piece_length: u32, shared: &SharedBlocks) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let mut piece = PieceDownload::new(index, piece_length);
+        let mut updates = shared.subscribe();
+        for (begin, block) in shared.received() {
+            piece.fill_block(begin, &block);
         }
+        let mut choked_since = None;
+
+        while !piece.is_complete() {
+            let requestable = !self.state.peer_choking || self.allowed_fast.contains(&index);
+            if requestable {
+                choked_since = None;
+                while piece.outstanding_count() < self.pipeline.depth() {
+                    match piece.next_request() {
+                        Some((begin, length)) => self.send_message(PeerMessage::Request { index, begin, length }).await?,
+                        None => break,
+                    }
+                }
+            }
+            let deadline = *choked_since.get_or_insert_with(Instant::now) + self.choke_timeout;
+            let choking = !requestable;
 
+            let event = tokio::select! {
+                message = self.wait_for_message() => PieceEvent::Message(message?),
+                update = updates.recv() => PieceEvent::ReceivedElsewhere(update),
+                _ = tokio::time::sleep_until(deadline), if choking => return Ok(None),
+            };
+            let message = match event {
+                PieceEvent::Message(message) => message,
+                PieceEvent::ReceivedElsewhere(update) => {
+                    let received = match update {
+                        Ok(begin) => shared.get(begin).map(|block| vec![(begin, block)]).unwrap_or_default(),
+                        // missed some updates, catch up on everything
+                        Err(broadcast::error::RecvError::Lagged(_)) => shared.received(),
+                        Err(broadcast::error::RecvError::Closed) => vec![],
+                    };
+                    self.cancel_blocks_received_elsewhere(&mut piece, received).await?;
+                    continue;
+                }
+            };
+
+            match &message {
+                PeerMessage::Piece { index: block_index, begin, block } if *block_index == index => {
+                    let accepted = piece.on_block(*begin, block)?;
+                    if accepted && shared.insert(*begin, block) {
+                        self.pipeline.record_block(block.len());
+                    } else {
+                        // arrived after we cancelled it or another peer was faster
+                        shared.discard_duplicate(block.len());
+                    }
+                }
+                // Requests are discarded by a choking peer and sent again once it unchokes us.
+                // With the fast extension the peer rejects them one by one instead
+                PeerMessage::Choke if !self.capabilities.fast => piece.requeue_outstanding(),
+                PeerMessage::RejectRequest { index: rejected_index, begin, .. } if *rejected_index == index => {
+                    let requeued = piece.requeue_block(*begin);
+                    if requeued && !self.state.peer_choking {
+                        // an unchoking peer will not serve the piece at all
+                        return Ok(None);
+                    }
+                    if requeued {
+                        // nor will a choking one that takes back an allowed fast piece
+                        self.allowed_fast.retain(|&allowed| allowed != index);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Some(piece.into_data()))
+    }
+
+    // Ask the peer for hashes of the Merkle tree of a file (BEP 52). Returns None if the peer
+    // rejects the request
+    pub async fn request_hashes(&mut self, request: HashRequest) -> Result<Option<Vec<[u8; 32]>>, Box<dyn Error>> {
+        self.send_message(PeerMessage::HashRequest(request)).await?;
+        loop {
+            match self.wait_for_message().await? {
+                PeerMessage::Hashes { request: answered, hashes } if answered == request => return Ok(Some(hashes)),
+                PeerMessage::HashReject(rejected) if rejected == request => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+
+    // Place blocks delivered by other peers and cancel our requests for them
+    async fn cancel_blocks_received_elsewhere(&mut self, piece: &mut PieceDownload, received: Vec<(u32, Vec<u8>)>) -> Result<(), Box<dyn Error>> {
+        for (begin, block) in received {
+            if piece.fill_block(begin, &block) {
+                self.send_message(PeerMessage::Cancel { index: piece.get_index(), begin, length: block.len() as u32 }).await?;
+            }
+        }
         Ok(())
     }
-    pub async fn download_block(&mut self, index: u32, begin: u32, length: u32) -> Result<(u32,u32,Vec<u8>), Box<dyn Error>>{
-    
+}
+
+async fn connect_tcp(peer_address: &str) -> std::io::Result<(Box<dyn PeerStream>, SocketAddr)> {
+    let stream = TcpStream::connect(peer_address).await?;
+    let address = stream.peer_addr()?;
+    Ok((Box::new(stream), address))
+}
+
+async fn connect_utp(utp_socket: &UtpSocket, peer_address: &str) -> std::io::Result<(Box<dyn PeerStream>, SocketAddr)> {
+    let address = tokio::net::lookup_host(peer_address).await?.next()
+        .ok_or_else(|| std::io::Error::other("address did not resolve"))?;
+    let stream = utp_socket.connect(address).await?;
+    Ok((Box::new(stream), address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::local_pieces::tests::MemorySource;
+    use crate::clients::peer_listener::PeerListener;
+    use crate::clients::request_pipeline::BLOCK_SIZE;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    // Serves one piece, answering each batch of requests in reverse order and choking once
+    // with requests outstanding
+    async fn run_mock_seeder(listener: TcpListener, piece: Vec<u8>) -> usize {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [9; 20]).encode()).await.unwrap();
+        stream.write_all(&PeerMessage::Bitfield { bitfield: vec![0x80] }.encode()).await.unwrap();
+
+        let mut choked_once = false;
+        let mut max_outstanding = 0;
+        let mut requests = vec![];
+        loop {
+            let message = match PeerMessage::read_from(&mut stream).await {
+                Ok(message) => message,
+                Err(_) => return max_outstanding,
+            };
+            match message {
+                PeerMessage::Interested => stream.write_all(&PeerMessage::Unchoke.encode()).await.unwrap(),
+                PeerMessage::Request { index, begin, length } => {
+                    requests.push((index, begin, length));
+                    max_outstanding = max_outstanding.max(requests.len());
+                    if requests.len() < 3 && (begin + length) as usize != piece.len() {
+                        continue;
+                    }
+                    if !choked_once {
+                        // drop everything outstanding, as a choking peer does
+                        choked_once = true;
+                        requests.clear();
+                        stream.write_all(&PeerMessage::Choke.encode()).await.unwrap();
+                        stream.write_all(&PeerMessage::Have { index: 3 }.encode()).await.unwrap();
+                        stream.write_all(&PeerMessage::Unchoke.encode()).await.unwrap();
+                        continue;
+                    }
+                    for (index, begin, length) in requests.drain(..).rev() {
+                        let block = piece[begin as usize..(begin + length) as usize].to_vec();
+                        stream.write_all(&PeerMessage::KeepAlive.encode()).await.unwrap();
+                        stream.write_all(&PeerMessage::Piece { index, begin, block }.encode()).await.unwrap();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_download_with_out_of_order_blocks_and_choke() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let piece: Vec<u8> = (0..5 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let seeder = tokio::spawn(run_mock_seeder(listener, piece.clone()));
+
+        let mut peer_client = PeerClient::new(ClientConfig::new());
+        peer_client.set_piece_count(8);
+        peer_client.connect(&address).await.unwrap();
+        peer_client.perform_handshake(INFO_HASH).await.unwrap();
+        peer_client.init_download().await.unwrap();
+        let shared = SharedBlocks::new(Arc::new(AtomicU64::new(0)));
+        let downloaded = peer_client.download_piece(0, piece.len() as u32, &shared).await.unwrap().unwrap();
+        peer_client.disconnect().await.unwrap();
+
+        assert_eq!(downloaded, piece);
+        assert_eq!(peer_client.peer_bitfield, vec![0x90]);
+        // several requests were outstanding at once
+        assert!(seeder.await.unwrap() >= 3);
+    }
+
+    #[tokio::test]
+    async fn test_have_beyond_the_last_piece_closes_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [9; 20]).encode()).await.unwrap();
+            stream.write_all(&PeerMessage::Have { index: u32::MAX }.encode()).await.unwrap();
+            let _ = PeerMessage::read_from(&mut stream).await;
+        });
+
+        let mut peer_client = PeerClient::new(ClientConfig::new());
+        peer_client.set_piece_count(10);
+        peer_client.connect(&address).await.unwrap();
+        peer_client.perform_handshake(INFO_HASH).await.unwrap();
+        assert!(peer_client.wait_for_message().await.is_err());
+        assert_eq!(peer_client.peer_bitfield, vec![0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_messages_are_skipped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [9; 20]).encode()).await.unwrap();
+            // a vendor message with a payload, then a have
+            stream.write_all(&[0, 0, 0, 4, 99, 1, 2, 3]).await.unwrap();
+            stream.write_all(&PeerMessage::Have { index: 3 }.encode()).await.unwrap();
+            let _ = PeerMessage::read_from(&mut stream).await;
+        });
+
+        let mut peer_client = PeerClient::new(ClientConfig::new());
+        peer_client.set_piece_count(10);
+        peer_client.connect(&address).await.unwrap();
+        peer_client.perform_handshake(INFO_HASH).await.unwrap();
+        assert_eq!(peer_client.wait_for_message().await.unwrap(), PeerMessage::Have { index: 3 });
+    }
 
-        let mut payload = Vec::new();
-        payload.extend_from_slice(&index.to_be_bytes());
-        payload.extend_from_slice(&begin.to_be_bytes());
-        payload.extend_from_slice(&length.to_be_bytes());
-        self.send_message(6, payload).await?;
+    #[tokio::test]
+    async fn test_cancels_blocks_delivered_by_another_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let piece: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| (i % 13) as u8).collect();
+        let duplicate_bytes = Arc::new(AtomicU64::new(0));
+        let shared = SharedBlocks::new(duplicate_bytes.clone());
 
-        // Wait for a piece message (message_id = 7)
-        let (message_id, payload) = self.wait_for_message().await?;
-        if message_id != 7 {
-            return Err("Could not find message id: \"piece message\"".into());
+        // Holds both requests until the first block was delivered elsewhere and cancelled, then
+        // sends that block anyway, followed by the second one
+        let other_peer = shared.clone();
+        let seeder_piece = piece.clone();
+        let seeder = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [9; 20]).encode()).await.unwrap();
+            let mut requests = 0;
+            loop {
+                match PeerMessage::read_from(&mut stream).await.unwrap() {
+                    PeerMessage::Interested => stream.write_all(&PeerMessage::Unchoke.encode()).await.unwrap(),
+                    PeerMessage::Request { .. } => {
+                        requests += 1;
+                        if requests == 2 {
+                            other_peer.insert(0, &seeder_piece[..BLOCK_SIZE as usize]);
+                        }
+                    }
+                    PeerMessage::Cancel { index, begin, length } => {
+                        assert_eq!((index, begin, length), (0, 0, BLOCK_SIZE));
+                        for begin in [0, BLOCK_SIZE] {
+                            let block = seeder_piece[begin as usize..(begin + BLOCK_SIZE) as usize].to_vec();
+                            stream.write_all(&PeerMessage::Piece { index, begin, block }.encode()).await.unwrap();
+                        }
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let mut peer_client = PeerClient::new(ClientConfig::new());
+        peer_client.connect(&address).await.unwrap();
+        peer_client.perform_handshake(INFO_HASH).await.unwrap();
+        peer_client.init_download().await.unwrap();
+        let downloaded = peer_client.download_piece(0, piece.len() as u32, &shared).await.unwrap().unwrap();
+        seeder.await.unwrap();
+
+        assert_eq!(downloaded, piece);
+        assert_eq!(duplicate_bytes.load(Ordering::Relaxed), BLOCK_SIZE as u64);
+    }
+
+    #[tokio::test]
+    async fn test_keepalives_and_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // Announces pieces before and after unchoking without a bitfield, then only listens,
+        // counting the keep-alives until the connection is closed
+        let seeder = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [9; 20]).encode()).await.unwrap();
+            stream.write_all(&PeerMessage::Have { index: 1 }.encode()).await.unwrap();
+            stream.write_all(&PeerMessage::Interested.encode()).await.unwrap();
+            let mut keepalives = 0;
+            while let Ok(message) = PeerMessage::read_from(&mut stream).await {
+                match message {
+                    PeerMessage::Interested => {
+                        stream.write_all(&PeerMessage::Unchoke.encode()).await.unwrap();
+                        stream.write_all(&PeerMessage::Have { index: 2 }.encode()).await.unwrap();
+                    }
+                    PeerMessage::KeepAlive => keepalives += 1,
+                    _ => {}
+                }
+            }
+            keepalives
+        });
+
+        let mut peer_client = PeerClient::new(ClientConfig::new());
+        peer_client.set_piece_count(8);
+        peer_client.keepalive_interval = Duration::from_millis(50);
+        peer_client.idle_timeout = Duration::from_millis(300);
+        peer_client.connect(&address).await.unwrap();
+        peer_client.perform_handshake(INFO_HASH).await.unwrap();
+        peer_client.init_download().await.unwrap();
+        assert_eq!(peer_client.state, ConnectionState { am_choking: true, am_interested: true, peer_choking: false, peer_interested: true });
+
+        // only the have message follows, after that the peer stays silent
+        assert!(matches!(peer_client.wait_for_message().await.unwrap(), PeerMessage::Have { index: 2 }));
+        assert_eq!(peer_client.peer_bitfield, vec![0x60]);
+        let error = peer_client.wait_for_message().await.unwrap_err();
+        assert!(error.to_string().starts_with("Peer sent nothing"));
+        drop(peer_client);
+
+        assert!(seeder.await.unwrap() >= 3);
+    }
+
+    const FAST: Capabilities = Capabilities { dht: false, fast: true, extension_protocol: false };
+
+    #[tokio::test]
+    async fn test_fast_extension_download() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let piece: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| (i % 7) as u8).collect();
+
+        // Has all pieces but only allows piece 0 while choking, unchokes after serving it and
+        // then rejects every request for piece 1
+        let seeder_piece = piece.clone();
+        let seeder = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(FAST, INFO_HASH, [9; 20]).encode()).await.unwrap();
+            assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::HaveNone);
+            stream.write_all(&PeerMessage::HaveAll.encode()).await.unwrap();
+            stream.write_all(&PeerMessage::SuggestPiece { index: 1 }.encode()).await.unwrap();
+            stream.write_all(&PeerMessage::AllowedFast { index: 0 }.encode()).await.unwrap();
+            // past the last piece, so ignored
+            stream.write_all(&PeerMessage::AllowedFast { index: 10 }.encode()).await.unwrap();
+            let mut rejected = 0;
+            while let Ok(message) = PeerMessage::read_from(&mut stream).await {
+                match message {
+                    PeerMessage::Request { index: 0, begin, length } => {
+                        let block = seeder_piece[begin as usize..(begin + length) as usize].to_vec();
+                        stream.write_all(&PeerMessage::Piece { index: 0, begin, block }.encode()).await.unwrap();
+                        if begin + length == 2 * BLOCK_SIZE {
+                            tokio::time::sleep(Duration::from_millis(300)).await;
+                            stream.write_all(&PeerMessage::Unchoke.encode()).await.unwrap();
+                        }
+                    }
+                    PeerMessage::Request { index, begin, length } => {
+                        rejected += 1;
+                        stream.write_all(&PeerMessage::RejectRequest { index, begin, length }.encode()).await.unwrap();
+                    }
+                    _ => {}
+                }
+            }
+            rejected
+        });
+
+        let mut peer_client = PeerClient::new(ClientConfig::new());
+        peer_client.set_piece_count(10);
+        peer_client.set_choke_timeout(Duration::from_millis(100));
+        peer_client.connect(&address).await.unwrap();
+        peer_client.perform_handshake(INFO_HASH).await.unwrap();
+        peer_client.init_download().await.unwrap();
+        peer_client.idle(Duration::from_millis(50)).await.unwrap();
+        assert!(peer_client.is_choked());
+        assert_eq!(peer_client.get_bitfield(), &vec![0xff, 0xc0]);
+        assert_eq!(peer_client.get_allowed_fast(), &[0]);
+        assert_eq!(peer_client.get_suggested(), vec![1]);
+
+        let shared = SharedBlocks::new(Arc::new(AtomicU64::new(0)));
+        let downloaded = peer_client.download_piece(0, piece.len() as u32, &shared).await.unwrap();
+        assert_eq!(downloaded, Some(piece));
+        // other pieces wait for the unchoke, and are given up once rejected
+        let shared = SharedBlocks::new(Arc::new(AtomicU64::new(0)));
+        assert_eq!(peer_client.download_piece(1, 2 * BLOCK_SIZE, &shared).await.unwrap(), None);
+        while peer_client.is_choked() {
+            peer_client.wait_for_message().await.unwrap();
+        }
+        assert_eq!(peer_client.download_piece(1, 2 * BLOCK_SIZE, &shared).await.unwrap(), None);
+        peer_client.disconnect().await.unwrap();
+        assert!(seeder.await.unwrap() >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_fast_extension_upload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let data: Vec<u8> = (0..200).collect();
+        let local_pieces = Arc::new(LocalPieces::new(vec![10; 20], Arc::new(MemorySource { data, piece_length: 10 })));
+        for index in 0..20 {
+            local_pieces.add_piece(index);
+        }
+
+        let mut peer_client = PeerClient::new(ClientConfig::new());
+        peer_client.set_local_pieces(local_pieces);
+        let uploader = tokio::spawn(async move {
+            peer_client.connect(&address).await.unwrap();
+            peer_client.perform_handshake(INFO_HASH).await.unwrap();
+            while peer_client.wait_for_message().await.is_ok() {}
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream.write_all(&Handshake::new(FAST, INFO_HASH, [9; 20]).encode()).await.unwrap();
+        assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::HaveAll);
+        let granted = allowed_fast_set(Ipv4Addr::LOCALHOST, &INFO_HASH, 20, ALLOWED_FAST_SET_SIZE);
+        for &index in &granted {
+            assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::AllowedFast { index });
+        }
+
+        // while choked only the allowed fast pieces are served
+        let refused = (0..20).find(|index| !granted.contains(index)).unwrap();
+        stream.write_all(&PeerMessage::Request { index: refused, begin: 0, length: 10 }.encode()).await.unwrap();
+        stream.write_all(&PeerMessage::Request { index: granted[0], begin: 2, length: 8 }.encode()).await.unwrap();
+        assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::RejectRequest { index: refused, begin: 0, length: 10 });
+        let expected: Vec<u8> = (granted[0] as u8 * 10 + 2..granted[0] as u8 * 10 + 10).collect();
+        assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::Piece { index: granted[0], begin: 2, block: expected });
+        drop(stream);
+        uploader.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_waits_keep_pieces_whole() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let data: Vec<u8> = (0..20 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        let local_pieces = Arc::new(LocalPieces::new(vec![BLOCK_SIZE; 20], Arc::new(MemorySource { data: data.clone(), piece_length: BLOCK_SIZE })));
+        for index in 0..20 {
+            local_pieces.add_piece(index);
         }
 
-        // Extract the index, begin, and block from the payload
-        let index_from_payload = u32::from_be_bytes(payload[0..4].try_into().unwrap());
-        let begin_from_payload = u32::from_be_bytes(payload[4..8].try_into().unwrap());
-        let block = payload[8..8 + length as usize].to_vec();
+        let mut peer_client = PeerClient::new(ClientConfig::new());
+        peer_client.set_local_pieces(local_pieces);
+        let uploader = tokio::spawn(async move {
+            peer_client.connect(&address).await.unwrap();
+            peer_client.perform_handshake(INFO_HASH).await.unwrap();
+            // the idle time runs out again and again while pieces are being written
+            while peer_client.idle(Duration::from_millis(1)).await.is_ok() {}
+        });
 
-        Ok((index_from_payload, begin_from_payload, block))
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream.write_all(&Handshake::new(FAST, INFO_HASH, [9; 20]).encode()).await.unwrap();
+        assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::HaveAll);
+        let granted = allowed_fast_set(Ipv4Addr::LOCALHOST, &INFO_HASH, 20, ALLOWED_FAST_SET_SIZE);
+        for &index in &granted {
+            assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::AllowedFast { index });
+        }
+
+        // far more than the socket buffers hold, read only once the writes are stuck
+        let index = granted[0];
+        let requests = 1000;
+        for _ in 0..requests {
+            stream.write_all(&PeerMessage::Request { index, begin: 0, length: BLOCK_SIZE }.encode()).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let block = data[(index * BLOCK_SIZE) as usize..((index + 1) * BLOCK_SIZE) as usize].to_vec();
+        for _ in 0..requests {
+            assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::Piece { index, begin: 0, block: block.clone() });
+        }
+        drop(stream);
+        uploader.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_serves_piece_layer_hashes() {
+        use crate::torrent_manager::merkle_tree::tests::{answer_from_data, describe_file};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let piece_length = 2 * BLOCK_SIZE as u64;
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        let (file, piece_layers) = describe_file("file.bin", &data, piece_length);
+        let pieces_root = *file.get_pieces_root().unwrap();
+
+        let mut peer_client = PeerClient::new(ClientConfig::new());
+        peer_client.set_merkle_trees(Arc::new(MerkleTrees::new(&[file], &piece_layers, piece_length)));
+        let uploader = tokio::spawn(async move {
+            peer_client.connect(&address).await.unwrap();
+            peer_client.perform_handshake(INFO_HASH).await.unwrap();
+            while peer_client.wait_for_message().await.is_ok() {}
+        });
 
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [9; 20]).encode()).await.unwrap();
 
+        // the whole piece layer of a known file, padded to a power of two
+        let request = HashRequest { pieces_root, base_layer: 1, index: 0, length: 4, proof_layers: 0 };
+        stream.write_all(&PeerMessage::HashRequest(request).encode()).await.unwrap();
+        let hashes = answer_from_data(&data, piece_length, &request);
+        assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::Hashes { request, hashes });
+
+        let unknown = HashRequest { pieces_root: [9; 32], ..request };
+        stream.write_all(&PeerMessage::HashRequest(unknown).encode()).await.unwrap();
+        assert_eq!(PeerMessage::read_from(&mut stream).await.unwrap(), PeerMessage::HashReject(unknown));
+        drop(stream);
+        uploader.await.unwrap();
+    }
 
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn test_preferred_encryption_falls_back_to_plain() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let peer = tokio::spawn(async move {
+            // a peer without MSE gives up on the key exchange, then gets a plain handshake
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut start = [0u8; 20];
+            stream.read_exact(&mut start).await.unwrap();
+            assert_ne!(&start[1..], crate::clients::handshake::PROTOCOL);
+            drop(stream);
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [9; 20]).encode()).await.unwrap();
+        });
+
+        let mut client_config = ClientConfig::new();
+        client_config.set_outgoing_encryption(EncryptionPolicy::Prefer);
+        let mut peer_client = PeerClient::new(client_config);
+        peer_client.connect(&address).await.unwrap();
+        assert_eq!(peer_client.perform_handshake(INFO_HASH).await.unwrap().get_peer_id(), &[9; 20]);
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_session() {
+        let mut listener = PeerListener::bind("127.0.0.1:0", 10).await.unwrap();
+        listener.set_encryption(EncryptionPolicy::Require);
+        let listener = Arc::new(listener);
+        let address = listener.local_addr().unwrap().to_string();
+        let mut incoming = listener.register(INFO_HASH, 5);
+        let running = listener.clone();
+        tokio::spawn(async move { let _ = running.run().await; });
+
+        let mut client_config = ClientConfig::new();
+        client_config.set_outgoing_encryption(EncryptionPolicy::Require);
+        let mut outgoing = PeerClient::new(client_config);
+        let mut accepting = PeerClient::new(ClientConfig::new());
+        let (handshake, opened) = tokio::join!(
+            async {
+                outgoing.connect(&address).await.unwrap();
+                outgoing.perform_handshake(INFO_HASH).await.unwrap()
+            },
+            async {
+                let peer = incoming.recv().await.unwrap();
+                accepting.open(PeerConnection::Incoming(peer), INFO_HASH).await.unwrap()
+            },
+        );
+        assert_eq!(handshake.get_peer_id(), accepting.client_config.get_peer_id());
+        assert!(opened.is_some());
+
+        // both sides use the fast extension, so the messages after the handshake are have nones
+        assert_eq!(accepting.wait_for_message().await.unwrap(), PeerMessage::HaveNone);
+        outgoing.set_interested(true).await.unwrap();
+        assert_eq!(outgoing.wait_for_message().await.unwrap(), PeerMessage::HaveNone);
+        assert!(matches!(accepting.wait_for_message().await.unwrap(), PeerMessage::Extended { .. }));
+        assert_eq!(accepting.wait_for_message().await.unwrap(), PeerMessage::Interested);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_utp() {
+        // the peer takes uTP connections on a port without a TCP listener
+        let utp_socket = Arc::new(UtpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = utp_socket.local_addr().unwrap().to_string();
+        let mut listener = PeerListener::bind("127.0.0.1:0", 10).await.unwrap();
+        listener.set_utp_socket(utp_socket);
+        let listener = Arc::new(listener);
+        let mut incoming = listener.register(INFO_HASH, 5);
+        let running = listener.clone();
+        tokio::spawn(async move { let _ = running.run().await; });
+
+        let mut outgoing = PeerClient::new(ClientConfig::new());
+        assert!(outgoing.connect(&address).await.is_err());
+        outgoing.set_utp_socket(Arc::new(UtpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let mut accepting = PeerClient::new(ClientConfig::new());
+        let (handshake, opened) = tokio::join!(
+            async {
+                outgoing.connect(&address).await.unwrap();
+                outgoing.perform_handshake(INFO_HASH).await.unwrap()
+            },
+            async {
+                let peer = incoming.recv().await.unwrap();
+                accepting.open(PeerConnection::Incoming(peer), INFO_HASH).await.unwrap()
+            },
+        );
+        assert_eq!(handshake.get_peer_id(), accepting.client_config.get_peer_id());
+        assert!(opened.is_some());
+        assert_eq!(outgoing.wait_for_message().await.unwrap(), PeerMessage::HaveNone);
+        assert_eq!(accepting.wait_for_message().await.unwrap(), PeerMessage::HaveNone);
+    }
+}
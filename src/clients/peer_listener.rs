@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+
+use crate::clients::handshake::{Handshake, HANDSHAKE_LENGTH, PROTOCOL};
+use crate::clients::mse::{self, EncryptionPolicy};
+use crate::clients::utp::{UtpSocket, UtpStream};
+
+// Incoming connections kept open at the same time over all torrents
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+// A peer that does not send its handshake within this time is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Pause after a failed accept, e.g. when out of file descriptors, before accepting again
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// The byte stream of a peer connection: TCP or uTP, possibly wrapped in MSE encryption
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for T {}
+
+// Holds a slot of the global and of the torrent's connection limit until the connection is dropped
+pub struct ConnectionPermit {
+    _global: OwnedSemaphorePermit,
+    _torrent: OwnedSemaphorePermit,
+}
+
+// A connection opened by a peer, after its handshake was read. The torrent answers the
+// handshake and keeps the permit for as long as it uses the connection
+pub struct IncomingPeer {
+    pub stream: Box<dyn PeerStream>,
+    pub handshake: Handshake,
+    pub address: SocketAddr,
+    pub permit: ConnectionPermit,
+}
+
+// How a connection to a peer comes about
+pub enum PeerConnection {
+    Outgoing(String),
+    Incoming(IncomingPeer),
+}
+
+impl PeerConnection {
+    pub fn address(&self) -> String {
+        match self {
+            PeerConnection::Outgoing(address) => address.clone(),
+            PeerConnection::Incoming(incoming) => incoming.address.to_string(),
+        }
+    }
+}
+
+struct RegisteredTorrent {
+    connections: Arc<Semaphore>,
+    peers: mpsc::UnboundedSender<IncomingPeer>,
+}
+
+// Accepts peer connections on one port for any number of torrents and hands each connection to
+// the torrent named by the info hash of its handshake
+pub struct PeerListener {
+    listener: TcpListener,
+    connections: Arc<Semaphore>,
+    torrents: Arc<Mutex<HashMap<[u8; 20], RegisteredTorrent>>>,
+    encryption: EncryptionPolicy,
+    // accepts uTP connections next to the TCP ones, and opens ours
+    utp_socket: Option<Arc<UtpSocket>>,
+}
+
+impl PeerListener {
+    pub async fn bind(address: &str, max_connections: usize) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(address).await?,
+            connections: Arc::new(Semaphore::new(max_connections)),
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+            encryption: EncryptionPolicy::Prefer,
+            utp_socket: None,
+        })
+    }
+
+    // Whether peers may, or must, encrypt their connections
+    pub fn set_encryption(&mut self, encryption: EncryptionPolicy) {
+        self.encryption = encryption;
+    }
+
+    // Setter for utp_socket
+    pub fn set_utp_socket(&mut self, utp_socket: Arc<UtpSocket>) {
+        self.utp_socket = Some(utp_socket);
+    }
+
+    // Getter for utp_socket
+    pub fn get_utp_socket(&self) -> Option<Arc<UtpSocket>> {
+        self.utp_socket.clone()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Connections to the torrent are delivered through the returned receiver until it is dropped.
+    // Registering a torrent again replaces the previous receiver
+    pub fn register(&self, info_hash: [u8; 20], max_connections: usize) -> mpsc::UnboundedReceiver<IncomingPeer> {
+        let (peers, receiver) = mpsc::unbounded_channel();
+        let torrent = RegisteredTorrent { connections: Arc::new(Semaphore::new(max_connections)), peers };
+        self.torrents.lock().unwrap().insert(info_hash, torrent);
+        receiver
+    }
+
+    // Accept connections until the task is dropped; failed accepts are retried after a pause
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        loop {
+            let (stream, address): (Box<dyn PeerStream>, SocketAddr) = tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, address)) => (Box::new(stream), address),
+                    Err(e) => {
+                        eprintln!("Could not accept peer: {}", e);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                },
+                Ok(stream) = accept_utp(&self.utp_socket) => {
+                    let address = stream.peer_addr();
+                    (Box::new(stream), address)
+                }
+            };
+            let global = match self.connections.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    eprintln!("Refused peer {}: too many connections", address);
+                    continue;
+                }
+            };
+            let (torrents, encryption) = (self.torrents.clone(), self.encryption);
+            tokio::spawn(async move {
+                if let Err(e) = route(&torrents, stream, address, global, encryption).await {
+                    eprintln!("Refused peer {}: {}", address, e);
+                }
+            });
+        }
+    }
+}
+
+// The next uTP connection, or never without a uTP socket
+async fn accept_utp(utp_socket: &Option<Arc<UtpSocket>>) -> io::Result<UtpStream> {
+    match utp_socket {
+        Some(utp_socket) => utp_socket.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Read the handshake of a new connection and pass the connection on to its torrent
+async fn route(
+    torrents: &Mutex<HashMap<[u8; 20], RegisteredTorrent>>,
+    stream: Box<dyn PeerStream>,
+    address: SocketAddr,
+    global: OwnedSemaphorePermit,
+    encryption: EncryptionPolicy,
+) -> Result<(), Box<dyn Error>> {
+    let (stream, handshake) = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_handshake(torrents, stream, encryption)).await {
+        Ok(read) => read?,
+        Err(_) => return Err("no handshake received".into()),
+    };
+    let info_hash = *handshake.get_info_hash();
+
+    let mut torrents = torrents.lock().unwrap();
+    let torrent = torrents.get(&info_hash).ok_or_else(|| format!("unknown info hash {}", hex::encode(info_hash)))?;
+    let torrent_permit = torrent.connections.clone().try_acquire_owned()
+        .map_err(|_| "too many connections to the torrent")?;
+    let permit = ConnectionPermit { _global: global, _torrent: torrent_permit };
+    if torrent.peers.send(IncomingPeer { stream, handshake, address, permit }).is_err() {
+        torrents.remove(&info_hash);
+        return Err("torrent is no longer served".into());
+    }
+    Ok(())
+}
+
+// Read the BitTorrent handshake, after the MSE handshake if the connection starts with one
+async fn read_handshake(
+    torrents: &Mutex<HashMap<[u8; 20], RegisteredTorrent>>,
+    mut stream: Box<dyn PeerStream>,
+    encryption: EncryptionPolicy,
+) -> Result<(Box<dyn PeerStream>, Handshake), Box<dyn Error>> {
+    let mut start = [0u8; 20];
+    stream.read_exact(&mut start).await?;
+    let plain = start[0] as usize == PROTOCOL.len() && &start[1..] == PROTOCOL;
+    let (mut stream, read, encrypted_for): (Box<dyn PeerStream>, &[u8], _) = match (plain, encryption) {
+        (true, EncryptionPolicy::Require) => return Err("unencrypted connection".into()),
+        (true, _) => (stream, &start, None),
+        (false, EncryptionPolicy::Disable) => return Err("encrypted connection".into()),
+        (false, _) => {
+            // the MSE handshake names one of the torrents without revealing which
+            let info_hashes: Vec<[u8; 20]> = torrents.lock().unwrap().keys().copied().collect();
+            let (stream, info_hash) = mse::respond(stream, &start, &info_hashes, encryption).await?;
+            (Box::new(stream), &[], Some(info_hash))
+        }
+    };
+
+    let mut buffer = [0u8; HANDSHAKE_LENGTH];
+    buffer[..read.len()].copy_from_slice(read);
+    stream.read_exact(&mut buffer[read.len()..]).await?;
+    let handshake = Handshake::decode(&buffer)?;
+    if encrypted_for.is_some_and(|info_hash| &info_hash != handshake.get_info_hash()) {
+        return Err("handshake for another torrent than the encryption".into());
+    }
+    Ok((stream, handshake))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::handshake::Capabilities;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    // Open a connection to the listener and send a handshake for the torrent
+    async fn connect(address: SocketAddr, info_hash: [u8; 20]) -> TcpStream {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&Handshake::new(Capabilities::default(), info_hash, [9; 20]).encode()).await.unwrap();
+        stream
+    }
+
+    async fn is_closed(stream: &mut TcpStream) -> bool {
+        let mut byte = [0u8; 1];
+        matches!(tokio::time::timeout(Duration::from_millis(200), stream.read(&mut byte)).await, Ok(Ok(0)) | Ok(Err(_)))
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_info_hash_within_limits() {
+        let listener = Arc::new(PeerListener::bind("127.0.0.1:0", 3).await.unwrap());
+        let address = listener.local_addr().unwrap();
+        let mut first = listener.register([1; 20], 1);
+        let mut second = listener.register([2; 20], 5);
+        let running = listener.clone();
+        tokio::spawn(async move { let _ = running.run().await; });
+
+        let _a = connect(address, [1; 20]).await;
+        let incoming = first.recv().await.unwrap();
+        assert_eq!(incoming.handshake.get_info_hash(), &[1; 20]);
+
+        // the first torrent is full, the second takes connections until the global limit is hit
+        let mut refused = connect(address, [1; 20]).await;
+        assert!(is_closed(&mut refused).await);
+        let mut unknown = connect(address, [3; 20]).await;
+        assert!(is_closed(&mut unknown).await);
+        let _b = connect(address, [2; 20]).await;
+        let _c = connect(address, [2; 20]).await;
+        let kept = vec![second.recv().await.unwrap(), second.recv().await.unwrap()];
+        let mut over_limit = connect(address, [2; 20]).await;
+        assert!(is_closed(&mut over_limit).await);
+
+        // dropping a connection frees its slots
+        drop(incoming);
+        let _d = connect(address, [1; 20]).await;
+        assert!(first.recv().await.is_some());
+        drop(kept);
+    }
+
+    #[tokio::test]
+    async fn test_routes_encrypted_connections() {
+        let mut listener = PeerListener::bind("127.0.0.1:0", 10).await.unwrap();
+        listener.set_encryption(EncryptionPolicy::Require);
+        let listener = Arc::new(listener);
+        let address = listener.local_addr().unwrap();
+        let _other = listener.register([1; 20], 5);
+        let mut torrent = listener.register([2; 20], 5);
+        let running = listener.clone();
+        tokio::spawn(async move { let _ = running.run().await; });
+
+        let mut plain = connect(address, [2; 20]).await;
+        assert!(is_closed(&mut plain).await);
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut encrypted = mse::initiate(stream, &[2; 20], EncryptionPolicy::Require).await.unwrap();
+        encrypted.write_all(&Handshake::new(Capabilities::default(), [2; 20], [9; 20]).encode()).await.unwrap();
+        encrypted.write_all(b"ping").await.unwrap();
+        encrypted.flush().await.unwrap();
+        let mut incoming = torrent.recv().await.unwrap();
+        assert_eq!(incoming.handshake.get_info_hash(), &[2; 20]);
+        let mut ping = [0u8; 4];
+        incoming.stream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+    }
+}
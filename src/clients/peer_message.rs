@@ -0,0 +1,391 @@
+use bytes::{Buf, BytesMut};
+use thiserror::Error;
+#[cfg(test)]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Largest message accepted from a peer; fits a 16 KiB block as well as the bitfield of
+// torrents with millions of pieces
+pub const MAX_MESSAGE_SIZE: u32 = 1 << 20;
+
+const ID_CHOKE: u8 = 0;
+const ID_UNCHOKE: u8 = 1;
+const ID_INTERESTED: u8 = 2;
+const ID_NOT_INTERESTED: u8 = 3;
+const ID_HAVE: u8 = 4;
+const ID_BITFIELD: u8 = 5;
+const ID_REQUEST: u8 = 6;
+const ID_PIECE: u8 = 7;
+const ID_CANCEL: u8 = 8;
+const ID_PORT: u8 = 9;
+// Fast extension (BEP 6)
+const ID_SUGGEST_PIECE: u8 = 0x0d;
+const ID_HAVE_ALL: u8 = 0x0e;
+const ID_HAVE_NONE: u8 = 0x0f;
+const ID_REJECT_REQUEST: u8 = 0x10;
+const ID_ALLOWED_FAST: u8 = 0x11;
+// Extension protocol (BEP 10)
+const ID_EXTENDED: u8 = 20;
+// Merkle tree hashes of v2 torrents (BEP 52)
+const ID_HASH_REQUEST: u8 = 21;
+const ID_HASHES: u8 = 22;
+const ID_HASH_REJECT: u8 = 23;
+
+const HASH_REQUEST_LENGTH: usize = 48;
+
+#[derive(Debug, Error)]
+pub enum PeerMessageError {
+    #[error("peer message of {length} bytes exceeds the maximum of {max} bytes")]
+    MessageTooLarge { length: u32, max: u32 },
+    #[error("invalid length {length} for peer message with id {id}")]
+    InvalidLength { id: u8, length: usize },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+// Hashes of one layer of the Merkle tree of a file, identified by its pieces root. Layer 0 holds
+// the hashes of the 16 KiB blocks; the proof adds the uncle hashes of as many layers above the
+// requested ones
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    fn encode(&self, body: &mut Vec<u8>) {
+        body.extend_from_slice(&self.pieces_root);
+        for value in [self.base_layer, self.index, self.length, self.proof_layers] {
+            body.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn decode(payload: &[u8]) -> HashRequest {
+        HashRequest {
+            pieces_root: payload[..32].try_into().unwrap(),
+            base_layer: read_u32(payload, 32),
+            index: read_u32(payload, 36),
+            length: read_u32(payload, 40),
+            proof_layers: read_u32(payload, 44),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have { index: u32 },
+    Bitfield { bitfield: Vec<u8> },
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Port { port: u16 },
+    SuggestPiece { index: u32 },
+    HaveAll,
+    HaveNone,
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    AllowedFast { index: u32 },
+    Extended { extended_id: u8, payload: Vec<u8> },
+    HashRequest(HashRequest),
+    // the requested hashes followed by the proof
+    Hashes { request: HashRequest, hashes: Vec<[u8; 32]> },
+    HashReject(HashRequest),
+    // a message we do not implement, e.g. of a vendor extension; its payload is skipped
+    Unknown { id: u8 },
+}
+
+impl PeerMessage {
+
+    // Human readable message name used for logging
+    pub fn name(&self) -> &'static str {
+        match self {
+            PeerMessage::KeepAlive => "keep-alive",
+            PeerMessage::Choke => "choke",
+            PeerMessage::Unchoke => "unchoke",
+            PeerMessage::Interested => "interested",
+            PeerMessage::NotInterested => "not interested",
+            PeerMessage::Have { .. } => "have",
+            PeerMessage::Bitfield { .. } => "bitfield",
+            PeerMessage::Request { .. } => "request",
+            PeerMessage::Piece { .. } => "piece",
+            PeerMessage::Cancel { .. } => "cancel",
+            PeerMessage::Port { .. } => "port",
+            PeerMessage::SuggestPiece { .. } => "suggest piece",
+            PeerMessage::HaveAll => "have all",
+            PeerMessage::HaveNone => "have none",
+            PeerMessage::RejectRequest { .. } => "reject request",
+            PeerMessage::AllowedFast { .. } => "allowed fast",
+            PeerMessage::Extended { .. } => "extended",
+            PeerMessage::HashRequest(_) => "hash request",
+            PeerMessage::Hashes { .. } => "hashes",
+            PeerMessage::HashReject(_) => "hash reject",
+            PeerMessage::Unknown { .. } => "unknown",
+        }
+    }
+
+    // Serialize the message including its 4 byte length prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body: Vec<u8> = vec![];
+        match self {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => body.push(ID_CHOKE),
+            PeerMessage::Unchoke => body.push(ID_UNCHOKE),
+            PeerMessage::Interested => body.push(ID_INTERESTED),
+            PeerMessage::NotInterested => body.push(ID_NOT_INTERESTED),
+            PeerMessage::Have { index } => push_u32s(&mut body, ID_HAVE, &[*index]),
+            PeerMessage::Bitfield { bitfield } => {
+                body.push(ID_BITFIELD);
+                body.extend_from_slice(bitfield);
+            }
+            PeerMessage::Request { index, begin, length } => push_u32s(&mut body, ID_REQUEST, &[*index, *begin, *length]),
+            PeerMessage::Piece { index, begin, block } => {
+                push_u32s(&mut body, ID_PIECE, &[*index, *begin]);
+                body.extend_from_slice(block);
+            }
+            PeerMessage::Cancel { index, begin, length } => push_u32s(&mut body, ID_CANCEL, &[*index, *begin, *length]),
+            PeerMessage::Port { port } => {
+                body.push(ID_PORT);
+                body.extend_from_slice(&port.to_be_bytes());
+            }
+            PeerMessage::SuggestPiece { index } => push_u32s(&mut body, ID_SUGGEST_PIECE, &[*index]),
+            PeerMessage::HaveAll => body.push(ID_HAVE_ALL),
+            PeerMessage::HaveNone => body.push(ID_HAVE_NONE),
+            PeerMessage::RejectRequest { index, begin, length } => push_u32s(&mut body, ID_REJECT_REQUEST, &[*index, *begin, *length]),
+            PeerMessage::AllowedFast { index } => push_u32s(&mut body, ID_ALLOWED_FAST, &[*index]),
+            PeerMessage::Extended { extended_id, payload } => {
+                body.push(ID_EXTENDED);
+                body.push(*extended_id);
+                body.extend_from_slice(payload);
+            }
+            PeerMessage::HashRequest(request) => {
+                body.push(ID_HASH_REQUEST);
+                request.encode(&mut body);
+            }
+            PeerMessage::Hashes { request, hashes } => {
+                body.push(ID_HASHES);
+                request.encode(&mut body);
+                for hash in hashes {
+                    body.extend_from_slice(hash);
+                }
+            }
+            PeerMessage::HashReject(request) => {
+                body.push(ID_HASH_REJECT);
+                request.encode(&mut body);
+            }
+            PeerMessage::Unknown { id } => body.push(*id),
+        }
+
+        let mut message = Vec::with_capacity(4 + body.len());
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        message.extend_from_slice(&body);
+        message
+    }
+
+    // Parse a message body, i.e. everything after the length prefix
+    pub fn decode(body: &[u8]) -> Result<PeerMessage, PeerMessageError> {
+        if body.is_empty() {
+            return Ok(PeerMessage::KeepAlive);
+        }
+        let id = body[0];
+        let payload = &body[1..];
+        let expect_length = |length: usize| {
+            if payload.len() == length {
+                Ok(())
+            } else {
+                Err(PeerMessageError::InvalidLength { id, length: body.len() })
+            }
+        };
+
+        let message = match id {
+            ID_CHOKE => expect_length(0).map(|_| PeerMessage::Choke)?,
+            ID_UNCHOKE => expect_length(0).map(|_| PeerMessage::Unchoke)?,
+            ID_INTERESTED => expect_length(0).map(|_| PeerMessage::Interested)?,
+            ID_NOT_INTERESTED => expect_length(0).map(|_| PeerMessage::NotInterested)?,
+            ID_HAVE => expect_length(4).map(|_| PeerMessage::Have { index: read_u32(payload, 0) })?,
+            ID_BITFIELD => PeerMessage::Bitfield { bitfield: payload.to_vec() },
+            ID_REQUEST => {
+                expect_length(12)?;
+                PeerMessage::Request { index: read_u32(payload, 0), begin: read_u32(payload, 4), length: read_u32(payload, 8) }
+            }
+            ID_PIECE => {
+                if payload.len() < 8 {
+                    return Err(PeerMessageError::InvalidLength { id, length: body.len() });
+                }
+                PeerMessage::Piece { index: read_u32(payload, 0), begin: read_u32(payload, 4), block: payload[8..].to_vec() }
+            }
+            ID_CANCEL => {
+                expect_length(12)?;
+                PeerMessage::Cancel { index: read_u32(payload, 0), begin: read_u32(payload, 4), length: read_u32(payload, 8) }
+            }
+            ID_PORT => expect_length(2).map(|_| PeerMessage::Port { port: u16::from_be_bytes([payload[0], payload[1]]) })?,
+            ID_SUGGEST_PIECE => expect_length(4).map(|_| PeerMessage::SuggestPiece { index: read_u32(payload, 0) })?,
+            ID_HAVE_ALL => expect_length(0).map(|_| PeerMessage::HaveAll)?,
+            ID_HAVE_NONE => expect_length(0).map(|_| PeerMessage::HaveNone)?,
+            ID_REJECT_REQUEST => {
+                expect_length(12)?;
+                PeerMessage::RejectRequest { index: read_u32(payload, 0), begin: read_u32(payload, 4), length: read_u32(payload, 8) }
+            }
+            ID_ALLOWED_FAST => expect_length(4).map(|_| PeerMessage::AllowedFast { index: read_u32(payload, 0) })?,
+            ID_EXTENDED => {
+                if payload.is_empty() {
+                    return Err(PeerMessageError::InvalidLength { id, length: body.len() });
+                }
+                PeerMessage::Extended { extended_id: payload[0], payload: payload[1..].to_vec() }
+            }
+            ID_HASH_REQUEST => expect_length(HASH_REQUEST_LENGTH).map(|_| PeerMessage::HashRequest(HashRequest::decode(payload)))?,
+            ID_HASHES => {
+                if payload.len() < HASH_REQUEST_LENGTH || !(payload.len() - HASH_REQUEST_LENGTH).is_multiple_of(32) {
+                    return Err(PeerMessageError::InvalidLength { id, length: body.len() });
+                }
+                let hashes = payload[HASH_REQUEST_LENGTH..].chunks(32).map(|hash| hash.try_into().unwrap()).collect();
+                PeerMessage::Hashes { request: HashRequest::decode(payload), hashes }
+            }
+            ID_HASH_REJECT => expect_length(HASH_REQUEST_LENGTH).map(|_| PeerMessage::HashReject(HashRequest::decode(payload)))?,
+            _ => PeerMessage::Unknown { id },
+        };
+        Ok(message)
+    }
+
+    // Read one length prefixed message from a stream. Not cancel safe, so only the mock peers
+    // of the tests read this way
+    #[cfg(test)]
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<PeerMessage, PeerMessageError> {
+        let mut length_prefix = [0u8; 4];
+        reader.read_exact(&mut length_prefix).await?;
+        let length = u32::from_be_bytes(length_prefix);
+        if length > MAX_MESSAGE_SIZE {
+            return Err(PeerMessageError::MessageTooLarge { length, max: MAX_MESSAGE_SIZE });
+        }
+
+        let mut body = vec![0u8; length as usize];
+        reader.read_exact(&mut body).await?;
+        PeerMessage::decode(&body)
+    }
+
+    // Take one complete message off the front of a receive buffer, None if more data is needed
+    pub fn take_from(buffer: &mut BytesMut) -> Result<Option<PeerMessage>, PeerMessageError> {
+        if buffer.len() < 4 {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(buffer[..4].try_into().unwrap());
+        if length > MAX_MESSAGE_SIZE {
+            return Err(PeerMessageError::MessageTooLarge { length, max: MAX_MESSAGE_SIZE });
+        }
+        if buffer.len() < 4 + length as usize {
+            buffer.reserve(4 + length as usize - buffer.len());
+            return Ok(None);
+        }
+
+        buffer.advance(4);
+        let body = buffer.split_to(length as usize);
+        PeerMessage::decode(&body).map(Some)
+    }
+}
+
+fn push_u32s(body: &mut Vec<u8>, id: u8, values: &[u32]) {
+    body.push(id);
+    for value in values {
+        body.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn read_u32(payload: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(payload[offset..offset + 4].try_into().unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_REQUEST: HashRequest = HashRequest { pieces_root: [9; 32], base_layer: 0, index: 4, length: 2, proof_layers: 1 };
+
+    #[test]
+    fn test_round_trip() {
+        let messages = vec![
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have { index: 7 },
+            PeerMessage::Bitfield { bitfield: vec![0b1010_0000, 0xff] },
+            PeerMessage::Request { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Piece { index: 1, begin: 0, block: vec![1, 2, 3] },
+            PeerMessage::Cancel { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Port { port: 6881 },
+            PeerMessage::SuggestPiece { index: 3 },
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest { index: 1, begin: 0, length: 16384 },
+            PeerMessage::AllowedFast { index: 4 },
+            PeerMessage::Extended { extended_id: 0, payload: b"de".to_vec() },
+            PeerMessage::HashRequest(HASH_REQUEST),
+            PeerMessage::Hashes { request: HASH_REQUEST, hashes: vec![[1; 32], [2; 32], [3; 32]] },
+            PeerMessage::HashReject(HASH_REQUEST),
+        ];
+        for message in messages {
+            let encoded = message.encode();
+            assert_eq!(u32::from_be_bytes(encoded[0..4].try_into().unwrap()) as usize, encoded.len() - 4);
+            assert_eq!(PeerMessage::decode(&encoded[4..]).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_eq!(PeerMessage::KeepAlive.encode(), vec![0, 0, 0, 0]);
+        assert_eq!(PeerMessage::Have { index: 258 }.encode(), vec![0, 0, 0, 5, 4, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        assert!(matches!(PeerMessage::decode(&[ID_HAVE, 0, 0]), Err(PeerMessageError::InvalidLength { id: ID_HAVE, length: 3 })));
+        assert!(matches!(PeerMessage::decode(&[ID_CHOKE, 0]), Err(PeerMessageError::InvalidLength { .. })));
+        assert!(matches!(PeerMessage::decode(&[ID_PIECE, 0, 0, 0, 0]), Err(PeerMessageError::InvalidLength { .. })));
+        assert!(matches!(PeerMessage::decode(&[ID_EXTENDED]), Err(PeerMessageError::InvalidLength { .. })));
+        // messages we do not implement are not an error, whatever their payload
+        assert_eq!(PeerMessage::decode(&[99, 1, 2]).unwrap(), PeerMessage::Unknown { id: 99 });
+
+        // a hashes message holds whole hashes after the request
+        let mut hashes = PeerMessage::Hashes { request: HASH_REQUEST, hashes: vec![[1; 32]] }.encode();
+        hashes.pop();
+        assert!(matches!(PeerMessage::decode(&hashes[4..]), Err(PeerMessageError::InvalidLength { id: ID_HASHES, .. })));
+        assert!(matches!(PeerMessage::decode(&[ID_HASH_REQUEST, 0]), Err(PeerMessageError::InvalidLength { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_read_from_stream() {
+        let mut data = PeerMessage::KeepAlive.encode();
+        data.extend(PeerMessage::Unchoke.encode());
+        let mut reader = data.as_slice();
+        assert_eq!(PeerMessage::read_from(&mut reader).await.unwrap(), PeerMessage::KeepAlive);
+        assert_eq!(PeerMessage::read_from(&mut reader).await.unwrap(), PeerMessage::Unchoke);
+        assert!(matches!(PeerMessage::read_from(&mut reader).await, Err(PeerMessageError::Io(_))));
+
+        let too_large = (MAX_MESSAGE_SIZE + 1).to_be_bytes();
+        let mut reader = too_large.as_slice();
+        assert!(matches!(PeerMessage::read_from(&mut reader).await, Err(PeerMessageError::MessageTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_take_from_partial_buffer() {
+        let mut data = PeerMessage::Have { index: 7 }.encode();
+        data.extend(PeerMessage::KeepAlive.encode());
+        let mut buffer = BytesMut::from(&data[..6]);
+        assert_eq!(PeerMessage::take_from(&mut buffer).unwrap(), None);
+
+        buffer.extend_from_slice(&data[6..]);
+        assert_eq!(PeerMessage::take_from(&mut buffer).unwrap(), Some(PeerMessage::Have { index: 7 }));
+        assert_eq!(PeerMessage::take_from(&mut buffer).unwrap(), Some(PeerMessage::KeepAlive));
+        assert_eq!(PeerMessage::take_from(&mut buffer).unwrap(), None);
+
+        let mut buffer = BytesMut::from(&(MAX_MESSAGE_SIZE + 1).to_be_bytes()[..]);
+        assert!(matches!(PeerMessage::take_from(&mut buffer), Err(PeerMessageError::MessageTooLarge { .. })));
+    }
+}
@@ -0,0 +1,69 @@
+// The RC4 stream cipher. Encryption and decryption are the same operation
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    // Skip the start of the keystream, which leaks information about the key
+    pub fn discard(&mut self, length: usize) {
+        self.apply(&mut vec![0u8; length]);
+    }
+
+    // XOR the data with the next bytes of the keystream
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_keystreams() {
+        for (key, plaintext, ciphertext) in [
+            ("Key", "Plaintext", "bbf316e8d940af0ad3"),
+            ("Wiki", "pedia", "1021bf0420"),
+            ("Secret", "Attack at dawn", "45a01f645fc35b383552544b9bf5"),
+        ] {
+            let mut data = plaintext.as_bytes().to_vec();
+            Rc4::new(key.as_bytes()).apply(&mut data);
+            assert_eq!(hex::encode(&data), ciphertext);
+            Rc4::new(key.as_bytes()).apply(&mut data);
+            assert_eq!(data, plaintext.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_discard_advances_the_keystream() {
+        let mut whole = vec![0u8; 12];
+        Rc4::new(b"Key").apply(&mut whole);
+        let mut cipher = Rc4::new(b"Key");
+        cipher.discard(5);
+        let mut rest = vec![0u8; 7];
+        cipher.apply(&mut rest);
+        assert_eq!(rest, &whole[5..]);
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+// Size of the blocks a piece is requested in
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+// Outstanding requests before the download rate of the peer is known
+const INITIAL_DEPTH: usize = 5;
+const MIN_DEPTH: usize = 2;
+// Keep enough requests outstanding to cover this much time at the measured rate
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+// Length of the window the download rate is measured over
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// Decides how many requests to keep outstanding on one connection, adapting to the peer's
+// advertised request queue (reqq) and the measured download rate
+pub struct RequestPipeline {
+    max_depth: usize,
+    rate: f64, // bytes per second, 0 until the first window completed
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl RequestPipeline {
+    pub fn new(max_depth: usize) -> Self {
+        Self { max_depth: max_depth.max(1), rate: 0.0, window_start: Instant::now(), window_bytes: 0 }
+    }
+
+    // Never queue more requests than the peer said it accepts
+    pub fn set_peer_limit(&mut self, reqq: u32) {
+        self.max_depth = self.max_depth.min(reqq.max(1) as usize);
+    }
+
+    pub fn record_block(&mut self, bytes: usize) {
+        self.record_block_at(bytes, Instant::now());
+    }
+
+    fn record_block_at(&mut self, bytes: usize, now: Instant) {
+        self.window_bytes += bytes as u64;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            let window_rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+            // exponential moving average smooths out bursts
+            self.rate = if self.rate == 0.0 { window_rate } else { 0.7 * self.rate + 0.3 * window_rate };
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        if self.rate == 0.0 {
+            return INITIAL_DEPTH.min(self.max_depth);
+        }
+        let depth = (self.rate * QUEUE_TIME.as_secs_f64() / BLOCK_SIZE as f64).ceil() as usize;
+        depth.clamp(MIN_DEPTH.min(self.max_depth), self.max_depth)
+    }
+}
+
+// Block bookkeeping of one piece being downloaded from a peer
+pub struct PieceDownload {
+    index: u32,
+    buffer: Vec<u8>,
+    pending: VecDeque<(u32, u32)>,     // (begin, length) not yet requested
+    outstanding: HashMap<u32, u32>,    // begin -> length of requested blocks
+    received_bytes: usize,
+}
+
+impl PieceDownload {
+    pub fn new(index: u32, piece_length: u32) -> Self {
+        let pending = (0..piece_length)
+            .step_by(BLOCK_SIZE as usize)
+            .map(|begin| (begin, BLOCK_SIZE.min(piece_length - begin)))
+            .collect();
+        Self {
+            index,
+            buffer: vec![0u8; piece_length as usize],
+            pending,
+            outstanding: HashMap::new(),
+            received_bytes: 0,
+        }
+    }
+
+    // Getter for index
+    pub fn get_index(&self) -> u32 {
+        self.index
+    }
+
+    // Take the next block to request, remembering it as outstanding
+    pub fn next_request(&mut self) -> Option<(u32, u32)> {
+        let (begin, length) = self.pending.pop_front()?;
+        self.outstanding.insert(begin, length);
+        Some((begin, length))
+    }
+
+    pub fn outstanding_count(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    // Place a received block by its offset; blocks may arrive in any order. Returns false for
+    // blocks that are not outstanding, e.g. sent by the peer after it choked us
+    pub fn on_block(&mut self, begin: u32, block: &[u8]) -> Result<bool, Box<dyn Error>> {
+        match self.outstanding.get(&begin) {
+            Some(&length) if length as usize == block.len() => {
+                self.outstanding.remove(&begin);
+                self.buffer[begin as usize..begin as usize + block.len()].copy_from_slice(block);
+                self.received_bytes += block.len();
+                Ok(true)
+            }
+            Some(&length) => Err(format!(
+                "Block {}:{} has {} bytes, requested {}", self.index, begin, block.len(), length
+            ).into()),
+            None => Ok(false),
+        }
+    }
+
+    // Place a block another peer downloaded. Returns true if we had requested it ourselves, in
+    // which case the request should be cancelled
+    pub fn fill_block(&mut self, begin: u32, block: &[u8]) -> bool {
+        let was_outstanding = self.outstanding.remove(&begin).is_some();
+        let was_pending = match self.pending.iter().position(|&(pending_begin, _)| pending_begin == begin) {
+            Some(position) => self.pending.remove(position).is_some(),
+            None => false,
+        };
+        if was_outstanding || was_pending {
+            self.buffer[begin as usize..begin as usize + block.len()].copy_from_slice(block);
+            self.received_bytes += block.len();
+        }
+        was_outstanding
+    }
+
+    // A choking peer discards our requests, so they have to be sent again after unchoke
+    pub fn requeue_outstanding(&mut self) {
+        let mut outstanding: Vec<(u32, u32)> = self.outstanding.drain().collect();
+        outstanding.sort_unstable();
+        for block in outstanding.into_iter().rev() {
+            self.pending.push_front(block);
+        }
+    }
+
+    // A block the peer rejected is requested again later; returns false if it was not outstanding
+    pub fn requeue_block(&mut self, begin: u32) -> bool {
+        match self.outstanding.remove(&begin) {
+            Some(length) => {
+                self.pending.push_front((begin, length));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received_bytes == self.buffer.len()
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+// Blocks of one piece received by any of the peers downloading it. In endgame mode several
+// peers download the same piece and learn about each other's blocks through this
+#[derive(Clone)]
+pub struct SharedBlocks {
+    blocks: Arc<Mutex<HashMap<u32, Vec<u8>>>>,
+    sender: broadcast::Sender<u32>,
+    duplicate_bytes: Arc<AtomicU64>,
+}
+
+impl SharedBlocks {
+    // Duplicate blocks of every piece are counted in duplicate_bytes
+    pub fn new(duplicate_bytes: Arc<AtomicU64>) -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self { blocks: Arc::new(Mutex::new(HashMap::new())), sender, duplicate_bytes }
+    }
+
+    // Offsets of the blocks received from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<u32> {
+        self.sender.subscribe()
+    }
+
+    // Publish a received block; returns false if another peer delivered it first
+    pub fn insert(&self, begin: u32, block: &[u8]) -> bool {
+        let mut blocks = self.blocks.lock().unwrap();
+        if blocks.contains_key(&begin) {
+            return false;
+        }
+        blocks.insert(begin, block.to_vec());
+        let _ = self.sender.send(begin);
+        true
+    }
+
+    pub fn get(&self, begin: u32) -> Option<Vec<u8>> {
+        self.blocks.lock().unwrap().get(&begin).cloned()
+    }
+
+    // Forget a block that turned out to be bad, so that it is downloaded again
+    pub fn remove(&self, begin: u32) {
+        self.blocks.lock().unwrap().remove(&begin);
+    }
+
+    // All blocks received so far
+    pub fn received(&self) -> Vec<(u32, Vec<u8>)> {
+        self.blocks.lock().unwrap().iter().map(|(&begin, block)| (begin, block.clone())).collect()
+    }
+
+    pub fn discard_duplicate(&self, bytes: usize) {
+        self.duplicate_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_of_a_piece() {
+        let mut piece = PieceDownload::new(0, 2 * BLOCK_SIZE + 10);
+        assert_eq!(piece.next_request(), Some((0, BLOCK_SIZE)));
+        assert_eq!(piece.next_request(), Some((BLOCK_SIZE, BLOCK_SIZE)));
+        assert_eq!(piece.next_request(), Some((2 * BLOCK_SIZE, 10)));
+        assert_eq!(piece.next_request(), None);
+
+        // A piece that is a multiple of the block size has no empty trailing block
+        let mut piece = PieceDownload::new(0, BLOCK_SIZE);
+        assert_eq!(piece.next_request(), Some((0, BLOCK_SIZE)));
+        assert_eq!(piece.next_request(), None);
+    }
+
+    #[test]
+    fn test_out_of_order_blocks() {
+        let mut piece = PieceDownload::new(3, BLOCK_SIZE + 4);
+        piece.next_request();
+        piece.next_request();
+        assert!(piece.on_block(BLOCK_SIZE, &[1, 2, 3, 4]).unwrap());
+        assert!(!piece.is_complete());
+        assert!(piece.on_block(0, &vec![9; BLOCK_SIZE as usize]).unwrap());
+        assert!(piece.is_complete());
+
+        let data = piece.into_data();
+        assert_eq!(&data[BLOCK_SIZE as usize..], &[1, 2, 3, 4]);
+        assert_eq!(data[0], 9);
+    }
+
+    #[test]
+    fn test_ignores_unrequested_and_rejects_wrong_length_blocks() {
+        let mut piece = PieceDownload::new(0, 2 * BLOCK_SIZE);
+        piece.next_request();
+        assert!(!piece.on_block(BLOCK_SIZE, &vec![0; BLOCK_SIZE as usize]).unwrap());
+        assert!(piece.on_block(0, &[0; 10]).is_err());
+        assert!(piece.on_block(0, &vec![0; BLOCK_SIZE as usize]).unwrap());
+        // a duplicate of a block already received is ignored as well
+        assert!(!piece.on_block(0, &vec![0; BLOCK_SIZE as usize]).unwrap());
+    }
+
+    #[test]
+    fn test_requeue_after_choke() {
+        let mut piece = PieceDownload::new(0, 3 * BLOCK_SIZE);
+        piece.next_request();
+        piece.next_request();
+        piece.requeue_outstanding();
+        assert_eq!(piece.outstanding_count(), 0);
+        assert_eq!(piece.next_request(), Some((0, BLOCK_SIZE)));
+        assert_eq!(piece.next_request(), Some((BLOCK_SIZE, BLOCK_SIZE)));
+    }
+
+    #[test]
+    fn test_requeue_rejected_block() {
+        let mut piece = PieceDownload::new(0, 3 * BLOCK_SIZE);
+        piece.next_request();
+        piece.next_request();
+        assert!(piece.requeue_block(BLOCK_SIZE));
+        assert!(!piece.requeue_block(2 * BLOCK_SIZE));
+        assert_eq!(piece.outstanding_count(), 1);
+        assert_eq!(piece.next_request(), Some((BLOCK_SIZE, BLOCK_SIZE)));
+    }
+
+    #[test]
+    fn test_fill_blocks_from_other_peers() {
+        let mut piece = PieceDownload::new(0, 3 * BLOCK_SIZE);
+        piece.next_request();
+        assert!(piece.fill_block(0, &vec![1; BLOCK_SIZE as usize]));
+        assert!(!piece.fill_block(2 * BLOCK_SIZE, &vec![3; BLOCK_SIZE as usize]));
+        assert_eq!(piece.outstanding_count(), 0);
+        // already filled, nothing changes
+        assert!(!piece.fill_block(0, &vec![7; BLOCK_SIZE as usize]));
+
+        assert_eq!(piece.next_request(), Some((BLOCK_SIZE, BLOCK_SIZE)));
+        assert_eq!(piece.next_request(), None);
+        piece.on_block(BLOCK_SIZE, &vec![2; BLOCK_SIZE as usize]).unwrap();
+        assert!(piece.is_complete());
+        let data = piece.into_data();
+        assert_eq!((data[0], data[BLOCK_SIZE as usize], data[2 * BLOCK_SIZE as usize]), (1, 2, 3));
+    }
+
+    #[test]
+    fn test_shared_blocks() {
+        let duplicate_bytes = Arc::new(AtomicU64::new(0));
+        let shared = SharedBlocks::new(duplicate_bytes.clone());
+        let mut updates = shared.subscribe();
+        assert!(shared.insert(BLOCK_SIZE, &[1, 2]));
+        assert!(!shared.insert(BLOCK_SIZE, &[1, 2]));
+        assert_eq!(updates.try_recv().unwrap(), BLOCK_SIZE);
+        assert!(updates.try_recv().is_err());
+        assert_eq!(shared.get(BLOCK_SIZE), Some(vec![1, 2]));
+        assert_eq!(shared.received(), vec![(BLOCK_SIZE, vec![1, 2])]);
+
+        shared.discard_duplicate(2);
+        assert_eq!(duplicate_bytes.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_pipeline_depth_adapts_to_rate_and_reqq() {
+        let mut pipeline = RequestPipeline::new(100);
+        assert_eq!(pipeline.depth(), INITIAL_DEPTH);
+
+        // 1 MiB/s for 3s worth of queue is 192 blocks, capped by the maximum
+        let start = pipeline.window_start;
+        pipeline.record_block_at(1 << 20, start + Duration::from_secs(1));
+        assert_eq!(pipeline.depth(), 100);
+
+        pipeline.set_peer_limit(20);
+        assert_eq!(pipeline.depth(), 20);
+
+        // A slow peer gets the minimum depth
+        let mut pipeline = RequestPipeline::new(100);
+        let start = pipeline.window_start;
+        pipeline.record_block_at(1000, start + Duration::from_secs(1));
+        assert_eq!(pipeline.depth(), MIN_DEPTH);
+    }
+}
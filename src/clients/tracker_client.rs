@@ -1,56 +1,171 @@
-use crate::clients::helper;
+use crate::clients::helper::TrackerUrlBuilder;
+use crate::clients::client_config::ClientConfig;
+use crate::clients::udp_tracker_client::UdpTrackerClient;
+use crate::torrent_manager::torrent_spec::scrape_info::ScrapeInfo;
 use std::collections::HashMap;
+use std::error::Error;
 use anyhow::anyhow;
 use reqwest::blocking::Client;
-use nanoid::nanoid;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
 use hex;
 
 pub struct TrackerClient {
     client: Client,
     root_url: String,
+    client_config: ClientConfig,
+}
+
+// Bencoded body of a scrape response
+#[derive(Deserialize)]
+struct ScrapeResponse {
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeFile>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ScrapeFile {
+    #[serde(default)]
+    complete: i64,
+    #[serde(default)]
+    incomplete: i64,
+    #[serde(default)]
+    downloaded: i64,
 }
-use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
 
 impl Default for TrackerClient {
     fn default() -> Self {
         Self {
             client: Client::new(),
             root_url: "".to_string(),
+            client_config: ClientConfig::default(),
         }
     }
 }
 
 impl TrackerClient {
 
-    pub fn new(root_url: String) -> Self {
-            Self{root_url, ..Default::default()}
+    pub fn new(root_url: String, client_config: ClientConfig) -> Self {
+            Self{root_url, client_config, ..Default::default()}
     }
 
     pub fn request_peers(&self, length: i64, hex_info_hash: String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let mut params = HashMap::new();
-        
-        let bytes_info_hash = hex::decode(hex_info_hash).unwrap();
-        let url_encoded_info_hash = percent_encode(&bytes_info_hash, NON_ALPHANUMERIC).to_string();
+        let bytes_info_hash = hex::decode(hex_info_hash)?;
 
         // query parameters
-        params.insert("info_hash", url_encoded_info_hash.clone());
-        params.insert("peer_id", nanoid!(20));
-        params.insert("port", 6881.to_string());
-        params.insert("uploaded", 0.to_string());
-        params.insert("downloaded", 0.to_string());
-        params.insert("left", length.to_string());
-        params.insert("compact", 1.to_string());
-        
-
-        let request_url = helper::create_request_url(self.root_url.clone(), params);
-        let response = self.client.get(request_url.clone()).send()?;
+        let request_url = TrackerUrlBuilder::new(&self.root_url)
+            .bytes_param("info_hash", &bytes_info_hash)
+            .bytes_param("peer_id", self.client_config.get_peer_id())
+            .param("port", &self.client_config.get_port().to_string())
+            .param("uploaded", "0")
+            .param("downloaded", "0")
+            .param("left", &length.to_string())
+            .param("compact", "1")
+            .param("key", self.client_config.get_key())
+            .build();
+        let response = self.client.get(request_url).send()?;
 
 
         if response.status().is_success() {
             let body_bytes = response.bytes().unwrap().to_vec();
             return Ok(body_bytes);
         }
-        
+
         Err(anyhow!("Error").into())
     }
-}
\ No newline at end of file
+
+    // Request swarm statistics for one or many hex encoded info hashes (BEP 48 / BEP 15)
+    pub fn scrape(&self, hex_info_hashes: &[String]) -> Result<Vec<ScrapeInfo>, Box<dyn Error>> {
+        if self.root_url.starts_with("udp://") {
+            return UdpTrackerClient::new(&self.root_url)?.scrape(hex_info_hashes);
+        }
+
+        let mut url_builder = TrackerUrlBuilder::new(&self.scrape_url()?);
+        for hex_info_hash in hex_info_hashes {
+            url_builder = url_builder.bytes_param("info_hash", &hex::decode(hex_info_hash)?);
+        }
+
+        let response = self.client.get(url_builder.build()).send()?;
+        if !response.status().is_success() {
+            return Err(format!("Scrape request failed with status {}", response.status()).into());
+        }
+        parse_scrape_response(&response.bytes()?, hex_info_hashes)
+    }
+
+    // Derive the scrape url from the announce url: the text after the last '/' must start
+    // with "announce", which is replaced by "scrape"
+    pub fn scrape_url(&self) -> Result<String, Box<dyn Error>> {
+        let last_slash = self.root_url.rfind('/').ok_or_else(|| scrape_not_supported(&self.root_url))?;
+        let last_segment = &self.root_url[last_slash + 1..];
+        if !last_segment.starts_with("announce") {
+            return Err(scrape_not_supported(&self.root_url));
+        }
+        Ok(format!("{}scrape{}", &self.root_url[..=last_slash], &last_segment["announce".len()..]))
+    }
+}
+
+fn scrape_not_supported(announce_url: &str) -> Box<dyn Error> {
+    format!("Tracker does not support scrape: {}", announce_url).into()
+}
+
+// Parse a bencoded scrape response, keeping the order of the requested info hashes
+fn parse_scrape_response(body: &[u8], hex_info_hashes: &[String]) -> Result<Vec<ScrapeInfo>, Box<dyn Error>> {
+    let response: ScrapeResponse = serde_bencode::from_bytes(body)?;
+    if let Some(failure_reason) = response.failure_reason {
+        return Err(format!("Tracker error: {}", failure_reason).into());
+    }
+
+    let mut scrape_infos = vec![];
+    for hex_info_hash in hex_info_hashes {
+        let key = ByteBuf::from(hex::decode(hex_info_hash)?);
+        match response.files.get(&key) {
+            Some(file) => scrape_infos.push(ScrapeInfo::new(hex_info_hash.clone(), file.complete, file.incomplete, file.downloaded)),
+            None => return Err(format!("Tracker returned no scrape data for {}", hex_info_hash).into()),
+        }
+    }
+    Ok(scrape_infos)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_url_from_announce() {
+        let cases = [
+            ("http://example.com/announce", "http://example.com/scrape"),
+            ("http://example.com/x/announce", "http://example.com/x/scrape"),
+            ("http://example.com/announce.php", "http://example.com/scrape.php"),
+            ("http://example.com/announce?x2%0644", "http://example.com/scrape?x2%0644"),
+        ];
+        for (announce, expected) in cases {
+            assert_eq!(TrackerClient::new(announce.to_string(), ClientConfig::new()).scrape_url().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_scrape_url_not_supported() {
+        for announce in ["http://example.com/a", "http://example.com/announce?x=2/4", "http://example.com/x%064announce", "udp-less"] {
+            let err = TrackerClient::new(announce.to_string(), ClientConfig::new()).scrape_url().unwrap_err();
+            assert!(err.to_string().contains("does not support scrape"), "{}", announce);
+        }
+    }
+
+    #[test]
+    fn test_parse_scrape_response() {
+        let info_hash = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f".to_string();
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&hex::decode(&info_hash).unwrap());
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+
+        let scrape_infos = parse_scrape_response(&body, std::slice::from_ref(&info_hash)).unwrap();
+        assert_eq!(scrape_infos.len(), 1);
+        assert_eq!(
+            scrape_infos[0].get_formatted_info(),
+            format!("Info Hash: {}\nSeeders: 5\nLeechers: 10\nCompleted: 50\n", info_hash)
+        );
+    }
+}
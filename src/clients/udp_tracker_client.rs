@@ -0,0 +1,121 @@
+use std::error::Error;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use crate::torrent_manager::torrent_spec::scrape_info::ScrapeInfo;
+use crate::utils;
+
+// Magic constant identifying the UDP tracker protocol (BEP 15)
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// A single scrape request may carry at most this many info hashes
+const MAX_SCRAPE_HASHES: usize = 74;
+
+const RETRIES: u32 = 3;
+
+pub struct UdpTrackerClient {
+    tracker_address: String, // format <host:port>
+    timeout: Duration,
+}
+
+impl UdpTrackerClient {
+
+    // Create a client from a tracker url of the form udp://host:port[/announce]
+    pub fn new(tracker_url: &str) -> Result<Self, Box<dyn Error>> {
+        let tracker_address = tracker_url
+            .strip_prefix("udp://")
+            .ok_or("Not a udp tracker url")?
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        if tracker_address.is_empty() {
+            return Err("Missing tracker address in udp tracker url".into());
+        }
+        Ok(Self { tracker_address, timeout: Duration::from_secs(5) })
+    }
+
+    // Request seeders, leechers and completed counts for the given hex encoded info hashes
+    pub fn scrape(&self, info_hashes: &[String]) -> Result<Vec<ScrapeInfo>, Box<dyn Error>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(&self.tracker_address)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+
+        let connection_id = self.connect(&socket)?;
+
+        let mut scrape_infos = vec![];
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let transaction_id = new_transaction_id();
+            let mut request = Vec::with_capacity(16 + 20 * chunk.len());
+            request.extend_from_slice(&connection_id.to_be_bytes());
+            request.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+            request.extend_from_slice(&transaction_id.to_be_bytes());
+            for info_hash in chunk {
+                request.extend_from_slice(&hex::decode(info_hash)?);
+            }
+
+            let response = self.exchange(&socket, &request, ACTION_SCRAPE, transaction_id)?;
+            let counts = &response[8..];
+            if counts.len() < 12 * chunk.len() {
+                return Err("Truncated udp scrape response".into());
+            }
+            for (info_hash, entry) in chunk.iter().zip(counts.chunks_exact(12)) {
+                let seeders = u32::from_be_bytes(entry[0..4].try_into()?);
+                let completed = u32::from_be_bytes(entry[4..8].try_into()?);
+                let leechers = u32::from_be_bytes(entry[8..12].try_into()?);
+                scrape_infos.push(ScrapeInfo::new(info_hash.clone(), seeders as i64, leechers as i64, completed as i64));
+            }
+        }
+
+        Ok(scrape_infos)
+    }
+
+    // Obtain a connection id from the tracker
+    fn connect(&self, socket: &UdpSocket) -> Result<u64, Box<dyn Error>> {
+        let transaction_id = new_transaction_id();
+        let mut request = Vec::with_capacity(16);
+        request.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        request.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+
+        let response = self.exchange(socket, &request, ACTION_CONNECT, transaction_id)?;
+        if response.len() < 16 {
+            return Err("Truncated udp connect response".into());
+        }
+        Ok(u64::from_be_bytes(response[8..16].try_into()?))
+    }
+
+    // Send a request and wait for the matching response, retrying on timeouts
+    fn exchange(&self, socket: &UdpSocket, request: &[u8], action: u32, transaction_id: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = vec![0u8; 2048];
+        for _ in 0..RETRIES {
+            socket.send(request)?;
+            let received = match socket.recv(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let response = &buffer[..received];
+            if response.len() < 8 || u32::from_be_bytes(response[4..8].try_into()?) != transaction_id {
+                continue;
+            }
+            let response_action = u32::from_be_bytes(response[0..4].try_into()?);
+            if response_action == ACTION_ERROR {
+                return Err(format!("Tracker error: {}", String::from_utf8_lossy(&response[8..])).into());
+            }
+            if response_action != action {
+                return Err(format!("Unexpected udp tracker action: {}", response_action).into());
+            }
+            return Ok(response.to_vec());
+        }
+        Err(format!("Udp tracker {} did not respond", self.tracker_address).into())
+    }
+}
+
+fn new_transaction_id() -> u32 {
+    u32::from_be_bytes(utils::random_bytes(4).try_into().unwrap())
+}
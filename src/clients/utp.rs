@@ -0,0 +1,927 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::clients::utp_packet::{seq_less, Packet, PacketType, HEADER_LENGTH};
+use crate::utils;
+
+// Payload bytes per packet, small enough to pass common paths without fragmentation
+const PAYLOAD_SIZE: usize = 1200;
+// LEDBAT keeps the queuing delay it adds to the path around this many microseconds
+const TARGET_DELAY: u32 = 100_000;
+// Most the congestion window grows per round trip, in bytes
+const MAX_WINDOW_GAIN: f64 = 3000.0;
+const INITIAL_WINDOW: f64 = (4 * PAYLOAD_SIZE) as f64;
+const MIN_WINDOW: f64 = PAYLOAD_SIZE as f64;
+const MAX_WINDOW: f64 = (MAX_OUT_OF_ORDER as usize * PAYLOAD_SIZE) as f64;
+// The base delay is the lowest one-way delay over the last two minutes, kept per minute
+const DELAY_BUCKET: Duration = Duration::from_secs(60);
+const DELAY_BUCKETS: usize = 2;
+// Received bytes buffered until they are read; what is left is advertised as our window
+const RECEIVE_BUFFER: usize = 1024 * 1024;
+// Written bytes buffered until the windows let them out
+const SEND_BUFFER: usize = 256 * 1024;
+// Packets further ahead than this are dropped instead of buffered
+const MAX_OUT_OF_ORDER: u16 = 512;
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(8);
+// Giving up after this many transmissions of a packet takes about half a minute
+const MAX_TRANSMISSIONS: u32 = 8;
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+// Acks of this many later packets mean the first unacked packet was lost
+const DUPLICATE_ACK_THRESHOLD: u32 = 3;
+// A closed connection is kept this long after the last packet of the peer, to ack its FIN again
+// should our ack get lost
+const LINGER: Duration = Duration::from_secs(30);
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+// The transports outgoing peer connections are tried over
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Utp,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(transport: &str) -> Result<Self, Self::Err> {
+        match transport {
+            "tcp" => Ok(Transport::Tcp),
+            "utp" => Ok(Transport::Utp),
+            _ => Err(format!("Unknown transport {:?}, expected tcp or utp", transport)),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Utp => write!(f, "utp"),
+        }
+    }
+}
+
+// A comma separated order of preference such as "utp,tcp"
+pub fn parse_transports(order: &str) -> Result<Vec<Transport>, String> {
+    let transports = order.split(',').map(str::parse).collect::<Result<Vec<Transport>, String>>()?;
+    if transports.iter().enumerate().any(|(i, transport)| transports[..i].contains(transport)) {
+        return Err(format!("Transport listed twice in {:?}", order));
+    }
+    Ok(transports)
+}
+
+// Microseconds on a clock of our own, wrapping around
+fn timestamp() -> u32 {
+    static START: OnceLock<std::time::Instant> = OnceLock::new();
+    START.get_or_init(std::time::Instant::now).elapsed().as_micros() as u32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    // lost, waiting for room in the window to be sent again
+    resend: bool,
+}
+
+// One side of a uTP connection: the sequence numbers, the packets in flight and the received data
+struct Connection {
+    socket: Arc<UdpSocket>,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: State,
+    error: Option<io::ErrorKind>,
+    // next sequence number to send and last one received in order
+    seq_nr: u16,
+    ack_nr: u16,
+
+    send_buffer: VecDeque<u8>,
+    unacked: VecDeque<SentPacket>,
+    closing: bool,
+    fin_sent: bool,
+    window: f64,
+    peer_window: u32,
+    // smoothed round trip time and its variance
+    rtt: Option<(Duration, Duration)>,
+    timeout: Duration,
+    delay_minima: VecDeque<(Instant, u32)>,
+    last_ack: u16,
+    duplicate_acks: u32,
+    // the window is halved at most once until everything sent before the loss is acked
+    recovery_until: Option<u16>,
+
+    received: VecDeque<u8>,
+    out_of_order: HashMap<u16, Packet>,
+    fin_received: Option<u16>,
+    eof: bool,
+    reply_delay: u32,
+    advertised_window: u32,
+    last_received: Instant,
+
+    dropped: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(socket: Arc<UdpSocket>, remote: SocketAddr, recv_id: u16, send_id: u16, state: State) -> Self {
+        Self {
+            socket,
+            remote,
+            recv_id,
+            send_id,
+            state,
+            error: None,
+            seq_nr: 1,
+            ack_nr: 0,
+            send_buffer: VecDeque::new(),
+            unacked: VecDeque::new(),
+            closing: false,
+            fin_sent: false,
+            window: INITIAL_WINDOW,
+            peer_window: RECEIVE_BUFFER as u32,
+            rtt: None,
+            timeout: INITIAL_TIMEOUT,
+            delay_minima: VecDeque::new(),
+            last_ack: 0,
+            duplicate_acks: 0,
+            recovery_until: None,
+            received: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_received: None,
+            eof: false,
+            reply_delay: 0,
+            advertised_window: RECEIVE_BUFFER as u32,
+            last_received: Instant::now(),
+            dropped: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn receive_window(&self) -> u32 {
+        RECEIVE_BUFFER.saturating_sub(self.received.len()) as u32
+    }
+
+    fn packet(&self, packet_type: PacketType, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            packet_type,
+            // the SYN names the id we receive on, everything else the id the peer receives on
+            connection_id: if packet_type == PacketType::Syn { self.recv_id } else { self.send_id },
+            timestamp: timestamp(),
+            timestamp_difference: self.reply_delay,
+            window: self.receive_window(),
+            seq_nr,
+            ack_nr: self.ack_nr,
+            selective_ack: None,
+            payload,
+        }
+    }
+
+    fn transmit(&mut self, packet: &Packet) {
+        self.advertised_window = packet.window;
+        // a full socket buffer is no different from a packet lost on the way
+        let _ = self.socket.try_send_to(&packet.encode(), self.remote);
+    }
+
+    fn send_ack(&mut self) {
+        let mut packet = self.packet(PacketType::State, self.seq_nr, vec![]);
+        packet.selective_ack = self.selective_ack();
+        self.transmit(&packet);
+    }
+
+    // Bit i marks packet ack_nr + 2 + i as received, in a multiple of four bytes
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        let offsets: Vec<usize> = self.out_of_order.keys()
+            .map(|seq| seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+            .collect();
+        let last = *offsets.iter().max()?;
+        let mut mask = vec![0u8; (last / 8 + 4) / 4 * 4];
+        for offset in offsets {
+            mask[offset / 8] |= 1 << (offset % 8);
+        }
+        Some(mask)
+    }
+
+    // Number a packet and send it, keeping it until it is acked
+    fn queue(&mut self, packet_type: PacketType, payload: Vec<u8>) {
+        let packet = self.packet(packet_type, self.seq_nr, payload);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(&packet);
+        self.unacked.push_back(SentPacket { packet, sent_at: Instant::now(), transmissions: 1, resend: false });
+    }
+
+    fn in_flight(&self) -> usize {
+        self.unacked.iter().filter(|sent| !sent.resend).map(|sent| HEADER_LENGTH + sent.packet.payload.len()).sum()
+    }
+
+    // Send lost packets again and then new data, as far as the congestion window and the peer's
+    // window allow. With nothing in flight one packet always goes out, which probes a closed window
+    fn send_pending(&mut self) {
+        let limit = (self.window as usize).min(self.peer_window as usize);
+        let mut in_flight = self.in_flight();
+        let (now, ack_nr, reply_delay, window) = (Instant::now(), self.ack_nr, self.reply_delay, self.receive_window());
+        for index in 0..self.unacked.len() {
+            let sent = &mut self.unacked[index];
+            if !sent.resend {
+                continue;
+            }
+            let size = HEADER_LENGTH + sent.packet.payload.len();
+            if in_flight != 0 && in_flight + size > limit {
+                break;
+            }
+            in_flight += size;
+            sent.resend = false;
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            sent.packet.timestamp = timestamp();
+            sent.packet.timestamp_difference = reply_delay;
+            sent.packet.window = window;
+            sent.packet.ack_nr = ack_nr;
+            let packet = sent.packet.clone();
+            self.transmit(&packet);
+        }
+
+        if self.state != State::Connected {
+            return;
+        }
+        while !self.send_buffer.is_empty() {
+            let size = self.send_buffer.len().min(PAYLOAD_SIZE);
+            if in_flight != 0 && in_flight + HEADER_LENGTH + size > limit {
+                break;
+            }
+            in_flight += HEADER_LENGTH + size;
+            let payload = self.send_buffer.drain(..size).collect();
+            self.queue(PacketType::Data, payload);
+        }
+        if self.closing && self.send_buffer.is_empty() && !self.fin_sent {
+            self.fin_sent = true;
+            self.queue(PacketType::Fin, vec![]);
+        }
+        if self.send_buffer.len() < SEND_BUFFER {
+            if let Some(waker) = self.write_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn on_packet(&mut self, packet: Packet) {
+        self.last_received = Instant::now();
+        if packet.packet_type == PacketType::Reset {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return;
+        }
+        self.reply_delay = timestamp().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window;
+        match self.state {
+            State::Closed => return,
+            // only the answer to our SYN establishes the connection
+            State::SynSent if packet.packet_type != PacketType::State => return,
+            State::SynSent => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            }
+            State::Connected => {}
+        }
+
+        self.process_ack(&packet);
+        if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) {
+            self.receive(packet);
+            self.send_ack();
+        }
+        self.send_pending();
+        self.wake();
+    }
+
+    fn process_ack(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let mut acked_bytes = 0;
+        while let Some(sent) = self.unacked.front() {
+            if seq_less(packet.ack_nr, sent.packet.seq_nr) {
+                break;
+            }
+            let sent = self.unacked.pop_front().unwrap();
+            acked_bytes += sent.packet.payload.len();
+            self.sample_rtt(&sent, now);
+        }
+
+        let mut later_acked = 0;
+        if let Some(mask) = &packet.selective_ack {
+            for offset in 0..mask.len() * 8 {
+                if mask[offset / 8] >> (offset % 8) & 1 == 0 {
+                    continue;
+                }
+                later_acked += 1;
+                let seq = packet.ack_nr.wrapping_add(2 + offset as u16);
+                if let Some(position) = self.unacked.iter().position(|sent| sent.packet.seq_nr == seq) {
+                    let sent = self.unacked.remove(position).unwrap();
+                    acked_bytes += sent.packet.payload.len();
+                    self.sample_rtt(&sent, now);
+                }
+            }
+        }
+
+        if packet.ack_nr != self.last_ack {
+            self.duplicate_acks = 0;
+        } else if packet.packet_type == PacketType::State && acked_bytes == 0 && !self.unacked.is_empty() {
+            self.duplicate_acks += 1;
+        }
+        if seq_less(self.last_ack, packet.ack_nr) {
+            self.last_ack = packet.ack_nr;
+        }
+        if self.recovery_until.is_some_and(|until| !seq_less(self.last_ack, until)) {
+            self.recovery_until = None;
+        }
+
+        // fast retransmit of the packet the peer is waiting for; later losses wait for the timeout
+        if self.duplicate_acks >= DUPLICATE_ACK_THRESHOLD || later_acked >= DUPLICATE_ACK_THRESHOLD {
+            let waiting_for = packet.ack_nr.wrapping_add(1);
+            if let Some(sent) = self.unacked.front_mut().filter(|sent| sent.packet.seq_nr == waiting_for) {
+                if sent.transmissions == 1 && !sent.resend {
+                    sent.resend = true;
+                    self.duplicate_acks = 0;
+                    if self.recovery_until.is_none() {
+                        self.window = (self.window / 2.0).max(MIN_WINDOW);
+                        self.recovery_until = Some(self.seq_nr);
+                    }
+                }
+            }
+        }
+
+        if acked_bytes > 0 {
+            self.ledbat(packet.timestamp_difference, acked_bytes);
+        }
+    }
+
+    // Round trip estimate of RFC 6298, leaving out packets sent more than once
+    fn sample_rtt(&mut self, sent: &SentPacket, now: Instant) {
+        if sent.transmissions != 1 {
+            return;
+        }
+        let sample = now - sent.sent_at;
+        let (rtt, variance) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, variance)) => {
+                (rtt * 7 / 8 + sample / 8, variance * 3 / 4 + rtt.abs_diff(sample) / 4)
+            }
+        };
+        self.rtt = Some((rtt, variance));
+        self.timeout = (rtt + variance * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    // Grow the window while the delay our packets add stays below the target and shrink it above
+    fn ledbat(&mut self, delay: u32, acked_bytes: usize) {
+        let now = Instant::now();
+        match self.delay_minima.back_mut() {
+            Some((start, minimum)) if now - *start < DELAY_BUCKET => *minimum = (*minimum).min(delay),
+            _ => {
+                self.delay_minima.push_back((now, delay));
+                if self.delay_minima.len() > DELAY_BUCKETS {
+                    self.delay_minima.pop_front();
+                }
+            }
+        }
+        let base_delay = self.delay_minima.iter().map(|(_, minimum)| *minimum).min().unwrap();
+        let queuing_delay = delay.saturating_sub(base_delay) as f64;
+        let off_target = (TARGET_DELAY as f64 - queuing_delay) / TARGET_DELAY as f64;
+        self.window += MAX_WINDOW_GAIN * off_target * acked_bytes as f64 / self.window;
+        self.window = self.window.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    fn receive(&mut self, packet: Packet) {
+        let seq = packet.seq_nr;
+        if packet.packet_type == PacketType::Fin {
+            self.fin_received = Some(seq);
+        }
+        if seq == self.ack_nr.wrapping_add(1) {
+            self.deliver(packet);
+            while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.deliver(next);
+            }
+        } else if seq_less(self.ack_nr, seq) && seq.wrapping_sub(self.ack_nr) <= MAX_OUT_OF_ORDER {
+            self.out_of_order.insert(seq, packet);
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+        self.received.extend(&packet.payload);
+        if self.fin_received == Some(self.ack_nr) {
+            self.eof = true;
+            self.out_of_order.clear();
+        }
+    }
+
+    fn on_tick(&mut self, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+        let expired = self.unacked.iter().any(|sent| !sent.resend && now - sent.sent_at >= self.timeout);
+        if expired {
+            let limit = if self.state == State::SynSent { MAX_SYN_TRANSMISSIONS } else { MAX_TRANSMISSIONS };
+            if self.unacked.iter().any(|sent| sent.transmissions >= limit) {
+                self.fail(io::ErrorKind::TimedOut);
+                return;
+            }
+            // start over from a single packet, sending everything unacked again
+            self.window = MIN_WINDOW;
+            self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+            for sent in self.unacked.iter_mut() {
+                sent.resend = true;
+            }
+        }
+        self.send_pending();
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        if self.error.is_none() {
+            let refused = self.state == State::SynSent && kind == io::ErrorKind::ConnectionReset;
+            self.error = Some(if refused { io::ErrorKind::ConnectionRefused } else { kind });
+        }
+        self.state = State::Closed;
+        self.unacked.clear();
+        self.wake();
+    }
+
+    // Whether the socket can forget the connection
+    fn is_finished(&self, now: Instant) -> bool {
+        self.state == State::Closed
+            || self.dropped && self.fin_sent && self.unacked.is_empty() && now - self.last_received >= LINGER
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// A uTP connection (BEP 29) as a byte stream, usable wherever a TCP stream is
+pub struct UtpStream {
+    connection: Arc<Mutex<Connection>>,
+    peer_address: SocketAddr,
+    // keeps the socket receiving for as long as the stream is around
+    _socket: Arc<SocketShared>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_address
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut connection = self.connection.lock().unwrap();
+        if !connection.received.is_empty() {
+            let length = connection.received.len().min(buf.remaining());
+            let data: Vec<u8> = connection.received.drain(..length).collect();
+            buf.put_slice(&data);
+            // a peer held back by our window learns about the room without waiting for its timeout
+            let half = (RECEIVE_BUFFER / 2) as u32;
+            if connection.advertised_window < half && connection.receive_window() >= half {
+                connection.send_ack();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if connection.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        connection.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if connection.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let room = SEND_BUFFER.saturating_sub(connection.send_buffer.len());
+        if room == 0 {
+            connection.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let length = room.min(data.len());
+        connection.send_buffer.extend(&data[..length]);
+        connection.send_pending();
+        Poll::Ready(Ok(length))
+    }
+
+    // Written data is on its way as soon as the windows allow, as with a TCP socket
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.connection.lock().unwrap().error {
+            Some(kind) => Poll::Ready(Err(kind.into())),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    // Completes once all data and the FIN are acked
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if !connection.closing {
+            connection.closing = true;
+            connection.send_pending();
+        }
+        if connection.fin_sent && connection.unacked.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        connection.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for UtpStream {
+    // Buffered data and the FIN still go out after the stream is dropped
+    fn drop(&mut self) {
+        let mut connection = self.connection.lock().unwrap();
+        connection.dropped = true;
+        if !connection.closing {
+            connection.closing = true;
+            connection.send_pending();
+        }
+    }
+}
+
+// Connections by remote address and the connection id we receive on
+type Connections = HashMap<(SocketAddr, u16), Arc<Mutex<Connection>>>;
+
+struct SocketShared {
+    socket: Arc<UdpSocket>,
+    connections: Mutex<Connections>,
+    incoming: mpsc::UnboundedSender<UtpStream>,
+}
+
+impl SocketShared {
+    fn on_datagram(self: &Arc<Self>, datagram: &[u8], from: SocketAddr) {
+        let Some(packet) = Packet::decode(datagram) else { return };
+        let connection = self.connections.lock().unwrap().get(&(from, packet.connection_id)).cloned();
+        match (connection, packet.packet_type) {
+            (Some(connection), _) => connection.lock().unwrap().on_packet(packet),
+            (None, PacketType::Syn) => self.on_syn(packet, from),
+            // some implementations reset with the id they receive on rather than ours
+            (None, PacketType::Reset) => {
+                let connections: Vec<_> = self.connections.lock().unwrap().values().cloned().collect();
+                for connection in connections {
+                    let mut connection = connection.lock().unwrap();
+                    if connection.remote == from && connection.send_id == packet.connection_id {
+                        connection.on_packet(packet.clone());
+                    }
+                }
+            }
+            // tell the peer its connection is gone
+            (None, _) => {
+                let reset = Packet {
+                    packet_type: PacketType::Reset,
+                    connection_id: packet.connection_id,
+                    timestamp: timestamp(),
+                    timestamp_difference: 0,
+                    window: 0,
+                    seq_nr: 0,
+                    ack_nr: packet.seq_nr,
+                    selective_ack: None,
+                    payload: vec![],
+                };
+                let _ = self.socket.try_send_to(&reset.encode(), from);
+            }
+        }
+    }
+
+    fn on_syn(self: &Arc<Self>, syn: Packet, from: SocketAddr) {
+        let recv_id = syn.connection_id.wrapping_add(1);
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(connection) = connections.get(&(from, recv_id)) {
+            // our answer got lost and the peer repeats its SYN
+            connection.lock().unwrap().send_ack();
+            return;
+        }
+        let mut connection = Connection::new(self.socket.clone(), from, recv_id, syn.connection_id, State::Connected);
+        connection.seq_nr = utils::random_below(1 << 16) as u16;
+        connection.last_ack = connection.seq_nr.wrapping_sub(1);
+        connection.ack_nr = syn.seq_nr;
+        connection.reply_delay = timestamp().wrapping_sub(syn.timestamp);
+        connection.peer_window = syn.window;
+        connection.send_ack();
+        let connection = Arc::new(Mutex::new(connection));
+        connections.insert((from, recv_id), connection.clone());
+        let _ = self.incoming.send(UtpStream { connection, peer_address: from, _socket: self.clone() });
+    }
+
+    fn on_tick(&self) {
+        let now = Instant::now();
+        let connections: Vec<_> = self.connections.lock().unwrap().iter()
+            .map(|(key, connection)| (*key, connection.clone()))
+            .collect();
+        let finished: Vec<_> = connections.into_iter()
+            .filter(|(_, connection)| {
+                let mut connection = connection.lock().unwrap();
+                connection.on_tick(now);
+                connection.is_finished(now)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        if !finished.is_empty() {
+            let mut connections = self.connections.lock().unwrap();
+            for key in finished {
+                connections.remove(&key);
+            }
+        }
+    }
+}
+
+// Receive datagrams and drive the timers of all connections until the socket and its streams are gone
+async fn run(socket: Arc<UdpSocket>, shared: Weak<SocketShared>) {
+    let mut buffer = vec![0u8; 65536];
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buffer) => Some(received),
+            _ = tick.tick() => None,
+        };
+        let Some(shared) = shared.upgrade() else { return };
+        match received {
+            Some(Ok((length, from))) => shared.on_datagram(&buffer[..length], from),
+            // errors such as an unreachable port reported for an earlier datagram
+            Some(Err(_)) => {}
+            None => shared.on_tick(),
+        }
+    }
+}
+
+// A UDP socket carrying any number of uTP connections, opened by us or by peers
+pub struct UtpSocket {
+    shared: Arc<SocketShared>,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<UtpStream>>,
+}
+
+impl UtpSocket {
+    pub async fn bind(address: &str) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        let (incoming, receiver) = mpsc::unbounded_channel();
+        let shared = Arc::new(SocketShared { socket: socket.clone(), connections: Mutex::new(HashMap::new()), incoming });
+        tokio::spawn(run(socket, Arc::downgrade(&shared)));
+        Ok(Self { shared, incoming: tokio::sync::Mutex::new(receiver) })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    pub async fn connect(&self, address: SocketAddr) -> io::Result<UtpStream> {
+        let connection = {
+            let mut connections = self.shared.connections.lock().unwrap();
+            let recv_id = loop {
+                let id = utils::random_below(1 << 16) as u16;
+                if !connections.contains_key(&(address, id)) {
+                    break id;
+                }
+            };
+            let mut connection = Connection::new(self.shared.socket.clone(), address, recv_id, recv_id.wrapping_add(1), State::SynSent);
+            connection.queue(PacketType::Syn, vec![]);
+            let connection = Arc::new(Mutex::new(connection));
+            connections.insert((address, recv_id), connection.clone());
+            connection
+        };
+        poll_fn(|cx| {
+            let mut connection = connection.lock().unwrap();
+            match connection.state {
+                State::Connected => Poll::Ready(Ok::<(), io::Error>(())),
+                State::Closed => Poll::Ready(Err(connection.error.unwrap_or(io::ErrorKind::ConnectionRefused).into())),
+                State::SynSent => {
+                    connection.write_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }).await?;
+        Ok(UtpStream { connection, peer_address: address, _socket: self.shared.clone() })
+    }
+
+    // The next connection a peer opened
+    pub async fn accept(&self) -> io::Result<UtpStream> {
+        self.incoming.lock().await.recv().await.ok_or_else(|| io::Error::other("uTP socket closed"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn connected_pair(client: &UtpSocket, server_address: SocketAddr, server: &UtpSocket) -> (UtpStream, UtpStream) {
+        let (connected, accepted) = tokio::join!(client.connect(server_address), server.accept());
+        (connected.unwrap(), accepted.unwrap())
+    }
+
+    // Send the same data each way at once and check it arrives intact, followed by the end of the stream
+    async fn exchange(first: UtpStream, second: UtpStream, length: usize) {
+        let data: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+        let transfer = |stream: UtpStream| {
+            let data = data.clone();
+            async move {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                let send = async {
+                    writer.write_all(&data).await.unwrap();
+                    writer.shutdown().await.unwrap();
+                };
+                let receive = async {
+                    let mut received = vec![];
+                    reader.read_to_end(&mut received).await.unwrap();
+                    received
+                };
+                let ((), received) = tokio::join!(send, receive);
+                assert!(received == data, "received {} of {} bytes", received.len(), data.len());
+            }
+        };
+        let exchanged = async { tokio::join!(transfer(first), transfer(second)) };
+        tokio::time::timeout(Duration::from_secs(60), exchanged).await.unwrap();
+    }
+
+    // Forward datagrams between one client and the server, dropping the given percentage and
+    // delaying the rest by random amounts, which also reorders them
+    async fn lossy_relay(server: SocketAddr, loss_percent: Arc<AtomicUsize>, max_delay: Duration) -> SocketAddr {
+        let relay = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = relay.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut buffer = vec![0u8; 65536];
+            loop {
+                let (length, from) = relay.recv_from(&mut buffer).await.unwrap();
+                let to = match (from == server, client) {
+                    (true, Some(client)) => client,
+                    (true, None) => continue,
+                    (false, _) => {
+                        client = Some(from);
+                        server
+                    }
+                };
+                if utils::random_below(100) < loss_percent.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let delay = Duration::from_micros(utils::random_below(max_delay.as_micros() as usize + 1) as u64);
+                let (relay, datagram) = (relay.clone(), buffer[..length].to_vec());
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = relay.send_to(&datagram, to).await;
+                });
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_transfer_over_loopback() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (first, second) = connected_pair(&client, server.local_addr().unwrap(), &server).await;
+        assert_eq!(first.peer_addr(), server.local_addr().unwrap());
+        assert_eq!(second.peer_addr(), client.local_addr().unwrap());
+        exchange(first, second, 1 << 20).await;
+    }
+
+    #[tokio::test]
+    async fn test_transfer_with_loss_and_delay() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let loss_percent = Arc::new(AtomicUsize::new(0));
+        let relay = lossy_relay(server.local_addr().unwrap(), loss_percent.clone(), Duration::from_millis(20)).await;
+        // the handshake gives up after a few lost SYNs, so the path turns lossy once connected
+        let (first, second) = connected_pair(&client, relay, &server).await;
+        loss_percent.store(10, Ordering::Relaxed);
+        exchange(first, second, 256 * 1024).await;
+    }
+
+    #[tokio::test]
+    async fn test_reset_ends_the_connection() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (first, second) = connected_pair(&client, server.local_addr().unwrap(), &server).await;
+
+        // the server forgets the connection and answers further data with a reset
+        second.connection.lock().unwrap().fail(io::ErrorKind::ConnectionAborted);
+        server.shared.on_tick();
+        let (mut reader, mut writer) = tokio::io::split(first);
+        writer.write_all(b"hello").await.unwrap();
+        let mut byte = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), reader.read(&mut byte)).await.unwrap();
+        assert_eq!(read.err().map(|e| e.kind()), Some(io::ErrorKind::ConnectionReset));
+    }
+
+    fn test_connection(socket: Arc<UdpSocket>) -> Connection {
+        Connection::new(socket, "127.0.0.1:9".parse().unwrap(), 1, 2, State::Connected)
+    }
+
+    #[tokio::test]
+    async fn test_window_follows_queuing_delay() {
+        let mut connection = test_connection(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let start = connection.window;
+        connection.ledbat(5_000, 4 * PAYLOAD_SIZE);
+        assert!(connection.window > start);
+        let grown = connection.window;
+        connection.ledbat(5_000 + 2 * TARGET_DELAY, 4 * PAYLOAD_SIZE);
+        assert!(connection.window < grown);
+        for _ in 0..100 {
+            connection.ledbat(5_000 + 10 * TARGET_DELAY, 4 * PAYLOAD_SIZE);
+        }
+        assert_eq!(connection.window, MIN_WINDOW);
+    }
+
+    #[tokio::test]
+    async fn test_selective_ack_retransmits_the_gap() {
+        let mut connection = test_connection(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        connection.window = (10 * PAYLOAD_SIZE) as f64;
+        connection.send_buffer.extend(vec![0u8; 5 * PAYLOAD_SIZE]);
+        connection.send_pending();
+        assert_eq!(connection.unacked.len(), 5);
+
+        // packet 1 arrived in order, 3 to 5 out of order
+        let ack = Packet {
+            packet_type: PacketType::State,
+            connection_id: 1,
+            timestamp: timestamp(),
+            timestamp_difference: 5_000,
+            window: RECEIVE_BUFFER as u32,
+            seq_nr: 100,
+            ack_nr: 1,
+            selective_ack: Some(vec![0b111, 0, 0, 0]),
+            payload: vec![],
+        };
+        connection.on_packet(ack);
+        assert_eq!(connection.unacked.len(), 1);
+        let gap = &connection.unacked[0];
+        assert_eq!((gap.packet.seq_nr, gap.transmissions, gap.resend), (2, 2, false));
+        assert!(connection.window < (10 * PAYLOAD_SIZE) as f64);
+
+        // our own acks report what arrived out of order the same way
+        let mut receiver = test_connection(connection.socket.clone());
+        receiver.ack_nr = 10;
+        for seq in [12, 13, 20] {
+            let mut packet = data_packet(seq);
+            packet.payload = vec![seq as u8];
+            receiver.receive(packet);
+        }
+        assert_eq!(receiver.selective_ack(), Some(vec![0b11, 0b1, 0, 0]));
+        receiver.receive(data_packet(11));
+        assert_eq!(receiver.ack_nr, 13);
+        assert_eq!(receiver.selective_ack(), Some(vec![0b100000, 0, 0, 0]));
+    }
+
+    fn data_packet(seq_nr: u16) -> Packet {
+        Packet {
+            packet_type: PacketType::Data,
+            connection_id: 1,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: 0,
+            seq_nr,
+            ack_nr: 0,
+            selective_ack: None,
+            payload: vec![0],
+        }
+    }
+
+    #[test]
+    fn test_parse_transports() {
+        assert_eq!(parse_transports("utp,tcp"), Ok(vec![Transport::Utp, Transport::Tcp]));
+        assert_eq!(parse_transports("tcp"), Ok(vec![Transport::Tcp]));
+        assert!(parse_transports("tcp,tcp").is_err());
+        assert!(parse_transports("quic").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+pub const HEADER_LENGTH: usize = 20;
+
+const VERSION: u8 = 1;
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None,
+        }
+    }
+}
+
+// A uTP packet (BEP 29). Timestamps are in microseconds; the timestamp difference is the
+// one-way delay the sender measured on the last packet it received from us
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    pub window: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    // bit i of the mask acknowledges ack_nr + 2 + i, least significant bit first
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        datagram.push((self.packet_type as u8) << 4 | VERSION);
+        datagram.push(if self.selective_ack.is_some() { EXTENSION_SELECTIVE_ACK } else { EXTENSION_NONE });
+        datagram.extend_from_slice(&self.connection_id.to_be_bytes());
+        datagram.extend_from_slice(&self.timestamp.to_be_bytes());
+        datagram.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        datagram.extend_from_slice(&self.window.to_be_bytes());
+        datagram.extend_from_slice(&self.seq_nr.to_be_bytes());
+        datagram.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            datagram.push(EXTENSION_NONE);
+            datagram.push(mask.len() as u8);
+            datagram.extend_from_slice(mask);
+        }
+        datagram.extend_from_slice(&self.payload);
+        datagram
+    }
+
+    // None for datagrams that are not valid uTP packets; unknown extensions are skipped
+    pub fn decode(datagram: &[u8]) -> Option<Packet> {
+        if datagram.len() < HEADER_LENGTH || datagram[0] & 0x0f != VERSION {
+            return None;
+        }
+        let packet_type = PacketType::from_u8(datagram[0] >> 4)?;
+        let u16_at = |offset: usize| u16::from_be_bytes([datagram[offset], datagram[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_be_bytes(datagram[offset..offset + 4].try_into().unwrap());
+
+        let mut selective_ack = None;
+        let mut extension = datagram[1];
+        let mut offset = HEADER_LENGTH;
+        while extension != EXTENSION_NONE {
+            let next_extension = *datagram.get(offset)?;
+            let length = *datagram.get(offset + 1)? as usize;
+            let data = datagram.get(offset + 2..offset + 2 + length)?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next_extension;
+            offset += 2 + length;
+        }
+
+        Some(Packet {
+            packet_type,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: datagram[offset..].to_vec(),
+        })
+    }
+}
+
+// Whether sequence number a comes before b, allowing for wrap-around
+pub fn seq_less(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let packet = Packet {
+            packet_type: PacketType::State,
+            connection_id: 4242,
+            timestamp: 1_000_000,
+            timestamp_difference: 250,
+            window: 65536,
+            seq_nr: 7,
+            ack_nr: 65535,
+            selective_ack: Some(vec![0b101, 0, 0, 0]),
+            payload: vec![],
+        };
+        let encoded = packet.encode();
+        assert_eq!(&encoded[..2], &[0x21, 1]);
+        assert_eq!(encoded.len(), HEADER_LENGTH + 6);
+        assert_eq!(Packet::decode(&encoded), Some(packet));
+
+        let data = Packet { packet_type: PacketType::Data, selective_ack: None, payload: b"hello".to_vec(), ..Packet::decode(&encoded).unwrap() };
+        assert_eq!(Packet::decode(&data.encode()), Some(data));
+    }
+
+    #[test]
+    fn test_rejects_invalid_datagrams() {
+        assert_eq!(Packet::decode(&[0x01; 10]), None);
+        let mut datagram = vec![0u8; HEADER_LENGTH];
+        datagram[0] = 0x02;
+        assert_eq!(Packet::decode(&datagram), None);
+        datagram[0] = 0x51;
+        assert_eq!(Packet::decode(&datagram), None);
+        // an extension running past the end
+        datagram[0] = 0x21;
+        datagram[1] = 1;
+        datagram.extend_from_slice(&[0, 8, 0]);
+        assert_eq!(Packet::decode(&datagram), None);
+    }
+
+    #[test]
+    fn test_sequence_numbers_wrap() {
+        assert!(seq_less(1, 2));
+        assert!(seq_less(65535, 0));
+        assert!(!seq_less(0, 65535));
+        assert!(!seq_less(5, 5));
+    }
+}
@@ -0,0 +1,329 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{RANGE, RETRY_AFTER};
+use reqwest::{Client, Response, StatusCode};
+use thiserror::Error;
+
+use crate::torrent_manager::torrent_spec::file_info::FileInfo;
+
+// Path components are escaped except for the unreserved characters of RFC 3986
+const PATH_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum WebSeedError {
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+    // the server asks us to come back later, optionally saying when
+    #[error("web seed is busy")]
+    Busy(Option<Duration>),
+    #[error("web seed answered with status {0}")]
+    Status(StatusCode),
+    #[error("web seed sent {received} bytes, expected {expected}")]
+    Length { expected: usize, received: usize },
+}
+
+pub type PieceFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, WebSeedError>> + Send + 'a>>;
+
+// An HTTP server the downloader fetches whole pieces from, like a peer having every piece
+pub trait PieceSource: Send + Sync {
+    fn get_url(&self) -> &str;
+    // The piece as sent by the server; the caller verifies its hash
+    fn fetch_piece(&self, index: u32, size: u32) -> PieceFuture<'_>;
+}
+
+// A file as the web seed serves it, and where its data starts within the torrent data
+struct WebSeedFile {
+    url: Option<String>, // none for padding files, whose zeros are not served
+    offset: u64,
+    length: u64,
+}
+
+// An HTTP server holding the files of the torrent (BEP 19). Pieces are fetched with byte range
+// requests for the parts of the files they span
+pub struct WebSeed {
+    client: Client,
+    url: String,
+    files: Vec<WebSeedFile>,
+    piece_length: u64,
+}
+
+impl WebSeed {
+    // The url of a url-list entry. A single-file torrent is the url itself, unless the url ends
+    // with '/' and names the directory holding the file; the files of a multi-file torrent are
+    // below the directory of the torrent name
+    pub fn new(url: &str, name: &str, files: Option<&[FileInfo]>, length: u64, piece_length: u64) -> Self {
+        let files = match files {
+            None if url.ends_with('/') => vec![(Some(format!("{}{}", url, encode(name))), length)],
+            None => vec![(Some(url.to_string()), length)],
+            Some(files) => {
+                let directory = if url.ends_with('/') { url.to_string() } else { format!("{}/", url) };
+                files.iter()
+                    .map(|file| {
+                        let path: Vec<String> = file.get_path().iter().map(|component| encode(component)).collect();
+                        let url = (!file.is_padding()).then(|| format!("{}{}/{}", directory, encode(name), path.join("/")));
+                        (url, file.get_length() as u64)
+                    })
+                    .collect()
+            }
+        };
+        let mut offset = 0;
+        let files = files.into_iter()
+            .map(|(url, length)| {
+                let file = WebSeedFile { url, offset, length };
+                offset += length;
+                file
+            })
+            .collect();
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).build().unwrap_or_default();
+        Self { client, url: url.to_string(), files, piece_length }
+    }
+
+    // Fetch a piece, one request per file it spans; padding files are filled in with zeros
+    async fn fetch(&self, index: u32, size: u32) -> Result<Vec<u8>, WebSeedError> {
+        let start = index as u64 * self.piece_length;
+        let end = start + size as u64;
+        let mut piece = Vec::with_capacity(size as usize);
+        for file in &self.files {
+            let (from, to) = (start.max(file.offset), end.min(file.offset + file.length));
+            match &file.url {
+                Some(url) if from < to => piece.extend(self.fetch_range(url, from - file.offset, to - file.offset).await?),
+                None if from < to => piece.resize(piece.len() + (to - from) as usize, 0),
+                _ => {}
+            }
+        }
+        if piece.len() != size as usize {
+            return Err(WebSeedError::Length { expected: size as usize, received: piece.len() });
+        }
+        Ok(piece)
+    }
+
+    // Bytes from..to of a file
+    async fn fetch_range(&self, url: &str, from: u64, to: u64) -> Result<Vec<u8>, WebSeedError> {
+        let response = self.client.get(url).header(RANGE, format!("bytes={}-{}", from, to - 1)).send().await?;
+        let status = response.status();
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after = response.headers().get(RETRY_AFTER)
+                .and_then(|value| parse_retry_after(value.to_str().ok()?));
+            return Err(WebSeedError::Busy(retry_after));
+        }
+        if status != StatusCode::PARTIAL_CONTENT && status != StatusCode::OK {
+            return Err(WebSeedError::Status(status));
+        }
+
+        // a server ignoring the range sends the whole file, which is only read up to the range
+        let body = match status {
+            StatusCode::PARTIAL_CONTENT => read_body(response, 0, to - from).await?,
+            _ => read_body(response, from, to).await?,
+        };
+        let expected = (to - from) as usize;
+        if body.len() != expected {
+            return Err(WebSeedError::Length { expected, received: body.len() });
+        }
+        Ok(body)
+    }
+}
+
+// Bytes from..to of a response body, which is not read any further
+pub async fn read_body(mut response: Response, from: u64, to: u64) -> Result<Vec<u8>, WebSeedError> {
+    let mut body = Vec::with_capacity((to - from) as usize);
+    let mut position = 0;
+    while position < to {
+        let Some(chunk) = response.chunk().await? else { break };
+        let chunk_end = position + chunk.len() as u64;
+        let (start, end) = (from.clamp(position, chunk_end), to.min(chunk_end));
+        if start < end {
+            body.extend_from_slice(&chunk[(start - position) as usize..(end - position) as usize]);
+        }
+        position = chunk_end;
+    }
+    Ok(body)
+}
+
+impl PieceSource for WebSeed {
+    fn get_url(&self) -> &str {
+        &self.url
+    }
+
+    fn fetch_piece(&self, index: u32, size: u32) -> PieceFuture<'_> {
+        Box::pin(self.fetch(index, size))
+    }
+}
+
+// The seconds to wait that a busy server sends, in a Retry-After header or as the body
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+fn encode(component: &str) -> String {
+    utf8_percent_encode(component, PATH_ENCODE_SET).to_string()
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use percent_encoding::percent_decode_str;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // What the stand-in HTTP server makes of a request
+    pub(crate) struct StandInRequest {
+        // percent-decoded, without the leading '/' and the query
+        pub(crate) path: String,
+        // percent-decoded query parameters
+        pub(crate) query: HashMap<String, Vec<u8>>,
+        // the inclusive byte range of the Range header
+        pub(crate) range: Option<(usize, usize)>,
+    }
+
+    // Answer HTTP requests on a local port, one per connection, with the status and body the
+    // handler returns for the number of requests before it and the request. Returns the address
+    pub(crate) async fn spawn_stand_in<H>(handler: H) -> String
+    where
+        H: Fn(usize, StandInRequest) -> (&'static str, Vec<u8>) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            let mut requests = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(answer(stream, handler.clone(), requests));
+                requests += 1;
+            }
+        });
+        address
+    }
+
+    async fn answer<H>(mut stream: TcpStream, handler: Arc<H>, earlier_requests: usize)
+    where
+        H: Fn(usize, StandInRequest) -> (&'static str, Vec<u8>),
+    {
+        let mut request = vec![];
+        let mut buffer = [0u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => request.extend_from_slice(&buffer[..read]),
+            }
+        }
+        let request = String::from_utf8_lossy(&request).to_string();
+        let target = request.split(' ').nth(1).unwrap_or("/");
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query.split('&')
+            .filter_map(|param| param.split_once('='))
+            .map(|(key, value)| (key.to_string(), percent_decode_str(value).collect()))
+            .collect();
+        let range = request.lines()
+            .find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").and_then(parse_range));
+        let path = percent_decode_str(path.trim_start_matches('/')).decode_utf8_lossy().to_string();
+
+        let (status, body) = handler(earlier_requests, StandInRequest { path, query, range });
+        let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+        let _ = stream.write_all(head.as_bytes()).await;
+        let _ = stream.write_all(&body).await;
+    }
+
+    // An inclusive byte range written as "from-to"
+    pub(crate) fn parse_range(range: &str) -> Option<(usize, usize)> {
+        let (from, to) = range.split_once('-')?;
+        Some((from.parse().ok()?, to.parse().ok()?))
+    }
+
+    // How the stand-in HTTP server answers
+    #[derive(Clone, Copy)]
+    pub(crate) struct ServerBehaviour {
+        // requests answered with 503 before the files are served
+        pub(crate) busy_requests: usize,
+        pub(crate) honour_ranges: bool,
+    }
+
+    // Serve the files by path on a local port. Returns the root url
+    pub(crate) async fn spawn_http_server(files: HashMap<String, Vec<u8>>, behaviour: ServerBehaviour) -> String {
+        let address = spawn_stand_in(move |earlier_requests, request| {
+            match (earlier_requests < behaviour.busy_requests, files.get(&request.path), request.range) {
+                (true, _, _) => ("503 Service Unavailable\r\nRetry-After: 0", vec![]),
+                (false, None, _) => ("404 Not Found", vec![]),
+                (false, Some(file), Some((from, to))) if behaviour.honour_ranges => ("206 Partial Content", file[from..=to].to_vec()),
+                (false, Some(file), _) => ("200 OK", file.clone()),
+            }
+        }).await;
+        format!("http://{}/", address)
+    }
+
+    fn serve_files() -> ServerBehaviour {
+        ServerBehaviour { busy_requests: 0, honour_ranges: true }
+    }
+
+    #[test]
+    fn test_file_urls() {
+        let urls = |seed: &WebSeed| seed.files.iter().map(|file| file.url.clone()).collect::<Vec<_>>();
+        let url = |url: &str| Some(url.to_string());
+        assert_eq!(urls(&WebSeed::new("http://host/file.iso", "name.iso", None, 10, 4)), vec![url("http://host/file.iso")]);
+        assert_eq!(urls(&WebSeed::new("http://host/dir/", "a b.iso", None, 10, 4)), vec![url("http://host/dir/a%20b.iso")]);
+
+        let mut padding = FileInfo::new(vec![".pad".to_string(), "1".to_string()], 1);
+        padding.set_padding();
+        let files = vec![
+            FileInfo::new(vec!["cover.jpg".to_string()], 3),
+            padding,
+            FileInfo::new(vec!["cd 1".to_string(), "track#1.flac".to_string()], 5),
+        ];
+        let seed = WebSeed::new("http://host/music", "album", Some(&files), 9, 4);
+        assert_eq!(urls(&seed), vec![url("http://host/music/album/cover.jpg"), None, url("http://host/music/album/cd%201/track%231.flac")]);
+        assert_eq!(seed.files.iter().map(|file| (file.offset, file.length)).collect::<Vec<_>>(), vec![(0, 3), (3, 1), (4, 5)]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_pieces_across_files() {
+        let data: Vec<u8> = (0..50).collect();
+        let files = HashMap::from([
+            ("album/a.bin".to_string(), data[..12].to_vec()),
+            ("album/sub/b.bin".to_string(), data[12..].to_vec()),
+        ]);
+        let layout = vec![
+            FileInfo::new(vec!["a.bin".to_string()], 12),
+            FileInfo::new(vec!["sub".to_string(), "b.bin".to_string()], 38),
+        ];
+
+        for honour_ranges in [true, false] {
+            let url = spawn_http_server(files.clone(), ServerBehaviour { busy_requests: 0, honour_ranges }).await;
+            let seed = WebSeed::new(&url, "album", Some(&layout), 50, 16);
+            assert_eq!(seed.fetch_piece(0, 16).await.unwrap(), &data[..16]);
+            assert_eq!(seed.fetch_piece(3, 2).await.unwrap(), &data[48..]);
+        }
+
+        // the zeros of a padding file are not requested
+        let mut padding = FileInfo::new(vec![".pad".to_string(), "4".to_string()], 4);
+        padding.set_padding();
+        let padded_layout = vec![
+            FileInfo::new(vec!["a.bin".to_string()], 12),
+            padding,
+            FileInfo::new(vec!["sub".to_string(), "b.bin".to_string()], 38),
+        ];
+        let url = spawn_http_server(files.clone(), serve_files()).await;
+        let seed = WebSeed::new(&url, "album", Some(&padded_layout), 54, 16);
+        let piece = seed.fetch_piece(0, 16).await.unwrap();
+        assert_eq!((&piece[..12], &piece[12..]), (&data[..12], &[0u8; 4][..]));
+        assert_eq!(seed.fetch_piece(1, 16).await.unwrap(), &data[12..28]);
+
+        // a file the server does not have
+        let url = spawn_http_server(HashMap::new(), serve_files()).await;
+        let seed = WebSeed::new(&url, "album", Some(&layout), 50, 16);
+        assert!(matches!(seed.fetch_piece(0, 16).await, Err(WebSeedError::Status(StatusCode::NOT_FOUND))));
+    }
+
+    #[tokio::test]
+    async fn test_busy_server_says_when_to_retry() {
+        let files = HashMap::from([("file".to_string(), vec![7u8; 10])]);
+        let url = spawn_http_server(files, ServerBehaviour { busy_requests: 1, honour_ranges: true }).await;
+        let seed = WebSeed::new(&format!("{}file", url), "file", None, 10, 10);
+        assert!(matches!(seed.fetch_piece(0, 10).await, Err(WebSeedError::Busy(Some(delay))) if delay.is_zero()));
+        assert_eq!(seed.fetch_piece(0, 10).await.unwrap(), vec![7u8; 10]);
+    }
+}
@@ -3,35 +3,194 @@ mod file_processing;
 mod bencode_processing;
 mod utils;
 mod torrent_manager;
+mod tracker_server;
 
+use clients::client_config::ClientConfig;
+use clients::local_discovery::{LocalDiscovery, IPV4_GROUP, IPV6_GROUP};
+use clients::mse::EncryptionPolicy;
+use clients::peer_listener::{PeerListener, DEFAULT_MAX_CONNECTIONS};
+use clients::utp::UtpSocket;
 use file_processing::filereader;
 use torrent_manager::torrent_manager::TorrentManager;
+use torrent_manager::storage::FilePriority;
 use std::env;
+use tokio::io::AsyncWriteExt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracker_server::swarm::SwarmStore;
 use bencode_processing::decoder::decode_bencoded_value;
 use bencode_processing::encoder::encode_bencoded_value;
 
 // Main function to handle command-line arguments and execute commands
 #[tokio::main]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // Session-wide options shared by all commands
+    let mut client_config = ClientConfig::new();
+    if let Some(port) = extract_option(&mut args, "--port") {
+        match port.parse::<u16>() {
+            Ok(port) => client_config.set_port(port),
+            Err(_) => {
+                println!("Invalid port: {}", port);
+                return;
+            }
+        }
+    }
+    if let Some(key) = extract_option(&mut args, "--key") {
+        client_config.set_key(key);
+    }
+    if let Some(max_requests) = extract_option(&mut args, "--max-requests") {
+        match max_requests.parse::<usize>() {
+            Ok(max_requests) => client_config.set_max_outstanding_requests(max_requests),
+            Err(_) => {
+                println!("Invalid number of requests: {}", max_requests);
+                return;
+            }
+        }
+    }
+
+    if let Some(slots) = extract_option(&mut args, "--upload-slots") {
+        match slots.parse::<usize>() {
+            Ok(slots) => client_config.set_upload_slots(slots),
+            Err(_) => {
+                println!("Invalid number of upload slots: {}", slots);
+                return;
+            }
+        }
+    }
+    if let Some(slots) = extract_option(&mut args, "--optimistic-slots") {
+        match slots.parse::<usize>() {
+            Ok(slots) => client_config.set_optimistic_slots(slots),
+            Err(_) => {
+                println!("Invalid number of optimistic slots: {}", slots);
+                return;
+            }
+        }
+    }
+    if let Some(policy) = extract_option(&mut args, "--outgoing-encryption") {
+        match policy.parse::<EncryptionPolicy>() {
+            Ok(policy) => client_config.set_outgoing_encryption(policy),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        }
+    }
+    if let Some(policy) = extract_option(&mut args, "--incoming-encryption") {
+        match policy.parse::<EncryptionPolicy>() {
+            Ok(policy) => client_config.set_incoming_encryption(policy),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        }
+    }
+
+    if let Some(order) = extract_option(&mut args, "--transports") {
+        match clients::utp::parse_transports(&order) {
+            Ok(transports) => client_config.set_transports(transports),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        }
+    }
+
     if args.len() < 2 {
-        println!("Usage: <command> [args]");
+        println!("Usage: [--port <port>] [--key <key>] [--max-requests <n>] [--upload-slots <n>] [--optimistic-slots <n>] \
+                  [--outgoing-encryption <disable|prefer|require>] [--incoming-encryption <disable|prefer|require>] \
+                  [--transports <tcp,utp>] <command> [args]");
         return;
     }
     let command = &args[1];
     let mut torrent_manager = TorrentManager::new(&encode_bencoded_value, &decode_bencoded_value);
+    let (port, incoming_encryption) = (client_config.get_port(), client_config.get_incoming_encryption());
+    torrent_manager.set_client_config(client_config);
 
     match command.as_str() {
         "decode" => decode_command(&args),
         "info" => info_command(&mut torrent_manager, &args),
         "peers" => peers_command(&mut torrent_manager, &args),
+        "scrape" => scrape_command(&mut torrent_manager, &args),
         "handshake" => handshake_command(&mut torrent_manager, &args).await,
         "download_piece" => download_piece_command(&mut torrent_manager, &args).await,
-        "download" => download_command(&mut torrent_manager, &args).await,
+        "download" | "cat" | "seed" => {
+            // peers may connect to us while we download or seed
+            match start_peer_listener(port, incoming_encryption).await {
+                Ok(peer_listener) => torrent_manager.set_peer_listener(peer_listener),
+                Err(e) => eprintln!("Not accepting connections from peers: {}", e),
+            }
+            match start_local_discovery(port).await {
+                Ok(local_discovery) => torrent_manager.set_local_discovery(local_discovery),
+                Err(e) => eprintln!("Not using local service discovery: {}", e),
+            }
+            match command.as_str() {
+                "download" => download_command(&mut torrent_manager, &args).await,
+                "cat" => cat_command(&mut torrent_manager, &args).await,
+                _ => seed_command(&mut torrent_manager, &args).await,
+            }
+        }
+        "tracker" => tracker_command(&args).await,
         _ => println!("unknown command: {}", command),
     }
 }
 
+// Listen for peer connections on the client port for the torrents of this session
+async fn start_peer_listener(port: u16, encryption: EncryptionPolicy) -> Result<Arc<PeerListener>, Box<dyn std::error::Error>> {
+    let mut peer_listener = PeerListener::bind(&format!("0.0.0.0:{}", port), DEFAULT_MAX_CONNECTIONS).await?;
+    peer_listener.set_encryption(encryption);
+    // uTP peers reach us on the UDP port of the same number
+    match UtpSocket::bind(&format!("0.0.0.0:{}", port)).await {
+        Ok(utp_socket) => {
+            eprintln!("Listening for uTP peers on {}", utp_socket.local_addr()?);
+            peer_listener.set_utp_socket(Arc::new(utp_socket));
+        }
+        Err(e) => eprintln!("Not accepting uTP connections: {}", e),
+    }
+    let peer_listener = Arc::new(peer_listener);
+    eprintln!("Listening for peers on {}", peer_listener.local_addr()?);
+    let running = peer_listener.clone();
+    tokio::spawn(async move {
+        if let Err(e) = running.run().await {
+            eprintln!("Peer listener stopped: {}", e);
+        }
+    });
+    Ok(peer_listener)
+}
+
+// Announce our torrents on the local network and look for the peers announcing them there
+async fn start_local_discovery(port: u16) -> Result<Arc<LocalDiscovery>, Box<dyn std::error::Error>> {
+    let local_discovery = Arc::new(LocalDiscovery::bind(&[IPV4_GROUP, IPV6_GROUP], port).await?);
+    for group in local_discovery.groups() {
+        eprintln!("Looking for local peers on {}", group);
+    }
+    let running = local_discovery.clone();
+    tokio::spawn(async move {
+        if let Err(e) = running.run().await {
+            eprintln!("Local service discovery stopped: {}", e);
+        }
+    });
+    Ok(local_discovery)
+}
+
+// Remove an option of the form "<name> <value>" from the arguments and return its value
+fn extract_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let position = args.iter().position(|arg| arg == name)?;
+    if position + 1 >= args.len() {
+        args.remove(position);
+        return None;
+    }
+    let value = args.remove(position + 1);
+    args.remove(position);
+    Some(value)
+}
+
+// Remove every occurrence of an option of the form "<name> <value>" and return the values
+fn extract_repeated_option(args: &mut Vec<String>, name: &str) -> Vec<String> {
+    std::iter::from_fn(|| extract_option(args, name)).collect()
+}
+
 // Decode a bencoded value passed as an argument
 fn decode_command(args: &[String]) {
     if args.len() < 3 {
@@ -71,6 +230,25 @@ fn peers_command(torrent_manager: &mut TorrentManager, args: &[String]) {
     let _ = torrent_manager.print_peers();
 }
 
+// Print seeders, leechers and completed counts of a torrent and optional extra info hashes
+fn scrape_command(torrent_manager: &mut TorrentManager, args: &[String]) {
+    if args.len() < 3 {
+        println!("Usage: scrape <file> [<info_hash>...]");
+        return;
+    }
+    let file = &args[2];
+    let content = filereader::read_file_as_vector(file).unwrap();
+    let _ = torrent_manager.parse_meta_info_file(content);
+    match torrent_manager.scrape_tracker(&args[3..]) {
+        Ok(scrape_infos) => {
+            for scrape_info in scrape_infos {
+                println!("{}", scrape_info.get_formatted_info());
+            }
+        }
+        Err(e) => println!("Scrape failed: {}", e),
+    }
+}
+
 // Perform a handshake with a peer
 async fn handshake_command(torrent_manager: &mut TorrentManager<'_>, args: &[String]) {
     if args.len() < 4 {
@@ -83,7 +261,7 @@ async fn handshake_command(torrent_manager: &mut TorrentManager<'_>, args: &[Str
     let _ = torrent_manager.parse_meta_info_file(content);
     let _ = torrent_manager.init_clients();
     match torrent_manager.perform_peer_handshake(peer_address).await {
-        Ok(resp) => print!("Peer ID: {}\n", hex::encode(resp[48..].to_vec())),
+        Ok(handshake) => println!("Peer ID: {}", hex::encode(handshake.get_peer_id())),
         Err(e) => println!("Handshake failed: {}", e),
     }
 }
@@ -110,22 +288,146 @@ async fn download_piece_command(torrent_manager: &mut TorrentManager<'_>, args:
     }
 }
 
-// Download the entire file from a torrent
+// Download the files of a torrent, optionally selecting files by glob:
+// --only <glob> downloads only matching files, --skip <glob> leaves matching files out and
+// --priority <glob>=<skip|low|normal|high> sets the priority of matching files
 async fn download_command(torrent_manager: &mut TorrentManager<'_>, args: &[String]) {
+    let mut args = args.to_vec();
+    let only = extract_repeated_option(&mut args, "--only");
+    let skip = extract_repeated_option(&mut args, "--skip");
+    let priorities = extract_repeated_option(&mut args, "--priority");
     if args.len() < 5 {
-        println!("Usage: download <file> <output_path>");
+        println!("Usage: download [--only <glob>]... [--skip <glob>]... [--priority <glob>=<priority>]... -o <output_path> <file>");
         return;
     }
     let output_path = &args[3];
     let file = &args[4];
     let content = filereader::read_file_as_vector(file).unwrap();
     let _ = torrent_manager.parse_meta_info_file(content);
-    let _ = torrent_manager.init_clients();
-    match torrent_manager.download_file().await {
-        Ok(file) => {
-            let _ = filereader::write_vector_to_file(output_path, file);
-            println!("File downloaded to {}", output_path);
+
+    let mut selection = vec![];
+    if !only.is_empty() {
+        selection.push(("**".to_string(), FilePriority::Skip));
+        selection.extend(only.into_iter().map(|glob| (glob, FilePriority::Normal)));
+    }
+    selection.extend(skip.into_iter().map(|glob| (glob, FilePriority::Skip)));
+    for option in priorities {
+        let parsed = option.rsplit_once('=').ok_or(format!("Invalid priority option: {}", option))
+            .and_then(|(glob, priority)| Ok((glob.to_string(), priority.parse::<FilePriority>()?)));
+        match parsed {
+            Ok(priority) => selection.push(priority),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
         }
+    }
+    for (glob, priority) in selection {
+        if let Err(e) = torrent_manager.set_file_priority_by_glob(&glob, priority) {
+            println!("Invalid file selection {}: {}", glob, e);
+            return;
+        }
+    }
+
+    let _ = torrent_manager.init_clients();
+    match torrent_manager.download_to(output_path).await {
+        Ok(()) => println!("File downloaded to {}", output_path),
         Err(e) => println!("Failed to download file: {}", e),
     }
 }
+
+// Write a file of a torrent to stdout in order while it downloads. The file is chosen by index
+// or glob, and may be left out for a torrent of a single file
+async fn cat_command(torrent_manager: &mut TorrentManager<'_>, args: &[String]) {
+    if args.len() < 3 {
+        println!("Usage: cat <file> [<file_index>|<glob>]");
+        return;
+    }
+    let file = &args[2];
+    let selector = args.get(3).map(String::as_str);
+    let content = filereader::read_file_as_vector(file).unwrap();
+    let _ = torrent_manager.parse_meta_info_file(content);
+    let _ = torrent_manager.init_clients();
+
+    // stdout carries the file, so problems are reported on stderr
+    let mut stream = match torrent_manager.stream_file(selector) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to stream file: {}", e);
+            return;
+        }
+    };
+    let mut stdout = tokio::io::stdout();
+    if let Err(e) = tokio::io::copy(&mut stream, &mut stdout).await {
+        eprintln!("Failed to stream file: {}", e);
+    }
+    let _ = stdout.flush().await;
+}
+
+// Upload a completed download to the peers of the torrent until interrupted
+async fn seed_command(torrent_manager: &mut TorrentManager<'_>, args: &[String]) {
+    if args.len() < 5 {
+        println!("Usage: seed -o <output_path> <file>");
+        return;
+    }
+    let output_path = &args[3];
+    let file = &args[4];
+    let content = filereader::read_file_as_vector(file).unwrap();
+    let _ = torrent_manager.parse_meta_info_file(content);
+    let _ = torrent_manager.init_seeding_clients();
+
+    if let Err(e) = torrent_manager.seed(output_path).await {
+        println!("Failed to seed: {}", e);
+    }
+}
+
+// Run the built-in tracker, optionally restricted to a list of hex encoded info hashes
+async fn tracker_command(args: &[String]) {
+    if args.len() < 3 {
+        println!("Usage: tracker <port> [<allowed_info_hash>...]");
+        return;
+    }
+    let port = &args[2];
+
+    let mut swarm_store = SwarmStore::new(Duration::from_secs(1800));
+    if args.len() > 3 {
+        let allowlist: Result<Vec<[u8; 20]>, _> = args[3..].iter()
+            .map(|info_hash| hex::decode(info_hash).ok().and_then(|bytes| bytes.try_into().ok()).ok_or(info_hash))
+            .collect();
+        match allowlist {
+            Ok(allowlist) => swarm_store.set_allowlist(allowlist),
+            Err(info_hash) => {
+                println!("Invalid info hash: {}", info_hash);
+                return;
+            }
+        }
+    }
+    let swarms = Arc::new(Mutex::new(swarm_store));
+
+    // HTTP and UDP front ends listen on the same port number and share the swarms
+    let address = format!("0.0.0.0:{}", port);
+    let http_tracker = match tracker_server::http_tracker::HttpTracker::bind(&address, swarms.clone()).await {
+        Ok(http_tracker) => http_tracker,
+        Err(e) => {
+            println!("Failed to start HTTP tracker: {}", e);
+            return;
+        }
+    };
+    let udp_tracker = match tracker_server::udp_tracker::UdpTracker::bind(&address, swarms).await {
+        Ok(udp_tracker) => udp_tracker,
+        Err(e) => {
+            println!("Failed to start UDP tracker: {}", e);
+            return;
+        }
+    };
+
+    println!("Tracker listening on http://{}/announce", http_tracker.local_addr().unwrap());
+    println!("Tracker listening on udp://{}/announce", udp_tracker.local_addr().unwrap());
+    let result = tokio::select! {
+        result = http_tracker.run() => result,
+        result = udp_tracker.run() => result,
+    };
+    if let Err(e) = result {
+        println!("Tracker stopped: {}", e);
+    }
+}
@@ -0,0 +1,426 @@
+use std::sync::Mutex;
+
+use crate::clients::peer_message::HashRequest;
+use crate::clients::request_pipeline::BLOCK_SIZE;
+use crate::utils;
+use super::torrent_spec::file_info::FileInfo;
+use super::torrent_spec::meta_info::PieceLayers;
+
+// Most hashes a peer asks for or sends in one message, proof aside
+const MAX_REQUEST_LENGTH: u32 = 512;
+
+// What checking a downloaded piece against the Merkle tree of its file found
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    Valid,
+    Invalid,
+    // the piece layer of the file is not known yet; the request fetches the part of it covering
+    // the piece
+    MissingHashes(HashRequest),
+}
+
+// The Merkle tree of one file down to the piece layer. Pieces of a file larger than a piece are
+// checked against the piece layer, the single piece of a smaller file against the root
+struct FileTree {
+    pieces_root: [u8; 32],
+    length: u64,
+    first_piece: u32,
+    piece_count: u32,
+    // hashes not received yet are None
+    piece_layer: Mutex<Vec<Option<[u8; 32]>>>,
+}
+
+// The Merkle trees of the files of a v2 torrent (BEP 52): SHA-256 over 16 KiB blocks, padded
+// with zero hashes to a power of two. Every file starts at a piece boundary, so a piece never
+// spans two files
+pub struct MerkleTrees {
+    files: Vec<FileTree>,
+    piece_length: u64,
+}
+
+impl MerkleTrees {
+    // The files are given in file tree order. Piece layers missing from the metainfo are fetched
+    // from peers
+    pub fn new(files: &[FileInfo], piece_layers: &PieceLayers, piece_length: u64) -> Self {
+        let mut first_piece = 0;
+        let files = files.iter()
+            // empty files have no pieces, nor a pieces root
+            .filter_map(|file| Some((*file.get_pieces_root()?, file.get_length() as u64)))
+            .filter(|&(_, length)| length > 0)
+            .map(|(pieces_root, length)| {
+                let piece_count = length.div_ceil(piece_length) as u32;
+                let piece_layer = match piece_layers.get(&pieces_root) {
+                    _ if piece_count == 1 => vec![Some(pieces_root)],
+                    Some(layer) => layer.iter().map(|&hash| Some(hash)).collect(),
+                    None => vec![None; piece_count as usize],
+                };
+                let file = FileTree { pieces_root, length, first_piece, piece_count, piece_layer: Mutex::new(piece_layer) };
+                first_piece += piece_count;
+                file
+            })
+            .collect();
+        Self { files, piece_length }
+    }
+
+    // Sizes of the pieces; the last piece of every file ends with the file
+    pub fn piece_sizes(&self) -> Vec<u32> {
+        let piece_length = self.piece_length;
+        self.files.iter()
+            .flat_map(|file| (0..file.piece_count as u64).map(move |piece| (file.length - piece * piece_length).min(piece_length) as u32))
+            .collect()
+    }
+
+    // Check a piece against the hash of its subtree
+    pub fn verify_piece(&self, index: u32, data: &[u8]) -> Verification {
+        let Some((file, piece)) = self.locate(index) else { return Verification::Invalid };
+        let piece_hash = file.piece_layer.lock().unwrap()[piece as usize];
+        match piece_hash {
+            Some(piece_hash) if subtree_root(&block_hashes(data), self.piece_width(file), [0; 32]) == piece_hash => Verification::Valid,
+            Some(_) => Verification::Invalid,
+            None => Verification::MissingHashes(self.layer_request(file, piece)),
+        }
+    }
+
+    // Store piece layer hashes a peer sent, once their proof leads to the pieces root of the
+    // file. Returns false if it does not
+    pub fn add_hashes(&self, request: &HashRequest, hashes: &[[u8; 32]]) -> bool {
+        let Some(file) = self.find_file(request) else { return false };
+        let width = file.piece_count.next_power_of_two();
+        // the proof has to reach the root for the hashes to be checked
+        let proof_layers = (width / request.length.max(1)).trailing_zeros();
+        if !is_valid_request(request, width) || request.proof_layers != proof_layers || hashes.len() != (request.length + proof_layers) as usize {
+            return false;
+        }
+
+        let (layer_hashes, proof) = hashes.split_at(request.length as usize);
+        let subtree = subtree_root(layer_hashes, layer_hashes.len(), pad_hash(self.piece_level()));
+        if climb(subtree, (request.index / request.length) as usize, proof) != file.pieces_root {
+            return false;
+        }
+        let mut piece_layer = file.piece_layer.lock().unwrap();
+        for (piece, &hash) in (request.index as usize..).zip(layer_hashes) {
+            if let Some(known) = piece_layer.get_mut(piece) {
+                *known = Some(hash);
+            }
+        }
+        true
+    }
+
+    // The request for the block hashes of a piece, which tell its bad blocks apart. None when
+    // the piece is a single block or has too many to ask for at once
+    pub fn block_request(&self, index: u32) -> Option<HashRequest> {
+        let (file, piece) = self.locate(index)?;
+        let width = self.piece_width(file) as u32;
+        if !(2..=MAX_REQUEST_LENGTH).contains(&width) {
+            return None;
+        }
+        Some(HashRequest { pieces_root: file.pieces_root, base_layer: 0, index: piece * width, length: width, proof_layers: 0 })
+    }
+
+    // Offsets of the blocks of a piece that differ from the block hashes a peer sent, once the
+    // hashes lead to the hash of the piece. None if they do not
+    pub fn find_bad_blocks(&self, index: u32, data: &[u8], hashes: &[[u8; 32]]) -> Option<Vec<u32>> {
+        let (file, piece) = self.locate(index)?;
+        let piece_hash = file.piece_layer.lock().unwrap()[piece as usize]?;
+        let width = self.piece_width(file);
+        if hashes.len() != width || subtree_root(hashes, width, [0; 32]) != piece_hash {
+            return None;
+        }
+        let bad_blocks = block_hashes(data).iter()
+            .zip(hashes)
+            .enumerate()
+            .filter(|(_, (own, expected))| own != expected)
+            .map(|(block, _)| block as u32 * BLOCK_SIZE)
+            .collect();
+        Some(bad_blocks)
+    }
+
+    // Bitfield of the pieces whose hashes are known, which are verified without asking peers
+    pub fn known_pieces(&self) -> Vec<u8> {
+        let piece_count: u32 = self.files.iter().map(|file| file.piece_count).sum();
+        let mut bitfield = vec![0; piece_count.div_ceil(8) as usize];
+        for file in &self.files {
+            for (piece, hash) in file.piece_layer.lock().unwrap().iter().enumerate() {
+                if hash.is_some() {
+                    utils::set_bit(&mut bitfield, file.first_piece as usize + piece);
+                }
+            }
+        }
+        bitfield
+    }
+
+    // The piece layer hashes a peer asked for, followed by their proof. Only complete piece
+    // layers are served; block hashes are not kept
+    pub fn answer(&self, request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+        let file = self.find_file(request)?;
+        let width = file.piece_count.next_power_of_two();
+        if !is_valid_request(request, width) || request.proof_layers > (width / request.length).trailing_zeros() {
+            return None;
+        }
+        let mut layer = file.piece_layer.lock().unwrap().iter().copied().collect::<Option<Vec<_>>>()?;
+        layer.resize(width as usize, pad_hash(self.piece_level()));
+        let mut hashes = layer[request.index as usize..(request.index + request.length) as usize].to_vec();
+        hashes.extend(proof(layer, request));
+        Some(hashes)
+    }
+
+    // The file holding a piece and the index of the piece within the file
+    fn locate(&self, index: u32) -> Option<(&FileTree, u32)> {
+        let file = self.files.iter().find(|file| (file.first_piece..file.first_piece + file.piece_count).contains(&index))?;
+        Some((file, index - file.first_piece))
+    }
+
+    // The file whose piece layer a request is for; files of a single piece have none
+    fn find_file(&self, request: &HashRequest) -> Option<&FileTree> {
+        self.files.iter()
+            .filter(|file| file.piece_count > 1 && request.base_layer == self.piece_level())
+            .find(|file| file.pieces_root == request.pieces_root)
+    }
+
+    // The layer of the piece hashes, counted from the block layer
+    fn piece_level(&self) -> u32 {
+        (self.piece_length / BLOCK_SIZE as u64).trailing_zeros()
+    }
+
+    // Leaves below the hash of a piece: a piece of blocks, or the blocks of a file smaller than
+    // a piece rounded up to a power of two
+    fn piece_width(&self, file: &FileTree) -> usize {
+        if file.piece_count == 1 {
+            (file.length.div_ceil(BLOCK_SIZE as u64) as usize).next_power_of_two()
+        } else {
+            (self.piece_length / BLOCK_SIZE as u64) as usize
+        }
+    }
+
+    // Request the part of the piece layer covering a piece, with the proof up to the root
+    fn layer_request(&self, file: &FileTree, piece: u32) -> HashRequest {
+        let width = file.piece_count.next_power_of_two();
+        let length = width.min(MAX_REQUEST_LENGTH);
+        HashRequest {
+            pieces_root: file.pieces_root,
+            base_layer: self.piece_level(),
+            index: piece / length * length,
+            length,
+            proof_layers: (width / length).trailing_zeros(),
+        }
+    }
+}
+
+// The root a piece layer leads to; the layer is padded with the hashes of pieces of zero blocks
+pub fn piece_layer_root(piece_layer: &[[u8; 32]], piece_length: u64) -> [u8; 32] {
+    let piece_level = (piece_length / BLOCK_SIZE as u64).trailing_zeros();
+    subtree_root(piece_layer, piece_layer.len().next_power_of_two(), pad_hash(piece_level))
+}
+
+// The hashes of the 16 KiB blocks of data, the leaves of the tree
+fn block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(BLOCK_SIZE as usize).map(utils::calculate_sha256_hash).collect()
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    utils::calculate_sha256_hash(&[&left[..], &right[..]].concat())
+}
+
+fn next_layer(layer: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    layer.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect()
+}
+
+// Root of a subtree of width leaves, a power of two; leaves past the hashes are the pad hash
+fn subtree_root(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    if hashes.len() > width {
+        return [0; 32];
+    }
+    let mut layer = hashes.to_vec();
+    layer.resize(width, pad);
+    while layer.len() > 1 {
+        layer = next_layer(&layer);
+    }
+    layer[0]
+}
+
+// Root of a subtree of 2^height zero leaves, which pads the layers above the leaves
+fn pad_hash(height: u32) -> [u8; 32] {
+    (0..height).fold([0; 32], |pad, _| hash_pair(&pad, &pad))
+}
+
+// Climb from the root of a subtree at a position of its layer towards the root of the tree, with
+// the uncle hashes of the proof
+fn climb(subtree: [u8; 32], position: usize, proof: &[[u8; 32]]) -> [u8; 32] {
+    let (node, _) = proof.iter().fold((subtree, position), |(node, position), uncle| {
+        let parent = if position % 2 == 0 { hash_pair(&node, uncle) } else { hash_pair(uncle, &node) };
+        (parent, position / 2)
+    });
+    node
+}
+
+// The uncle hashes proving the requested part of a complete layer, from the layer above it up
+fn proof(layer: Vec<[u8; 32]>, request: &HashRequest) -> Vec<[u8; 32]> {
+    let mut layers = vec![layer];
+    while layers.last().unwrap().len() > 1 {
+        layers.push(next_layer(layers.last().unwrap()));
+    }
+    let mut position = (request.index / request.length) as usize;
+    let mut uncles = vec![];
+    for layer in layers.iter().skip(request.length.trailing_zeros() as usize).take(request.proof_layers as usize) {
+        uncles.push(layer[position ^ 1]);
+        position /= 2;
+    }
+    uncles
+}
+
+// Requests are for whole subtrees of at most MAX_REQUEST_LENGTH hashes of a layer of the given width
+fn is_valid_request(request: &HashRequest, width: u32) -> bool {
+    request.length.is_power_of_two()
+        && (2..=MAX_REQUEST_LENGTH).contains(&request.length)
+        && request.index.is_multiple_of(request.length)
+        && request.index.checked_add(request.length).is_some_and(|end| end <= width)
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // The layers of the whole tree of a file, from the blocks up to the root
+    fn tree_layers(data: &[u8], piece_length: u64) -> Vec<Vec<[u8; 32]>> {
+        let blocks_per_piece = (piece_length / BLOCK_SIZE as u64) as usize;
+        let piece_count = (data.len() as u64).div_ceil(piece_length) as usize;
+        let block_count = data.len().div_ceil(BLOCK_SIZE as usize);
+        let width = if piece_count == 1 { block_count.next_power_of_two() } else { piece_count.next_power_of_two() * blocks_per_piece };
+        let mut layers = vec![block_hashes(data)];
+        layers[0].resize(width, [0; 32]);
+        while layers.last().unwrap().len() > 1 {
+            layers.push(next_layer(layers.last().unwrap()));
+        }
+        layers
+    }
+
+    // The file tree entry of a file holding data, and its piece layer if it spans several pieces
+    pub(crate) fn describe_file(path: &str, data: &[u8], piece_length: u64) -> (FileInfo, PieceLayers) {
+        let layers = tree_layers(data, piece_length);
+        let pieces_root = layers.last().unwrap()[0];
+        let mut file = FileInfo::new(vec![path.to_string()], data.len() as i64);
+        file.set_pieces_root(pieces_root);
+        let piece_count = (data.len() as u64).div_ceil(piece_length) as usize;
+        let mut piece_layers = PieceLayers::new();
+        if piece_count > 1 {
+            let piece_level = (piece_length / BLOCK_SIZE as u64).trailing_zeros() as usize;
+            piece_layers.insert(pieces_root, layers[piece_level][..piece_count].to_vec());
+        }
+        (file, piece_layers)
+    }
+
+    // Answer a hash request for any layer of the tree of a file the way a peer having the whole
+    // file does
+    pub(crate) fn answer_from_data(data: &[u8], piece_length: u64, request: &HashRequest) -> Vec<[u8; 32]> {
+        let layers = tree_layers(data, piece_length);
+        let (index, length) = (request.index as usize, request.length as usize);
+        let mut hashes = layers[request.base_layer as usize][index..index + length].to_vec();
+        hashes.extend(proof(layers[request.base_layer as usize].clone(), request));
+        hashes
+    }
+
+    fn make_data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    const PIECE_LENGTH: u64 = 4 * BLOCK_SIZE as u64;
+
+    #[test]
+    fn test_pad_hash_and_roots() {
+        assert_eq!(pad_hash(0), [0; 32]);
+        assert_eq!(pad_hash(2), subtree_root(&[], 4, [0; 32]));
+
+        // the piece layer leads to the same root as the blocks
+        let data = make_data(5 * PIECE_LENGTH as usize + 100);
+        let (file, piece_layers) = describe_file("file", &data, PIECE_LENGTH);
+        let pieces_root = file.get_pieces_root().unwrap();
+        assert_eq!(&piece_layer_root(&piece_layers[pieces_root], PIECE_LENGTH), pieces_root);
+    }
+
+    #[test]
+    fn test_verify_pieces_of_several_files() {
+        let (small, large) = (make_data(3 * BLOCK_SIZE as usize), make_data(2 * PIECE_LENGTH as usize + 10));
+        let (small_file, _) = describe_file("small", &small, PIECE_LENGTH);
+        let (large_file, piece_layers) = describe_file("large", &large, PIECE_LENGTH);
+        let empty_file = FileInfo::new(vec!["empty".to_string()], 0);
+        let trees = MerkleTrees::new(&[small_file, empty_file, large_file], &piece_layers, PIECE_LENGTH);
+
+        assert_eq!(trees.piece_sizes(), vec![3 * BLOCK_SIZE, PIECE_LENGTH as u32, PIECE_LENGTH as u32, 10]);
+        assert_eq!(trees.verify_piece(0, &small), Verification::Valid);
+        assert_eq!(trees.verify_piece(2, &large[PIECE_LENGTH as usize..2 * PIECE_LENGTH as usize]), Verification::Valid);
+        assert_eq!(trees.verify_piece(3, &large[2 * PIECE_LENGTH as usize..]), Verification::Valid);
+        assert_eq!(trees.verify_piece(3, &[0; 10]), Verification::Invalid);
+        assert_eq!(trees.verify_piece(4, &[]), Verification::Invalid);
+    }
+
+    #[test]
+    fn test_find_bad_blocks() {
+        let data = make_data(3 * PIECE_LENGTH as usize);
+        let (file, piece_layers) = describe_file("file", &data, PIECE_LENGTH);
+        let trees = MerkleTrees::new(&[file], &piece_layers, PIECE_LENGTH);
+
+        let mut piece = data[PIECE_LENGTH as usize..2 * PIECE_LENGTH as usize].to_vec();
+        piece[2 * BLOCK_SIZE as usize + 7] ^= 0xff;
+        assert_eq!(trees.verify_piece(1, &piece), Verification::Invalid);
+
+        let request = trees.block_request(1).unwrap();
+        assert_eq!((request.base_layer, request.index, request.length, request.proof_layers), (0, 4, 4, 0));
+        let hashes = answer_from_data(&data, PIECE_LENGTH, &request);
+        assert_eq!(trees.find_bad_blocks(1, &piece, &hashes), Some(vec![2 * BLOCK_SIZE]));
+
+        // hashes that do not lead to the piece hash are of no use
+        let mut wrong = hashes.clone();
+        wrong[0] = [1; 32];
+        assert_eq!(trees.find_bad_blocks(1, &piece, &wrong), None);
+        assert_eq!(trees.find_bad_blocks(1, &piece, &hashes[..2]), None);
+    }
+
+    #[test]
+    fn test_fetch_missing_piece_layer() {
+        let data = make_data(5 * PIECE_LENGTH as usize + 100);
+        let (file, piece_layers) = describe_file("file", &data, PIECE_LENGTH);
+        let complete = MerkleTrees::new(std::slice::from_ref(&file), &piece_layers, PIECE_LENGTH);
+        let trees = MerkleTrees::new(&[file], &PieceLayers::new(), PIECE_LENGTH);
+
+        let piece = &data[..PIECE_LENGTH as usize];
+        let request = match trees.verify_piece(0, piece) {
+            Verification::MissingHashes(request) => request,
+            verification => panic!("Expected missing hashes, got {:?}", verification),
+        };
+        assert_eq!((request.base_layer, request.index, request.length, request.proof_layers), (2, 0, 8, 0));
+        assert_eq!(trees.answer(&request), None);
+        assert_eq!(trees.known_pieces(), vec![0]);
+
+        // tampered hashes are refused, the ones of a peer with the layer accepted
+        let hashes = complete.answer(&request).unwrap();
+        let mut tampered = hashes.clone();
+        tampered[1] = [1; 32];
+        assert!(!trees.add_hashes(&request, &tampered));
+        assert!(trees.add_hashes(&request, &hashes));
+        assert_eq!(trees.known_pieces(), vec![0xfc]);
+        assert_eq!(trees.verify_piece(0, piece), Verification::Valid);
+        assert_eq!(trees.verify_piece(5, &data[5 * PIECE_LENGTH as usize..]), Verification::Valid);
+    }
+
+    #[test]
+    fn test_proofs_of_part_of_a_layer() {
+        let data = make_data(5 * PIECE_LENGTH as usize + 100);
+        let (file, piece_layers) = describe_file("file", &data, PIECE_LENGTH);
+        let complete = MerkleTrees::new(std::slice::from_ref(&file), &piece_layers, PIECE_LENGTH);
+        let trees = MerkleTrees::new(&[file], &PieceLayers::new(), PIECE_LENGTH);
+
+        // pieces 4 and 5 with the uncles of the two layers above them
+        let request = HashRequest { pieces_root: trees.files[0].pieces_root, base_layer: 2, index: 4, length: 2, proof_layers: 2 };
+        let hashes = complete.answer(&request).unwrap();
+        assert_eq!(hashes, answer_from_data(&data, PIECE_LENGTH, &request));
+        assert!(trees.add_hashes(&request, &hashes));
+        assert_eq!(trees.verify_piece(5, &data[5 * PIECE_LENGTH as usize..]), Verification::Valid);
+        assert!(matches!(trees.verify_piece(0, &data[..PIECE_LENGTH as usize]), Verification::MissingHashes(_)));
+
+        // a proof that stops short of the root cannot be checked
+        let short = HashRequest { proof_layers: 1, ..request };
+        assert!(!trees.add_hashes(&short, &complete.answer(&short).unwrap()));
+        assert_eq!(complete.answer(&HashRequest { index: 3, ..request }), None);
+    }
+}
@@ -1,2 +1,8 @@
 pub mod torrent_manager;
-pub mod torrent_spec;
\ No newline at end of file
+pub mod torrent_spec;
+pub mod swarm_downloader;
+pub mod piece_picker;
+pub mod merkle_tree;
+pub mod torrent_stream;
+pub mod storage;
+pub mod seeder;
@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::utils;
+use super::storage::FilePriority;
+
+// Pieces picked at random before switching to rarest first, so there is soon something to share
+const RANDOM_FIRST_PIECES: usize = 4;
+// Peers downloading the same piece at most in endgame mode
+const MAX_ENDGAME_PEERS: usize = 3;
+
+// Order in which pending pieces are picked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PickOrder {
+    RarestFirst,
+    // in index order starting from the cursor, for reading data while it downloads
+    Sequential,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PieceState {
+    Unwanted,
+    Pending,
+    InProgress,
+    Done,
+}
+
+// Decides which piece to request from which peer. Availability is counted from the bitfields
+// of the connected peers; the rarest pieces are picked first so they spread through the swarm.
+// Once every remaining piece is in progress, endgame mode hands pieces to further peers so a
+// slow peer cannot hold up the end of the download
+pub struct PiecePicker {
+    states: Vec<PieceState>,
+    availability: Vec<u32>,
+    peer_bitfields: HashMap<usize, Vec<u8>>,
+    downloaders: HashMap<u32, HashSet<usize>>,
+    picked: usize,
+    order: PickOrder,
+    cursor: u32,
+    priorities: Vec<FilePriority>,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize, wanted: &[u32]) -> Self {
+        let mut states = vec![PieceState::Unwanted; piece_count];
+        for &index in wanted {
+            states[index as usize] = PieceState::Pending;
+        }
+        Self {
+            states,
+            availability: vec![0; piece_count],
+            peer_bitfields: HashMap::new(),
+            downloaders: HashMap::new(),
+            picked: 0,
+            order: PickOrder::RarestFirst,
+            cursor: 0,
+            priorities: vec![FilePriority::Normal; piece_count],
+        }
+    }
+
+    // Setter for order
+    pub fn set_order(&mut self, order: PickOrder) {
+        self.order = order;
+    }
+
+    // Pieces of higher priority are picked before any others
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>) {
+        self.priorities = priorities;
+    }
+
+    // Sequential picking continues from this piece, e.g. after the reader seeked
+    pub fn set_cursor(&mut self, cursor: u32) {
+        self.cursor = cursor;
+    }
+
+    // Record the pieces a peer has, as announced by its bitfield and have messages so far
+    pub fn update_peer(&mut self, peer: usize, bitfield: &[u8]) {
+        let old_bitfield = self.peer_bitfields.remove(&peer).unwrap_or_default();
+        for index in 0..self.states.len() {
+            match (utils::has_bit(&old_bitfield, index), utils::has_bit(bitfield, index)) {
+                (false, true) => self.availability[index] += 1,
+                (true, false) => self.availability[index] -= 1,
+                _ => {}
+            }
+        }
+        self.peer_bitfields.insert(peer, bitfield.to_vec());
+    }
+
+    // Forget a disconnected peer and the pieces it contributed to the availability
+    pub fn remove_peer(&mut self, peer: usize) {
+        self.update_peer(peer, &[]);
+        self.peer_bitfields.remove(&peer);
+    }
+
+    // Pick a pending piece the peer has and mark it in progress, among those of the highest
+    // priority. In rarest first order the first few picks are random, after that the rarest
+    // pieces win, ties broken at random. Sequential order takes the first piece at or after the
+    // cursor, wrapping around to the start. In endgame mode the piece with the fewest
+    // downloaders the peer is not already downloading is picked instead
+    pub fn pick(&mut self, peer: usize) -> Option<u32> {
+        let bitfield = self.peer_bitfields.get(&peer)?;
+        let mut candidates = self.pending_candidates(bitfield);
+
+        if candidates.is_empty() && self.in_endgame() {
+            candidates = self.downloaders.iter()
+                .filter(|(&index, peers)| {
+                    peers.len() < MAX_ENDGAME_PEERS && !peers.contains(&peer) && utils::has_bit(bitfield, index as usize)
+                })
+                .map(|(&index, _)| index as usize)
+                .collect();
+            let fewest = candidates.iter().map(|index| self.downloaders[&(*index as u32)].len()).min()?;
+            candidates.retain(|index| self.downloaders[&(*index as u32)].len() == fewest);
+        } else if self.order == PickOrder::Sequential {
+            let cursor = self.cursor as usize;
+            let next = candidates.iter().copied().find(|&index| index >= cursor).or(candidates.first().copied());
+            candidates = next.into_iter().collect();
+        } else if self.picked >= RANDOM_FIRST_PIECES {
+            let rarest = candidates.iter().map(|&index| self.availability[index]).min()?;
+            candidates.retain(|&index| self.availability[index] == rarest);
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = candidates[utils::random_below(candidates.len())] as u32;
+        self.start(peer, index);
+        Some(index)
+    }
+
+    // Pick the first of the given pieces that pick would consider, e.g. the pieces a peer
+    // suggested or allows us to download while it chokes us
+    pub fn pick_among(&mut self, peer: usize, pieces: &[u32]) -> Option<u32> {
+        let bitfield = self.peer_bitfields.get(&peer)?;
+        let candidates = self.pending_candidates(bitfield);
+        let index = pieces.iter().copied().find(|&index| candidates.contains(&(index as usize)))?;
+        self.start(peer, index);
+        Some(index)
+    }
+
+    // Pending pieces of the highest priority among those in the bitfield
+    fn pending_candidates(&self, bitfield: &[u8]) -> Vec<usize> {
+        let mut candidates: Vec<usize> = (0..self.states.len())
+            .filter(|&index| self.states[index] == PieceState::Pending && utils::has_bit(bitfield, index))
+            .collect();
+        if let Some(highest) = candidates.iter().map(|&index| self.priorities[index]).max() {
+            candidates.retain(|&index| self.priorities[index] == highest);
+        }
+        candidates
+    }
+
+    fn start(&mut self, peer: usize, index: u32) {
+        self.states[index as usize] = PieceState::InProgress;
+        self.downloaders.entry(index).or_default().insert(peer);
+        self.picked += 1;
+    }
+
+    // Whether the peer has a piece we still need, pending or in progress
+    pub fn is_interesting(&self, peer: usize) -> bool {
+        let bitfield = match self.peer_bitfields.get(&peer) {
+            Some(bitfield) => bitfield,
+            None => return false,
+        };
+        (0..self.states.len()).any(|index| {
+            matches!(self.states[index], PieceState::Pending | PieceState::InProgress) && utils::has_bit(bitfield, index)
+        })
+    }
+
+    // Endgame mode starts once no wanted piece is left to be requested
+    pub fn in_endgame(&self) -> bool {
+        !self.states.contains(&PieceState::Pending)
+    }
+
+    // The peer gave up on a piece; it becomes pending again unless other peers still download it
+    pub fn abort(&mut self, peer: usize, index: u32) {
+        if let Some(peers) = self.downloaders.get_mut(&index) {
+            peers.remove(&peer);
+            if peers.is_empty() {
+                self.downloaders.remove(&index);
+                self.states[index as usize] = PieceState::Pending;
+            }
+        }
+    }
+
+    pub fn complete(&mut self, index: u32) {
+        self.states[index as usize] = PieceState::Done;
+        self.downloaders.remove(&index);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Picker past its random start, so picks are rarest first
+    fn rarest_first_picker(piece_count: usize) -> PiecePicker {
+        let wanted: Vec<u32> = (0..piece_count as u32).collect();
+        let mut picker = PiecePicker::new(piece_count, &wanted);
+        picker.picked = RANDOM_FIRST_PIECES;
+        picker
+    }
+
+    #[test]
+    fn test_availability_follows_bitfields_and_haves() {
+        let mut picker = rarest_first_picker(10);
+        picker.update_peer(1, &[0b1100_0000, 0b0100_0000]);
+        picker.update_peer(2, &[0b1000_0000]);
+        assert_eq!(&picker.availability[..3], &[2, 1, 0]);
+        assert_eq!(picker.availability[9], 1);
+
+        // a have message sets one more bit
+        picker.update_peer(2, &[0b1010_0000]);
+        assert_eq!(&picker.availability[..3], &[2, 1, 1]);
+
+        picker.remove_peer(1);
+        assert_eq!(&picker.availability[..3], &[1, 0, 1]);
+        assert_eq!(picker.availability[9], 0);
+        assert_eq!(picker.pick(1), None);
+    }
+
+    #[test]
+    fn test_picks_rarest_piece_the_peer_has() {
+        let mut picker = rarest_first_picker(8);
+        picker.update_peer(1, &[0b1111_0000]);
+        picker.update_peer(2, &[0b1101_0000]);
+        picker.update_peer(3, &[0b0100_1000]);
+        // availability of pieces 0..5 is 2, 3, 1, 2, 1; peer 1 lacks piece 4
+        assert_eq!(picker.pick(1), Some(2));
+        assert_eq!(picker.pick(3), Some(4));
+
+        let next = picker.pick(1).unwrap();
+        assert!(next == 0 || next == 3);
+        assert_eq!(picker.pick(2), Some(if next == 0 { 3 } else { 0 }));
+        assert_eq!(picker.pick(2), Some(1));
+        assert_eq!(picker.pick(1), None);
+    }
+
+    #[test]
+    fn test_abort_and_complete() {
+        let mut picker = rarest_first_picker(2);
+        picker.update_peer(1, &[0b1000_0000]);
+        assert_eq!(picker.pick(1), Some(0));
+        assert_eq!(picker.pick(1), None);
+
+        assert!(picker.is_interesting(1));
+        picker.abort(1, 0);
+        assert_eq!(picker.pick(1), Some(0));
+        picker.complete(0);
+        assert!(!picker.is_interesting(1) && !picker.is_interesting(2));
+        picker.abort(1, 0);
+        assert_eq!(picker.pick(1), None);
+    }
+
+    #[test]
+    fn test_pick_among_given_pieces() {
+        let mut picker = rarest_first_picker(8);
+        let mut priorities = vec![FilePriority::Normal; 8];
+        priorities[1] = FilePriority::High;
+        picker.set_priorities(priorities);
+        picker.update_peer(1, &[0b1011_1000]);
+        // the peer lacks piece 1, so the high priority piece does not stand in the way
+        assert_eq!(picker.pick_among(1, &[6, 1, 3, 2]), Some(3));
+        assert_eq!(picker.pick_among(1, &[3, 6]), None);
+        picker.update_peer(1, &[0b1111_1000]);
+        assert_eq!(picker.pick_among(1, &[2, 1]), Some(1));
+    }
+
+    #[test]
+    fn test_sequential_order_from_cursor() {
+        let mut picker = PiecePicker::new(6, &[0, 1, 2, 3, 4, 5]);
+        picker.set_order(PickOrder::Sequential);
+        picker.update_peer(1, &[0b1101_1100]);
+        // rarity does not matter in sequential order
+        picker.update_peer(2, &[0b0100_0000]);
+        assert_eq!(picker.pick(1), Some(0));
+        assert_eq!(picker.pick(1), Some(1));
+
+        picker.set_cursor(4);
+        assert_eq!(picker.pick(1), Some(4));
+        assert_eq!(picker.pick(1), Some(5));
+        // nothing left after the cursor, continue from the start
+        assert_eq!(picker.pick(1), Some(3));
+    }
+
+    #[test]
+    fn test_higher_priority_first() {
+        let mut picker = rarest_first_picker(4);
+        picker.set_priorities(vec![FilePriority::Low, FilePriority::Normal, FilePriority::High, FilePriority::Normal]);
+        picker.update_peer(1, &[0b1111_0000]);
+        picker.update_peer(2, &[0b0100_0000]);
+        assert_eq!(picker.pick(1), Some(2));
+        // the rarer of the normal priority pieces
+        assert_eq!(picker.pick(1), Some(3));
+        assert_eq!(picker.pick(1), Some(1));
+        assert_eq!(picker.pick(1), Some(0));
+    }
+
+    #[test]
+    fn test_endgame() {
+        let mut picker = rarest_first_picker(3);
+        for peer in 1..=5 {
+            picker.update_peer(peer, &[0b1100_0000]);
+        }
+        picker.update_peer(6, &[0b0010_0000]);
+        assert_eq!(picker.pick(6), Some(2));
+        assert!(!picker.in_endgame());
+        let first = picker.pick(1).unwrap();
+        let second = picker.pick(2).unwrap();
+        assert!(picker.in_endgame());
+
+        // a peer already downloading every piece it has gets nothing more
+        assert_eq!(picker.pick(6), None);
+        // others join the pieces with the fewest downloaders
+        let joined = picker.pick(3).unwrap();
+        assert!(joined == first || joined == second);
+        assert_eq!(picker.pick(4), Some(if joined == first { second } else { first }));
+        assert_eq!(picker.pick(5).map(|index| index < 2), Some(true));
+        assert_eq!(picker.downloaders.values().map(|peers| peers.len()).sum::<usize>(), 6);
+
+        // aborting one of several downloaders keeps the piece in progress
+        picker.abort(1, first);
+        assert!(picker.in_endgame());
+        picker.complete(first);
+        picker.complete(second);
+        picker.complete(2);
+        assert_eq!(picker.pick(1), None);
+    }
+
+    #[test]
+    fn test_random_start_and_unwanted_pieces() {
+        let mut picker = PiecePicker::new(16, &[3, 5, 7, 9, 11]);
+        picker.update_peer(1, &[0xff, 0xff]);
+        picker.update_peer(2, &[0x00, 0x40]);
+
+        let mut picked: Vec<u32> = std::iter::from_fn(|| picker.pick(1)).collect();
+        picked.sort_unstable();
+        assert_eq!(picked, vec![3, 5, 7, 9, 11]);
+    }
+}
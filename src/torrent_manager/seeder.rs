@@ -0,0 +1,224 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::clients::choker::{ChokeController, PeerLink};
+use crate::clients::client_config::ClientConfig;
+use crate::clients::local_discovery::LocalDiscovery;
+use crate::clients::local_pieces::LocalPieces;
+use crate::clients::peer_client::PeerClient;
+use crate::clients::peer_listener::{PeerConnection, PeerListener};
+use crate::clients::utp::UtpSocket;
+use super::merkle_tree::MerkleTrees;
+
+// Pause before connecting again to peers that dropped the connection
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
+// Peers connecting to us that are served at the same time
+const MAX_INCOMING_CONNECTIONS: usize = 50;
+
+// Uploads the pieces we have to the peers of a torrent
+pub struct Seeder {
+    client_config: ClientConfig,
+    info_hash: [u8; 20],
+    local_pieces: Arc<LocalPieces>,
+    reconnect_interval: Duration,
+    peer_listener: Option<Arc<PeerListener>>,
+    local_discovery: Option<Arc<LocalDiscovery>>,
+    merkle_trees: Option<Arc<MerkleTrees>>,
+}
+
+impl Seeder {
+    pub fn new(client_config: ClientConfig, info_hash: [u8; 20], local_pieces: Arc<LocalPieces>) -> Self {
+        Self { client_config, info_hash, local_pieces, reconnect_interval: RECONNECT_INTERVAL, peer_listener: None, local_discovery: None, merkle_trees: None }
+    }
+
+    // Peers connecting to us through the listener are served as well
+    pub fn set_peer_listener(&mut self, peer_listener: Arc<PeerListener>) {
+        self.peer_listener = Some(peer_listener);
+    }
+
+    // The torrent is announced on the local network, and the peers announcing it are served
+    pub fn set_local_discovery(&mut self, local_discovery: Arc<LocalDiscovery>) {
+        self.local_discovery = Some(local_discovery);
+    }
+
+    // Peers are sent the piece layers of a v2 torrent they ask for
+    pub fn set_merkle_trees(&mut self, merkle_trees: Arc<MerkleTrees>) {
+        self.merkle_trees = Some(merkle_trees);
+    }
+
+    // Serve the peers until interrupted, connecting again to those that closed the connection
+    pub async fn run(&self, peers: &[String]) {
+        let mut incoming = self.peer_listener.as_ref()
+            .map(|peer_listener| peer_listener.register(self.info_hash, MAX_INCOMING_CONNECTIONS));
+        let mut discovered = self.local_discovery.as_ref().map(|local_discovery| local_discovery.register(self.info_hash));
+        let choke_controller = Arc::new(ChokeController::new(self.client_config.create_choker()));
+        let choking = choke_controller.run(true);
+        tokio::pin!(choking);
+        let mut tasks = JoinSet::new();
+        let mut connected = HashSet::new();
+        let (closed_sender, mut closed) = mpsc::unbounded_channel();
+        let mut reconnect = tokio::time::interval(self.reconnect_interval);
+        loop {
+            tokio::select! {
+                _ = &mut choking => {}
+                _ = reconnect.tick() => {
+                    for address in peers {
+                        if connected.insert(address.clone()) {
+                            tasks.spawn(self.serve_peer(PeerConnection::Outgoing(address.clone()), choke_controller.register(), closed_sender.clone()));
+                        }
+                    }
+                }
+                Some(peer) = async { incoming.as_mut()?.recv().await }, if incoming.is_some() => {
+                    tasks.spawn(self.serve_peer(PeerConnection::Incoming(peer), choke_controller.register(), closed_sender.clone()));
+                }
+                Some(address) = async { discovered.as_mut()?.recv().await }, if discovered.is_some() => {
+                    if connected.insert(address.clone()) {
+                        tasks.spawn(self.serve_peer(PeerConnection::Outgoing(address), choke_controller.register(), closed_sender.clone()));
+                    }
+                }
+                Some(address) = closed.recv() => {
+                    connected.remove(&address);
+                }
+                Some(result) = tasks.join_next() => {
+                    if let Err(e) = result {
+                        eprintln!("Peer task failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    // Serve one peer until the connection fails; the address of a peer we connected to is sent
+    // back on `closed`, to connect to it again later
+    fn serve_peer(&self, connection: PeerConnection, choke_link: PeerLink, closed: mpsc::UnboundedSender<String>) -> impl Future<Output = ()> {
+        let (client_config, info_hash, local_pieces) = (self.client_config.clone(), self.info_hash, self.local_pieces.clone());
+        let utp_socket = self.peer_listener.as_ref().and_then(|peer_listener| peer_listener.get_utp_socket());
+        let merkle_trees = self.merkle_trees.clone();
+        async move {
+            let address = connection.address();
+            let _reconnect = matches!(connection, PeerConnection::Outgoing(_))
+                .then(|| Reconnect { address: address.clone(), closed });
+            if let Err(e) = upload_to_peer(client_config, info_hash, local_pieces, choke_link, utp_socket, merkle_trees, connection).await {
+                eprintln!("Dropped peer {}: {}", address, e);
+            }
+        }
+    }
+}
+
+// Sends the address of a peer back to the seeder when its task ends, even by panicking
+struct Reconnect {
+    address: String,
+    closed: mpsc::UnboundedSender<String>,
+}
+
+impl Drop for Reconnect {
+    fn drop(&mut self) {
+        let _ = self.closed.send(std::mem::take(&mut self.address));
+    }
+}
+
+async fn upload_to_peer(
+    client_config: ClientConfig,
+    info_hash: [u8; 20],
+    local_pieces: Arc<LocalPieces>,
+    choke_link: PeerLink,
+    utp_socket: Option<Arc<UtpSocket>>,
+    merkle_trees: Option<Arc<MerkleTrees>>,
+    connection: PeerConnection,
+) -> Result<(), Box<dyn Error>> {
+    let mut peer_client = PeerClient::new(client_config);
+    peer_client.set_local_pieces(local_pieces);
+    peer_client.set_choke_link(choke_link);
+    if let Some(utp_socket) = utp_socket {
+        peer_client.set_utp_socket(utp_socket);
+    }
+    if let Some(merkle_trees) = merkle_trees {
+        peer_client.set_merkle_trees(merkle_trees);
+    }
+    let _permit = peer_client.open(connection, info_hash).await?;
+    // requests are served while waiting for messages
+    loop {
+        peer_client.wait_for_message().await?;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::handshake::{Capabilities, Handshake, HANDSHAKE_LENGTH};
+    use crate::clients::local_pieces::tests::MemorySource;
+    use crate::clients::peer_message::PeerMessage;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const INFO_HASH: [u8; 20] = [3; 20];
+
+    #[tokio::test]
+    async fn test_serves_requests_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let data: Vec<u8> = (0..40000).map(|i| (i % 241) as u8).collect();
+        let local_pieces = Arc::new(LocalPieces::new(vec![20000, 20000], Arc::new(MemorySource { data: data.clone(), piece_length: 20000 })));
+        local_pieces.add_piece(1);
+
+        let mut seeder = Seeder::new(ClientConfig::new(), INFO_HASH, local_pieces.clone());
+        seeder.reconnect_interval = Duration::from_millis(50);
+        let peers = vec![address];
+        let seeding = tokio::spawn(async move { seeder.run(&peers).await });
+
+        // The leecher is told about a piece added during its first connection, closes it and
+        // is served again on the second, whose bitfield has both pieces
+        for connection in 0..2 {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [9; 20]).encode()).await.unwrap();
+            let expected_bitfield = if connection == 0 { vec![0x40] } else { vec![0xc0] };
+            loop {
+                match PeerMessage::read_from(&mut stream).await.unwrap() {
+                    PeerMessage::Bitfield { bitfield } => {
+                        assert_eq!(bitfield, expected_bitfield);
+                        stream.write_all(&PeerMessage::Interested.encode()).await.unwrap();
+                    }
+                    PeerMessage::Unchoke => {
+                        // too large, then a valid request
+                        stream.write_all(&PeerMessage::Request { index: 1, begin: 0, length: 32768 }.encode()).await.unwrap();
+                        stream.write_all(&PeerMessage::Request { index: 1, begin: 16384, length: 3616 }.encode()).await.unwrap();
+                    }
+                    PeerMessage::Piece { index, begin, block } => {
+                        assert_eq!((index, begin), (1, 16384));
+                        assert_eq!(block, &data[36384..]);
+                        if connection == 1 {
+                            break;
+                        }
+                        local_pieces.add_piece(0);
+                    }
+                    PeerMessage::Have { index } => {
+                        assert_eq!(index, 0);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        seeding.abort();
+    }
+
+    #[tokio::test]
+    async fn test_panicking_task_sends_back_its_address() {
+        let (closed_sender, mut closed) = mpsc::unbounded_channel();
+        let reconnect = Reconnect { address: "127.0.0.1:6881".to_string(), closed: closed_sender };
+        let task = tokio::spawn(async move {
+            let _reconnect = reconnect;
+            panic!("peer task failed");
+        });
+        assert!(task.await.unwrap_err().is_panic());
+        assert_eq!(closed.recv().await.unwrap(), "127.0.0.1:6881");
+    }
+}
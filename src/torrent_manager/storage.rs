@@ -0,0 +1,233 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::clients::local_pieces::BlockSource;
+
+// Download priority of a file. A piece gets the highest priority of the files it overlaps, so
+// pieces of skipped files are only downloaded when they are shared with a wanted file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl FromStr for FilePriority {
+    type Err = String;
+
+    fn from_str(priority: &str) -> Result<Self, Self::Err> {
+        match priority {
+            "skip" => Ok(FilePriority::Skip),
+            "low" => Ok(FilePriority::Low),
+            "normal" => Ok(FilePriority::Normal),
+            "high" => Ok(FilePriority::High),
+            _ => Err(format!("Unknown priority {:?}, expected skip, low, normal or high", priority)),
+        }
+    }
+}
+
+// A file of the torrent and where its data starts within the concatenated torrent data
+struct StorageFile {
+    path: Option<PathBuf>, // none for padding files, whose zeros are not stored
+    offset: u64,
+    length: u64,
+    priority: FilePriority,
+}
+
+// Writes verified pieces into the files they span and reads them back for uploading
+pub struct Storage {
+    files: Vec<StorageFile>,
+    piece_length: u64,
+}
+
+impl Storage {
+    // Files are given in torrent order as (path, length, priority), padding files without a path
+    pub fn new(files: Vec<(Option<PathBuf>, u64, FilePriority)>, piece_length: u64) -> Self {
+        Self::with_alignment(files, piece_length, 1)
+    }
+
+    // Files of v2 torrents each start at a piece boundary (BEP 52)
+    pub fn aligned(files: Vec<(Option<PathBuf>, u64, FilePriority)>, piece_length: u64) -> Self {
+        Self::with_alignment(files, piece_length, piece_length)
+    }
+
+    fn with_alignment(files: Vec<(Option<PathBuf>, u64, FilePriority)>, piece_length: u64, alignment: u64) -> Self {
+        let offsets = file_offsets(files.iter().map(|&(_, length, _)| length), alignment);
+        let files = files.into_iter()
+            .zip(offsets)
+            .map(|((path, length, priority), offset)| StorageFile { path, offset, length, priority })
+            .collect();
+        Self { files, piece_length }
+    }
+
+    // Create the wanted files up front, including empty ones. Skipped files are only created
+    // when a piece shared with a wanted file writes into them
+    pub fn create_wanted_files(&self) -> io::Result<()> {
+        for file in self.files.iter().filter(|file| file.priority != FilePriority::Skip) {
+            if let Some(path) = &file.path {
+                open_file(path)?.set_len(file.length)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The highest priority of the files each piece overlaps
+    pub fn piece_priorities(&self, piece_count: usize) -> Vec<FilePriority> {
+        let mut priorities = vec![FilePriority::Skip; piece_count];
+        for file in self.files.iter().filter(|file| file.length > 0) {
+            let first_piece = (file.offset / self.piece_length) as usize;
+            let last_piece = ((file.offset + file.length - 1) / self.piece_length) as usize;
+            for priority in &mut priorities[first_piece..=last_piece.min(piece_count - 1)] {
+                *priority = (*priority).max(file.priority);
+            }
+        }
+        priorities
+    }
+
+    pub fn write_piece(&self, index: u32, data: &[u8]) -> io::Result<()> {
+        let start = index as u64 * self.piece_length;
+        for (file, path, from, to) in self.segments(start, start + data.len() as u64) {
+            let mut handle = open_file(path)?;
+            handle.seek(SeekFrom::Start(from - file.offset))?;
+            handle.write_all(&data[(from - start) as usize..(to - start) as usize])?;
+        }
+        Ok(())
+    }
+
+    // The stored files overlapping a range of the torrent data, with the overlapping part of the
+    // range; padding files in the range read as zeros
+    fn segments(&self, start: u64, end: u64) -> impl Iterator<Item = (&StorageFile, &PathBuf, u64, u64)> {
+        self.files.iter()
+            .filter_map(move |file| Some((file, file.path.as_ref()?, start.max(file.offset), end.min(file.offset + file.length))))
+            .filter(|(_, _, from, to)| from < to)
+    }
+}
+
+impl BlockSource for Storage {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let start = index as u64 * self.piece_length + begin as u64;
+        let mut block = vec![0; length as usize];
+        for (file, path, from, to) in self.segments(start, start + length as u64) {
+            let mut handle = fs::File::open(path)?;
+            handle.seek(SeekFrom::Start(from - file.offset))?;
+            handle.read_exact(&mut block[(from - start) as usize..(to - start) as usize])?;
+        }
+        Ok(block)
+    }
+}
+
+// Where each file starts within the torrent data, the files following each other at multiples of
+// the alignment
+pub fn file_offsets(lengths: impl IntoIterator<Item = u64>, alignment: u64) -> Vec<u64> {
+    let mut offset = 0;
+    lengths.into_iter()
+        .map(|length| {
+            let start = offset;
+            offset = (offset + length).next_multiple_of(alignment);
+            start
+        })
+        .collect()
+}
+
+// Open a file for writing without truncating it, creating it and its directories if needed
+fn open_file(path: &PathBuf) -> io::Result<fs::File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().write(true).create(true).truncate(false).open(path)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skipped_files_only_get_boundary_data() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path();
+        // a: 0..10, b: 10..35, empty, c: 35..45 with 16 byte pieces
+        let storage = Storage::new(vec![
+            (Some(root.join("a")), 10, FilePriority::Normal),
+            (Some(root.join("b")), 25, FilePriority::Skip),
+            (Some(root.join("empty")), 0, FilePriority::Normal),
+            (Some(root.join("sub").join("c")), 10, FilePriority::High),
+        ], 16);
+
+        let priorities = storage.piece_priorities(3);
+        assert_eq!(priorities, vec![FilePriority::Normal, FilePriority::Skip, FilePriority::High]);
+
+        storage.create_wanted_files().unwrap();
+        assert!(root.join("empty").exists() && root.join("sub").join("c").exists());
+        assert!(!root.join("b").exists());
+
+        let data: Vec<u8> = (0..45).collect();
+        storage.write_piece(0, &data[..16]).unwrap();
+        storage.write_piece(2, &data[32..]).unwrap();
+
+        assert_eq!(fs::read(root.join("a")).unwrap(), &data[..10]);
+        assert_eq!(fs::read(root.join("sub").join("c")).unwrap(), &data[35..]);
+        // the skipped file holds the parts of the boundary pieces, the middle stays empty
+        let skipped = fs::read(root.join("b")).unwrap();
+        assert_eq!(skipped.len(), 25);
+        assert_eq!(&skipped[..6], &data[10..16]);
+        assert_eq!(&skipped[22..], &data[32..35]);
+        assert!(skipped[6..22].iter().all(|&byte| byte == 0));
+
+        // a block spanning a file boundary reads back from both files
+        assert_eq!(storage.read_block(0, 8, 8).unwrap(), &data[8..16]);
+        assert_eq!(storage.read_block(2, 0, 13).unwrap(), &data[32..]);
+    }
+
+    #[test]
+    fn test_aligned_files_start_at_piece_boundaries() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path();
+        // a: piece 0, b: pieces 1 and 2, c: piece 3 with 16 byte pieces
+        let storage = Storage::aligned(vec![
+            (Some(root.join("a")), 10, FilePriority::Normal),
+            (Some(root.join("b")), 20, FilePriority::Skip),
+            (Some(root.join("c")), 16, FilePriority::Normal),
+        ], 16);
+        assert_eq!(storage.piece_priorities(4), vec![FilePriority::Normal, FilePriority::Skip, FilePriority::Skip, FilePriority::Normal]);
+
+        storage.write_piece(0, &[1; 10]).unwrap();
+        storage.write_piece(3, &[3; 16]).unwrap();
+        assert_eq!(fs::read(root.join("a")).unwrap(), vec![1; 10]);
+        assert_eq!(fs::read(root.join("c")).unwrap(), vec![3; 16]);
+        assert!(!root.join("b").exists());
+        assert_eq!(storage.read_block(3, 4, 8).unwrap(), vec![3; 8]);
+    }
+
+    #[test]
+    fn test_padding_files_are_not_stored() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path();
+        // a: 0..10, padding: 10..16, b: 16..26 with 16 byte pieces
+        let storage = Storage::new(vec![
+            (Some(root.join("a")), 10, FilePriority::Normal),
+            (None, 6, FilePriority::Skip),
+            (Some(root.join("b")), 10, FilePriority::Normal),
+        ], 16);
+        storage.create_wanted_files().unwrap();
+
+        let mut piece = vec![1; 10];
+        piece.extend([0; 6]);
+        storage.write_piece(0, &piece).unwrap();
+        storage.write_piece(1, &[2; 10]).unwrap();
+        assert_eq!(fs::read(root.join("a")).unwrap(), vec![1; 10]);
+        assert_eq!(fs::read(root.join("b")).unwrap(), vec![2; 10]);
+        assert_eq!(fs::read_dir(root).unwrap().count(), 2);
+        assert_eq!(storage.read_block(0, 0, 16).unwrap(), piece);
+    }
+
+    #[test]
+    fn test_parse_priority() {
+        assert_eq!("high".parse::<FilePriority>(), Ok(FilePriority::High));
+        assert_eq!("skip".parse::<FilePriority>(), Ok(FilePriority::Skip));
+        assert!("urgent".parse::<FilePriority>().is_err());
+    }
+}
@@ -0,0 +1,945 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::clients::choker::ChokeController;
+use crate::clients::client_config::ClientConfig;
+use crate::clients::local_discovery::LocalDiscovery;
+use crate::clients::local_pieces::LocalPieces;
+use crate::clients::peer_client::PeerClient;
+use crate::clients::peer_listener::{IncomingPeer, PeerConnection, PeerListener};
+use crate::clients::peer_message::HashRequest;
+use crate::clients::request_pipeline::SharedBlocks;
+use crate::clients::utp::UtpSocket;
+use crate::clients::web_seed::{PieceSource, WebSeedError};
+use crate::utils;
+use super::merkle_tree::{MerkleTrees, Verification};
+use super::piece_picker::{PickOrder, PiecePicker};
+use super::storage::FilePriority;
+
+// Peer connections kept open at the same time
+const MAX_CONNECTIONS: usize = 30;
+// A piece that is not completed within this time is given to another peer
+const PIECE_TIMEOUT: Duration = Duration::from_secs(60);
+// Pause before looking for work again when a peer has none of the pending pieces
+const IDLE_INTERVAL: Duration = Duration::from_millis(500);
+// A web seed failing this many times in a row is given up
+const MAX_WEB_SEED_FAILURES: u32 = 5;
+// Upper bound of the pause between attempts at a failing web seed
+const MAX_WEB_SEED_RETRY_DELAY: Duration = Duration::from_secs(120);
+// How long a peer has to answer a request for Merkle tree hashes
+const HASH_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Reported by the peer tasks to the downloader
+enum PeerEvent {
+    Verified { index: u32, data: Vec<u8> },
+    Closed { address: String, reason: String },
+}
+
+// Where peers join a running download from, besides the initial list
+struct PeerSources {
+    // connections peers open to us
+    incoming: Option<mpsc::UnboundedReceiver<IncomingPeer>>,
+    // addresses of peers found on the local network
+    discovered: Option<mpsc::UnboundedReceiver<String>>,
+}
+
+// State shared by the peer tasks of one download
+struct SwarmContext {
+    client_config: ClientConfig,
+    info_hash: [u8; 20],
+    piece_hashes: Vec<String>,
+    piece_sizes: Vec<u32>,
+    merkle_trees: Option<Arc<MerkleTrees>>,
+    choke_timeout: Duration,
+    web_seeds: Vec<Arc<dyn PieceSource>>,
+    web_seed_retry_delay: Duration,
+    picker: Mutex<PiecePicker>,
+    // blocks received so far of the pieces in progress
+    shared_blocks: Mutex<HashMap<u32, SharedBlocks>>,
+    duplicate_bytes: Arc<AtomicU64>,
+    local_pieces: Option<Arc<LocalPieces>>,
+    // decides whom we upload to, when uploading
+    choke_controller: Option<Arc<ChokeController>>,
+    // the listener's uTP socket, for uTP connections to peers
+    utp_socket: Option<Arc<UtpSocket>>,
+    events: mpsc::UnboundedSender<PeerEvent>,
+}
+
+// Downloads pieces from many peers in parallel. Every connection is reused for as many pieces
+// as the peer serves; pieces of peers that disconnect, stall or keep us choked go back to the
+// queue for the other peers
+pub struct SwarmDownloader {
+    client_config: ClientConfig,
+    info_hash: [u8; 20],
+    piece_hashes: Vec<String>,
+    piece_sizes: Vec<u32>,
+    merkle_trees: Option<Arc<MerkleTrees>>,
+    max_connections: usize,
+    choke_timeout: Duration,
+    pick_order: PickOrder,
+    piece_priorities: Option<Vec<FilePriority>>,
+    duplicate_bytes: Arc<AtomicU64>,
+    local_pieces: Option<Arc<LocalPieces>>,
+    peer_listener: Option<Arc<PeerListener>>,
+    local_discovery: Option<Arc<LocalDiscovery>>,
+    web_seeds: Vec<Arc<dyn PieceSource>>,
+    // pause after the first failure of a web seed, doubled with every further one
+    web_seed_retry_delay: Duration,
+}
+
+impl SwarmDownloader {
+    pub fn new(client_config: ClientConfig, info_hash: [u8; 20], piece_hashes: Vec<String>, piece_sizes: Vec<u32>) -> Self {
+        Self {
+            client_config,
+            info_hash,
+            piece_hashes,
+            piece_sizes,
+            merkle_trees: None,
+            max_connections: MAX_CONNECTIONS,
+            choke_timeout: Duration::from_secs(30),
+            pick_order: PickOrder::RarestFirst,
+            piece_priorities: None,
+            duplicate_bytes: Arc::new(AtomicU64::new(0)),
+            local_pieces: None,
+            peer_listener: None,
+            local_discovery: None,
+            web_seeds: vec![],
+            web_seed_retry_delay: Duration::from_secs(5),
+        }
+    }
+
+    // Pieces are verified against the Merkle trees of their files (v2) instead of the piece
+    // hashes, which tells the bad blocks of a piece apart
+    pub fn set_merkle_trees(&mut self, merkle_trees: Arc<MerkleTrees>) {
+        self.merkle_trees = Some(merkle_trees);
+    }
+
+    // Setter for pick_order
+    pub fn set_pick_order(&mut self, pick_order: PickOrder) {
+        self.pick_order = pick_order;
+    }
+
+    // Setter for piece_priorities
+    pub fn set_piece_priorities(&mut self, piece_priorities: Vec<FilePriority>) {
+        self.piece_priorities = Some(piece_priorities);
+    }
+
+    // Pieces uploaded to the peers while downloading
+    pub fn set_local_pieces(&mut self, local_pieces: Arc<LocalPieces>) {
+        self.local_pieces = Some(local_pieces);
+    }
+
+    // Peers connecting to us through the listener join the download
+    pub fn set_peer_listener(&mut self, peer_listener: Arc<PeerListener>) {
+        self.peer_listener = Some(peer_listener);
+    }
+
+    // Peers announcing the torrent on the local network join the download
+    pub fn set_local_discovery(&mut self, local_discovery: Arc<LocalDiscovery>) {
+        self.local_discovery = Some(local_discovery);
+    }
+
+    // Pieces are also fetched from the web seed, as from a peer having all of them
+    pub fn add_web_seed(&mut self, web_seed: Arc<dyn PieceSource>) {
+        self.web_seeds.push(web_seed);
+    }
+
+    // Download the given pieces and return them by index once every one has been verified
+    pub async fn download(&self, peers: &[String], pieces: &[u32]) -> Result<HashMap<u32, Vec<u8>>, Box<dyn Error>> {
+        let mut download = self.start(peers, pieces)?;
+        let mut verified = HashMap::new();
+        while let Some((index, data)) = download.next_piece().await {
+            verified.insert(index, data);
+        }
+        download.finish().await?;
+        Ok(verified)
+    }
+
+    // Start downloading the given pieces in the background
+    pub fn start(&self, peers: &[String], pieces: &[u32]) -> Result<SwarmDownload, Box<dyn Error>> {
+        if let Some(index) = pieces.iter().find(|&&index| index as usize >= self.piece_sizes.len()) {
+            return Err(format!("Piece index {} out of range", index).into());
+        }
+        let wanted = pieces.iter().collect::<HashSet<_>>().len();
+
+        let mut picker = PiecePicker::new(self.piece_sizes.len(), pieces);
+        picker.set_order(self.pick_order);
+        if let Some(piece_priorities) = self.piece_priorities.as_ref() {
+            picker.set_priorities(piece_priorities.clone());
+        }
+        let (events, receiver) = mpsc::unbounded_channel();
+        let context = Arc::new(SwarmContext {
+            client_config: self.client_config.clone(),
+            info_hash: self.info_hash,
+            piece_hashes: self.piece_hashes.clone(),
+            piece_sizes: self.piece_sizes.clone(),
+            merkle_trees: self.merkle_trees.clone(),
+            choke_timeout: self.choke_timeout,
+            web_seeds: self.web_seeds.clone(),
+            web_seed_retry_delay: self.web_seed_retry_delay,
+            picker: Mutex::new(picker),
+            shared_blocks: Mutex::new(HashMap::new()),
+            duplicate_bytes: self.duplicate_bytes.clone(),
+            local_pieces: self.local_pieces.clone(),
+            choke_controller: self.local_pieces.as_ref()
+                .map(|_| Arc::new(ChokeController::new(self.client_config.create_choker()))),
+            utp_socket: self.peer_listener.as_ref().and_then(|peer_listener| peer_listener.get_utp_socket()),
+            events,
+        });
+
+        let (verified, verified_receiver) = mpsc::unbounded_channel();
+        let sources = PeerSources {
+            incoming: self.peer_listener.as_ref()
+                .map(|peer_listener| peer_listener.register(self.info_hash, self.max_connections)),
+            discovered: self.local_discovery.as_ref().map(|local_discovery| local_discovery.register(self.info_hash)),
+        };
+        let coordinator = tokio::spawn(coordinate(
+            context.clone(), receiver, sources, peers.to_vec(), self.max_connections, wanted, verified,
+        ));
+        Ok(SwarmDownload { context, verified: verified_receiver, coordinator })
+    }
+}
+
+// A download running in the background, handing out pieces as they are verified. Dropping it
+// stops the download
+pub struct SwarmDownload {
+    context: Arc<SwarmContext>,
+    verified: mpsc::UnboundedReceiver<(u32, Vec<u8>)>,
+    coordinator: JoinHandle<Result<(), String>>,
+}
+
+impl SwarmDownload {
+    // The next verified piece, None once the download ended
+    pub async fn next_piece(&mut self) -> Option<(u32, Vec<u8>)> {
+        self.verified.recv().await
+    }
+
+    pub fn poll_next_piece(&mut self, cx: &mut Context<'_>) -> Poll<Option<(u32, Vec<u8>)>> {
+        self.verified.poll_recv(cx)
+    }
+
+    // Download pieces from this one on first, when picking sequentially
+    pub fn prioritise(&self, index: u32) {
+        self.context.picker.lock().unwrap().set_cursor(index);
+    }
+
+    // Wait for the download to end; fails if it stopped before every piece was verified
+    pub async fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        match (&mut self.coordinator).await {
+            Ok(result) => Ok(result?),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for SwarmDownload {
+    fn drop(&mut self) {
+        self.coordinator.abort();
+    }
+}
+
+// Peer tasks of a download, stopped together with it
+struct PeerTasks(Vec<JoinHandle<()>>);
+
+impl Drop for PeerTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+// Keep up to max_connections peers busy, replacing dropped peers from the spare ones, until the
+// wanted pieces are verified. Peers connecting to us or found on the local network are taken on
+// as well, and the web seeds work next to the peers
+async fn coordinate(
+    context: Arc<SwarmContext>,
+    mut events: mpsc::UnboundedReceiver<PeerEvent>,
+    mut sources: PeerSources,
+    peers: Vec<String>,
+    max_connections: usize,
+    wanted: usize,
+    verified: mpsc::UnboundedSender<(u32, Vec<u8>)>,
+) -> Result<(), String> {
+    let mut known_peers: HashSet<String> = peers.iter().cloned().collect();
+    let mut spare_peers: VecDeque<String> = peers.into();
+    let mut tasks = PeerTasks(vec![]);
+    let _choking = context.choke_controller.clone()
+        .map(|choke_controller| PeerTasks(vec![tokio::spawn(async move { choke_controller.run(false).await })]));
+    while tasks.0.len() < max_connections {
+        match spare_peers.pop_front() {
+            Some(address) => tasks.0.push(spawn_peer(context.clone(), tasks.0.len(), PeerConnection::Outgoing(address))),
+            None => break,
+        }
+    }
+    for web_seed in context.web_seeds.clone() {
+        tasks.0.push(spawn_web_seed(context.clone(), tasks.0.len(), web_seed));
+    }
+    let mut active = tasks.0.len();
+
+    let mut done = HashSet::new();
+    while done.len() < wanted && active > 0 {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            Some(peer) = async { sources.incoming.as_mut()?.recv().await }, if sources.incoming.is_some() => {
+                // a connection cannot wait as a spare peer, so it is closed
+                if active < max_connections {
+                    tasks.0.push(spawn_peer(context.clone(), tasks.0.len(), PeerConnection::Incoming(peer)));
+                    active += 1;
+                } else {
+                    eprintln!("Turned away peer {}: {} connections open", peer.address, active);
+                }
+                continue;
+            }
+            Some(address) = async { sources.discovered.as_mut()?.recv().await }, if sources.discovered.is_some() => {
+                // peers announce themselves again every few minutes
+                if known_peers.insert(address.clone()) {
+                    if active < max_connections {
+                        tasks.0.push(spawn_peer(context.clone(), tasks.0.len(), PeerConnection::Outgoing(address)));
+                        active += 1;
+                    } else {
+                        spare_peers.push_back(address);
+                    }
+                }
+                continue;
+            }
+        };
+        match event {
+            // in endgame mode a piece may be completed by several peers
+            Some(PeerEvent::Verified { index, data }) => {
+                if done.insert(index) {
+                    let _ = verified.send((index, data));
+                }
+            }
+            Some(PeerEvent::Closed { address, reason }) => {
+                eprintln!("Dropped peer {}: {}", address, reason);
+                active -= 1;
+                if let Some(address) = spare_peers.pop_front() {
+                    tasks.0.push(spawn_peer(context.clone(), tasks.0.len(), PeerConnection::Outgoing(address)));
+                    active += 1;
+                }
+            }
+            None => break,
+        }
+    }
+
+    let duplicate_bytes = context.duplicate_bytes.load(Ordering::Relaxed);
+    if duplicate_bytes > 0 {
+        eprintln!("Discarded {} bytes of duplicate blocks", duplicate_bytes);
+    }
+    if done.len() < wanted {
+        return Err(format!("No peers left, {} of {} pieces missing", wanted - done.len(), wanted));
+    }
+    Ok(())
+}
+
+fn spawn_peer(context: Arc<SwarmContext>, peer: usize, connection: PeerConnection) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let address = connection.address();
+        let reason = match run_peer(&context, peer, connection).await {
+            Ok(()) => "connection closed".to_string(),
+            Err(e) => e.to_string(),
+        };
+        context.picker.lock().unwrap().remove_peer(peer);
+        let _ = context.events.send(PeerEvent::Closed { address, reason });
+    })
+}
+
+fn spawn_web_seed(context: Arc<SwarmContext>, peer: usize, web_seed: Arc<dyn PieceSource>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let reason = match run_web_seed(&context, peer, web_seed.as_ref()).await {
+            Ok(()) => "web seed closed".to_string(),
+            Err(e) => e,
+        };
+        context.picker.lock().unwrap().remove_peer(peer);
+        let _ = context.events.send(PeerEvent::Closed { address: web_seed.get_url().to_string(), reason });
+    })
+}
+
+// Let the picker choose among the pieces the peer has announced so far. Pieces the peer
+// suggested are preferred; while it chokes us only its allowed fast pieces can be downloaded
+fn take_piece(context: &SwarmContext, peer: usize, peer_client: &PeerClient) -> Option<(u32, SharedBlocks)> {
+    let mut picker = context.picker.lock().unwrap();
+    picker.update_peer(peer, peer_client.get_bitfield());
+    let index = if peer_client.is_choked() {
+        picker.pick_among(peer, peer_client.get_allowed_fast())?
+    } else {
+        picker.pick_among(peer, &peer_client.get_suggested()).or_else(|| picker.pick(peer))?
+    };
+    let shared = context.shared_blocks.lock().unwrap()
+        .entry(index)
+        .or_insert_with(|| SharedBlocks::new(context.duplicate_bytes.clone()))
+        .clone();
+    Some((index, shared))
+}
+
+fn requeue_piece(context: &SwarmContext, peer: usize, index: u32) {
+    context.picker.lock().unwrap().abort(peer, index);
+}
+
+fn complete_piece(context: &SwarmContext, index: u32) {
+    context.picker.lock().unwrap().complete(index);
+    context.shared_blocks.lock().unwrap().remove(&index);
+}
+
+// Download pieces from one peer until the downloader stops the task or the peer fails
+async fn run_peer(context: &SwarmContext, peer: usize, connection: PeerConnection) -> Result<(), Box<dyn Error>> {
+    let mut peer_client = PeerClient::new(context.client_config.clone());
+    peer_client.set_choke_timeout(context.choke_timeout);
+    peer_client.set_piece_count(context.piece_sizes.len());
+    if let Some(local_pieces) = context.local_pieces.as_ref() {
+        peer_client.set_local_pieces(local_pieces.clone());
+    }
+    if let Some(choke_controller) = context.choke_controller.as_ref() {
+        peer_client.set_choke_link(choke_controller.register());
+    }
+    if let Some(utp_socket) = context.utp_socket.as_ref() {
+        peer_client.set_utp_socket(utp_socket.clone());
+    }
+    if let Some(merkle_trees) = context.merkle_trees.as_ref() {
+        peer_client.set_merkle_trees(merkle_trees.clone());
+    }
+    let _permit = peer_client.open(connection, context.info_hash).await?;
+    peer_client.init_download().await?;
+
+    loop {
+        let (index, shared) = match take_piece(context, peer, &peer_client) {
+            Some(picked) => picked,
+            None => {
+                // the peer may still announce pieces we need, or become interesting again
+                // when another peer gives up a piece
+                let interesting = context.picker.lock().unwrap().is_interesting(peer);
+                peer_client.set_interested(interesting).await?;
+                peer_client.idle(IDLE_INTERVAL).await?;
+                continue;
+            }
+        };
+        peer_client.set_interested(true).await?;
+
+        let piece_size = context.piece_sizes[index as usize];
+        let downloaded = match tokio::time::timeout(PIECE_TIMEOUT, peer_client.download_piece(index, piece_size, &shared)).await {
+            Ok(Ok(downloaded)) => downloaded,
+            Ok(Err(e)) => {
+                requeue_piece(context, peer, index);
+                return Err(e);
+            }
+            Err(_) => {
+                requeue_piece(context, peer, index);
+                return Err(format!("Piece {} timed out", index).into());
+            }
+        };
+        let piece = match downloaded {
+            Some(piece) => piece,
+            None => {
+                // choked for too long or rejected: let another peer have the piece and wait to
+                // be unchoked, or a moment before asking an unchoking peer for something else
+                requeue_piece(context, peer, index);
+                if peer_client.is_choked() {
+                    peer_client.wait_for_unchoke().await?;
+                } else {
+                    peer_client.idle(IDLE_INTERVAL).await?;
+                }
+                continue;
+            }
+        };
+
+        check_piece(context, peer, &mut peer_client, index, &piece).await?;
+        complete_piece(context, index);
+        if context.events.send(PeerEvent::Verified { index, data: piece }).is_err() {
+            // the download is over
+            return peer_client.disconnect().await;
+        }
+    }
+}
+
+// Check a piece downloaded from a peer. Piece layer hashes missing from the Merkle tree of its
+// file are asked from the peer; a piece the peer cannot prove goes back to the queue with its
+// blocks kept. Of a bad piece only the blocks that do not match the block hashes are discarded, or
+// all of them when the block hashes cannot be had. Fails if the piece is not verified
+async fn check_piece(context: &SwarmContext, peer: usize, peer_client: &mut PeerClient, index: u32, piece: &[u8]) -> Result<(), Box<dyn Error>> {
+    let Some(merkle_trees) = context.merkle_trees.as_ref() else {
+        if has_valid_hash(context, index, piece) {
+            return Ok(());
+        }
+        // the blocks cannot be told apart, so start the piece over
+        context.shared_blocks.lock().unwrap().remove(&index);
+        requeue_piece(context, peer, index);
+        return Err(format!("Piece {} failed hash verification", index).into());
+    };
+
+    loop {
+        match merkle_trees.verify_piece(index, piece) {
+            Verification::Valid => return Ok(()),
+            Verification::MissingHashes(request) => {
+                let hashes = request_hashes(peer_client, request).await;
+                if !hashes.is_some_and(|hashes| merkle_trees.add_hashes(&request, &hashes)) {
+                    requeue_piece(context, peer, index);
+                    return Err(format!("Peer could not prove the hashes of piece {}", index).into());
+                }
+            }
+            Verification::Invalid => {
+                let bad_blocks = match merkle_trees.block_request(index) {
+                    Some(request) => request_hashes(peer_client, request).await
+                        .and_then(|hashes| merkle_trees.find_bad_blocks(index, piece, &hashes)),
+                    None => None,
+                };
+                let mut shared_blocks = context.shared_blocks.lock().unwrap();
+                let error = match (bad_blocks, shared_blocks.get(&index)) {
+                    (Some(bad_blocks), Some(shared)) => {
+                        for &begin in &bad_blocks {
+                            shared.remove(begin);
+                        }
+                        format!("Piece {} failed hash verification in the blocks at {:?}", index, bad_blocks)
+                    }
+                    _ => {
+                        shared_blocks.remove(&index);
+                        format!("Piece {} failed hash verification", index)
+                    }
+                };
+                drop(shared_blocks);
+                requeue_piece(context, peer, index);
+                return Err(error.into());
+            }
+        }
+    }
+}
+
+// Whether a piece matches its hash, without asking for missing Merkle tree hashes
+fn has_valid_hash(context: &SwarmContext, index: u32, piece: &[u8]) -> bool {
+    match context.merkle_trees.as_ref() {
+        Some(merkle_trees) => merkle_trees.verify_piece(index, piece) == Verification::Valid,
+        None => utils::calculate_hash(utils::HashAlgorithm::Sha1, piece) == context.piece_hashes[index as usize],
+    }
+}
+
+// Ask the peer for Merkle tree hashes; None if it rejects the request, fails or takes too long
+async fn request_hashes(peer_client: &mut PeerClient, request: HashRequest) -> Option<Vec<[u8; 32]>> {
+    match tokio::time::timeout(HASH_REQUEST_TIMEOUT, peer_client.request_hashes(request)).await {
+        Ok(Ok(hashes)) => hashes,
+        _ => None,
+    }
+}
+
+// Fetch pieces from a web seed until the downloader stops the task or the web seed keeps failing.
+// Failed pieces go back to the queue, and the next attempt waits longer each time, or as long
+// as a busy server asks for. Of a v2 torrent the web seed only gets the pieces whose hashes are
+// known, as it cannot send the missing piece layers the way peers do
+async fn run_web_seed(context: &SwarmContext, peer: usize, web_seed: &dyn PieceSource) -> Result<(), String> {
+    let mut bitfield = vec![];
+    let mut failures = 0;
+    loop {
+        let known = match context.merkle_trees.as_ref() {
+            Some(merkle_trees) => merkle_trees.known_pieces(),
+            None => vec![0xff; context.piece_sizes.len().div_ceil(8)],
+        };
+        let picked = {
+            let mut picker = context.picker.lock().unwrap();
+            if known != bitfield {
+                picker.update_peer(peer, &known);
+                bitfield = known;
+            }
+            picker.pick(peer)
+        };
+        let index = match picked {
+            Some(index) => index,
+            None => {
+                tokio::time::sleep(IDLE_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let piece_size = context.piece_sizes[index as usize];
+        let (error, busy_delay) = match web_seed.fetch_piece(index, piece_size).await {
+            Ok(piece) if has_valid_hash(context, index, &piece) => {
+                failures = 0;
+                complete_piece(context, index);
+                if context.events.send(PeerEvent::Verified { index, data: piece }).is_err() {
+                    // the download is over
+                    return Ok(());
+                }
+                continue;
+            }
+            Ok(_) => (format!("Piece {} failed hash verification", index), None),
+            Err(WebSeedError::Busy(delay)) => (WebSeedError::Busy(delay).to_string(), delay),
+            Err(e) => (e.to_string(), None),
+        };
+
+        requeue_piece(context, peer, index);
+        failures += 1;
+        if failures == MAX_WEB_SEED_FAILURES {
+            return Err(error);
+        }
+        let backoff = context.web_seed_retry_delay.saturating_mul(1 << (failures - 1)).min(MAX_WEB_SEED_RETRY_DELAY);
+        tokio::time::sleep(busy_delay.unwrap_or(backoff)).await;
+    }
+}
+
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::clients::handshake::{Capabilities, Handshake, HANDSHAKE_LENGTH};
+    use crate::clients::peer_message::PeerMessage;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicUsize;
+    use crate::clients::request_pipeline::BLOCK_SIZE;
+    use crate::clients::web_seed::PieceFuture;
+    use crate::torrent_manager::merkle_tree;
+    use crate::torrent_manager::torrent_spec::meta_info::PieceLayers;
+
+    pub(in crate::torrent_manager) const INFO_HASH: [u8; 20] = [5; 20];
+    pub(in crate::torrent_manager) const PIECE_SIZE: u32 = 20000;
+
+    #[derive(Clone, Copy)]
+    pub(in crate::torrent_manager) enum Behaviour {
+        Serve,
+        // close the connection after answering this many requests
+        DisconnectAfter(usize),
+        // choke after the first request and never unchoke again
+        ChokeForever,
+        // wait this many milliseconds before answering each request
+        Slow(u64),
+        // never answer requests
+        Stall,
+    }
+
+    pub(in crate::torrent_manager) fn make_torrent(piece_count: u32) -> (Vec<u8>, Vec<String>, Vec<u32>) {
+        let data: Vec<u8> = (0..piece_count * PIECE_SIZE - 123).map(|i| (i % 241) as u8).collect();
+        let hashes = data.chunks(PIECE_SIZE as usize).map(|piece| utils::calculate_sha1_hash(piece.to_vec())).collect();
+        let sizes = data.chunks(PIECE_SIZE as usize).map(|piece| piece.len() as u32).collect();
+        (data, hashes, sizes)
+    }
+
+    // Returns the address of the seeder and the number of cancel messages it received
+    pub(in crate::torrent_manager) async fn spawn_seeder(data: Vec<u8>, behaviour: Behaviour) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let cancels = Arc::new(AtomicUsize::new(0));
+        let received_cancels = cancels.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [3; 20]).encode()).await.unwrap();
+            serve_blocks(stream, data, behaviour, received_cancels).await;
+        });
+        (address, cancels)
+    }
+
+    // Announce every piece and answer requests as the behaviour says, after the handshakes
+    async fn serve_blocks(mut stream: TcpStream, data: Vec<u8>, behaviour: Behaviour, cancels: Arc<AtomicUsize>) {
+        stream.write_all(&PeerMessage::Bitfield { bitfield: vec![0xff] }.encode()).await.unwrap();
+
+        let mut answered = 0;
+        while let Ok(message) = PeerMessage::read_from(&mut stream).await {
+            match message {
+                PeerMessage::Interested => stream.write_all(&PeerMessage::Unchoke.encode()).await.unwrap(),
+                PeerMessage::Request { index, begin, length } => {
+                    match behaviour {
+                        Behaviour::DisconnectAfter(count) if answered == count => return,
+                        Behaviour::ChokeForever => {
+                            stream.write_all(&PeerMessage::Choke.encode()).await.unwrap();
+                            continue;
+                        }
+                        Behaviour::Slow(delay) => tokio::time::sleep(Duration::from_millis(delay)).await,
+                        Behaviour::Stall => continue,
+                        _ => {}
+                    }
+                    let start = (index * PIECE_SIZE + begin) as usize;
+                    let block = data[start..start + length as usize].to_vec();
+                    stream.write_all(&PeerMessage::Piece { index, begin, block }.encode()).await.unwrap();
+                    answered += 1;
+                }
+                PeerMessage::Cancel { .. } => {
+                    cancels.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // A peer of a v2 torrent with a single file: it answers hash requests and serves the blocks of
+    // data, with the block at (piece, begin) corrupted if given. Returns its address and the
+    // blocks asked from it
+    pub(in crate::torrent_manager) async fn spawn_v2_seeder(data: Vec<u8>, piece_length: u64, corrupt_block: Option<(u32, u32)>) -> (String, Arc<Mutex<Vec<(u32, u32)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received_requests = requests.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [3; 20]).encode()).await.unwrap();
+            stream.write_all(&PeerMessage::Bitfield { bitfield: vec![0xff] }.encode()).await.unwrap();
+            while let Ok(message) = PeerMessage::read_from(&mut stream).await {
+                let answer = match message {
+                    PeerMessage::Interested => PeerMessage::Unchoke,
+                    PeerMessage::HashRequest(request) => {
+                        PeerMessage::Hashes { request, hashes: merkle_tree::tests::answer_from_data(&data, piece_length, &request) }
+                    }
+                    PeerMessage::Request { index, begin, length } => {
+                        received_requests.lock().unwrap().push((index, begin));
+                        let start = (index as u64 * piece_length) as usize + begin as usize;
+                        let mut block = data[start..start + length as usize].to_vec();
+                        if corrupt_block == Some((index, begin)) {
+                            block[0] ^= 0xff;
+                        }
+                        PeerMessage::Piece { index, begin, block }
+                    }
+                    _ => continue,
+                };
+                stream.write_all(&answer.encode()).await.unwrap();
+            }
+        });
+        (address, requests)
+    }
+
+    #[tokio::test]
+    async fn test_only_the_bad_block_of_a_v2_piece_is_downloaded_again() {
+        let piece_length = 4 * BLOCK_SIZE as u64;
+        let data: Vec<u8> = (0..3 * piece_length as usize - 1000).map(|i| (i % 241) as u8).collect();
+        // the piece layer is missing from the metainfo, the peers send it
+        let (file, _) = merkle_tree::tests::describe_file("file.bin", &data, piece_length);
+        let merkle_trees = Arc::new(MerkleTrees::new(&[file], &PieceLayers::new(), piece_length));
+
+        let (bad, _) = spawn_v2_seeder(data.clone(), piece_length, Some((1, BLOCK_SIZE))).await;
+        let (good, good_requests) = spawn_v2_seeder(data.clone(), piece_length, None).await;
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, vec![], merkle_trees.piece_sizes());
+        downloader.set_merkle_trees(merkle_trees);
+        // the good peer only joins once the bad one is dropped
+        downloader.max_connections = 1;
+        let pieces = downloader.download(&[bad, good], &[0, 1, 2]).await.unwrap();
+
+        let file: Vec<u8> = (0..3).flat_map(|index| pieces[&index].clone()).collect();
+        assert_eq!(file, data);
+        let refetched: Vec<(u32, u32)> = good_requests.lock().unwrap().iter().filter(|&&(index, _)| index == 1).copied().collect();
+        assert_eq!(refetched, vec![(1, BLOCK_SIZE)]);
+    }
+
+    #[tokio::test]
+    async fn test_download_from_several_peers_with_failures() {
+        let (data, hashes, sizes) = make_torrent(6);
+        let peers = vec![
+            spawn_seeder(data.clone(), Behaviour::DisconnectAfter(1)).await.0,
+            spawn_seeder(data.clone(), Behaviour::ChokeForever).await.0,
+            spawn_seeder(data.clone(), Behaviour::Serve).await.0,
+            spawn_seeder(data.clone(), Behaviour::Serve).await.0,
+        ];
+
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        downloader.choke_timeout = Duration::from_millis(200);
+        let pieces = downloader.download(&peers, &[0, 1, 2, 3, 4, 5]).await.unwrap();
+
+        let file: Vec<u8> = (0..6).flat_map(|index| pieces[&index].clone()).collect();
+        assert_eq!(file, data);
+    }
+
+    #[tokio::test]
+    async fn test_download_fails_without_usable_peers() {
+        let (data, hashes, sizes) = make_torrent(2);
+        let peers = vec![spawn_seeder(data, Behaviour::DisconnectAfter(0)).await.0];
+
+        let downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        assert!(downloader.download(&peers, &[1]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_endgame_cancels_requests_of_the_stalled_peer() {
+        let (data, hashes, sizes) = make_torrent(1);
+        let (stalled, cancels) = spawn_seeder(data.clone(), Behaviour::Stall).await;
+        let (slow, _) = spawn_seeder(data.clone(), Behaviour::Slow(100)).await;
+
+        // whichever peer picks the piece first, the other joins it in endgame mode
+        let downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        let pieces = downloader.download(&[stalled, slow], &[0]).await.unwrap();
+
+        assert_eq!(pieces[&0], data);
+        assert!(cancels.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_from_web_seeds() {
+        use crate::clients::web_seed::tests::{spawn_http_server, ServerBehaviour};
+        use crate::clients::web_seed::WebSeed;
+        use crate::torrent_manager::torrent_spec::file_info::FileInfo;
+
+        let (data, hashes, sizes) = make_torrent(4);
+        let split = 30000;
+        let files = HashMap::from([
+            ("album/a.bin".to_string(), data[..split].to_vec()),
+            ("album/b.bin".to_string(), data[split..].to_vec()),
+        ]);
+        let layout = vec![
+            FileInfo::new(vec!["a.bin".to_string()], split as i64),
+            FileInfo::new(vec!["b.bin".to_string()], (data.len() - split) as i64),
+        ];
+        let corrupted = files.iter()
+            .map(|(path, file)| (path.clone(), file.iter().map(|byte| byte ^ 0xff).collect()))
+            .collect();
+
+        // the good server is busy at first; the other one serves corrupted data until it is given up
+        let good = spawn_http_server(files, ServerBehaviour { busy_requests: 2, honour_ranges: true }).await;
+        let bad = spawn_http_server(corrupted, ServerBehaviour { busy_requests: 0, honour_ranges: true }).await;
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        downloader.web_seed_retry_delay = Duration::from_millis(10);
+        for url in [good, bad] {
+            downloader.add_web_seed(Arc::new(WebSeed::new(&url, "album", Some(&layout), data.len() as u64, PIECE_SIZE as u64)));
+        }
+        let pieces = downloader.download(&[], &[0, 1, 2, 3]).await.unwrap();
+
+        let file: Vec<u8> = (0..4).flat_map(|index| pieces[&index].clone()).collect();
+        assert_eq!(file, data);
+    }
+
+    // A web seed serving pieces from memory, recording the pieces asked from it
+    struct MemoryWebSeed {
+        data: Vec<u8>,
+        piece_length: u64,
+        fetched: Mutex<Vec<u32>>,
+    }
+
+    impl PieceSource for MemoryWebSeed {
+        fn get_url(&self) -> &str {
+            "memory"
+        }
+
+        fn fetch_piece(&self, index: u32, size: u32) -> PieceFuture<'_> {
+            self.fetched.lock().unwrap().push(index);
+            let start = (index as u64 * self.piece_length) as usize;
+            let piece = self.data[start..start + size as usize].to_vec();
+            Box::pin(async move { Ok(piece) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_web_seed_waits_for_the_piece_layer() {
+        let piece_length = 4 * BLOCK_SIZE as u64;
+        let data: Vec<u8> = (0..6 * piece_length as usize - 1000).map(|i| (i % 241) as u8).collect();
+        // the piece layer is missing from the metainfo, only the peer can send it
+        let (file, _) = merkle_tree::tests::describe_file("file.bin", &data, piece_length);
+        let merkle_trees = Arc::new(MerkleTrees::new(&[file], &PieceLayers::new(), piece_length));
+
+        let (peer, _) = spawn_v2_seeder(data.clone(), piece_length, None).await;
+        let web_seed = Arc::new(MemoryWebSeed { data: data.clone(), piece_length, fetched: Mutex::new(vec![]) });
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, vec![], merkle_trees.piece_sizes());
+        downloader.set_merkle_trees(merkle_trees);
+        downloader.web_seed_retry_delay = Duration::from_millis(1);
+        downloader.add_web_seed(web_seed.clone());
+        let pieces = downloader.download(&[peer], &[0, 1, 2, 3, 4, 5]).await.unwrap();
+
+        let file: Vec<u8> = (0..6).flat_map(|index| pieces[&index].clone()).collect();
+        assert_eq!(file, data);
+        // every piece the web seed sent could be verified, none was fetched twice
+        let mut fetched = web_seed.fetched.lock().unwrap().clone();
+        fetched.sort();
+        fetched.dedup();
+        assert_eq!(fetched.len(), web_seed.fetched.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_download_from_an_http_seed_and_a_peer() {
+        use crate::clients::http_seed::tests::spawn_http_seed;
+        use crate::clients::http_seed::HttpSeed;
+
+        let (data, hashes, sizes) = make_torrent(6);
+        let url = spawn_http_seed(data.clone(), PIECE_SIZE as usize, INFO_HASH, 1).await;
+        let (peer, _) = spawn_seeder(data.clone(), Behaviour::Slow(20)).await;
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        downloader.web_seed_retry_delay = Duration::from_millis(10);
+        downloader.add_web_seed(Arc::new(HttpSeed::new(&url, INFO_HASH)));
+        let pieces = downloader.download(&[peer], &[0, 1, 2, 3, 4, 5]).await.unwrap();
+
+        let file: Vec<u8> = (0..6).flat_map(|index| pieces[&index].clone()).collect();
+        assert_eq!(file, data);
+    }
+
+    #[tokio::test]
+    async fn test_download_from_a_peer_found_on_the_local_network() {
+        use crate::clients::local_discovery::tests::receive_announce;
+        use crate::clients::local_discovery::{Announce, IPV4_GROUP};
+        use std::net::SocketAddr;
+
+        let (data, hashes, sizes) = make_torrent(3);
+        let local_discovery = Arc::new(LocalDiscovery::bind(&[SocketAddr::new(IPV4_GROUP.ip(), 0)], 6881).await.unwrap());
+
+        // the only peer from the tracker never answers, the one announcing itself serves everything
+        let (stalled, _) = spawn_seeder(data.clone(), Behaviour::Stall).await;
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        downloader.set_local_discovery(local_discovery.clone());
+        let mut download = downloader.start(&[stalled], &[0, 1, 2]).unwrap();
+
+        let (seeder, _) = spawn_seeder(data.clone(), Behaviour::Serve).await;
+        let seeder: SocketAddr = seeder.parse().unwrap();
+        let announce = Announce { port: seeder.port(), info_hashes: vec![INFO_HASH], cookie: None };
+        receive_announce(&local_discovery, announce, seeder.ip());
+
+        let mut pieces = HashMap::new();
+        while let Some((index, piece)) = download.next_piece().await {
+            pieces.insert(index, piece);
+        }
+        download.finish().await.unwrap();
+        let file: Vec<u8> = (0..3).flat_map(|index| pieces[&index].clone()).collect();
+        assert_eq!(file, data);
+    }
+
+    #[tokio::test]
+    async fn test_peers_connecting_to_us_count_towards_the_limit() {
+        let (data, hashes, sizes) = make_torrent(3);
+        let peer_listener = Arc::new(PeerListener::bind("127.0.0.1:0", 10).await.unwrap());
+        let listening = peer_listener.local_addr().unwrap();
+        let running = peer_listener.clone();
+        tokio::spawn(async move { let _ = running.run().await; });
+
+        let (stalled, _) = spawn_seeder(data, Behaviour::Stall).await;
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        downloader.set_peer_listener(peer_listener);
+        downloader.max_connections = 1;
+        let _download = downloader.start(&[stalled], &[0, 1, 2]).unwrap();
+
+        // the only connection is taken, so ours is closed without a handshake
+        let mut stream = TcpStream::connect(listening).await.unwrap();
+        stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [4; 20]).encode()).await.unwrap();
+        let mut handshake = [0u8; HANDSHAKE_LENGTH];
+        assert!(stream.read_exact(&mut handshake).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_from_a_peer_connecting_to_us() {
+        let (data, hashes, sizes) = make_torrent(3);
+        let peer_listener = Arc::new(PeerListener::bind("127.0.0.1:0", 10).await.unwrap());
+        let listening = peer_listener.local_addr().unwrap();
+        let running = peer_listener.clone();
+        tokio::spawn(async move { let _ = running.run().await; });
+
+        // the only peer we know never answers, the one connecting to us serves everything
+        let (stalled, _) = spawn_seeder(data.clone(), Behaviour::Stall).await;
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        downloader.set_peer_listener(peer_listener);
+        let mut download = downloader.start(&[stalled], &[0, 1, 2]).unwrap();
+
+        let seeder_data = data.clone();
+        tokio::spawn(async move {
+            let mut stream = TcpStream::connect(listening).await.unwrap();
+            stream.write_all(&Handshake::new(Capabilities::default(), INFO_HASH, [4; 20]).encode()).await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            serve_blocks(stream, seeder_data, Behaviour::Serve, Arc::new(AtomicUsize::new(0))).await;
+        });
+
+        let mut pieces = HashMap::new();
+        while let Some((index, piece)) = download.next_piece().await {
+            pieces.insert(index, piece);
+        }
+        download.finish().await.unwrap();
+        let file: Vec<u8> = (0..3).flat_map(|index| pieces[&index].clone()).collect();
+        assert_eq!(file, data);
+    }
+}
@@ -1,9 +1,23 @@
-use crate::utils;
+use crate::utils::{self, HashAlgorithm};
 use crate::clients;
 
 use std::error::Error;
 use serde_json::Value;
+use base64::{engine::general_purpose, Engine};
 use super::torrent_spec::{self};
+use torrent_spec::file_info::FileInfo;
+use torrent_spec::meta_info::{PieceLayers, TorrentVersion};
+use super::swarm_downloader::SwarmDownloader;
+use super::merkle_tree::{self, MerkleTrees, Verification};
+use super::piece_picker::PickOrder;
+use super::torrent_stream::TorrentStream;
+use super::storage::{self, FilePriority, Storage};
+use super::seeder::Seeder;
+use clients::local_pieces::{BlockSource, LocalPieces};
+use clients::http_seed::HttpSeed;
+use clients::web_seed::WebSeed;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 // Define function types for encoding and decoding
 type EncoderFn = dyn Fn(&Value) -> Result<Vec<u8>, Box<dyn Error>>;
@@ -16,6 +30,11 @@ pub struct TorrentManager<'a> {
     metainfo: Option<torrent_spec::meta_info::Metainfo>,  // Optional Metainfo
     tracker_client: Option<clients::tracker_client::TrackerClient>,  // Optional TrackerClient
     peers: Option<Vec<torrent_spec::peer_info::Peer>>,  // Optional vector of Peers
+    client_config: clients::client_config::ClientConfig,  // Session-wide peer id, port and key
+    file_priorities: Vec<FilePriority>,  // One per file of the torrent
+    peer_listener: Option<Arc<clients::peer_listener::PeerListener>>,  // Accepts connections from peers
+    local_discovery: Option<Arc<clients::local_discovery::LocalDiscovery>>,  // Finds peers on the local network
+    merkle_trees: Option<Arc<MerkleTrees>>,  // Verify the pieces of v2 torrents
 }
 
 impl<'a> TorrentManager<'a> {
@@ -26,10 +45,30 @@ impl<'a> TorrentManager<'a> {
             decoder, 
             metainfo: None, 
             tracker_client: None, 
-            peers: None
+            peers: None,
+            client_config: clients::client_config::ClientConfig::new(),
+            file_priorities: vec![],
+            peer_listener: None,
+            local_discovery: None,
+            merkle_trees: None,
         }
     }
 
+    // Replace the session-wide client configuration used by the tracker and peer clients
+    pub fn set_client_config(&mut self, client_config: clients::client_config::ClientConfig) {
+        self.client_config = client_config;
+    }
+
+    // Downloads and seeding also take on the peers connecting to the listener
+    pub fn set_peer_listener(&mut self, peer_listener: Arc<clients::peer_listener::PeerListener>) {
+        self.peer_listener = Some(peer_listener);
+    }
+
+    // Downloads and seeding of public torrents also take on the peers found on the local network
+    pub fn set_local_discovery(&mut self, local_discovery: Arc<clients::local_discovery::LocalDiscovery>) {
+        self.local_discovery = Some(local_discovery);
+    }
+
     // Parses the meta info file from a byte vector
     pub fn parse_meta_info_file(&mut self, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
         // Decode the data using the decoder function
@@ -38,24 +77,91 @@ impl<'a> TorrentManager<'a> {
 
         // Set various metainfo fields from the decoded data
         metainfo.set_tracker_url(utils::decode_base64_to_utf8_string(decoded_value["announce"].as_str().unwrap()).unwrap());
-        metainfo.set_length(decoded_value["info"]["length"].as_i64().unwrap());
+        if let Some(name) = decoded_value["info"]["name"].as_str() {
+            metainfo.set_name(utils::decode_base64_to_utf8_string(name)?);
+        }
         metainfo.set_piece_length(decoded_value["info"]["piece length"].as_i64().unwrap());
+        metainfo.set_private(decoded_value["info"]["private"].as_i64() == Some(1));
 
-        // Split the piece hash string into 40-character chunks
-        let pieces_str = decoded_value["info"]["pieces"].as_str().unwrap();
-        let piece_hashes: Vec<String> = pieces_str
-            .as_bytes()
-            .chunks(40)
-            .map(|chunk| String::from_utf8(chunk.to_vec()).unwrap())
-            .collect();
-        metainfo.set_piece_hashes(piece_hashes);
+        // v2 and hybrid torrents describe their files as a tree, with a Merkle tree root per file
+        if let Some(meta_version) = decoded_value["info"].get("meta version") {
+            if meta_version.as_i64() != Some(2) {
+                return Err(format!("Error: unsupported meta version {}!", meta_version).into());
+            }
+            let piece_length = metainfo.get_piece_length().unwrap();
+            if piece_length < clients::request_pipeline::BLOCK_SIZE as i64 || (piece_length as u64).count_ones() != 1 {
+                return Err(format!("Error: invalid v2 piece length {}!", piece_length).into());
+            }
+            let mut file_tree = vec![];
+            parse_file_tree(&decoded_value["info"]["file tree"], &mut vec![], &mut file_tree)?;
+            metainfo.set_piece_layers(parse_piece_layers(&decoded_value["piece layers"], &file_tree, piece_length)?);
+            metainfo.set_file_tree(file_tree);
+        }
+
+        // Multi-file torrents list their files instead of a single length; v2 torrents only have
+        // their file tree, which holds a single file named after the torrent for single-file ones
+        match (decoded_value["info"]["files"].as_array(), decoded_value["info"]["length"].as_i64(), metainfo.get_file_tree()) {
+            (Some(files), _, _) => {
+                let files = parse_files(files)?;
+                metainfo.set_length(files.iter().map(|file| file.get_length()).sum());
+                metainfo.set_files(files);
+            }
+            (None, Some(length), _) => metainfo.set_length(length),
+            (None, None, Some(file_tree)) if !file_tree.is_empty() => {
+                let single_file = file_tree.len() == 1 && metainfo.get_name().as_ref() == file_tree[0].get_path().first();
+                let files: Vec<FileInfo> = file_tree.iter()
+                    .map(|file| FileInfo::new(file.get_path().clone(), file.get_length()))
+                    .collect();
+                metainfo.set_length(files.iter().map(|file| file.get_length()).sum());
+                if !single_file {
+                    metainfo.set_files(files);
+                }
+            }
+            _ => return Err("Error: torrent without files!".into()),
+        }
+
+        // Split the concatenated 20 byte SHA1 hashes and hex encode each of them; v2 torrents
+        // have none
+        if let Some(pieces_str) = decoded_value["info"]["pieces"].as_str() {
+            let pieces_bytes = general_purpose::STANDARD.decode(pieces_str)?;
+            let piece_hashes: Vec<String> = pieces_bytes
+                .chunks(20)
+                .map(hex::encode)
+                .collect();
+            metainfo.set_piece_hashes(piece_hashes);
+        }
+
+        // Web seeds are listed as a single url or a list of urls
+        metainfo.set_url_list(parse_urls(&decoded_value["url-list"])?);
+        metainfo.set_http_seeds(parse_urls(&decoded_value["httpseeds"])?);
         
-        // Encode the info data and calculate its SHA1 hash
+        // Encode the info data and calculate its SHA1 hash, and its SHA-256 hash for v2. Trackers
+        // and peers know v2 torrents by the truncated SHA-256 hash
         let info_data = &decoded_value["info"];
         let encoded_info = (self.encoder)(info_data)?;
-        let hash = utils::calculate_sha1_hash(encoded_info);
+        if metainfo.get_file_tree().is_some() {
+            metainfo.set_hash_v2(utils::calculate_hash(HashAlgorithm::Sha256, &encoded_info));
+        }
+        let hash = match metainfo.get_version() {
+            TorrentVersion::V2 => metainfo.get_truncated_hash_v2().unwrap(),
+            _ => utils::calculate_sha1_hash(encoded_info),
+        };
         metainfo.set_hash(hash);
 
+        // v2 torrents are verified against the Merkle trees of their files. Hybrid torrents keep to
+        // their piece hashes, as their pieces run through padding files
+        self.merkle_trees = match (metainfo.get_version(), metainfo.get_file_tree()) {
+            (TorrentVersion::V2, Some(file_tree)) => {
+                let piece_length = metainfo.get_piece_length().unwrap() as u64;
+                Some(Arc::new(MerkleTrees::new(file_tree, metainfo.get_piece_layers(), piece_length)))
+            }
+            _ => None,
+        };
+
+        // Every file is downloaded until told otherwise
+        let file_count = metainfo.get_files().as_ref().map_or(1, |files| files.len());
+        self.file_priorities = vec![FilePriority::Normal; file_count];
+
         // Set the parsed metainfo to the struct
         self.metainfo = Some(metainfo);
         
@@ -65,15 +171,24 @@ impl<'a> TorrentManager<'a> {
     // Initialize clients such as TrackerClient and PeerClient
     pub fn init_clients(&mut self) -> Result<(), Box<dyn Error>> {
         self.is_meta_info_ok()?;
+        let length = self.metainfo.as_ref().unwrap().get_length().unwrap();
+        self.announce(length)
+    }
+
+    // Initialize the clients for seeding, announcing that nothing is left to download
+    pub fn init_seeding_clients(&mut self) -> Result<(), Box<dyn Error>> {
+        self.is_meta_info_ok()?;
+        self.announce(0)
+    }
 
+    fn announce(&mut self, left: i64) -> Result<(), Box<dyn Error>> {
         let metainfo = self.metainfo.as_ref().unwrap();
         let tracker_url = metainfo.get_tracker_url().as_ref().unwrap().clone();
-        let length = metainfo.get_length().as_ref().unwrap().clone();
         let info_hash = metainfo.get_hash().as_ref().unwrap().clone();
 
         // Create a new TrackerClient and request peers
-        self.tracker_client = Some(clients::tracker_client::TrackerClient::new(tracker_url));
-        let resp = self.tracker_client.as_ref().unwrap().request_peers(length, info_hash).unwrap();
+        self.tracker_client = Some(clients::tracker_client::TrackerClient::new(tracker_url, self.client_config.clone()));
+        let resp = self.tracker_client.as_ref().unwrap().request_peers(left, info_hash).unwrap();
         let decoded_peer_info = (self.decoder)(&resp, false).unwrap().0;
 
         // Extract peers from the decoded peer info
@@ -88,11 +203,22 @@ impl<'a> TorrentManager<'a> {
         Ok(())
     }
 
+    // Request swarm statistics for this torrent and any additional hex encoded info hashes
+    pub fn scrape_tracker(&self, additional_info_hashes: &[String]) -> Result<Vec<torrent_spec::scrape_info::ScrapeInfo>, Box<dyn Error>> {
+        self.is_meta_info_ok()?;
+
+        let metainfo = self.metainfo.as_ref().unwrap();
+        let tracker_url = metainfo.get_tracker_url().as_ref().unwrap().clone();
+        let mut info_hashes = vec![metainfo.get_hash().as_ref().unwrap().clone()];
+        info_hashes.extend_from_slice(additional_info_hashes);
+
+        clients::tracker_client::TrackerClient::new(tracker_url, self.client_config.clone()).scrape(&info_hashes)
+    }
+
     // Perform handshake with a peer asynchronously
-    pub async fn perform_peer_handshake(&self, peer_address: &String) -> Result<Vec<u8>, Box<dyn Error>>  {
-        let mut peer_client = clients::peer_client::PeerClient::new();
-        let info_hash = self.metainfo.as_ref().unwrap().get_hash().as_ref().unwrap().clone();
-        let info_hash_bytes = utils::hex_to_byte_representation(&info_hash);
+    pub async fn perform_peer_handshake(&self, peer_address: &String) -> Result<clients::handshake::Handshake, Box<dyn Error>>  {
+        let mut peer_client = clients::peer_client::PeerClient::new(self.client_config.clone());
+        let info_hash_bytes = self.get_info_hash_bytes()?;
 
         peer_client.connect(peer_address).await?;
         let resp = peer_client.perform_handshake(info_hash_bytes).await;
@@ -100,6 +226,14 @@ impl<'a> TorrentManager<'a> {
         resp
     }
 
+    // Raw 20 byte info hash of the parsed torrent
+    fn get_info_hash_bytes(&self) -> Result<[u8; 20], Box<dyn Error>> {
+        self.is_meta_info_ok()?;
+        let info_hash = self.metainfo.as_ref().unwrap().get_hash().as_ref().unwrap();
+        let info_hash_bytes = utils::hex_to_byte_representation(info_hash);
+        info_hash_bytes.try_into().map_err(|_| "Info hash must be 20 bytes".into())
+    }
+
     // Print the list of peers
     pub fn print_peers(&self) -> Result<(), Box<dyn Error>> {
         if self.peers.is_none() {
@@ -128,75 +262,382 @@ impl<'a> TorrentManager<'a> {
         Ok(())
     }
 
-    // Download the entire file by downloading each piece
-    pub async fn download_file(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut file = vec![];
-        let piece_hashes = self.metainfo.as_ref().unwrap().get_piece_hashes().clone().unwrap();
-        let piece_count = piece_hashes.len();
+    // Set the download priority of the file at index, in torrent order
+    pub fn set_file_priority(&mut self, index: usize, priority: FilePriority) -> Result<(), Box<dyn Error>> {
+        match self.file_priorities.get_mut(index) {
+            Some(file_priority) => {
+                *file_priority = priority;
+                Ok(())
+            }
+            None => Err(format!("Error: no file with index {}!", index).into()),
+        }
+    }
+
+    // Set the download priority of every file whose path matches the glob. Returns the number of
+    // matching files, and fails if there are none
+    pub fn set_file_priority_by_glob(&mut self, glob: &str, priority: FilePriority) -> Result<usize, Box<dyn Error>> {
+        let matching = self.find_files(glob)?;
+        for &index in &matching {
+            self.set_file_priority(index, priority)?;
+        }
+        Ok(matching.len())
+    }
+
+    // Indexes of the files whose path matches the glob; a single-file torrent is matched by its
+    // name and padding files never match. Fails if there are none, as the glob is most likely
+    // mistyped
+    fn find_files(&self, glob: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+        self.is_meta_info_ok()?;
+        let pattern = utils::glob_to_regex(glob)?;
+        let metainfo = self.metainfo.as_ref().unwrap();
+        let paths: Vec<Option<String>> = match metainfo.get_files() {
+            Some(files) => files.iter().map(|file| (!file.is_padding()).then(|| file.get_path_string())).collect(),
+            None => vec![Some(metainfo.get_name().clone().unwrap_or_default())],
+        };
+
+        let matching: Vec<usize> = (0..paths.len())
+            .filter(|&index| paths[index].as_ref().is_some_and(|path| pattern.is_match(path)))
+            .collect();
+        if matching.is_empty() {
+            return Err(format!("Error: no file matches {}!", glob).into());
+        }
+        Ok(matching)
+    }
+
+    // The file a selector names, by index or by a glob matching a single file. Without a
+    // selector the torrent has to hold a single file
+    fn select_file(&self, selector: Option<&str>) -> Result<usize, Box<dyn Error>> {
+        self.is_meta_info_ok()?;
+        let is_file = |index: usize| match self.metainfo.as_ref().unwrap().get_files() {
+            Some(files) => files.get(index).is_some_and(|file| !file.is_padding()),
+            None => index == 0,
+        };
+        let matching = match selector {
+            Some(selector) => match selector.parse::<usize>() {
+                Ok(index) if is_file(index) => vec![index],
+                Ok(index) => return Err(format!("Error: no file with index {}!", index).into()),
+                Err(_) => self.find_files(selector)?,
+            },
+            None => self.find_files("**")?,
+        };
+        match matching[..] {
+            [index] => Ok(index),
+            _ => Err(format!("Error: {} files match, select one of them!", matching.len()).into()),
+        }
+    }
+
+    // Where a file starts within the torrent data, and its length. The files of v2 torrents start
+    // at piece boundaries, as in storage
+    fn get_file_range(&self, index: usize) -> (u64, u64) {
+        let metainfo = self.metainfo.as_ref().unwrap();
+        match metainfo.get_files() {
+            Some(files) => {
+                let alignment = match self.merkle_trees {
+                    Some(_) => metainfo.get_piece_length().unwrap() as u64,
+                    None => 1,
+                };
+                let offsets = storage::file_offsets(files.iter().map(|file| file.get_length() as u64), alignment);
+                (offsets[index], files[index].get_length() as u64)
+            }
+            None => (0, metainfo.get_length().unwrap() as u64),
+        }
+    }
+
+    // Download the wanted files from the swarm, several pieces in parallel. A single-file torrent
+    // is written to output_path, the files of a multi-file torrent below it
+    pub async fn download_to(&self, output_path: &str) -> Result<(), Box<dyn Error>> {
+        let mut downloader = self.create_swarm_downloader()?;
+        let piece_count = self.get_piece_sizes()?.len();
+
+        let storage = Arc::new(self.create_storage(output_path));
+        let piece_priorities = storage.piece_priorities(piece_count);
+        let wanted: Vec<u32> = (0..piece_count as u32)
+            .filter(|&index| piece_priorities[index as usize] != FilePriority::Skip)
+            .collect();
+        storage.create_wanted_files()?;
+
+        // pieces are uploaded to the peers once they are written
+        let local_pieces = Arc::new(LocalPieces::new(self.get_piece_sizes()?, storage.clone()));
+        downloader.set_local_pieces(local_pieces.clone());
+        downloader.set_piece_priorities(piece_priorities);
+        let mut download = downloader.start(&self.get_peer_addresses()?, &wanted)?;
+        while let Some((index, piece)) = download.next_piece().await {
+            storage.write_piece(index, &piece)?;
+            local_pieces.add_piece(index);
+        }
+        download.finish().await
+    }
+
+    // Verify the downloaded files and upload their pieces to the peers until interrupted
+    pub async fn seed(&self, output_path: &str) -> Result<(), Box<dyn Error>> {
+        self.is_meta_info_ok()?;
+        let local_pieces = self.verify_local_pieces(output_path)?;
+        let piece_count = self.get_piece_sizes()?.len();
+        if local_pieces.count() == 0 {
+            return Err(format!("Error: no verified pieces found in {}!", output_path).into());
+        }
+        eprintln!("Seeding {} of {} pieces", local_pieces.count(), piece_count);
+
+        let mut seeder = Seeder::new(self.client_config.clone(), self.get_info_hash_bytes()?, Arc::new(local_pieces));
+        if let Some(peer_listener) = self.peer_listener.as_ref() {
+            seeder.set_peer_listener(peer_listener.clone());
+        }
+        if let Some(local_discovery) = self.get_local_discovery() {
+            seeder.set_local_discovery(local_discovery);
+        }
+        if let Some(merkle_trees) = self.merkle_trees.as_ref() {
+            seeder.set_merkle_trees(merkle_trees.clone());
+        }
+        seeder.run(&self.get_peer_addresses()?).await;
+        Ok(())
+    }
+
+    // Check every piece of the files in the output path against its hash
+    fn verify_local_pieces(&self, output_path: &str) -> Result<LocalPieces, Box<dyn Error>> {
+        let piece_hashes = self.metainfo.as_ref().unwrap().get_piece_hashes();
+        let piece_sizes = self.get_piece_sizes()?;
+        let storage = Arc::new(self.create_storage(output_path));
+
+        let local_pieces = LocalPieces::new(piece_sizes.clone(), storage.clone());
+        for (index, &piece_size) in piece_sizes.iter().enumerate() {
+            // missing or short files just leave their pieces out
+            let Ok(piece) = storage.read_block(index as u32, 0, piece_size) else { continue };
+            let valid = match (self.merkle_trees.as_ref(), piece_hashes) {
+                (Some(merkle_trees), _) => merkle_trees.verify_piece(index as u32, &piece) == Verification::Valid,
+                (None, Some(piece_hashes)) => utils::calculate_sha1_hash_with_ref(&piece) == piece_hashes[index],
+                (None, None) => false,
+            };
+            if valid {
+                local_pieces.add_piece(index as u32);
+            }
+        }
+        Ok(local_pieces)
+    }
+
+    // Storage for the files in the output path; the files of v2 torrents start at piece boundaries
+    fn create_storage(&self, output_path: &str) -> Storage {
+        let piece_length = self.metainfo.as_ref().unwrap().get_piece_length().unwrap() as u64;
+        let file_layout = self.get_file_layout(Path::new(output_path));
+        match self.merkle_trees {
+            Some(_) => Storage::aligned(file_layout, piece_length),
+            None => Storage::new(file_layout, piece_length),
+        }
+    }
 
-        for piece_index in 0..piece_count {
-            let piece = self.download_piece_with_index(piece_index as u32).await.unwrap();
-            file.extend_from_slice(&piece);
+    // Where each file of the torrent is stored, with its length and priority. Padding files take
+    // their place in the torrent data but are not stored
+    fn get_file_layout(&self, output_path: &Path) -> Vec<(Option<PathBuf>, u64, FilePriority)> {
+        let metainfo = self.metainfo.as_ref().unwrap();
+        match metainfo.get_files() {
+            Some(files) => files.iter()
+                .zip(&self.file_priorities)
+                .map(|(file, &priority)| match file.is_padding() {
+                    true => (None, file.get_length() as u64, FilePriority::Skip),
+                    false => {
+                        let path = file.get_path().iter().fold(output_path.to_path_buf(), |path, component| path.join(component));
+                        (Some(path), file.get_length() as u64, priority)
+                    }
+                })
+                .collect(),
+            None => vec![(Some(output_path.to_path_buf()), metainfo.get_length().unwrap() as u64, self.file_priorities[0])],
         }
-        Ok(file)
     }
 
-    // Download a piece of the file with a specific index
+    // Download a piece of the file with a specific index from whichever peer serves it
     pub async fn download_piece_with_index(&self, piece_index: u32) -> Result<Vec<u8>, Box<dyn Error>> {
-        let peer = &self.peers.as_ref().unwrap()[1];
-        let piece_hashes = self.metainfo.as_ref().unwrap().get_piece_hashes().clone().unwrap();
-        let piece_count = piece_hashes.len();
-
-        // Determine the length of the piece
-        let piece_length = if piece_index as usize == piece_count - 1 {
-            let length = self.metainfo.as_ref().unwrap().get_length().unwrap();
-            let piece_length = self.metainfo.as_ref().unwrap().get_piece_length().unwrap();
-            let remainder = length % piece_length;
-            if remainder == 0 { piece_length } else { remainder }
-        } else {
-            self.metainfo.as_ref().unwrap().get_piece_length().unwrap()
+        let downloader = self.create_swarm_downloader()?;
+        let mut pieces = downloader.download(&self.get_peer_addresses()?, &[piece_index]).await?;
+        Ok(pieces.remove(&piece_index).unwrap())
+    }
+
+    // Stream a file of the torrent while it downloads, chosen by index or glob as select_file
+    // does. Only the pieces of the file are fetched, in order from the read position
+    pub fn stream_file(&self, selector: Option<&str>) -> Result<TorrentStream, Box<dyn Error>> {
+        let (offset, length) = self.get_file_range(self.select_file(selector)?);
+        let mut downloader = self.create_swarm_downloader()?;
+        downloader.set_pick_order(PickOrder::Sequential);
+        let piece_length = self.metainfo.as_ref().unwrap().get_piece_length().unwrap() as u64;
+        let pieces: Vec<u32> = match length {
+            0 => vec![],
+            _ => ((offset / piece_length) as u32..=((offset + length - 1) / piece_length) as u32).collect(),
         };
+        let download = downloader.start(&self.get_peer_addresses()?, &pieces)?;
+        Ok(TorrentStream::new(download, piece_length, offset, length)?)
+    }
 
-        self.download_piece(peer.get_ip_address(), piece_index, piece_length as u32, &piece_hashes[piece_index as usize]).await
+    // Create a downloader for the pieces of the parsed torrent
+    fn create_swarm_downloader(&self) -> Result<SwarmDownloader, Box<dyn Error>> {
+        let info_hash_bytes = self.get_info_hash_bytes()?;
+        // v2 torrents have no piece hashes, their pieces are verified against the Merkle trees
+        let piece_hashes = match self.merkle_trees {
+            Some(_) => vec![],
+            None => self.metainfo.as_ref().unwrap().get_piece_hashes().clone().ok_or("Error: piece hashes missing!")?,
+        };
+        let mut downloader = SwarmDownloader::new(self.client_config.clone(), info_hash_bytes, piece_hashes, self.get_piece_sizes()?);
+        if let Some(merkle_trees) = self.merkle_trees.as_ref() {
+            downloader.set_merkle_trees(merkle_trees.clone());
+        }
+        if let Some(peer_listener) = self.peer_listener.as_ref() {
+            downloader.set_peer_listener(peer_listener.clone());
+        }
+        if let Some(local_discovery) = self.get_local_discovery() {
+            downloader.set_local_discovery(local_discovery);
+        }
+
+        // web seeds serve every piece, next to the peers
+        let metainfo = self.metainfo.as_ref().unwrap();
+        let is_http = |url: &String| {
+            let supported = url.starts_with("http://") || url.starts_with("https://");
+            if !supported {
+                eprintln!("Skipping web seed {}: only HTTP web seeds are supported", url);
+            }
+            supported
+        };
+        // the files of a multi-file v2 torrent do not follow each other the way web seeds serve them
+        let url_list = match (metainfo.get_version(), metainfo.get_files()) {
+            (TorrentVersion::V2, Some(_)) if !metainfo.get_url_list().is_empty() => {
+                eprintln!("Skipping web seeds: the files of multi-file v2 torrents start at piece boundaries");
+                &[][..]
+            }
+            _ => &metainfo.get_url_list()[..],
+        };
+        for url in url_list.iter().filter(|url| is_http(url)) {
+            let name = metainfo.get_name().clone().unwrap_or_default();
+            let length = metainfo.get_length().unwrap() as u64;
+            let piece_length = metainfo.get_piece_length().unwrap() as u64;
+            downloader.add_web_seed(Arc::new(WebSeed::new(url, &name, metainfo.get_files().as_deref(), length, piece_length)));
+        }
+        for url in metainfo.get_http_seeds().iter().filter(|url| is_http(url)) {
+            downloader.add_web_seed(Arc::new(HttpSeed::new(url, info_hash_bytes)));
+        }
+        Ok(downloader)
     }
 
-    // Download a piece of the file from a peer
-    pub async fn download_piece(&self, peer_address: &String, piece_index: u32, piece_length: u32, piece_hash: &String) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut peer_client = clients::peer_client::PeerClient::new();
-        peer_client.connect(peer_address).await?;
+    // Local service discovery for the parsed torrent; private torrents only get peers from their tracker
+    fn get_local_discovery(&self) -> Option<Arc<clients::local_discovery::LocalDiscovery>> {
+        let private = self.metainfo.as_ref().is_some_and(|metainfo| metainfo.is_private());
+        self.local_discovery.clone().filter(|_| !private)
+    }
 
-        let info_hash = self.metainfo.as_ref().unwrap().get_hash().as_ref().unwrap().clone();
-        let info_hash_bytes = utils::hex_to_byte_representation(&info_hash);
+    fn get_piece_sizes(&self) -> Result<Vec<u32>, Box<dyn Error>> {
+        if let Some(merkle_trees) = self.merkle_trees.as_ref() {
+            return Ok(merkle_trees.piece_sizes());
+        }
+        let metainfo = self.metainfo.as_ref().unwrap();
+        let piece_count = metainfo.get_piece_hashes().as_ref().ok_or("Error: piece hashes missing!")?.len();
+        (0..piece_count)
+            .map(|index| metainfo.get_piece_size(index).map(|size| size as u32))
+            .collect::<Option<Vec<u32>>>()
+            .ok_or("Error: piece hashes do not match the file length!".into())
+    }
 
-        println!("Performing handshake...");
-        peer_client.perform_handshake(info_hash_bytes.clone()).await?;
+    // Addresses of the peers returned by the tracker
+    fn get_peer_addresses(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        match self.peers.as_ref() {
+            Some(peers) => Ok(peers.iter().map(|peer| peer.get_ip_address().clone()).collect()),
+            None => Err("Error: peers were not initialized!".into()),
+        }
+    }
+}
 
-        let mut piece = vec![];
-        let block_size = 16 * 1024;
-        let num_full_blocks = piece_length / block_size;
-        let last_block_length = piece_length % block_size;
+// Read a list of base64 encoded urls, which may also be a single url
+fn parse_urls(urls: &Value) -> Result<Vec<String>, Box<dyn Error>> {
+    let urls = match urls {
+        Value::String(url) => vec![url.as_str()],
+        Value::Array(urls) => urls.iter().filter_map(|url| url.as_str()).collect(),
+        _ => vec![],
+    };
+    urls.into_iter().map(|url| Ok(utils::decode_base64_to_utf8_string(url)?)).collect()
+}
 
-        peer_client.init_download().await?;
+// Read the file list of a multi-file torrent, rejecting paths that would leave the download
+// directory
+fn parse_files(files: &[Value]) -> Result<Vec<FileInfo>, Box<dyn Error>> {
+    let mut parsed_files = vec![];
+    for file in files {
+        let length = file["length"].as_i64().ok_or("Error: file without length!")?;
+        let components = file["path"].as_array().ok_or("Error: file without path!")?;
+
+        let mut path = vec![];
+        for component in components {
+            let component = utils::decode_base64_to_utf8_string(component.as_str().ok_or("Error: invalid file path!")?)?;
+            check_path_component(&component)?;
+            path.push(component);
+        }
+        if path.is_empty() {
+            return Err("Error: file without path!".into());
+        }
+        let mut file_info = FileInfo::new(path, length);
+        // padding files (BEP 47) are marked with a 'p' in their attributes
+        if let Some(attr) = file["attr"].as_str() {
+            if utils::decode_base64_to_utf8_string(attr)?.contains('p') {
+                file_info.set_padding();
+            }
+        }
+        parsed_files.push(file_info);
+    }
+    Ok(parsed_files)
+}
 
-        // Download all full blocks
-        for block_index in 0..num_full_blocks {
-            let block = peer_client.download_block(piece_index, block_size * block_index, block_size).await?;
-            piece.extend_from_slice(&block.2);
+// Read the file tree of a v2 torrent in tree order: directories are dictionaries keyed by path
+// component, and a file is a dictionary holding its length and pieces root under an empty key
+fn parse_file_tree(tree: &Value, path: &mut Vec<String>, files: &mut Vec<FileInfo>) -> Result<(), Box<dyn Error>> {
+    for (component, node) in tree.as_object().ok_or("Error: invalid file tree!")? {
+        check_path_component(component)?;
+        path.push(component.clone());
+        match node.get("") {
+            Some(file) => {
+                let length = file["length"].as_i64().ok_or("Error: file without length!")?;
+                let mut file_info = FileInfo::new(path.clone(), length);
+                match file["pieces root"].as_str() {
+                    Some(pieces_root) => {
+                        let pieces_root = general_purpose::STANDARD.decode(pieces_root)?;
+                        file_info.set_pieces_root(pieces_root.try_into().map_err(|_| "Error: invalid pieces root!")?);
+                    }
+                    None if length > 0 => return Err(format!("Error: no pieces root for {}!", file_info.get_path_string()).into()),
+                    None => {}
+                }
+                files.push(file_info);
+            }
+            None => parse_file_tree(node, path, files)?,
         }
-        // Download the last block
-        let last_block = peer_client.download_block(piece_index, num_full_blocks * block_size, last_block_length).await?;
-        piece.extend_from_slice(&last_block.2);
+        path.pop();
+    }
+    Ok(())
+}
 
-        // Verify the piece hash
-        let got_piece_hash = utils::calculate_sha1_hash_with_ref(&piece);
-        if &got_piece_hash != piece_hash {
-            return Err("Hash did not match!".into());
+// Read the piece hashes of the v2 files, keyed by pieces root. The layer of a file larger than a
+// piece has one hash per piece and leads to its pieces root; missing layers are fetched from peers
+fn parse_piece_layers(layers: &Value, file_tree: &[FileInfo], piece_length: i64) -> Result<PieceLayers, Box<dyn Error>> {
+    let mut piece_layers = PieceLayers::new();
+    for (key, hashes) in layers.as_object().into_iter().flatten() {
+        // roots that are not UTF-8 arrive base64 encoded
+        let pieces_root = if key.len() == 32 { key.as_bytes().to_vec() } else { general_purpose::STANDARD.decode(key)? };
+        let pieces_root: [u8; 32] = pieces_root.try_into().map_err(|_| "Error: invalid pieces root!")?;
+        let hashes = general_purpose::STANDARD.decode(hashes.as_str().ok_or("Error: invalid piece layer!")?)?;
+        if hashes.len() % 32 != 0 {
+            return Err("Error: invalid piece layer!".into());
         }
+        piece_layers.insert(pieces_root, hashes.chunks(32).map(|hash| hash.try_into().unwrap()).collect());
+    }
 
-        peer_client.disconnect().await?;
+    for file in file_tree.iter().filter(|file| file.get_length() > piece_length) {
+        let pieces = (file.get_length() as u64).div_ceil(piece_length as u64) as usize;
+        let Some(pieces_root) = file.get_pieces_root() else { continue };
+        let Some(layer) = piece_layers.get(pieces_root) else { continue };
+        if layer.len() != pieces || merkle_tree::piece_layer_root(layer, piece_length as u64) != *pieces_root {
+            return Err(format!("Error: invalid piece layer for {}!", file.get_path_string()).into());
+        }
+    }
+    Ok(piece_layers)
+}
 
-        return Ok(piece)
+// Path components must name a file or directory within the download directory
+fn check_path_component(component: &str) -> Result<(), Box<dyn Error>> {
+    if component.is_empty() || component == "." || component == ".." || component.contains(['/', '\\']) {
+        return Err(format!("Error: unsafe file path component {:?}", component).into());
     }
+    Ok(())
 }
 
 
@@ -204,7 +645,6 @@ impl<'a> TorrentManager<'a> {
 mod tests {
     use super::*;
     use serde_json::json;
-    use base64::{engine::general_purpose, Engine};
 
     fn mock_encoder(value: &Value) -> Result<Vec<u8>, Box<dyn Error>> {
         Ok(serde_json::to_vec(value)?)
@@ -223,11 +663,268 @@ mod tests {
             "info": {
                 "length": 12345,
                 "piece length": 512,
-                "pieces": general_purpose::STANDARD.encode("a94a8fe5ccb19ba61c4c0873d391e987982fbbd3")
-            }
+                "pieces": general_purpose::STANDARD.encode("a94a8fe5ccb19ba61c4c0873d391e987982fbbd3"),
+                "private": 1
+            },
+            "url-list": general_purpose::STANDARD.encode("http://mirror.example.com/file"),
+            "httpseeds": [general_purpose::STANDARD.encode("http://seed.example.com/seed.php")]
         }).to_string().into_bytes();
 
         assert!(manager.parse_meta_info_file(data).is_ok());
+        assert_eq!(manager.metainfo.as_ref().unwrap().get_url_list(), &vec!["http://mirror.example.com/file".to_string()]);
+        assert_eq!(manager.metainfo.as_ref().unwrap().get_http_seeds(), &vec!["http://seed.example.com/seed.php".to_string()]);
+        assert!(manager.metainfo.as_ref().unwrap().is_private());
+        assert_eq!(manager.metainfo.as_ref().unwrap().get_version(), TorrentVersion::V1);
+    }
+
+    #[test]
+    fn test_parse_v2_meta_info() {
+        let track: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let (track_file, track_layers) = merkle_tree::tests::describe_file("track1.flac", &track, 16384);
+        let (root_a, root_b) = ([1u8; 32], *track_file.get_pieces_root().unwrap());
+        let info = |root_b: [u8; 32]| json!({
+            "name": general_purpose::STANDARD.encode("album"),
+            "meta version": 2,
+            "piece length": 16384,
+            "file tree": {
+                "cover.jpg": {"": {"length": 300, "pieces root": general_purpose::STANDARD.encode(root_a)}},
+                "cd1": {"track1.flac": {"": {"length": 40000, "pieces root": general_purpose::STANDARD.encode(root_b)}}},
+                "empty": {"": {"length": 0}}
+            }
+        });
+        let layers = json!({
+            general_purpose::STANDARD.encode(root_b): general_purpose::STANDARD.encode(track_layers[&root_b].concat())
+        });
+        let meta_info = |info: &Value, layers: &Value| json!({
+            "announce": general_purpose::STANDARD.encode("http://tracker.example.com/announce"),
+            "info": info,
+            "piece layers": layers
+        }).to_string().into_bytes();
+
+        let mut manager = TorrentManager::new(&mock_encoder, &mock_decoder);
+        manager.parse_meta_info_file(meta_info(&info(root_b), &layers)).unwrap();
+        let metainfo = manager.metainfo.as_ref().unwrap();
+        assert_eq!(metainfo.get_version(), TorrentVersion::V2);
+        assert_eq!(metainfo.get_length(), &Some(40300));
+        let files = metainfo.get_file_tree().as_ref().unwrap();
+        let paths: Vec<String> = files.iter().map(|file| file.get_path_string()).collect();
+        assert_eq!(paths, vec!["cd1/track1.flac", "cover.jpg", "empty"]);
+        assert_eq!(files[0].get_pieces_root(), Some(&root_b));
+        assert_eq!(files[1].get_pieces_root(), Some(&root_a));
+        assert_eq!(files[2].get_pieces_root(), None);
+        assert_eq!(metainfo.get_files().as_ref().unwrap().len(), 3);
+        assert_eq!(metainfo.get_piece_layers()[&root_b], track_layers[&root_b]);
+        // every file starts a piece of its own
+        assert_eq!(manager.get_piece_sizes().unwrap(), vec![16384, 16384, 7232, 300]);
+
+        let hash_v2 = hex::encode(utils::calculate_sha256_hash(&mock_encoder(&info(root_b)).unwrap()));
+        let metainfo = manager.metainfo.as_ref().unwrap();
+        assert_eq!(metainfo.get_hash_v2().as_deref(), Some(hash_v2.as_str()));
+        assert_eq!(metainfo.get_hash().as_deref(), Some(&hash_v2[..40]));
+
+        // missing piece layers are fetched from peers, a layer not leading to its root is refused;
+        // UTF-8 roots arrive as they are, binary ones base64 encoded
+        assert!(manager.parse_meta_info_file(meta_info(&info(root_b), &json!({}))).is_ok());
+        let utf8_root = *b"0123456789abcdef0123456789abcdef";
+        let utf8_layers = json!({String::from_utf8(utf8_root.to_vec()).unwrap(): general_purpose::STANDARD.encode(track_layers[&root_b].concat())});
+        assert!(manager.parse_meta_info_file(meta_info(&info(utf8_root), &utf8_layers)).is_err());
+        let mut unsupported = info(root_b);
+        unsupported["meta version"] = json!(3);
+        assert!(manager.parse_meta_info_file(meta_info(&unsupported, &layers)).is_err());
+        let mut odd_piece_length = info(root_b);
+        odd_piece_length["piece length"] = json!(20000);
+        assert!(manager.parse_meta_info_file(meta_info(&odd_piece_length, &layers)).is_err());
+    }
+
+    #[test]
+    fn test_parse_hybrid_meta_info() {
+        let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        let (file, piece_layers) = merkle_tree::tests::describe_file("file.iso", &data, 16384);
+        let root = *file.get_pieces_root().unwrap();
+        let info = json!({
+            "name": general_purpose::STANDARD.encode("file.iso"),
+            "meta version": 2,
+            "length": 20000,
+            "piece length": 16384,
+            "pieces": general_purpose::STANDARD.encode([0u8; 40]),
+            "file tree": {"file.iso": {"": {"length": 20000, "pieces root": general_purpose::STANDARD.encode(root)}}}
+        });
+        let data = json!({
+            "announce": general_purpose::STANDARD.encode("http://tracker.example.com/announce"),
+            "info": info,
+            "piece layers": {general_purpose::STANDARD.encode(root): general_purpose::STANDARD.encode(piece_layers[&root].concat())}
+        }).to_string().into_bytes();
+
+        let mut manager = TorrentManager::new(&mock_encoder, &mock_decoder);
+        manager.parse_meta_info_file(data).unwrap();
+        let metainfo = manager.metainfo.as_ref().unwrap();
+        let encoded_info = mock_encoder(&info).unwrap();
+        assert_eq!(metainfo.get_version(), TorrentVersion::Hybrid);
+        assert_eq!(metainfo.get_length(), &Some(20000));
+        assert!(metainfo.get_files().is_none());
+        assert_eq!(metainfo.get_hash(), &Some(utils::calculate_sha1_hash(encoded_info.clone())));
+        assert_eq!(metainfo.get_hash_v2(), &Some(hex::encode(utils::calculate_sha256_hash(&encoded_info))));
+        assert_eq!(metainfo.get_piece_layers()[&root].len(), 2);
+        // hybrid pieces are verified with their SHA-1 hashes
+        assert!(manager.merkle_trees.is_none());
+    }
+
+    #[test]
+    fn test_parse_multi_file_meta_info() {
+        let file = |path: &[&str], length: i64| json!({
+            "length": length,
+            "path": path.iter().map(|component| general_purpose::STANDARD.encode(component)).collect::<Vec<_>>()
+        });
+        let meta_info = |files: Value| json!({
+            "announce": general_purpose::STANDARD.encode("http://tracker.example.com/announce"),
+            "info": {
+                "name": general_purpose::STANDARD.encode("album"),
+                "files": files,
+                "piece length": 512,
+                "pieces": general_purpose::STANDARD.encode([0u8; 40])
+            },
+            "url-list": [general_purpose::STANDARD.encode("http://a.example.com/"), general_purpose::STANDARD.encode("ftp://b.example.com/")]
+        }).to_string().into_bytes();
+
+        let mut manager = TorrentManager::new(&mock_encoder, &mock_decoder);
+        manager.parse_meta_info_file(meta_info(json!([file(&["cover.jpg"], 300), file(&["cd1", "track1.flac"], 500)]))).unwrap();
+        let metainfo = manager.metainfo.as_ref().unwrap();
+        assert_eq!(metainfo.get_length(), &Some(800));
+        assert_eq!(metainfo.get_name().as_deref(), Some("album"));
+        let paths: Vec<String> = metainfo.get_files().as_ref().unwrap().iter().map(|file| file.get_path_string()).collect();
+        assert_eq!(paths, vec!["cover.jpg", "cd1/track1.flac"]);
+        assert_eq!(metainfo.get_url_list().len(), 2);
+
+        // a padding file keeps its place in the torrent data but is never stored
+        let mut padding = file(&[".pad", "212"], 212);
+        padding["attr"] = json!(general_purpose::STANDARD.encode("p"));
+        manager.parse_meta_info_file(meta_info(json!([file(&["cover.jpg"], 300), padding, file(&["cd1", "track1.flac"], 500)]))).unwrap();
+        let files = manager.metainfo.as_ref().unwrap().get_files().as_ref().unwrap();
+        assert_eq!(files.iter().map(|file| file.is_padding()).collect::<Vec<_>>(), vec![false, true, false]);
+        assert_eq!(manager.set_file_priority_by_glob("**", FilePriority::High).unwrap(), 2);
+        let layout = manager.get_file_layout(Path::new("album"));
+        assert_eq!(layout[1], (None, 212, FilePriority::Skip));
+        assert_eq!(layout[2], (Some(Path::new("album").join("cd1").join("track1.flac")), 500, FilePriority::High));
+
+        for unsafe_path in [&["..", "etc"][..], &["a/b"][..], &[][..]] {
+            let data = meta_info(json!([file(unsafe_path, 1)]));
+            assert!(manager.parse_meta_info_file(data).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_selected_files() {
+        use crate::torrent_manager::swarm_downloader::tests::{make_torrent, spawn_seeder, Behaviour, INFO_HASH, PIECE_SIZE};
+        use torrent_spec::file_info::FileInfo;
+
+        // 5 pieces; a.bin covers pieces 0-1, the skipped b.bin pieces 1-3, c/d.bin pieces 3-4
+        let (data, hashes, _) = make_torrent(5);
+        let mut metainfo = torrent_spec::meta_info::Metainfo::new();
+        metainfo.set_hash(hex::encode(INFO_HASH));
+        metainfo.set_piece_length(PIECE_SIZE as i64);
+        metainfo.set_piece_hashes(hashes);
+        metainfo.set_length(data.len() as i64);
+        metainfo.set_files(vec![
+            FileInfo::new(vec!["a.bin".to_string()], 30000),
+            FileInfo::new(vec!["b.bin".to_string()], 40000),
+            FileInfo::new(vec!["c".to_string(), "d.bin".to_string()], data.len() as i64 - 70000),
+        ]);
+
+        let mut manager = TorrentManager::new(&mock_encoder, &mock_decoder);
+        manager.metainfo = Some(metainfo);
+        manager.file_priorities = vec![FilePriority::Normal; 3];
+        let (peer, _) = spawn_seeder(data.clone(), Behaviour::Serve).await;
+        manager.peers = Some(vec![torrent_spec::peer_info::Peer::new(peer)]);
+        assert_eq!(manager.set_file_priority_by_glob("b.*", FilePriority::Skip).unwrap(), 1);
+        assert_eq!(manager.set_file_priority_by_glob("**/d.bin", FilePriority::High).unwrap(), 1);
+        assert!(manager.set_file_priority_by_glob("*.iso", FilePriority::Skip).is_err());
+
+        let directory = tempfile::tempdir().unwrap();
+        let output_path = directory.path().to_str().unwrap();
+        manager.download_to(output_path).await.unwrap();
+
+        assert_eq!(std::fs::read(directory.path().join("a.bin")).unwrap(), &data[..30000]);
+        assert_eq!(std::fs::read(directory.path().join("c").join("d.bin")).unwrap(), &data[70000..]);
+        // only the parts of b.bin in pieces 1 and 3 were downloaded, piece 2 was not
+        let skipped = std::fs::read(directory.path().join("b.bin")).unwrap();
+        assert_eq!(&skipped[..10000], &data[30000..40000]);
+        assert!(skipped[10000..30000].iter().all(|&byte| byte == 0));
+        assert_eq!(&skipped[30000..], &data[60000..70000]);
+
+        // only the complete pieces verify when seeding the partial download
+        let local_pieces = manager.verify_local_pieces(output_path).unwrap();
+        assert_eq!(local_pieces.get_bitfield(), vec![0b1101_1000]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_a_file_of_a_v2_torrent() {
+        use crate::clients::request_pipeline::BLOCK_SIZE;
+        use crate::torrent_manager::merkle_tree::tests::describe_file;
+        use crate::torrent_manager::swarm_downloader::tests::{spawn_v2_seeder, INFO_HASH};
+        use tokio::io::AsyncReadExt;
+
+        // a.bin ends with a short piece, b.bin starts at the next piece boundary
+        let piece_length = 2 * BLOCK_SIZE as u64;
+        let a: Vec<u8> = (0..piece_length as usize + 1000).map(|i| (i % 241) as u8).collect();
+        let b: Vec<u8> = (0..2 * piece_length as usize + 500).map(|i| (i % 239) as u8).collect();
+        let (file_a, mut piece_layers) = describe_file("a.bin", &a, piece_length);
+        let (file_b, layers_b) = describe_file("b.bin", &b, piece_length);
+        piece_layers.extend(layers_b);
+        let mut data = a.clone();
+        data.resize(2 * piece_length as usize, 0);
+        data.extend(&b);
+
+        let files = [file_a, file_b];
+        let mut metainfo = torrent_spec::meta_info::Metainfo::new();
+        metainfo.set_hash(hex::encode(INFO_HASH));
+        metainfo.set_piece_length(piece_length as i64);
+        metainfo.set_length((a.len() + b.len()) as i64);
+        metainfo.set_files(files.iter().map(|file| FileInfo::new(file.get_path().clone(), file.get_length())).collect());
+
+        let mut manager = TorrentManager::new(&mock_encoder, &mock_decoder);
+        manager.metainfo = Some(metainfo);
+        manager.merkle_trees = Some(Arc::new(MerkleTrees::new(&files, &piece_layers, piece_length)));
+        manager.file_priorities = vec![FilePriority::Normal; 2];
+        for (selector, expected) in [("b.bin", &b), ("a.bin", &a)] {
+            let (peer, _) = spawn_v2_seeder(data.clone(), piece_length, None).await;
+            manager.peers = Some(vec![torrent_spec::peer_info::Peer::new(peer)]);
+            let mut file = vec![];
+            manager.stream_file(Some(selector)).unwrap().read_to_end(&mut file).await.unwrap();
+            assert_eq!(&file, expected);
+        }
     }
 
+    #[tokio::test]
+    async fn test_stream_one_file_of_a_torrent() {
+        use crate::torrent_manager::swarm_downloader::tests::{make_torrent, spawn_seeder, Behaviour, INFO_HASH, PIECE_SIZE};
+        use tokio::io::AsyncReadExt;
+        use torrent_spec::file_info::FileInfo;
+
+        // a.bin covers pieces 0-1, b.bin pieces 1-2
+        let (data, hashes, _) = make_torrent(3);
+        let mut metainfo = torrent_spec::meta_info::Metainfo::new();
+        metainfo.set_hash(hex::encode(INFO_HASH));
+        metainfo.set_piece_length(PIECE_SIZE as i64);
+        metainfo.set_piece_hashes(hashes);
+        metainfo.set_length(data.len() as i64);
+        metainfo.set_files(vec![
+            FileInfo::new(vec!["a.bin".to_string()], 30000),
+            FileInfo::new(vec!["b.bin".to_string()], data.len() as i64 - 30000),
+        ]);
+
+        let mut manager = TorrentManager::new(&mock_encoder, &mock_decoder);
+        manager.metainfo = Some(metainfo);
+        manager.file_priorities = vec![FilePriority::Normal; 2];
+        assert!(manager.stream_file(None).is_err());
+        assert!(manager.stream_file(Some("2")).is_err());
+        assert!(manager.stream_file(Some("*.iso")).is_err());
+
+        for (selector, expected) in [("1", &data[30000..]), ("a.*", &data[..30000])] {
+            let (peer, _) = spawn_seeder(data.clone(), Behaviour::Serve).await;
+            manager.peers = Some(vec![torrent_spec::peer_info::Peer::new(peer)]);
+            let mut file = vec![];
+            manager.stream_file(Some(selector)).unwrap().read_to_end(&mut file).await.unwrap();
+            assert_eq!(file, expected);
+        }
+    }
 }
@@ -0,0 +1,48 @@
+// One file of a multi-file torrent
+pub struct FileInfo {
+    path: Vec<String>, // path components below the torrent's directory
+    length: i64,
+    pieces_root: Option<[u8; 32]>, // root of the file's Merkle tree in v2 torrents, none for empty files
+    padding: bool, // zeros aligning the next file to a piece boundary (BEP 47), never stored
+}
+
+impl FileInfo {
+    pub fn new(path: Vec<String>, length: i64) -> Self {
+        Self { path, length, pieces_root: None, padding: false }
+    }
+
+    // Getter for path
+    pub fn get_path(&self) -> &Vec<String> {
+        &self.path
+    }
+
+    // Getter for length
+    pub fn get_length(&self) -> i64 {
+        self.length
+    }
+
+    // Setter for pieces_root
+    pub fn set_pieces_root(&mut self, pieces_root: [u8; 32]) {
+        self.pieces_root = Some(pieces_root);
+    }
+
+    // Getter for pieces_root
+    pub fn get_pieces_root(&self) -> Option<&[u8; 32]> {
+        self.pieces_root.as_ref()
+    }
+
+    // Setter for padding
+    pub fn set_padding(&mut self) {
+        self.padding = true;
+    }
+
+    // Getter for padding
+    pub fn is_padding(&self) -> bool {
+        self.padding
+    }
+
+    // Path with components separated by '/', as matched by file selection globs
+    pub fn get_path_string(&self) -> String {
+        self.path.join("/")
+    }
+}
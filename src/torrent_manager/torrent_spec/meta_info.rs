@@ -1,19 +1,60 @@
+use std::collections::HashMap;
+use std::fmt;
+use super::file_info::FileInfo;
+
+// Which hashes a torrent carries: SHA-1 piece hashes (v1), SHA-256 Merkle trees (v2, BEP 52) or both
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TorrentVersion {
+    V1,
+    V2,
+    Hybrid,
+}
+
+impl fmt::Display for TorrentVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TorrentVersion::V1 => write!(f, "v1"),
+            TorrentVersion::V2 => write!(f, "v2"),
+            TorrentVersion::Hybrid => write!(f, "hybrid"),
+        }
+    }
+}
+
+// The piece hashes of the v2 files, keyed by the pieces root of their file
+pub type PieceLayers = HashMap<[u8; 32], Vec<[u8; 32]>>;
+
 pub struct Metainfo {
     tracker_url: Option<String>,
+    name: Option<String>,
+    files: Option<Vec<FileInfo>>, // only set for multi-file torrents
     length: Option<i64>,
-    hash: Option<String>,
+    hash: Option<String>, // info hash used with trackers and peers; the truncated v2 hash for v2 torrents
+    hash_v2: Option<String>, // SHA-256 of the info dictionary of v2 and hybrid torrents
     piece_length: Option<i64>,
     piece_hashes: Option<Vec<String>>,
+    url_list: Vec<String>, // web seeds (BEP 19)
+    http_seeds: Vec<String>, // HTTP seeds (BEP 17)
+    private: bool, // peers only come from the tracker (BEP 27)
+    file_tree: Option<Vec<FileInfo>>, // files of v2 and hybrid torrents, with their pieces roots
+    piece_layers: PieceLayers, // piece hashes of the v2 files
 }
 
 impl Default for Metainfo {
     fn default() -> Self {
         Self {
             tracker_url: None,
+            name: None,
+            files: None,
             length: None,
             hash: None,
+            hash_v2: None,
             piece_length: None,
             piece_hashes: None,
+            url_list: vec![],
+            http_seeds: vec![],
+            private: false,
+            file_tree: None,
+            piece_layers: HashMap::new(),
         }
     }
 }
@@ -33,6 +74,26 @@ impl Metainfo {
         &self.tracker_url
     }
 
+    // Setter for name
+    pub fn set_name(&mut self, name: String) {
+        self.name = Some(name);
+    }
+
+    // Getter for name
+    pub fn get_name(&self) -> &Option<String> {
+        &self.name
+    }
+
+    // Setter for files
+    pub fn set_files(&mut self, files: Vec<FileInfo>) {
+        self.files = Some(files);
+    }
+
+    // Getter for files
+    pub fn get_files(&self) -> &Option<Vec<FileInfo>> {
+        &self.files
+    }
+
     // Setter for length
     pub fn set_length(&mut self, length: i64) {
         self.length = Some(length);
@@ -55,6 +116,21 @@ impl Metainfo {
     }
 
 
+    // Setter for hash_v2
+    pub fn set_hash_v2(&mut self, hash_v2: String) {
+        self.hash_v2 = Some(hash_v2);
+    }
+
+    // Getter for hash_v2
+    pub fn get_hash_v2(&self) -> &Option<String> {
+        &self.hash_v2
+    }
+
+    // The v2 info hash truncated to 20 bytes, as sent to trackers and in handshakes
+    pub fn get_truncated_hash_v2(&self) -> Option<String> {
+        self.hash_v2.as_ref().map(|hash| hash[..40].to_string())
+    }
+
     // Setter for piece_length
     pub fn set_piece_length(&mut self, piece_length: i64) {
         self.piece_length = Some(piece_length);
@@ -75,6 +151,76 @@ impl Metainfo {
         &self.piece_hashes
     }
 
+    // Setter for url_list
+    pub fn set_url_list(&mut self, url_list: Vec<String>) {
+        self.url_list = url_list;
+    }
+
+    // Getter for url_list
+    pub fn get_url_list(&self) -> &Vec<String> {
+        &self.url_list
+    }
+
+    // Setter for http_seeds
+    pub fn set_http_seeds(&mut self, http_seeds: Vec<String>) {
+        self.http_seeds = http_seeds;
+    }
+
+    // Getter for http_seeds
+    pub fn get_http_seeds(&self) -> &Vec<String> {
+        &self.http_seeds
+    }
+
+    // Setter for private
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+    }
+
+    // Getter for private
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    // Setter for file_tree
+    pub fn set_file_tree(&mut self, file_tree: Vec<FileInfo>) {
+        self.file_tree = Some(file_tree);
+    }
+
+    // Getter for file_tree
+    pub fn get_file_tree(&self) -> &Option<Vec<FileInfo>> {
+        &self.file_tree
+    }
+
+    // Setter for piece_layers
+    pub fn set_piece_layers(&mut self, piece_layers: PieceLayers) {
+        self.piece_layers = piece_layers;
+    }
+
+    // Getter for piece_layers
+    pub fn get_piece_layers(&self) -> &PieceLayers {
+        &self.piece_layers
+    }
+
+    // v2 torrents have a file tree, v1 torrents piece hashes, hybrid torrents both
+    pub fn get_version(&self) -> TorrentVersion {
+        match (self.piece_hashes.is_some(), self.file_tree.is_some()) {
+            (true, true) => TorrentVersion::Hybrid,
+            (false, true) => TorrentVersion::V2,
+            _ => TorrentVersion::V1,
+        }
+    }
+
+
+    // Length of the piece at index; the last piece holds the remainder of the file
+    pub fn get_piece_size(&self, index: usize) -> Option<i64> {
+        let length = (*self.get_length())?;
+        let piece_length = (*self.get_piece_length())?;
+        let start = index as i64 * piece_length;
+        if start >= length {
+            return None;
+        }
+        Some(piece_length.min(length - start))
+    }
 
     pub fn get_formatted_info(&self) -> String {
         let tracker_url = self.tracker_url.as_ref().map_or("N/A", |url| url.as_str());
@@ -87,14 +233,26 @@ impl Metainfo {
             self.piece_hashes.as_ref().unwrap().join(", ")
         };
 
-        format!(
+        let mut info = format!(
             "Tracker URL: {}\nLength: {}\nInfo Hash: {}\nPiece Length: {}\nPiece Hashes: {}\n",
             tracker_url,
             length,
             hash,
             piece_length,
             piece_hashes
-        )
+        );
+        info.push_str(&format!("Version: {}\n", self.get_version()));
+        if let (Some(hash_v2), Some(truncated)) = (self.get_hash_v2(), self.get_truncated_hash_v2()) {
+            info.push_str(&format!("Info Hash v2: {}\nTruncated Info Hash v2: {}\n", hash_v2, truncated));
+            info.push_str(&format!("Piece Layers: {}\n", self.get_piece_layers().len()));
+        }
+        if let Some(files) = self.files.as_ref() {
+            info.push_str("Files:\n");
+            for file in files {
+                info.push_str(&format!("  {} ({} bytes)\n", file.get_path_string(), file.get_length()));
+            }
+        }
+        info
     }
     
 }
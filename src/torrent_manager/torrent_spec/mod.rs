@@ -1,2 +1,4 @@
 pub mod meta_info;
-pub mod peer_info;
\ No newline at end of file
+pub mod peer_info;
+pub mod scrape_info;
+pub mod file_info;
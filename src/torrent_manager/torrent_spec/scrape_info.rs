@@ -0,0 +1,22 @@
+pub struct ScrapeInfo {
+    info_hash: String, // hex encoded info hash
+    seeders: i64,
+    leechers: i64,
+    completed: i64,
+}
+
+impl ScrapeInfo {
+    pub fn new(info_hash: String, seeders: i64, leechers: i64, completed: i64) -> Self {
+        Self { info_hash, seeders, leechers, completed }
+    }
+
+    pub fn get_formatted_info(&self) -> String {
+        format!(
+            "Info Hash: {}\nSeeders: {}\nLeechers: {}\nCompleted: {}\n",
+            self.info_hash,
+            self.seeders,
+            self.leechers,
+            self.completed
+        )
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use super::swarm_downloader::SwarmDownload;
+
+// Reads a byte range of the torrent data while it downloads. Reads wait until the pieces they
+// need are verified, and the download continues from the piece at the read position. Verified
+// pieces go to a temporary file rather than memory, so any part of the range can be read again
+pub struct TorrentStream {
+    download: SwarmDownload,
+    cache: File,
+    verified: HashMap<u32, u64>, // lengths of the pieces in the cache file
+    piece_length: u64,
+    offset: u64,   // start of the range within the torrent data
+    length: u64,
+    position: u64, // relative to offset
+}
+
+impl TorrentStream {
+    // The download should pick pieces sequentially so reads do not wait for long
+    pub fn new(download: SwarmDownload, piece_length: u64, offset: u64, length: u64) -> io::Result<Self> {
+        if piece_length == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Piece length must not be zero"));
+        }
+        download.prioritise((offset / piece_length) as u32);
+        let cache = tempfile::tempfile()?;
+        Ok(Self { download, cache, verified: HashMap::new(), piece_length, offset, length, position: 0 })
+    }
+
+    // Store a verified piece at its place in the torrent data
+    fn cache_piece(&mut self, index: u32, data: &[u8]) -> io::Result<()> {
+        self.cache.seek(SeekFrom::Start(index as u64 * self.piece_length))?;
+        self.cache.write_all(data)?;
+        self.verified.insert(index, data.len() as u64);
+        Ok(())
+    }
+}
+
+impl AsyncRead for TorrentStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.position >= this.length || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let absolute = this.offset + this.position;
+        let index = (absolute / this.piece_length) as u32;
+
+        loop {
+            if let Some(&piece_size) = this.verified.get(&index) {
+                let piece_end = index as u64 * this.piece_length + piece_size;
+                let count = (piece_end - absolute).min(this.length - this.position).min(buf.remaining() as u64) as usize;
+                this.cache.seek(SeekFrom::Start(absolute))?;
+                this.cache.read_exact(buf.initialize_unfilled_to(count))?;
+                buf.advance(count);
+                this.position += count as u64;
+                return Poll::Ready(Ok(()));
+            }
+            match this.download.poll_next_piece(cx) {
+                Poll::Ready(Some((verified_index, data))) => this.cache_piece(verified_index, &data)?,
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("Download ended before piece {} was verified", index),
+                    )));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncSeek for TorrentStream {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => this.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.position.checked_add_signed(delta),
+        };
+        let position = position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the stream"))?;
+
+        this.position = position;
+        if position < this.length {
+            this.download.prioritise(((this.offset + position) / this.piece_length) as u32);
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::client_config::ClientConfig;
+    use crate::torrent_manager::piece_picker::PickOrder;
+    use crate::torrent_manager::swarm_downloader::tests::{make_torrent, spawn_seeder, Behaviour, INFO_HASH, PIECE_SIZE};
+    use crate::torrent_manager::swarm_downloader::SwarmDownloader;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[tokio::test]
+    async fn test_read_and_seek_while_downloading() {
+        let (data, hashes, sizes) = make_torrent(5);
+        let peers = vec![
+            spawn_seeder(data.clone(), Behaviour::Slow(10)).await.0,
+            spawn_seeder(data.clone(), Behaviour::Serve).await.0,
+        ];
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        downloader.set_pick_order(PickOrder::Sequential);
+        let download = downloader.start(&peers, &[0, 1, 2, 3, 4]).unwrap();
+
+        // a range starting within the first piece and ending within the last one
+        let (offset, length) = (5000, 4 * PIECE_SIZE as u64);
+        let mut stream = TorrentStream::new(download, PIECE_SIZE as u64, offset, length).unwrap();
+        let range = &data[offset as usize..(offset + length) as usize];
+
+        assert_eq!(stream.seek(SeekFrom::End(-100)).await.unwrap(), length - 100);
+        let mut tail = vec![];
+        stream.read_to_end(&mut tail).await.unwrap();
+        assert_eq!(tail, &range[range.len() - 100..]);
+
+        stream.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut all = vec![];
+        stream.read_to_end(&mut all).await.unwrap();
+        assert_eq!(all, range);
+        assert!(stream.seek(SeekFrom::Current(-(length as i64) - 1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_seek_back_into_pieces_read_already() {
+        let (data, hashes, sizes) = make_torrent(3);
+        let peers = vec![spawn_seeder(data.clone(), Behaviour::Serve).await.0];
+        let mut downloader = SwarmDownloader::new(ClientConfig::new(), INFO_HASH, hashes, sizes);
+        downloader.set_pick_order(PickOrder::Sequential);
+        let download = downloader.start(&peers, &[0, 1, 2]).unwrap();
+        let mut stream = TorrentStream::new(download, PIECE_SIZE as u64, 0, data.len() as u64).unwrap();
+
+        let mut all = vec![];
+        stream.read_to_end(&mut all).await.unwrap();
+        assert_eq!(all, data);
+
+        // a player reading its headers again, then a part spanning two pieces
+        stream.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut header = vec![0; 100];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header, &data[..100]);
+        let start = PIECE_SIZE as u64 - 50;
+        stream.seek(SeekFrom::Current(start as i64 - 100)).await.unwrap();
+        let mut across = vec![0; 100];
+        stream.read_exact(&mut across).await.unwrap();
+        assert_eq!(across, &data[start as usize..start as usize + 100]);
+    }
+}
@@ -0,0 +1,361 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::{engine::general_purpose, Engine};
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::bencode_processing::encoder::{encode_bencoded_dict_with_byte_keys, encode_bencoded_value};
+use super::swarm::{AnnounceEvent, AnnounceRequest, SwarmStore};
+
+// Requests larger than this are rejected; announces are a few hundred bytes
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+// Connections that do not send a whole request within this time are closed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Pause after a failed accept, e.g. when out of file descriptors, before accepting again
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// HTTP front end of the built-in tracker serving /announce and /scrape
+pub struct HttpTracker {
+    listener: TcpListener,
+    swarms: Arc<Mutex<SwarmStore>>,
+    request_timeout: Duration,
+}
+
+impl HttpTracker {
+    pub async fn bind(address: &str, swarms: Arc<Mutex<SwarmStore>>) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(address).await?;
+        Ok(Self { listener, swarms, request_timeout: REQUEST_TIMEOUT })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Accept connections until the task is dropped; failed accepts are retried after a pause
+    pub async fn run(self) -> Result<(), Box<dyn Error>> {
+        loop {
+            let (stream, remote_address) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Tracker could not accept a connection: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let (swarms, request_timeout) = (self.swarms.clone(), self.request_timeout);
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, remote_address, swarms, request_timeout).await {
+                    eprintln!("Tracker connection from {} failed: {}", remote_address, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    remote_address: SocketAddr,
+    swarms: Arc<Mutex<SwarmStore>>,
+    request_timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let request = match tokio::time::timeout(request_timeout, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => return Err("Request not received in time".into()),
+    };
+    let Some(request) = request else {
+        return write_response(&mut stream, "413 Payload Too Large", b"").await;
+    };
+
+    let request = String::from_utf8_lossy(&request);
+    let request_line = request.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    if method != "GET" {
+        return write_response(&mut stream, "405 Method Not Allowed", b"").await;
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = parse_query(query);
+    let body = match path {
+        "/announce" => handle_announce(&params, remote_address, &swarms),
+        "/scrape" => handle_scrape(&params, &swarms),
+        _ => return write_response(&mut stream, "404 Not Found", b"").await,
+    }?;
+
+    write_response(&mut stream, "200 OK", &body).await
+}
+
+// Read until the end of the request headers; None if the request is too large
+async fn read_request(stream: &mut TcpStream) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut request = vec![];
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err("Connection closed before request was complete".into());
+        }
+        request.extend_from_slice(&buffer[..read]);
+        if request.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+    }
+    Ok(Some(request))
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str, body: &[u8]) -> Result<(), Box<dyn Error>> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Split a query string into percent decoded key/value pairs, keeping repeated keys
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = percent_decode_str(key).decode_utf8_lossy().to_string();
+            (key, percent_decode_str(value).collect())
+        })
+        .collect()
+}
+
+fn get_param<'p>(params: &'p [(String, Vec<u8>)], key: &str) -> Option<&'p Vec<u8>> {
+    params.iter().find(|(k, _)| k == key).map(|(_, value)| value)
+}
+
+fn get_numeric_param<T: std::str::FromStr>(params: &[(String, Vec<u8>)], key: &str) -> Result<Option<T>, String> {
+    match get_param(params, key) {
+        Some(value) => std::str::from_utf8(value).ok()
+            .and_then(|value| value.parse::<T>().ok())
+            .map(Some)
+            .ok_or_else(|| format!("Invalid {}", key)),
+        None => Ok(None),
+    }
+}
+
+fn get_hash_param(params: &[(String, Vec<u8>)], key: &str) -> Result<[u8; 20], String> {
+    get_param(params, key)
+        .and_then(|value| <[u8; 20]>::try_from(value.as_slice()).ok())
+        .ok_or_else(|| format!("Missing or invalid {}", key))
+}
+
+fn parse_announce_request(params: &[(String, Vec<u8>)], remote_address: SocketAddr) -> Result<AnnounceRequest, String> {
+    let info_hash = get_hash_param(params, "info_hash")?;
+    let peer_id = get_hash_param(params, "peer_id")?;
+    let port = get_numeric_param::<u16>(params, "port")?.ok_or("Missing port")?;
+    let left = get_numeric_param::<i64>(params, "left")?.unwrap_or(0);
+    let numwant = get_numeric_param::<usize>(params, "numwant")?;
+    let event = match get_param(params, "event").map(|event| event.as_slice()) {
+        None | Some(b"") | Some(b"empty") => AnnounceEvent::None,
+        Some(b"started") => AnnounceEvent::Started,
+        Some(b"completed") => AnnounceEvent::Completed,
+        Some(b"stopped") => AnnounceEvent::Stopped,
+        Some(_) => return Err("Invalid event".to_string()),
+    };
+
+    Ok(AnnounceRequest {
+        info_hash,
+        peer_id,
+        address: SocketAddr::new(remote_address.ip(), port),
+        left,
+        event,
+        numwant,
+    })
+}
+
+fn handle_announce(params: &[(String, Vec<u8>)], remote_address: SocketAddr, swarms: &Mutex<SwarmStore>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let response = parse_announce_request(params, remote_address)
+        .and_then(|request| swarms.lock().unwrap().announce(request));
+    let response = match response {
+        Ok(response) => response,
+        Err(failure_reason) => return failure_response(&failure_reason),
+    };
+
+    let compact = get_param(params, "compact").is_none_or(|compact| compact.as_slice() != b"0");
+    let no_peer_id = get_param(params, "no_peer_id").is_some_and(|value| value.as_slice() == b"1");
+
+    let peers = if compact {
+        // IPv4 peers as 6 bytes each, IPv6 peers as 18 bytes each in peers6 (BEP 23 / BEP 7)
+        let mut peers = vec![];
+        let mut peers6 = vec![];
+        for peer in &response.peers {
+            match peer.get_address() {
+                SocketAddr::V4(address) => {
+                    peers.extend_from_slice(&address.ip().octets());
+                    peers.extend_from_slice(&address.port().to_be_bytes());
+                }
+                SocketAddr::V6(address) => {
+                    peers6.extend_from_slice(&address.ip().octets());
+                    peers6.extend_from_slice(&address.port().to_be_bytes());
+                }
+            }
+        }
+        let mut compact_peers = json!({ "peers": general_purpose::STANDARD.encode(peers) });
+        if !peers6.is_empty() {
+            compact_peers["peers6"] = general_purpose::STANDARD.encode(peers6).into();
+        }
+        compact_peers
+    } else {
+        let peer_list: Vec<Value> = response.peers.iter()
+            .map(|peer| {
+                let mut entry = json!({
+                    "ip": general_purpose::STANDARD.encode(peer.get_address().ip().to_string()),
+                    "port": peer.get_address().port(),
+                });
+                if !no_peer_id {
+                    entry["peer id"] = general_purpose::STANDARD.encode(peer.get_peer_id()).into();
+                }
+                entry
+            })
+            .collect();
+        json!({ "peers": peer_list })
+    };
+
+    let mut body = json!({
+        "interval": response.interval,
+        "complete": response.complete,
+        "incomplete": response.incomplete,
+    });
+    for (key, value) in peers.as_object().unwrap() {
+        body[key] = value.clone();
+    }
+    encode_bencoded_value(&body)
+}
+
+fn handle_scrape(params: &[(String, Vec<u8>)], swarms: &Mutex<SwarmStore>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut info_hashes = vec![];
+    for (key, value) in params {
+        if key == "info_hash" {
+            match <[u8; 20]>::try_from(value.as_slice()) {
+                Ok(info_hash) => info_hashes.push(info_hash),
+                Err(_) => return failure_response("Invalid info_hash"),
+            }
+        }
+    }
+
+    let entries = swarms.lock().unwrap().scrape(&info_hashes);
+    let files: Vec<(Vec<u8>, Value)> = entries.into_iter()
+        .map(|entry| (entry.info_hash.to_vec(), json!({
+            "complete": entry.complete,
+            "incomplete": entry.incomplete,
+            "downloaded": entry.downloaded,
+        })))
+        .collect();
+
+    // The files dictionary is keyed by raw info hashes and has to be spliced in by hand
+    let mut body = b"d5:files".to_vec();
+    body.extend_from_slice(&encode_bencoded_dict_with_byte_keys(&files)?);
+    body.extend_from_slice(b"e");
+    Ok(body)
+}
+
+fn failure_response(failure_reason: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    encode_bencoded_value(&json!({ "failure reason": general_purpose::STANDARD.encode(failure_reason) }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode_processing::decoder::decode_bencoded_value;
+    use crate::clients::client_config::ClientConfig;
+    use crate::clients::tracker_client::TrackerClient;
+
+    const INFO_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    async fn start_tracker() -> String {
+        let swarms = Arc::new(Mutex::new(SwarmStore::new(Duration::from_secs(60))));
+        let tracker = HttpTracker::bind("127.0.0.1:0", swarms).await.unwrap();
+        let announce_url = format!("http://{}/announce", tracker.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = tracker.run().await;
+        });
+        announce_url
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_scrape() {
+        let announce_url = start_tracker().await;
+
+        let (first, second, scrape_infos) = tokio::task::spawn_blocking(move || {
+            let mut second_config = ClientConfig::new();
+            second_config.set_port(6882);
+            let first_client = TrackerClient::new(announce_url.clone(), ClientConfig::new());
+            let second_client = TrackerClient::new(announce_url, second_config);
+            let first = first_client.request_peers(100, INFO_HASH.to_string()).unwrap();
+            let second = second_client.request_peers(100, INFO_HASH.to_string()).unwrap();
+            // Announcing again with the same peer id does not add another peer
+            second_client.request_peers(100, INFO_HASH.to_string()).unwrap();
+            let scrape_infos = first_client.scrape(&[INFO_HASH.to_string()]).unwrap();
+            (first, second, scrape_infos)
+        }).await.unwrap();
+
+        let first = decode_bencoded_value(&first, false).unwrap().0;
+        assert_eq!(first["peers"].as_str().unwrap(), "");
+
+        // The second client uses a different peer id and sees the first peer
+        let second = decode_bencoded_value(&second, false).unwrap().0;
+        let peers = crate::utils::extract_peers_from_base64_string(second["peers"].as_str().unwrap().to_string()).unwrap();
+        assert_eq!(peers, vec!["127.0.0.1:6881".to_string()]);
+        assert_eq!(second["incomplete"].as_i64(), Some(2));
+
+        assert_eq!(
+            scrape_infos[0].get_formatted_info(),
+            format!("Info Hash: {}\nSeeders: 0\nLeechers: 2\nCompleted: 0\n", INFO_HASH)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_connections_are_closed() {
+        let swarms = Arc::new(Mutex::new(SwarmStore::new(Duration::from_secs(60))));
+        let mut tracker = HttpTracker::bind("127.0.0.1:0", swarms).await.unwrap();
+        tracker.request_timeout = Duration::from_millis(100);
+        let address = tracker.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _ = tracker.run().await;
+        });
+
+        // a client sending part of a request and then nothing
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET /announce").await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut [0; 16])).await.unwrap();
+        assert!(matches!(closed, Ok(0) | Err(_)));
+    }
+
+    #[test]
+    fn test_non_compact_announce() {
+        let swarms = Mutex::new(SwarmStore::new(Duration::from_secs(60)));
+        let remote_address: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let params = |peer_id: &str| parse_query(&format!(
+            "info_hash=%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01&peer_id={}&port=6881&left=10&compact=0",
+            peer_id
+        ));
+
+        handle_announce(&params("AAAAAAAAAAAAAAAAAAAA"), remote_address, &swarms).unwrap();
+        let body = handle_announce(&params("BBBBBBBBBBBBBBBBBBBB"), remote_address, &swarms).unwrap();
+        let decoded = decode_bencoded_value(&body, true).unwrap().0;
+        assert_eq!(decoded["peers"][0]["ip"], "10.0.0.1");
+        assert_eq!(decoded["peers"][0]["peer id"], "AAAAAAAAAAAAAAAAAAAA");
+        assert_eq!(decoded["peers"][0]["port"], 6881);
+    }
+
+    #[test]
+    fn test_missing_info_hash_is_a_failure() {
+        let swarms = Mutex::new(SwarmStore::new(Duration::from_secs(60)));
+        let body = handle_announce(&parse_query("port=1"), "10.0.0.1:1".parse().unwrap(), &swarms).unwrap();
+        let decoded = decode_bencoded_value(&body, true).unwrap().0;
+        assert_eq!(decoded["failure reason"], "Missing or invalid info_hash");
+    }
+}
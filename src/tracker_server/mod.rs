@@ -0,0 +1,3 @@
+pub mod swarm;
+pub mod http_tracker;
+pub mod udp_tracker;
@@ -0,0 +1,228 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Number of peers returned when the client does not send numwant
+const DEFAULT_NUMWANT: usize = 50;
+// Upper bound for numwant regardless of what the client asks for
+const MAX_NUMWANT: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnnounceEvent {
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub address: SocketAddr,
+    pub left: i64,
+    pub event: AnnounceEvent,
+    pub numwant: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct SwarmPeer {
+    peer_id: [u8; 20],
+    address: SocketAddr,
+    left: i64,
+    last_seen: Instant,
+}
+
+impl SwarmPeer {
+    pub fn get_peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    pub fn get_address(&self) -> &SocketAddr {
+        &self.address
+    }
+}
+
+pub struct AnnounceResponse {
+    pub interval: u64,
+    pub complete: i64,
+    pub incomplete: i64,
+    pub peers: Vec<SwarmPeer>,
+}
+
+pub struct ScrapeEntry {
+    pub info_hash: [u8; 20],
+    pub complete: i64,
+    pub incomplete: i64,
+    pub downloaded: i64,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    downloaded: i64,
+}
+
+impl Swarm {
+    fn counts(&self) -> (i64, i64) {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as i64;
+        (complete, self.peers.len() as i64 - complete)
+    }
+}
+
+// In-memory state of all swarms served by the tracker
+pub struct SwarmStore {
+    swarms: HashMap<[u8; 20], Swarm>,
+    allowlist: Option<HashSet<[u8; 20]>>,
+    interval: Duration,
+    peer_timeout: Duration,
+}
+
+impl SwarmStore {
+    // Create a store; peers that have not announced within two intervals are dropped
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            swarms: HashMap::new(),
+            allowlist: None,
+            interval,
+            peer_timeout: interval * 2,
+        }
+    }
+
+    // Only serve the given info hashes
+    pub fn set_allowlist(&mut self, info_hashes: Vec<[u8; 20]>) {
+        self.allowlist = Some(info_hashes.into_iter().collect());
+    }
+
+    pub fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        self.allowlist.as_ref().is_none_or(|allowlist| allowlist.contains(info_hash))
+    }
+
+    // Register the announcing peer and return up to numwant other peers of the swarm
+    pub fn announce(&mut self, request: AnnounceRequest) -> Result<AnnounceResponse, String> {
+        if !self.is_allowed(&request.info_hash) {
+            return Err("Requested info hash is not served by this tracker".to_string());
+        }
+        self.expire_peers();
+
+        let swarm = self.swarms.entry(request.info_hash).or_default();
+        if request.event == AnnounceEvent::Stopped {
+            swarm.peers.remove(&request.peer_id);
+        } else {
+            if request.event == AnnounceEvent::Completed {
+                swarm.downloaded += 1;
+            }
+            swarm.peers.insert(request.peer_id, SwarmPeer {
+                peer_id: request.peer_id,
+                address: request.address,
+                left: request.left,
+                last_seen: Instant::now(),
+            });
+        }
+
+        let numwant = request.numwant.unwrap_or(DEFAULT_NUMWANT).min(MAX_NUMWANT);
+        let peers = swarm.peers.values()
+            .filter(|peer| peer.peer_id != request.peer_id)
+            // seeders have no use for other seeders
+            .filter(|peer| request.left > 0 || peer.left > 0)
+            .take(numwant)
+            .cloned()
+            .collect();
+        let (complete, incomplete) = swarm.counts();
+
+        Ok(AnnounceResponse { interval: self.interval.as_secs(), complete, incomplete, peers })
+    }
+
+    // Return statistics for the requested info hashes, or for all allowed swarms if none are given
+    pub fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Vec<ScrapeEntry> {
+        self.expire_peers();
+
+        let info_hashes: Vec<[u8; 20]> = if info_hashes.is_empty() {
+            self.swarms.keys().copied().collect()
+        } else {
+            info_hashes.to_vec()
+        };
+
+        info_hashes.into_iter()
+            .filter(|info_hash| self.is_allowed(info_hash))
+            .map(|info_hash| {
+                let (complete, incomplete, downloaded) = match self.swarms.get(&info_hash) {
+                    Some(swarm) => {
+                        let (complete, incomplete) = swarm.counts();
+                        (complete, incomplete, swarm.downloaded)
+                    }
+                    None => (0, 0, 0),
+                };
+                ScrapeEntry { info_hash, complete, incomplete, downloaded }
+            })
+            .collect()
+    }
+
+    // Drop peers that stopped announcing
+    fn expire_peers(&mut self) {
+        let peer_timeout = self.peer_timeout;
+        for swarm in self.swarms.values_mut() {
+            swarm.peers.retain(|_, peer| peer.last_seen.elapsed() < peer_timeout);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce_request(peer: u8, left: i64, event: AnnounceEvent) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [peer; 20],
+            address: SocketAddr::from(([127, 0, 0, 1], 6880 + peer as u16)),
+            left,
+            event,
+            numwant: None,
+        }
+    }
+
+    #[test]
+    fn test_announce_returns_other_peers() {
+        let mut store = SwarmStore::new(Duration::from_secs(60));
+        let response = store.announce(announce_request(1, 0, AnnounceEvent::Started)).unwrap();
+        assert!(response.peers.is_empty());
+
+        let response = store.announce(announce_request(2, 100, AnnounceEvent::Started)).unwrap();
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].get_peer_id(), &[1; 20]);
+        assert_eq!((response.complete, response.incomplete), (1, 1));
+    }
+
+    #[test]
+    fn test_announce_honours_numwant_and_stopped() {
+        let mut store = SwarmStore::new(Duration::from_secs(60));
+        for peer in 1..=5 {
+            store.announce(announce_request(peer, 100, AnnounceEvent::Started)).unwrap();
+        }
+        let mut request = announce_request(6, 100, AnnounceEvent::None);
+        request.numwant = Some(2);
+        assert_eq!(store.announce(request).unwrap().peers.len(), 2);
+
+        store.announce(announce_request(6, 100, AnnounceEvent::Stopped)).unwrap();
+        let entries = store.scrape(&[[1; 20]]);
+        assert_eq!((entries[0].complete, entries[0].incomplete), (0, 5));
+    }
+
+    #[test]
+    fn test_expired_peers_are_dropped() {
+        let mut store = SwarmStore::new(Duration::from_millis(5));
+        store.announce(announce_request(1, 100, AnnounceEvent::Started)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        let response = store.announce(announce_request(2, 100, AnnounceEvent::Started)).unwrap();
+        assert!(response.peers.is_empty());
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let mut store = SwarmStore::new(Duration::from_secs(60));
+        store.set_allowlist(vec![[2; 20]]);
+        assert!(store.announce(announce_request(1, 100, AnnounceEvent::Started)).is_err());
+        assert!(store.scrape(&[[1; 20]]).is_empty());
+    }
+}
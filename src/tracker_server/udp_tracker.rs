@@ -0,0 +1,306 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::net::UdpSocket;
+
+use crate::utils;
+use super::swarm::{AnnounceEvent, AnnounceRequest, SwarmStore};
+
+// Magic constant identifying the UDP tracker protocol (BEP 15)
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// Connection ids are valid for the current and the previous time slot, i.e. one to two minutes
+const CONNECTION_ID_SLOT_SECS: u64 = 60;
+
+const MAX_SCRAPE_HASHES: usize = 74;
+
+// UDP front end of the built-in tracker (BEP 15), sharing its swarms with the HTTP front end
+pub struct UdpTracker {
+    socket: UdpSocket,
+    swarms: Arc<Mutex<SwarmStore>>,
+    secret: Vec<u8>,
+}
+
+impl UdpTracker {
+    pub async fn bind(address: &str, swarms: Arc<Mutex<SwarmStore>>) -> Result<Self, Box<dyn Error>> {
+        let socket = UdpSocket::bind(address).await?;
+        Ok(Self { socket, swarms, secret: utils::random_bytes(20) })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // Answer requests until the task is dropped. A failed receive or send only costs the datagram
+    // at hand, e.g. when an earlier response was refused with an ICMP port unreachable
+    pub async fn run(self) -> Result<(), Box<dyn Error>> {
+        let mut buffer = vec![0u8; 2048];
+        loop {
+            let (received, remote_address) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("UDP tracker could not receive: {}", e);
+                    continue;
+                }
+            };
+            if let Some(response) = self.handle_packet(&buffer[..received], remote_address) {
+                if let Err(e) = self.socket.send_to(&response, remote_address).await {
+                    eprintln!("UDP tracker could not answer {}: {}", remote_address, e);
+                }
+            }
+        }
+    }
+
+    // Build the response for a single request; malformed packets are silently dropped
+    fn handle_packet(&self, packet: &[u8], remote_address: SocketAddr) -> Option<Vec<u8>> {
+        if packet.len() < 16 {
+            return None;
+        }
+        let connection_id = u64::from_be_bytes(packet[0..8].try_into().unwrap());
+        let action = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+        let transaction_id = u32::from_be_bytes(packet[12..16].try_into().unwrap());
+
+        if action == ACTION_CONNECT {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+            let mut response = response_header(ACTION_CONNECT, transaction_id);
+            response.extend_from_slice(&self.connection_id(&remote_address, current_slot()).to_be_bytes());
+            return Some(response);
+        }
+
+        if !self.is_valid_connection_id(connection_id, &remote_address) {
+            return Some(error_response(transaction_id, "Invalid connection id"));
+        }
+
+        let result = match action {
+            ACTION_ANNOUNCE => self.handle_announce(&packet[16..], remote_address, transaction_id),
+            ACTION_SCRAPE => self.handle_scrape(&packet[16..], transaction_id),
+            _ => Err("Unknown action".to_string()),
+        };
+        Some(result.unwrap_or_else(|message| error_response(transaction_id, &message)))
+    }
+
+    fn handle_announce(&self, body: &[u8], remote_address: SocketAddr, transaction_id: u32) -> Result<Vec<u8>, String> {
+        if body.len() < 82 {
+            return Err("Malformed announce request".to_string());
+        }
+        let event = match u32::from_be_bytes(body[64..68].try_into().unwrap()) {
+            1 => AnnounceEvent::Completed,
+            2 => AnnounceEvent::Started,
+            3 => AnnounceEvent::Stopped,
+            _ => AnnounceEvent::None,
+        };
+        let numwant = i32::from_be_bytes(body[76..80].try_into().unwrap());
+        let port = u16::from_be_bytes(body[80..82].try_into().unwrap());
+
+        let request = AnnounceRequest {
+            info_hash: body[0..20].try_into().unwrap(),
+            peer_id: body[20..40].try_into().unwrap(),
+            address: SocketAddr::new(remote_address.ip(), port),
+            left: i64::from_be_bytes(body[48..56].try_into().unwrap()),
+            event,
+            numwant: if numwant < 0 { None } else { Some(numwant as usize) },
+        };
+        let response = self.swarms.lock().unwrap().announce(request)?;
+
+        let mut packet = response_header(ACTION_ANNOUNCE, transaction_id);
+        packet.extend_from_slice(&(response.interval as u32).to_be_bytes());
+        packet.extend_from_slice(&(response.incomplete as u32).to_be_bytes());
+        packet.extend_from_slice(&(response.complete as u32).to_be_bytes());
+        // Only peers of the same address family as the request fit into the response
+        for peer in &response.peers {
+            match (peer.get_address(), remote_address) {
+                (SocketAddr::V4(address), SocketAddr::V4(_)) => {
+                    packet.extend_from_slice(&address.ip().octets());
+                    packet.extend_from_slice(&address.port().to_be_bytes());
+                }
+                (SocketAddr::V6(address), SocketAddr::V6(_)) => {
+                    packet.extend_from_slice(&address.ip().octets());
+                    packet.extend_from_slice(&address.port().to_be_bytes());
+                }
+                _ => {}
+            }
+        }
+        Ok(packet)
+    }
+
+    fn handle_scrape(&self, body: &[u8], transaction_id: u32) -> Result<Vec<u8>, String> {
+        let info_hashes: Vec<[u8; 20]> = body.chunks_exact(20)
+            .take(MAX_SCRAPE_HASHES)
+            .map(|chunk| chunk.try_into().unwrap())
+            .collect();
+        if info_hashes.is_empty() {
+            return Err("No info hash given".to_string());
+        }
+
+        let entries = self.swarms.lock().unwrap().scrape(&info_hashes);
+        if entries.len() != info_hashes.len() {
+            return Err("Requested info hash is not served by this tracker".to_string());
+        }
+
+        let mut packet = response_header(ACTION_SCRAPE, transaction_id);
+        for entry in entries {
+            packet.extend_from_slice(&(entry.complete as u32).to_be_bytes());
+            packet.extend_from_slice(&(entry.downloaded as u32).to_be_bytes());
+            packet.extend_from_slice(&(entry.incomplete as u32).to_be_bytes());
+        }
+        Ok(packet)
+    }
+
+    // Connection ids are an HMAC of the client address and the time slot, so no state is kept
+    fn connection_id(&self, remote_address: &SocketAddr, slot: u64) -> u64 {
+        let mut data = remote_address.to_string().into_bytes();
+        data.extend_from_slice(&slot.to_be_bytes());
+        let hmac = utils::calculate_hmac_sha1(&self.secret, &data);
+        u64::from_be_bytes(hmac[0..8].try_into().unwrap())
+    }
+
+    fn is_valid_connection_id(&self, connection_id: u64, remote_address: &SocketAddr) -> bool {
+        let slot = current_slot();
+        connection_id == self.connection_id(remote_address, slot)
+            || connection_id == self.connection_id(remote_address, slot.saturating_sub(1))
+    }
+}
+
+fn current_slot() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / CONNECTION_ID_SLOT_SECS
+}
+
+fn response_header(action: u32, transaction_id: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(16);
+    header.extend_from_slice(&action.to_be_bytes());
+    header.extend_from_slice(&transaction_id.to_be_bytes());
+    header
+}
+
+fn error_response(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut packet = response_header(ACTION_ERROR, transaction_id);
+    packet.extend_from_slice(message.as_bytes());
+    packet
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::clients::client_config::ClientConfig;
+    use crate::clients::tracker_client::TrackerClient;
+    use crate::tracker_server::http_tracker::HttpTracker;
+
+    const INFO_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    fn connect_packet() -> Vec<u8> {
+        let mut packet = PROTOCOL_ID.to_be_bytes().to_vec();
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        packet.extend_from_slice(&7u32.to_be_bytes());
+        packet
+    }
+
+    fn announce_packet(connection_id: u64, peer_id: u8, port: u16) -> Vec<u8> {
+        let mut packet = connection_id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend_from_slice(&8u32.to_be_bytes());
+        packet.extend_from_slice(&hex::decode(INFO_HASH).unwrap());
+        packet.extend_from_slice(&[peer_id; 20]);
+        packet.extend_from_slice(&0u64.to_be_bytes()); // downloaded
+        packet.extend_from_slice(&100u64.to_be_bytes()); // left
+        packet.extend_from_slice(&0u64.to_be_bytes()); // uploaded
+        packet.extend_from_slice(&2u32.to_be_bytes()); // started
+        packet.extend_from_slice(&[0; 8]); // ip and key
+        packet.extend_from_slice(&(-1i32).to_be_bytes());
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet
+    }
+
+    async fn new_tracker(swarms: Arc<Mutex<SwarmStore>>) -> UdpTracker {
+        UdpTracker::bind("127.0.0.1:0", swarms).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_and_announce() {
+        let tracker = new_tracker(Arc::new(Mutex::new(SwarmStore::new(Duration::from_secs(60))))).await;
+        let remote_address: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+
+        let response = tracker.handle_packet(&connect_packet(), remote_address).unwrap();
+        assert_eq!(&response[0..8], &[0, 0, 0, 0, 0, 0, 0, 7]);
+        let connection_id = u64::from_be_bytes(response[8..16].try_into().unwrap());
+
+        tracker.handle_packet(&announce_packet(connection_id, 1, 7001), remote_address).unwrap();
+        let response = tracker.handle_packet(&announce_packet(connection_id, 2, 7002), remote_address).unwrap();
+        assert_eq!(u32::from_be_bytes(response[0..4].try_into().unwrap()), ACTION_ANNOUNCE);
+        assert_eq!(u32::from_be_bytes(response[12..16].try_into().unwrap()), 2); // leechers
+        assert_eq!(&response[20..], &[127, 0, 0, 1, 0x1b, 0x59]);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_foreign_connection_id() {
+        let tracker = new_tracker(Arc::new(Mutex::new(SwarmStore::new(Duration::from_secs(60))))).await;
+        let response = tracker.handle_packet(&connect_packet(), "127.0.0.1:40000".parse().unwrap()).unwrap();
+        let connection_id = u64::from_be_bytes(response[8..16].try_into().unwrap());
+
+        // The same connection id presented from another address is invalid
+        let response = tracker.handle_packet(&announce_packet(connection_id, 1, 7001), "127.0.0.1:40001".parse().unwrap()).unwrap();
+        assert_eq!(u32::from_be_bytes(response[0..4].try_into().unwrap()), ACTION_ERROR);
+        assert_eq!(&response[8..], b"Invalid connection id");
+    }
+
+    #[tokio::test]
+    async fn test_keeps_answering_after_a_refused_response() {
+        let tracker = new_tracker(Arc::new(Mutex::new(SwarmStore::new(Duration::from_secs(60))))).await;
+        let tracker_address = tracker.local_addr().unwrap();
+        let running = tokio::spawn(async move { tracker.run().await.is_ok() });
+
+        // the first client is gone before the answer arrives, so it is refused
+        let gone = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        gone.send_to(&connect_packet(), tracker_address).await.unwrap();
+        drop(gone);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(tracker_address).await.unwrap();
+        let mut response = [0u8; 16];
+        for _ in 0..3 {
+            client.send(&connect_packet()).await.unwrap();
+            if let Ok(Ok(length)) = tokio::time::timeout(Duration::from_millis(500), client.recv(&mut response)).await {
+                assert_eq!(length, 16);
+                assert!(!running.is_finished());
+                return;
+            }
+        }
+        panic!("Tracker stopped answering");
+    }
+
+    #[tokio::test]
+    async fn test_scrape_sees_http_announces() {
+        let swarms = Arc::new(Mutex::new(SwarmStore::new(Duration::from_secs(60))));
+        let http_tracker = HttpTracker::bind("127.0.0.1:0", swarms.clone()).await.unwrap();
+        let udp_tracker = new_tracker(swarms).await;
+        let announce_url = format!("http://{}/announce", http_tracker.local_addr().unwrap());
+        let udp_url = format!("udp://{}/announce", udp_tracker.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = http_tracker.run().await;
+        });
+        tokio::spawn(async move {
+            let _ = udp_tracker.run().await;
+        });
+
+        let scrape_infos = tokio::task::spawn_blocking(move || {
+            TrackerClient::new(announce_url, ClientConfig::new()).request_peers(100, INFO_HASH.to_string()).unwrap();
+            TrackerClient::new(udp_url, ClientConfig::new()).scrape(&[INFO_HASH.to_string()]).unwrap()
+        }).await.unwrap();
+
+        assert_eq!(
+            scrape_infos[0].get_formatted_info(),
+            format!("Info Hash: {}\nSeeders: 0\nLeechers: 1\nCompleted: 0\n", INFO_HASH)
+        );
+    }
+}
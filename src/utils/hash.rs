@@ -0,0 +1,37 @@
+use sha1::{Digest, Sha1};
+
+use super::sha256::calculate_sha256_hash;
+
+// The hash functions torrents are verified with: SHA-1 for v1 torrents, SHA-256 for v2 torrents
+// (BEP 52)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha1 => Sha1::digest(data).to_vec(),
+            HashAlgorithm::Sha256 => calculate_sha256_hash(data).to_vec(),
+        }
+    }
+}
+
+// Calculates the hash of data and returns it hex encoded
+pub fn calculate_hash(algorithm: HashAlgorithm, data: &[u8]) -> String {
+    hex::encode(algorithm.digest(data))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calculate_hash() {
+        assert_eq!(calculate_hash(HashAlgorithm::Sha1, b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(calculate_hash(HashAlgorithm::Sha256, b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
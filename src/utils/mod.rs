@@ -3,4 +3,14 @@ pub use self::utils::calculate_sha1_hash;
 pub use self::utils::extract_peers_from_base64_string;
 pub use self::utils::hex_to_byte_representation;
 pub use self::utils::calculate_sha1_hash_with_ref;
-mod utils;
\ No newline at end of file
+pub use self::utils::random_bytes;
+pub use self::utils::random_below;
+pub use self::utils::calculate_hmac_sha1;
+pub use self::utils::glob_to_regex;
+pub use self::utils::has_bit;
+pub use self::utils::set_bit;
+pub use self::hash::{calculate_hash, HashAlgorithm};
+pub use self::sha256::calculate_sha256_hash;
+mod hash;
+mod sha256;
+mod utils;
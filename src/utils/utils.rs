@@ -6,6 +6,8 @@ use base64::{engine::general_purpose, Engine};
 use anyhow::{anyhow, Ok, Result};
 use sha1::{Sha1, Digest};
 
+use super::hash::{calculate_hash, HashAlgorithm};
+
 pub fn decode_base64_to_utf8_string(base64_string: &str) -> Result<String> {
     let bytes_string = general_purpose::STANDARD.decode(base64_string).map_err(|e| anyhow!(e))?;
     let utf8_string = std::str::from_utf8(&bytes_string).map_err(|e| anyhow!(e))?;
@@ -20,20 +22,30 @@ pub fn decode_base64_to_hex(base64_string: &str) -> Result<String> {
 
 // Calculates sha1 hash from binary and returns it hex encoded
 pub fn calculate_sha1_hash(data:Vec<u8>) -> String {
-    let mut hasher = Sha1::new();
-    hasher.update(data);
-    let result = hasher.finalize();
-    let hex_string = hex::encode(result);
-    hex_string
+    calculate_hash(HashAlgorithm::Sha1, &data)
 }
 
 // Calculates sha1 hash from binary and returns it hex encoded
 pub fn calculate_sha1_hash_with_ref(data: &Vec<u8>) -> String {
-    let mut hasher = Sha1::new();
-    hasher.update(data);
-    let result = hasher.finalize();
-    let hex_string = hex::encode(result);
-    hex_string
+    calculate_hash(HashAlgorithm::Sha1, data)
+}
+
+// Calculates the HMAC-SHA1 (RFC 2104) of data with the given key
+pub fn calculate_hmac_sha1(key: &[u8], data: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block_key = if key.len() > BLOCK_SIZE { Sha1::digest(key).to_vec() } else { key.to_vec() };
+    block_key.resize(BLOCK_SIZE, 0);
+
+    let mut inner = Sha1::new();
+    inner.update(block_key.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(data);
+    let inner_hash = inner.finalize();
+
+    let mut outer = Sha1::new();
+    outer.update(block_key.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner_hash);
+    outer.finalize().to_vec()
 }
 
 pub fn extract_peers_from_base64_string(peers_base64: String) -> Result<Vec<String>> {
@@ -66,4 +78,91 @@ pub fn hex_to_byte_representation(data: &String) -> Vec<u8> {
 pub fn byte_vector_to_utf8_string(byte_vector: Vec<u8>) -> Result<String> {
     let utf8_string = String::from_utf8(byte_vector).unwrap();
     Ok(utf8_string)
-}
\ No newline at end of file
+}
+// Returns a vector of cryptographically secure random bytes
+pub fn random_bytes(size: usize) -> Vec<u8> {
+    nanoid::rngs::default(size)
+}
+
+// A random number in 0..bound
+pub fn random_below(bound: usize) -> usize {
+    let random = u64::from_be_bytes(random_bytes(8).try_into().unwrap());
+    (random % bound as u64) as usize
+}
+
+// Whether the bit of a piece is set in a bitfield, the high bit of the first byte being piece 0
+pub fn has_bit(bitfield: &[u8], index: usize) -> bool {
+    bitfield.get(index / 8).is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+}
+
+// Set the bit of a piece in a bitfield; indexes past its end are ignored
+pub fn set_bit(bitfield: &mut [u8], index: usize) {
+    if let Some(byte) = bitfield.get_mut(index / 8) {
+        *byte |= 0x80 >> (index % 8);
+    }
+}
+
+// Translates a file glob into an anchored regex: '*' and '?' stay within one path component,
+// '**' matches across components
+pub fn glob_to_regex(glob: &str) -> Result<regex::Regex> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // "**/" also matches no directory at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    pattern.push_str("(?:.*/)?");
+                } else {
+                    pattern.push_str(".*");
+                }
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    regex::Regex::new(&pattern).map_err(|e| anyhow!(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_to_regex() {
+        let glob = glob_to_regex("*.flac").unwrap();
+        assert!(glob.is_match("track1.flac"));
+        assert!(!glob.is_match("cd1/track1.flac"));
+        assert!(!glob.is_match("track1.flac.txt"));
+
+        let glob = glob_to_regex("**/*.flac").unwrap();
+        assert!(glob.is_match("track1.flac"));
+        assert!(glob.is_match("cd1/disc/track1.flac"));
+
+        let glob = glob_to_regex("cd?/**").unwrap();
+        assert!(glob.is_match("cd1/track1.flac"));
+        assert!(!glob.is_match("cd10/track1.flac"));
+        assert!(glob_to_regex("a+(b).txt").unwrap().is_match("a+(b).txt"));
+    }
+
+    #[test]
+    fn test_bitfield_bits() {
+        let mut bitfield = vec![0; 2];
+        set_bit(&mut bitfield, 1);
+        set_bit(&mut bitfield, 10);
+        set_bit(&mut bitfield, usize::MAX);
+        assert_eq!(bitfield, vec![0x40, 0x20]);
+        assert!(has_bit(&bitfield, 10) && !has_bit(&bitfield, 0) && !has_bit(&bitfield, 100));
+    }
+
+    #[test]
+    fn test_hmac_sha1() {
+        // Test case 2 of RFC 2202
+        let hmac = calculate_hmac_sha1(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(hex::encode(hmac), "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79");
+    }
+}